@@ -6,10 +6,14 @@ use crate::plan::PlanError;
 use crate::grid::{
     gridview::{GridSubView, GridView},
     location::yx,
-    Blob, Droplet, DropletId, Grid, Location, Peripheral, SimpleBlob,
+    sizing::dimensions_for_volume,
+    Blob, Droplet, DropletId, Electrode, Grid, Location, Peripheral, Rectangle, SimpleBlob,
 };
 
-use crate::process::PuddleResult;
+use crate::process::{recorder::RecordedCommand, PuddleError, PuddleResult};
+use crate::util::seconds_duration;
+use indexmap::IndexSet;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug)]
 pub struct CommandRequest {
@@ -24,9 +28,17 @@ pub struct CommandRequest {
 pub enum RunStatus {
     Done,
     KeepGoing,
+    // done, but hold this step for at least this long
+    Hold(Duration),
+    // not done, but hold this step for at least this long
+    Wait(Duration),
 }
 
 pub trait Command: fmt::Debug + Send {
+    /// The command's type, like "Move", for telling commands apart in
+    /// things like `plan::Metrics`.
+    fn type_name(&self) -> &'static str;
+
     fn input_droplets(&self) -> Vec<DropletId> {
         vec![]
     }
@@ -37,6 +49,34 @@ pub trait Command: fmt::Debug + Send {
         false
     }
 
+    /// Whether the inputs are about to be merged, so the router should let
+    /// them get close to each other.
+    fn merges_inputs(&self) -> bool {
+        false
+    }
+
+    /// How hot this command gets its input droplets, if it heats them.
+    fn temperature(&self) -> Option<f32> {
+        None
+    }
+
+    /// The temperature the inputs should be at right now, for commands
+    /// that change it as they go.
+    fn setpoint(&self) -> Option<f32> {
+        self.temperature()
+    }
+
+    /// A GPIO to pulse right before this command's droplets start moving.
+    fn sync_pin(&self) -> Option<u32> {
+        None
+    }
+
+    /// The share of the input volume each output gets, if this splits a
+    /// droplet.
+    fn split_fractions(&self) -> Vec<f64> {
+        vec![]
+    }
+
     fn request(&self, gridview: &GridView) -> CommandRequest;
 
     // FIXME this is definitely a hack for combining droplets
@@ -48,6 +88,12 @@ pub trait Command: fmt::Debug + Send {
 
     fn finalize(&mut self, _: &GridSubView) {}
 
+    /// A copy of this command that can be written out and replayed later,
+    /// if it supports that. See `process::recorder`.
+    fn record(&self) -> Option<RecordedCommand> {
+        None
+    }
+
     fn abort(&mut self, err: PlanError) {
         error!("Aborting command {:?} with {:#?}", self, err);
     }
@@ -59,7 +105,7 @@ pub type BoxedCommand = Box<dyn Command>;
 //  Create
 //
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Create {
     inputs: Vec<DropletId>,
     outputs: Vec<DropletId>,
@@ -68,7 +114,6 @@ pub struct Create {
     volume: f64,
 }
 
-// TODO: dimensions probably shouldn't be optional?
 impl Create {
     pub fn new(
         loc: Option<Location>,
@@ -87,6 +132,14 @@ impl Create {
 }
 
 impl Command for Create {
+    fn type_name(&self) -> &'static str {
+        "Create"
+    }
+
+    fn record(&self) -> Option<RecordedCommand> {
+        Some(self.clone().into())
+    }
+
     fn input_droplets(&self) -> Vec<DropletId> {
         self.inputs.clone()
     }
@@ -117,15 +170,90 @@ impl Command for Create {
     }
 }
 
+/// Makes a droplet by flooding a whole region and then pulling it back in
+/// to the middle, for chips where droplets self-assemble like that.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateFromRegion {
+    outputs: Vec<DropletId>,
+    location: Location,
+    dimensions: Location,
+    volume: f64,
+    state: usize,
+}
+
+impl CreateFromRegion {
+    pub fn new(region: Rectangle, vol: f64, out_id: DropletId) -> PuddleResult<CreateFromRegion> {
+        Ok(CreateFromRegion {
+            outputs: vec![out_id],
+            location: region.location,
+            dimensions: region.dimensions,
+            volume: vol,
+            state: 0,
+        })
+    }
+
+    /// Where the droplet ends up, relative to the region.
+    fn center(&self) -> Location {
+        yx((self.dimensions.y - 1) / 2, (self.dimensions.x - 1) / 2)
+    }
+}
+
+impl Command for CreateFromRegion {
+    fn type_name(&self) -> &'static str {
+        "CreateFromRegion"
+    }
+
+    fn record(&self) -> Option<RecordedCommand> {
+        Some(self.clone().into())
+    }
+
+    fn input_droplets(&self) -> Vec<DropletId> {
+        vec![]
+    }
+
+    fn output_droplets(&self) -> Vec<DropletId> {
+        self.outputs.clone()
+    }
+
+    fn request(&self, _gridview: &GridView) -> CommandRequest {
+        let grid = Grid::rectangle(self.dimensions.y as usize, self.dimensions.x as usize);
+
+        CommandRequest {
+            name: format!("create_from_region -> {:?}", self.outputs[0]),
+            shape: grid,
+            input_locations: vec![],
+            offset: Some(self.location),
+        }
+    }
+
+    fn run(&mut self, gridview: &mut GridSubView) -> RunStatus {
+        let out = self.outputs[0];
+        if self.state == 0 {
+            self.state += 1;
+            // energize the whole region for a step by covering it
+            let flood = Droplet::new(out, self.volume, yx(0, 0), self.dimensions);
+            gridview.insert(flood);
+            RunStatus::KeepGoing
+        } else {
+            // then only the center, so it all pulls together there
+            gridview.remove(&out);
+            let droplet = Droplet::new(out, self.volume, self.center(), yx(1, 1));
+            gridview.insert(droplet);
+            RunStatus::Done
+        }
+    }
+}
+
 //
 //  Move
 //
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Move {
     inputs: Vec<DropletId>,
     outputs: Vec<DropletId>,
     destination: [Location; 1],
+    sync_pin: Option<u32>,
 }
 
 impl Move {
@@ -134,11 +262,26 @@ impl Move {
             inputs: vec![in_id],
             outputs: vec![out_id],
             destination: [loc],
+            sync_pin: None,
         })
     }
+
+    /// Pulses `pin` right before the droplet starts moving.
+    pub fn with_sync(mut self, pin: u32) -> Move {
+        self.sync_pin = Some(pin);
+        self
+    }
 }
 
 impl Command for Move {
+    fn type_name(&self) -> &'static str {
+        "Move"
+    }
+
+    fn record(&self) -> Option<RecordedCommand> {
+        Some(self.clone().into())
+    }
+
     fn input_droplets(&self) -> Vec<DropletId> {
         self.inputs.clone()
     }
@@ -147,6 +290,10 @@ impl Command for Move {
         self.outputs.clone()
     }
 
+    fn sync_pin(&self) -> Option<u32> {
+        self.sync_pin
+    }
+
     fn request(&self, gridview: &GridView) -> CommandRequest {
         let old_id = self.inputs[0];
         let dim = gridview.droplets[&old_id].dimensions;
@@ -169,11 +316,110 @@ impl Command for Move {
     }
 }
 
+//
+//  Wash
+//
+
+/// Drags a droplet of buffer over `path`, one electrode per step, to clean
+/// up after whatever went over it before. The path should already be
+/// checked to be contiguous, and the buffer to be a single electrode, see
+/// `Process::wash`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Wash {
+    inputs: Vec<DropletId>,
+    outputs: Vec<DropletId>,
+    // relative to `offset`, the top left of the path's bounding box
+    path: Vec<Location>,
+    offset: Location,
+    step: usize,
+}
+
+impl Wash {
+    pub fn new(buffer: DropletId, path: Vec<Location>, out_id: DropletId) -> PuddleResult<Wash> {
+        if path.is_empty() {
+            return Err(PuddleError::InvalidPath(path));
+        }
+        let offset = yx(
+            path.iter().map(|l| l.y).min().unwrap(),
+            path.iter().map(|l| l.x).min().unwrap(),
+        );
+        Ok(Wash {
+            inputs: vec![buffer],
+            outputs: vec![out_id],
+            path: path.into_iter().map(|l| l - offset).collect(),
+            offset,
+            step: 0,
+        })
+    }
+}
+
+impl Command for Wash {
+    fn type_name(&self) -> &'static str {
+        "Wash"
+    }
+
+    fn record(&self) -> Option<RecordedCommand> {
+        Some(self.clone().into())
+    }
+
+    fn input_droplets(&self) -> Vec<DropletId> {
+        self.inputs.clone()
+    }
+
+    fn output_droplets(&self) -> Vec<DropletId> {
+        self.outputs.clone()
+    }
+
+    fn request(&self, gridview: &GridView) -> CommandRequest {
+        let d = &gridview.droplets[&self.inputs[0]];
+
+        // only the path itself is reserved, so others can work around it
+        let height = self.path.iter().map(|l| l.y).max().unwrap() + 1;
+        let width = self.path.iter().map(|l| l.x).max().unwrap() + 1;
+        let shape = Grid::from_function(
+            |loc| {
+                if self.path.contains(&loc) {
+                    Some(Electrode {
+                        pin: 0,
+                        peripheral: None,
+                        enabled: true,
+                    })
+                } else {
+                    None
+                }
+            },
+            height as usize,
+            width as usize,
+        );
+
+        CommandRequest {
+            name: format!("wash({:?})", d.id),
+            shape,
+            input_locations: vec![self.path[0]],
+            offset: Some(self.offset),
+        }
+    }
+
+    fn run(&mut self, gridview: &mut GridSubView) -> RunStatus {
+        self.step += 1;
+        let mut d = gridview.remove(&self.inputs[0]);
+        if self.step < self.path.len() {
+            d.location = self.path[self.step];
+            gridview.insert(d);
+            RunStatus::KeepGoing
+        } else {
+            d.id = self.outputs[0];
+            gridview.insert(d);
+            RunStatus::Done
+        }
+    }
+}
+
 //
 //  Combine
 //
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Combine {
     inputs: Vec<DropletId>,
     outputs: Vec<DropletId>,
@@ -217,9 +463,26 @@ impl Combine {
             volume: d0.volume + d1.volume,
         }
     }
+
+    // the exact cells of the combined droplet, relative to its location:
+    // d1 is stacked on top of d0
+    fn combined_cells(&self, d0: &Droplet, d1: &Droplet) -> IndexSet<Location> {
+        let d0_offset = yx(d1.dimensions.y, 0);
+        let mut cells = d1.relative_cells();
+        cells.extend(d0.relative_cells().into_iter().map(|c| c + d0_offset));
+        cells
+    }
 }
 
 impl Command for Combine {
+    fn type_name(&self) -> &'static str {
+        "Combine"
+    }
+
+    fn record(&self) -> Option<RecordedCommand> {
+        Some(self.clone().into())
+    }
+
     fn input_droplets(&self) -> Vec<DropletId> {
         self.inputs.clone()
     }
@@ -228,6 +491,10 @@ impl Command for Combine {
         self.outputs.clone()
     }
 
+    fn merges_inputs(&self) -> bool {
+        true
+    }
+
     // FIXME remove bypass
     // fn bypass(&self, gridview: &GridView) -> bool {
     //     let droplets = &gridview.snapshot().droplets;
@@ -304,7 +571,13 @@ impl Command for Combine {
 
         // assert_eq!(d0.location.y, d1.location.y);
         // assert_eq!(d0.location.x + d0.dimensions.x, d1.location.x);
-        gridview.insert(combined.to_droplet(out));
+        let mut droplet = combined.to_droplet(out);
+        droplet.cells = Some(self.combined_cells(&d0, &d1));
+        droplet.tags = d0.mixed_tags(&d1);
+        droplet.inherit_temperatures(&[&d0, &d1]);
+        droplet.inherit_label(&[&d0, &d1]);
+        gridview.insert(droplet);
+        gridview.regroup_merged(out, &[d0.collision_group, d1.collision_group]);
         RunStatus::Done
     }
 }
@@ -313,30 +586,54 @@ impl Command for Combine {
 //  Agitate
 //
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Agitate {
     inputs: Vec<DropletId>,
     outputs: Vec<DropletId>,
     n_agitation_loops: u32,
+    // how many electrodes to go each way
+    #[serde(default = "default_intensity")]
+    intensity: u32,
     current_step: usize,
     current_loop: usize,
 }
 
+fn default_intensity() -> u32 {
+    1
+}
+
 impl Agitate {
-    pub fn new(in_id: DropletId, out_id: DropletId) -> PuddleResult<Agitate> {
+    /// Moves the droplet around a square `intensity` electrodes on a side,
+    /// `cycles` times. Both have to be at least 1.
+    pub fn new(
+        in_id: DropletId,
+        out_id: DropletId,
+        intensity: u32,
+        cycles: u32,
+    ) -> PuddleResult<Agitate> {
+        if intensity == 0 || cycles == 0 {
+            return Err(PuddleError::InvalidAgitation(intensity, cycles));
+        }
         Ok(Agitate {
             inputs: vec![in_id],
             outputs: vec![out_id],
-            n_agitation_loops: 1,
+            n_agitation_loops: cycles,
+            intensity,
             current_step: 0,
             current_loop: 0,
         })
     }
 }
 
-const AGITATE_PADDING: usize = 1;
-
 impl Command for Agitate {
+    fn type_name(&self) -> &'static str {
+        "Agitate"
+    }
+
+    fn record(&self) -> Option<RecordedCommand> {
+        Some(self.clone().into())
+    }
+
     fn input_droplets(&self) -> Vec<DropletId> {
         self.inputs.clone()
     }
@@ -351,8 +648,8 @@ impl Command for Agitate {
         CommandRequest {
             name: format!("agitate({:?})", self.inputs[0]),
             shape: Grid::rectangle(
-                droplet.dimensions.y as usize + AGITATE_PADDING,
-                droplet.dimensions.x as usize + AGITATE_PADDING,
+                droplet.dimensions.y as usize + self.intensity as usize,
+                droplet.dimensions.x as usize + self.intensity as usize,
             ),
             input_locations: vec![yx(0, 0)],
             offset: None,
@@ -362,7 +659,8 @@ impl Command for Agitate {
     fn run(&mut self, gridview: &mut GridSubView) -> RunStatus {
         let in_id = self.inputs[0];
 
-        match self.current_step {
+        let side = self.intensity as usize;
+        match self.current_step / side {
             0 => gridview.move_south(in_id),
             1 => gridview.move_east(in_id),
             2 => gridview.move_north(in_id),
@@ -372,7 +670,7 @@ impl Command for Agitate {
 
         self.current_step += 1;
 
-        if self.current_step == 4 {
+        if self.current_step == 4 * side {
             self.current_step = 0;
             self.current_loop += 1;
             if self.current_loop < self.n_agitation_loops as usize {
@@ -394,26 +692,92 @@ impl Command for Agitate {
 //  Split
 //
 
-#[derive(Debug)]
+/// Which way a split pulls the droplet apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SplitAxis {
+    /// daughters end up side by side, one west and one east
+    Horizontal,
+    /// daughters end up stacked, one north and one south
+    Vertical,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Split {
     inputs: Vec<DropletId>,
     outputs: Vec<DropletId>,
+    // the fraction of the volume that goes to the first output
+    ratio: f64,
+    axis: SplitAxis,
     state: usize,
 }
 
 impl Split {
     pub fn new(id: DropletId, out_id1: DropletId, out_id2: DropletId) -> PuddleResult<Split> {
+        Split::with_ratio(id, out_id1, out_id2, 0.5)
+    }
+
+    /// Splits so that `ratio` of the volume goes to `out_id1`.
+    pub fn with_ratio(
+        id: DropletId,
+        out_id1: DropletId,
+        out_id2: DropletId,
+        ratio: f64,
+    ) -> PuddleResult<Split> {
+        if !(ratio > 0.0 && ratio < 1.0) {
+            return Err(PuddleError::InvalidFraction(ratio));
+        }
         Ok(Split {
             inputs: vec![id],
             outputs: vec![out_id1, out_id2],
+            ratio,
+            axis: SplitAxis::Vertical,
             state: 0,
         })
     }
+
+    pub fn with_axis(mut self, axis: SplitAxis) -> Split {
+        self.axis = axis;
+        self
+    }
+
+    // the region to reserve for splitting a droplet with these dimensions
+    fn shape(&self, dimensions: Location) -> Location {
+        match self.axis {
+            SplitAxis::Vertical => dimensions + yx(SPLIT_PADDING, 0),
+            SplitAxis::Horizontal => dimensions + yx(0, SPLIT_PADDING),
+        }
+    }
+
+    /// Where the two daughters go within the requested shape, and how big
+    /// they are.
+    pub(crate) fn daughters(&self, dimensions: Location) -> (Location, Location, Location) {
+        let shape = self.shape(dimensions);
+        // TODO: this should be related to volume in some fashion
+        // currently, take the ceiling of the division of the split by two
+        match self.axis {
+            SplitAxis::Vertical => {
+                let dim = yx((dimensions.y + 1) / 2, dimensions.x);
+                (yx(1, 0), yx(shape.y - (dim.y + 1), 0), dim)
+            }
+            SplitAxis::Horizontal => {
+                let dim = yx(dimensions.y, (dimensions.x + 1) / 2);
+                (yx(0, 1), yx(0, shape.x - (dim.x + 1)), dim)
+            }
+        }
+    }
 }
 
-const SPLIT_PADDING: usize = 4;
+const SPLIT_PADDING: i32 = 4;
 
 impl Command for Split {
+    fn type_name(&self) -> &'static str {
+        "Split"
+    }
+
+    fn record(&self) -> Option<RecordedCommand> {
+        Some(self.clone().into())
+    }
+
     fn input_droplets(&self) -> Vec<DropletId> {
         self.inputs.clone()
     }
@@ -422,6 +786,10 @@ impl Command for Split {
         self.outputs.clone()
     }
 
+    fn split_fractions(&self) -> Vec<f64> {
+        vec![self.ratio, 1.0 - self.ratio]
+    }
+
     // FIXME skip bypass
     // fn bypass(&self, gridview: &GridView) -> bool {
     //     let droplets = &gridview.snapshot().droplets;
@@ -438,12 +806,14 @@ impl Command for Split {
 
     fn request(&self, gridview: &GridView) -> CommandRequest {
         let d0 = &gridview.droplets[&self.inputs[0]];
-        // we only split in the y right now, so we don't need x padding
-        let x_dim = d0.dimensions.x as usize;
-        let y_dim = (d0.dimensions.y as usize) + SPLIT_PADDING;
-        let grid = Grid::rectangle(y_dim, x_dim);
+        // only pad along the axis we're splitting on
+        let shape = self.shape(d0.dimensions);
+        let grid = Grid::rectangle(shape.y as usize, shape.x as usize);
 
-        let input_locations = vec![yx(2, 0)];
+        let input_locations = match self.axis {
+            SplitAxis::Vertical => vec![yx(2, 0)],
+            SplitAxis::Horizontal => vec![yx(0, 2)],
+        };
 
         CommandRequest {
             name: format!("split({:?})", self.inputs[0]),
@@ -461,27 +831,21 @@ impl Command for Split {
         if self.state == 0 {
             self.state += 1;
 
-            let y_dim = {
-                // limit the scope of d0 borrow
-                let d0 = gridview.get(&self.inputs[0]);
-                (d0.dimensions.y as usize) + SPLIT_PADDING
-            };
-
             let d = gridview.remove(&inp);
-            let vol = d.volume / 2.0;
-
-            // TODO: this should be related to volume in some fashion
-            // currently, take the ceiling of the division of the split by two
-            let dim = Location {
-                x: d.dimensions.x,
-                y: (d.dimensions.y + 1) / 2,
-            };
-
-            let loc0 = yx(1, 0);
-            let loc1 = yx(y_dim as i32 - (dim.y + 1), 0);
-
-            gridview.insert(Droplet::new(out0, vol, loc0, dim));
-            gridview.insert(Droplet::new(out1, vol, loc1, dim));
+            let vol0 = d.volume * self.ratio;
+            let vol1 = d.volume - vol0;
+
+            let (loc0, loc1, dim) = self.daughters(d.dimensions);
+
+            // the daughters always fill their whole rectangle
+            for &(id, vol, loc) in &[(out0, vol0, loc0), (out1, vol1, loc1)] {
+                let mut droplet = Droplet::new(id, vol, loc, dim);
+                droplet.cells = Some(droplet.relative_cells());
+                droplet.tags = d.tags.clone();
+                droplet.inherit_temperatures(&[&d]);
+                droplet.inherit_label(&[&d]);
+                gridview.insert(droplet);
+            }
 
             RunStatus::KeepGoing
         } else {
@@ -493,7 +857,111 @@ impl Command for Split {
     }
 }
 
-#[derive(Debug)]
+//
+//  SplitN
+//
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SplitN {
+    inputs: Vec<DropletId>,
+    outputs: Vec<DropletId>,
+    // the share of the volume for each output, adding up to 1
+    ratios: Vec<f64>,
+}
+
+impl SplitN {
+    pub fn new(id: DropletId, out_ids: Vec<DropletId>, ratios: &[f64]) -> PuddleResult<SplitN> {
+        let total: f64 = ratios.iter().sum();
+        // an infinite total would make every share NaN or zero
+        let valid = !ratios.is_empty()
+            && ratios.len() == out_ids.len()
+            && ratios.iter().all(|&r| r > 0.0)
+            && total.is_finite();
+        if !valid {
+            return Err(PuddleError::InvalidRatios(ratios.to_vec()));
+        }
+        Ok(SplitN {
+            inputs: vec![id],
+            outputs: out_ids,
+            ratios: ratios.iter().map(|r| r / total).collect(),
+        })
+    }
+}
+
+impl SplitN {
+    /// Where each daughter goes and how big it is, splitting a droplet with
+    /// these dimensions. Each gets its share of the footprint, and they're
+    /// stacked with a row in between so they keep their distance.
+    fn daughters(&self, dimensions: Location) -> Vec<(Location, Location)> {
+        let cells = f64::from(dimensions.y) * f64::from(dimensions.x);
+        let mut y = 0;
+        self.ratios
+            .iter()
+            .map(|ratio| {
+                let dim = dimensions_for_volume(ratio * cells, 1.0);
+                let loc = yx(y, 0);
+                y += dim.y + 1;
+                (loc, dim)
+            })
+            .collect()
+    }
+}
+
+impl Command for SplitN {
+    fn type_name(&self) -> &'static str {
+        "SplitN"
+    }
+
+    fn record(&self) -> Option<RecordedCommand> {
+        Some(self.clone().into())
+    }
+
+    fn input_droplets(&self) -> Vec<DropletId> {
+        self.inputs.clone()
+    }
+
+    fn output_droplets(&self) -> Vec<DropletId> {
+        self.outputs.clone()
+    }
+
+    fn split_fractions(&self) -> Vec<f64> {
+        self.ratios.clone()
+    }
+
+    fn request(&self, gridview: &GridView) -> CommandRequest {
+        let d0 = &gridview.droplets[&self.inputs[0]];
+        let daughters = self.daughters(d0.dimensions);
+        let bottom = daughters.iter().map(|(loc, dim)| loc.y + dim.y).max();
+        let right = daughters.iter().map(|(_, dim)| dim.x).max();
+        let y_dim = d0.dimensions.y.max(bottom.unwrap_or(1));
+        let x_dim = d0.dimensions.x.max(right.unwrap_or(1));
+
+        CommandRequest {
+            name: format!("split_n({:?})", self.inputs[0]),
+            shape: Grid::rectangle(y_dim as usize, x_dim as usize),
+            input_locations: vec![yx(0, 0)],
+            offset: None,
+        }
+    }
+
+    fn run(&mut self, gridview: &mut GridSubView) -> RunStatus {
+        let d = gridview.remove(&self.inputs[0]);
+        let daughters = self.daughters(d.dimensions);
+
+        let outputs = self.outputs.iter().zip(&self.ratios).zip(daughters);
+        for ((&id, ratio), (loc, dim)) in outputs {
+            let mut droplet = Droplet::new(id, d.volume * ratio, loc, dim);
+            droplet.tags = d.tags.clone();
+            droplet.inherit_temperatures(&[&d]);
+            droplet.inherit_label(&[&d]);
+            gridview.insert(droplet);
+        }
+
+        RunStatus::Done
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Heat {
     inputs: Vec<DropletId>,
     outputs: Vec<DropletId>,
@@ -520,6 +988,14 @@ impl Heat {
 }
 
 impl Command for Heat {
+    fn type_name(&self) -> &'static str {
+        "Heat"
+    }
+
+    fn record(&self) -> Option<RecordedCommand> {
+        Some(self.clone().into())
+    }
+
     fn input_droplets(&self) -> Vec<DropletId> {
         self.inputs.clone()
     }
@@ -528,33 +1004,13 @@ impl Command for Heat {
         self.outputs.clone()
     }
 
+    fn temperature(&self) -> Option<f32> {
+        Some(self.temperature)
+    }
+
     fn request(&self, gridview: &GridView) -> CommandRequest {
         let d = &gridview.droplets[&self.inputs[0]];
-        // we only split in the x right now, so we don't need y padding
-        let x_dim = d.dimensions.x as usize;
-        let y_dim = d.dimensions.y as usize;
-
-        // right now we can only heat droplets that are 1x1
-        // assert_eq!(y_dim, 1);
-        assert_eq!(x_dim, 1);
-        let mut grid = Grid::rectangle(y_dim, x_dim);
-
-        // the parameters of heater here don't matter, as it's just used to
-        // match up with the "real" heater in the actual grid
-        let loc = yx(y_dim as i32 - 1, 0);
-        grid.get_cell_mut(loc).unwrap().peripheral = Some(Peripheral::Heater {
-            pwm_channel: 0,
-            spi_channel: 0,
-        });
-
-        let input_locations = vec![loc];
-
-        CommandRequest {
-            name: format!("heat({:?})", d.id),
-            shape: grid,
-            input_locations: input_locations,
-            offset: None,
-        }
+        heater_request(format!("heat({:?})", d.id), d)
     }
 
     fn run(&mut self, gridview: &mut GridSubView) -> RunStatus {
@@ -564,12 +1020,214 @@ impl Command for Heat {
         let mut d = gridview.remove(&old_id);
         // NOTE this is a rare place it's ok to change an id, like move
         d.id = new_id;
+        d.max_temperature = Some(match d.max_temperature {
+            Some(t) => t.max(self.temperature),
+            None => self.temperature,
+        });
         gridview.insert(d);
         RunStatus::Done
     }
 }
 
-#[derive(Debug)]
+// a droplet-sized region with the heater under its bottom-left corner
+fn heater_request(name: String, d: &Droplet) -> CommandRequest {
+    // we only split in the x right now, so we don't need y padding
+    let x_dim = d.dimensions.x as usize;
+    let y_dim = d.dimensions.y as usize;
+
+    // right now we can only heat droplets that are 1x1; the planner turns
+    // anything wider away with PlanError::TooWideToHeat before we get here
+    // assert_eq!(y_dim, 1);
+    assert_eq!(x_dim, 1);
+    let mut grid = Grid::rectangle(y_dim, x_dim);
+
+    // the parameters of heater here don't matter, as it's just used to
+    // match up with the "real" heater in the actual grid
+    let loc = yx(y_dim as i32 - 1, 0);
+    grid.get_cell_mut(loc).unwrap().peripheral = Some(Peripheral::Heater {
+        pwm_channel: 0,
+        spi_channel: 0,
+    });
+
+    CommandRequest {
+        name,
+        shape: grid,
+        input_locations: vec![loc],
+        offset: None,
+    }
+}
+
+//
+//  HeatProfile
+//
+
+// what the heater can safely hold a droplet at
+pub const MIN_HEAT_TEMPERATURE: f32 = 0.0;
+pub const MAX_HEAT_TEMPERATURE: f32 = 120.0;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeatProfile {
+    inputs: Vec<DropletId>,
+    outputs: Vec<DropletId>,
+    // (target temperature, how long to hold it once it's there)
+    segments: Vec<(f32, Duration)>,
+    segment: usize,
+    holding: bool,
+}
+
+impl HeatProfile {
+    pub fn new(
+        id: DropletId,
+        out_id: DropletId,
+        segments: &[(f32, f64)],
+    ) -> PuddleResult<HeatProfile> {
+        let temp_ok = |t| (MIN_HEAT_TEMPERATURE..=MAX_HEAT_TEMPERATURE).contains(&t);
+        let valid = !segments.is_empty()
+            && segments
+                .iter()
+                .all(|&(t, secs)| temp_ok(t) && secs.is_finite() && secs >= 0.0);
+        if !valid {
+            return Err(PuddleError::InvalidHeatProfile(segments.to_vec()));
+        }
+
+        Ok(HeatProfile {
+            inputs: vec![id],
+            outputs: vec![out_id],
+            segments: segments
+                .iter()
+                .map(|&(t, secs)| (t, seconds_duration(secs)))
+                .collect(),
+            segment: 0,
+            holding: false,
+        })
+    }
+}
+
+impl Command for HeatProfile {
+    fn type_name(&self) -> &'static str {
+        "HeatProfile"
+    }
+
+    fn record(&self) -> Option<RecordedCommand> {
+        Some(self.clone().into())
+    }
+
+    fn input_droplets(&self) -> Vec<DropletId> {
+        self.inputs.clone()
+    }
+
+    fn output_droplets(&self) -> Vec<DropletId> {
+        self.outputs.clone()
+    }
+
+    // the hottest it gets, so the planner can check it against the limits
+    fn temperature(&self) -> Option<f32> {
+        let temps = self.segments.iter().map(|&(t, _)| t);
+        Some(temps.fold(MIN_HEAT_TEMPERATURE, f32::max))
+    }
+
+    fn setpoint(&self) -> Option<f32> {
+        Some(self.segments[self.segment].0)
+    }
+
+    fn request(&self, gridview: &GridView) -> CommandRequest {
+        let d = &gridview.droplets[&self.inputs[0]];
+        heater_request(format!("heat_profile({:?})", d.id), d)
+    }
+
+    // each run means the droplet made it to the current setpoint, or that
+    // it's done being held there
+    fn run(&mut self, gridview: &mut GridSubView) -> RunStatus {
+        if self.holding {
+            self.holding = false;
+            self.segment += 1;
+            return RunStatus::KeepGoing;
+        }
+
+        let (temperature, hold) = self.segments[self.segment];
+        let mut d = gridview.remove(&self.inputs[0]);
+        d.max_temperature = Some(match d.max_temperature {
+            Some(t) => t.max(temperature),
+            None => temperature,
+        });
+
+        let status = if self.segment + 1 < self.segments.len() {
+            self.holding = true;
+            RunStatus::Wait(hold)
+        } else {
+            // NOTE this is a rare place it's ok to change an id, like move
+            d.id = self.outputs[0];
+            RunStatus::Hold(hold)
+        };
+        gridview.insert(d);
+        status
+    }
+}
+
+//
+//  Dwell
+//
+
+/// Holds a droplet where it is for a while, like for an incubation. Other
+/// droplets carry on around it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Dwell {
+    inputs: Vec<DropletId>,
+    outputs: Vec<DropletId>,
+    duration: Duration,
+}
+
+impl Dwell {
+    pub fn new(in_id: DropletId, out_id: DropletId, seconds: f64) -> PuddleResult<Dwell> {
+        if !(seconds >= 0.0 && seconds.is_finite()) {
+            return Err(PuddleError::InvalidDuration(seconds));
+        }
+        Ok(Dwell {
+            inputs: vec![in_id],
+            outputs: vec![out_id],
+            duration: seconds_duration(seconds),
+        })
+    }
+}
+
+impl Command for Dwell {
+    fn type_name(&self) -> &'static str {
+        "Dwell"
+    }
+
+    fn record(&self) -> Option<RecordedCommand> {
+        Some(self.clone().into())
+    }
+
+    fn input_droplets(&self) -> Vec<DropletId> {
+        self.inputs.clone()
+    }
+
+    fn output_droplets(&self) -> Vec<DropletId> {
+        self.outputs.clone()
+    }
+
+    fn request(&self, gridview: &GridView) -> CommandRequest {
+        // right where the droplet already is, so nothing moves
+        let d = &gridview.droplets[&self.inputs[0]];
+        CommandRequest {
+            name: format!("dwell({:?}, {:?})", self.inputs[0], self.duration),
+            shape: Grid::rectangle(d.dimensions.y as usize, d.dimensions.x as usize),
+            input_locations: vec![yx(0, 0)],
+            offset: Some(d.location),
+        }
+    }
+
+    fn run(&mut self, gridview: &mut GridSubView) -> RunStatus {
+        let mut d = gridview.remove(&self.inputs[0]);
+        // NOTE this is a rare place it's ok to change an id, like move
+        d.id = self.outputs[0];
+        gridview.insert(d);
+        RunStatus::Hold(self.duration)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Input {
     substance: String,
     volume: f64,
@@ -596,6 +1254,14 @@ impl Input {
 }
 
 impl Command for Input {
+    fn type_name(&self) -> &'static str {
+        "Input"
+    }
+
+    fn record(&self) -> Option<RecordedCommand> {
+        Some(self.clone().into())
+    }
+
     fn input_droplets(&self) -> Vec<DropletId> {
         vec![]
     }
@@ -624,17 +1290,16 @@ impl Command for Input {
 
     fn run(&mut self, gridview: &mut GridSubView) -> RunStatus {
         assert_eq!(self.outputs.len(), 1);
-        gridview.insert(Droplet::new(
-            self.outputs[0],
-            self.volume,
-            yx(0, 0),
-            self.dimensions,
-        ));
+        let mut droplet = Droplet::new(self.outputs[0], self.volume, yx(0, 0), self.dimensions);
+        droplet.tags.insert(self.substance.clone(), 1.0);
+        droplet.label = Some(self.substance.clone());
+        gridview.insert(droplet);
+        gridview.draw_from_reservoir(&self.substance, self.volume);
         RunStatus::Done
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Output {
     name: String,
     inputs: Vec<DropletId>,
@@ -654,6 +1319,14 @@ impl Output {
 }
 
 impl Command for Output {
+    fn type_name(&self) -> &'static str {
+        "Output"
+    }
+
+    fn record(&self) -> Option<RecordedCommand> {
+        Some(self.clone().into())
+    }
+
     fn input_droplets(&self) -> Vec<DropletId> {
         self.inputs.clone()
     }
@@ -689,6 +1362,60 @@ impl Command for Output {
     }
 }
 
+/// Puts a droplet back into the input it came from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReturnToReservoir {
+    source: String,
+    inputs: Vec<DropletId>,
+}
+
+impl ReturnToReservoir {
+    pub fn new(source: String, id: DropletId) -> PuddleResult<ReturnToReservoir> {
+        Ok(ReturnToReservoir {
+            source,
+            inputs: vec![id],
+        })
+    }
+}
+
+impl Command for ReturnToReservoir {
+    fn type_name(&self) -> &'static str {
+        "ReturnToReservoir"
+    }
+
+    fn record(&self) -> Option<RecordedCommand> {
+        Some(self.clone().into())
+    }
+
+    fn input_droplets(&self) -> Vec<DropletId> {
+        self.inputs.clone()
+    }
+
+    fn request(&self, gridview: &GridView) -> CommandRequest {
+        let d = &gridview.droplets[&self.inputs[0]];
+
+        // just like output, but onto the input's electrode
+        let mut grid = Grid::rectangle(d.dimensions.y as usize, d.dimensions.x as usize);
+        grid.get_cell_mut(yx(0, 0)).unwrap().peripheral = Some(Peripheral::Input {
+            pwm_channel: 0,
+            name: self.source.clone(),
+        });
+
+        CommandRequest {
+            name: format!("return({:?}) -> {}", d.id, self.source),
+            shape: grid,
+            input_locations: vec![yx(0, 0)],
+            offset: None,
+        }
+    }
+
+    fn run(&mut self, gridview: &mut GridSubView) -> RunStatus {
+        let d = gridview.remove(&self.inputs[0]);
+        gridview.return_to_reservoir(&self.source, d.volume);
+        RunStatus::Done
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
 
@@ -713,6 +1440,10 @@ pub mod tests {
     }
 
     impl Command for Dummy {
+        fn type_name(&self) -> &'static str {
+            "Dummy"
+        }
+
         fn input_droplets(&self) -> Vec<DropletId> {
             self.ins.clone()
         }
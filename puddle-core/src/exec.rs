@@ -1,4 +1,7 @@
+use std::env;
 use std::fs::File;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::command::RunStatus;
 use crate::grid::{DropletId, DropletInfo, Grid, GridView, Location};
@@ -6,17 +9,107 @@ use crate::plan::{
     graph::{CmdIndex, Graph},
     Path, PlanPhase, PlannedCommand,
 };
+use crate::process::{PuddleError, PuddleResult};
 
 use indexmap::IndexMap;
 use serde::Serialize;
 
+const DEFAULT_STEP_DELAY_MS: u64 = 100;
+
 pub struct Executor {
     pub gridview: GridView,
     pub running_commands: IndexMap<CmdIndex, PlannedCommand>,
     ticks: usize,
+    // how long a normal step is held, and how much time has passed in total
+    step_duration: Duration,
+    elapsed: Duration,
+    // when each command was queued, and the ones waiting on their first step
+    queued: IndexMap<CmdIndex, Instant>,
+    awaiting_actuation: Vec<Instant>,
+    // when each running command started, and the timings of finished ones
+    started: IndexMap<CmdIndex, (usize, Duration, SystemTime)>,
+    timings: Vec<CommandTiming>,
+    // sync pins to pulse when each of these droplets first moves
+    pending_syncs: IndexMap<DropletId, u32>,
+    // what `sense` asks, if there's any hardware to ask
+    sensor: Option<Box<dyn Sensor>>,
+    // what sets the electrodes each step, and the first error it gave
+    actuator: Option<Box<dyn Actuator>>,
+    actuation_error: Option<PuddleError>,
+    // whether each step is really held for its duration, see `set_realtime`
+    realtime: bool,
+    pause: PauseHandle,
     log: Logger,
 }
 
+/// Pauses and resumes an `Executor` from another thread. While paused, the
+/// executor stops before its next step, and a step being held in real time
+/// doesn't count the paused time toward its duration.
+#[derive(Clone, Default)]
+pub struct PauseHandle {
+    paused: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl PauseHandle {
+    pub fn pause(&self) {
+        self.set_paused(true)
+    }
+
+    pub fn resume(&self) {
+        self.set_paused(false)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.0.lock().unwrap()
+    }
+
+    fn set_paused(&self, paused: bool) {
+        let (lock, cvar) = &*self.paused;
+        *lock.lock().unwrap() = paused;
+        cvar.notify_all();
+    }
+
+    // sleeps for `duration`, not counting any time spent paused
+    fn hold(&self, duration: Duration) {
+        let (lock, cvar) = &*self.paused;
+        let mut remaining = duration;
+        let mut paused = lock.lock().unwrap();
+        loop {
+            if *paused {
+                paused = cvar.wait(paused).unwrap();
+                continue;
+            }
+            if remaining == Duration::from_secs(0) {
+                return;
+            }
+            let start = Instant::now();
+            paused = cvar.wait_timeout(paused, remaining).unwrap().0;
+            remaining = remaining.checked_sub(start.elapsed()).unwrap_or_default();
+        }
+    }
+}
+
+/// Something that can tell whether a droplet is sitting on an electrode,
+/// like the capacitive feedback on a chip.
+pub trait Sensor: Send {
+    fn droplet_present(&mut self, loc: Location) -> PuddleResult<bool>;
+}
+
+/// Something that sets the electrodes to match a gridview, like the pins
+/// of a real chip.
+pub trait Actuator: Send {
+    fn actuate(&mut self, gv: &GridView) -> PuddleResult<()>;
+}
+
+/// How long a command ran for, in steps and in (held) step time.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct CommandTiming {
+    pub name: String,
+    pub start_tick: usize,
+    pub end_tick: usize,
+    pub duration: Duration,
+}
+
 #[derive(Serialize, Clone)]
 pub struct ModuleInfo {
     name: String,
@@ -28,6 +121,11 @@ pub struct ModuleInfo {
 pub struct StepInfo {
     droplets: Vec<DropletInfo>,
     modules: Vec<ModuleInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    render_interval_ms: Option<u64>,
+    duration_ms: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    sync_pins: Vec<u32>,
 }
 
 struct Logger {
@@ -36,16 +134,102 @@ struct Logger {
 
 pub enum ExecResponse {
     Ok,
+    /// the phase was cut short, this droplet never made it where it was sent
+    Stuck(DropletId),
+    /// the phase was cut short by something going wrong, like a command
+    /// changing the volume in a `SimExecutor`
+    Failed(PuddleError),
+}
+
+/// Carries out planned phases. `Executor` is the normal one, `SimExecutor`
+/// simulates droplet physics without any hardware around.
+pub trait Execute: Send {
+    fn run(&mut self, phase: PlanPhase, graph: &mut Graph) -> ExecResponse;
+
+    fn gridview(&self) -> &GridView;
+    fn gridview_mut(&mut self) -> &mut GridView;
+
+    fn ticks(&self) -> usize;
+    fn elapsed(&self) -> Duration;
+
+    /// Notes that a command was queued `at` some time, for measuring
+    /// actuation latency.
+    fn command_queued(&mut self, _cmd_id: CmdIndex, _at: Instant) {}
+
+    /// Whether a droplet is sitting on `loc` right now. By default this
+    /// goes by the gridview, but executors driving real hardware should
+    /// ask the chip, like `Executor` does when it's given a `Sensor`.
+    fn sense(&mut self, loc: Location) -> PuddleResult<bool> {
+        let droplets = self.gridview().droplets.values();
+        Ok(droplets.flat_map(|d| d.locations()).any(|l| l == loc))
+    }
+
+    fn get_logs(&self) -> &[StepInfo] {
+        &[]
+    }
+
+    /// Returns the timings of commands finalized since the last call.
+    fn take_timings(&mut self) -> Vec<CommandTiming> {
+        Vec::new()
+    }
 }
 
 impl Executor {
-    pub fn new(grid: Grid) -> Executor {
+    /// Fails if `PUDDLE_STEP_DELAY_MS` is set to something other than a
+    /// number of milliseconds.
+    pub fn new(grid: Grid) -> PuddleResult<Executor> {
         info!("Creating an Executor");
-        Executor {
+        Ok(Executor {
             gridview: GridView::new(grid),
             running_commands: IndexMap::default(),
             ticks: 0,
+            step_duration: step_duration()?,
+            elapsed: Duration::default(),
+            queued: IndexMap::default(),
+            awaiting_actuation: Vec::new(),
+            started: IndexMap::default(),
+            timings: Vec::new(),
+            pending_syncs: IndexMap::default(),
+            sensor: None,
+            actuator: None,
+            actuation_error: None,
+            realtime: false,
+            pause: PauseHandle::default(),
             log: Logger { steps: vec![] },
+        })
+    }
+
+    /// Really holds each step for as long as it takes, sleeping until the
+    /// next one, like hardware needs. Off by default, so only the elapsed
+    /// time is counted up.
+    pub fn set_realtime(&mut self, realtime: bool) {
+        self.realtime = realtime;
+    }
+
+    /// A handle for pausing this executor, see `PauseHandle`.
+    pub fn pause_handle(&self) -> PauseHandle {
+        self.pause.clone()
+    }
+
+    /// Makes `sense` ask `sensor` instead of going by the gridview.
+    pub fn set_sensor(&mut self, sensor: Option<Box<dyn Sensor>>) {
+        self.sensor = sensor;
+    }
+
+    /// Hands the gridview to `actuator` after every step, before the step
+    /// is held. If it fails, the phase stops there.
+    pub fn set_actuator(&mut self, actuator: Option<Box<dyn Actuator>>) {
+        self.actuator = actuator;
+    }
+
+    /// Whether a droplet is on `loc`, by the sensor if there is one.
+    pub fn sense(&mut self, loc: Location) -> PuddleResult<bool> {
+        match &mut self.sensor {
+            Some(sensor) => sensor.droplet_present(loc),
+            None => {
+                let droplets = self.gridview.droplets.values();
+                Ok(droplets.flat_map(|d| d.locations()).any(|l| l == loc))
+            }
         }
     }
 
@@ -53,10 +237,12 @@ impl Executor {
         &self.log.steps
     }
 
-    fn add_to_log(&mut self) {
+    fn add_to_log(&mut self, duration: Duration) {
         let modules: Vec<_> = self
             .running_commands
             .values()
+            // some commands, like dwell, don't take up any space
+            .filter(|planned| !planned.placement.mapping.is_empty())
             .map(|planned| ModuleInfo {
                 name: planned.request.name.clone(),
                 location: *planned.placement.mapping.values().min().unwrap(),
@@ -68,11 +254,22 @@ impl Executor {
             .collect();
 
         let droplets = self.gridview.droplet_info(None);
-        self.log.steps.push(StepInfo { modules, droplets })
+        let render_interval_ms = self
+            .gridview
+            .render_interval()
+            .map(|d| d.as_millis() as u64);
+        self.log.steps.push(StepInfo {
+            modules,
+            droplets,
+            render_interval_ms,
+            duration_ms: duration.as_millis() as u64,
+            sync_pins: self.gridview.sync_pins().to_vec(),
+        })
     }
 
     fn run_all_commands(&mut self, graph: &mut Graph) {
         let mut done = Vec::new();
+        let mut hold = self.step_duration;
 
         debug!("Run step, {} active commands", self.running_commands.len());
 
@@ -88,31 +285,86 @@ impl Executor {
 
             // write down if they are done
             debug!("Running command: {:?}", cmd);
-            match cmd.run(subview) {
-                RunStatus::Done => {
+            let status = cmd.run(subview);
+            if let RunStatus::Hold(duration) | RunStatus::Wait(duration) = status {
+                hold = hold.max(duration);
+            }
+            match status {
+                RunStatus::Done | RunStatus::Hold(_) => {
                     info!("Finalizing a command");
 
                     cmd.finalize(subview);
                     done.push(planned_cmd.cmd_id);
                 }
-                RunStatus::KeepGoing => (),
+                RunStatus::KeepGoing | RunStatus::Wait(_) => (),
             }
         }
 
-        self.commit();
+        self.commit(hold);
 
         // clean up all the done ones
         for cmd_id in done {
-            self.running_commands.remove(&cmd_id).unwrap();
+            let planned = self.running_commands.remove(&cmd_id).unwrap();
+            // normally gone once it's planned, but don't hang onto it either way
+            self.queued.swap_remove(&cmd_id);
+            let cmd = graph.graph[cmd_id].as_ref().expect("node unbound");
+            let (start_tick, start, started_at) = self.started.swap_remove(&cmd_id).unwrap();
+            self.gridview
+                .record_timeline(&planned.request.name, started_at, SystemTime::now());
+            self.timings.push(CommandTiming {
+                name: planned.request.name.clone(),
+                start_tick,
+                end_tick: self.ticks,
+                duration: self.elapsed - start,
+            });
+            self.gridview.record_provenance(
+                &planned.request.name,
+                cmd.input_droplets(),
+                cmd.output_droplets(),
+            );
         }
     }
 
-    fn commit(&mut self) {
+    fn commit(&mut self, duration: Duration) {
         self.ticks += 1;
-        self.add_to_log();
+        self.elapsed += duration;
+        self.add_to_log(duration);
+        if let Some(actuator) = &mut self.actuator {
+            if let Err(err) = actuator.actuate(&self.gridview) {
+                self.actuation_error.get_or_insert(err);
+            }
+        }
+        self.gridview.clear_sync_pins();
+
+        // the electrodes are set now, so anything waiting has been actuated
+        let now = Instant::now();
+        for queued_at in self.awaiting_actuation.drain(..) {
+            let latency = now - queued_at;
+            debug!("Actuation latency: {:?}", latency);
+            self.gridview.record_latency(latency);
+        }
+
+        // hold the electrodes like this for the step, and wait out any pause
+        let hold = if self.realtime {
+            duration
+        } else {
+            Duration::from_secs(0)
+        };
+        self.pause.hold(hold);
+    }
+
+    /// Notes that a command was queued `at` some time, for measuring
+    /// actuation latency.
+    pub fn command_queued(&mut self, cmd_id: CmdIndex, at: Instant) {
+        self.queued.insert(cmd_id, at);
     }
 
-    fn take_routes(&mut self, paths: &IndexMap<DropletId, Path>, graph: &mut Graph) {
+    // gives up on the first droplet to get stuck, see `GridView::stuck_droplets`
+    fn take_routes(
+        &mut self,
+        paths: &IndexMap<DropletId, Path>,
+        graph: &mut Graph,
+    ) -> Result<(), DropletId> {
         let max_len = paths.values().map(Vec::len).max().unwrap_or(0);
 
         // make sure that all droplets start where they are at this time step
@@ -121,26 +373,98 @@ impl Executor {
             assert_eq!(droplet.location, path[0]);
         }
 
+        let allow_diagonal = self.gridview.allow_diagonal();
         for i in 1..max_len {
-            for (id, path) in paths.iter() {
-                if i < path.len() {
-                    let droplet = self.gridview.droplets.get_mut(id).unwrap();
-                    assert!(droplet.location.distance_to(path[i]) <= 1);
+            for (&id, path) in paths.iter().filter(|(_, path)| i < path.len()) {
+                let step = path[i] - path[i - 1];
+                if allow_diagonal {
+                    assert!(step.y.abs() <= 1 && step.x.abs() <= 1);
+                } else {
+                    assert!(step.norm() <= 1);
+                }
+                // with a sensor, a droplet only moves once it shows up there
+                let arrived = match &mut self.sensor {
+                    Some(sensor) => sensor.droplet_present(path[i]).unwrap_or_else(|err| {
+                        warn!("Couldn't sense droplet {:?} at {}: {}", id, path[i], err);
+                        true
+                    }),
+                    None => true,
+                };
+                let droplet = self.gridview.droplets.get_mut(&id).unwrap();
+                if arrived {
                     droplet.location = path[i];
                 }
+                let observed = droplet.location;
+                self.gridview.record_move(id, path[i], observed);
+                if path[i] != path[i - 1] {
+                    if let Some(pin) = self.pending_syncs.swap_remove(&id) {
+                        self.gridview.add_sync_pin(pin);
+                    }
+                }
+            }
+            if let Some(threshold) = self.gridview.stuck_threshold() {
+                if let Some(&id) = self.gridview.stuck_droplets(threshold).first() {
+                    return Err(id);
+                }
             }
             self.run_all_commands(graph);
+            // `run` gives up on the phase
+            if self.actuation_error.is_some() {
+                break;
+            }
         }
+        Ok(())
+    }
+
+    // drops everything in flight after the actuator failed
+    fn actuation_failed(&mut self) -> Option<ExecResponse> {
+        let err = self.actuation_error.take()?;
+        error!("Couldn't actuate, stopping the phase: {}", err);
+        for (cmd_id, _) in self.running_commands.drain(..) {
+            self.started.swap_remove(&cmd_id);
+        }
+        self.pending_syncs.clear();
+        Some(ExecResponse::Failed(err))
     }
 
     pub fn run(&mut self, phase: PlanPhase, graph: &mut Graph) -> ExecResponse {
         info!("Run step");
 
+        for planned_cmd in &phase.planned_commands {
+            if let Some(queued_at) = self.queued.swap_remove(&planned_cmd.cmd_id) {
+                self.awaiting_actuation.push(queued_at);
+            }
+            let cmd = graph.graph[planned_cmd.cmd_id]
+                .as_ref()
+                .expect("node unbound");
+            if let Some(pin) = cmd.sync_pin() {
+                for id in cmd.input_droplets() {
+                    self.pending_syncs.insert(id, pin);
+                }
+            }
+        }
+
         // this could be inefficient if one route is much much longer than another
-        self.take_routes(&phase.routes, graph);
+        if let Err(id) = self.take_routes(&phase.routes, graph) {
+            // the commands expect their droplets in place, so don't run them
+            warn!("Droplet {:?} is stuck, stopping the phase", id);
+            self.pending_syncs.clear();
+            return ExecResponse::Stuck(id);
+        }
+        if let Some(resp) = self.actuation_failed() {
+            return resp;
+        }
+
+        // droplets that were already in place get their pulse with the
+        // command's first step instead
+        for (_, pin) in self.pending_syncs.drain(..) {
+            self.gridview.add_sync_pin(pin);
+        }
 
         // add all the planned commands
         for planned_cmd in phase.planned_commands {
+            let start = (self.ticks, self.elapsed, SystemTime::now());
+            self.started.insert(planned_cmd.cmd_id, start);
             let was_there = self
                 .running_commands
                 .insert(planned_cmd.cmd_id, planned_cmd);
@@ -150,6 +474,9 @@ impl Executor {
         // just drive all commands to completion for now
         while !self.running_commands.is_empty() {
             self.run_all_commands(graph);
+            if let Some(resp) = self.actuation_failed() {
+                return resp;
+            }
         }
 
         ExecResponse::Ok
@@ -158,6 +485,65 @@ impl Executor {
     pub fn ticks(&self) -> usize {
         self.ticks
     }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Returns the timings of commands finalized since the last call.
+    pub fn take_timings(&mut self) -> Vec<CommandTiming> {
+        self.timings.drain(..).collect()
+    }
+}
+
+impl Execute for Executor {
+    fn run(&mut self, phase: PlanPhase, graph: &mut Graph) -> ExecResponse {
+        Executor::run(self, phase, graph)
+    }
+
+    fn gridview(&self) -> &GridView {
+        &self.gridview
+    }
+
+    fn gridview_mut(&mut self) -> &mut GridView {
+        &mut self.gridview
+    }
+
+    fn ticks(&self) -> usize {
+        Executor::ticks(self)
+    }
+
+    fn elapsed(&self) -> Duration {
+        Executor::elapsed(self)
+    }
+
+    fn command_queued(&mut self, cmd_id: CmdIndex, at: Instant) {
+        Executor::command_queued(self, cmd_id, at)
+    }
+
+    fn sense(&mut self, loc: Location) -> PuddleResult<bool> {
+        Executor::sense(self, loc)
+    }
+
+    fn get_logs(&self) -> &[StepInfo] {
+        Executor::get_logs(self)
+    }
+
+    fn take_timings(&mut self) -> Vec<CommandTiming> {
+        Executor::take_timings(self)
+    }
+}
+
+fn step_duration() -> PuddleResult<Duration> {
+    parse_step_delay(env::var("PUDDLE_STEP_DELAY_MS").ok())
+}
+
+fn parse_step_delay(ms: Option<String>) -> PuddleResult<Duration> {
+    let ms = match ms {
+        Some(ms) => ms.parse().map_err(|_| PuddleError::InvalidStepDelay(ms))?,
+        None => DEFAULT_STEP_DELAY_MS,
+    };
+    Ok(Duration::from_millis(ms))
 }
 
 impl Logger {
@@ -186,3 +572,99 @@ impl Drop for Logger {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::location::yx;
+
+    /// Only ever sees droplets in the first column
+    struct FirstColumn;
+
+    impl Sensor for FirstColumn {
+        fn droplet_present(&mut self, loc: Location) -> PuddleResult<bool> {
+            Ok(loc.x == 0)
+        }
+    }
+
+    #[test]
+    fn test_sense_asks_sensor() {
+        let mut exec = Executor::new(Grid::rectangle(3, 3)).unwrap();
+        assert!(!exec.sense(yx(1, 0)).unwrap());
+
+        exec.set_sensor(Some(Box::new(FirstColumn)));
+        let exec: &mut dyn Execute = &mut exec;
+        assert!(exec.sense(yx(1, 0)).unwrap());
+        assert!(!exec.sense(yx(1, 1)).unwrap());
+    }
+
+    /// Gives up after a couple of steps
+    struct Flaky(usize);
+
+    impl Actuator for Flaky {
+        fn actuate(&mut self, _gv: &GridView) -> PuddleResult<()> {
+            self.0 += 1;
+            if self.0 > 2 {
+                return Err(PuddleError::ActuationFailed("unplugged".into()));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_actuator_failure_stops_phase() {
+        use crate::command::{Create, Move};
+        use crate::system::System;
+
+        let grid = Grid::rectangle(1, 6);
+        let mut executor = Executor::new(grid.clone()).unwrap();
+        executor.set_actuator(Some(Box::new(Flaky(0))));
+        let mut sys = System::with_executor(grid, Box::new(executor));
+
+        let create = Create::new(Some(yx(0, 0)), 1.0, None, 0.into()).unwrap();
+        let mv = Move::new(0.into(), yx(0, 5), 1.into()).unwrap();
+        sys.add(Box::new(create)).unwrap();
+        sys.add(Box::new(mv)).unwrap();
+        match sys.flush(&[]) {
+            Err(PuddleError::ActuationFailed(why)) => assert_eq!(why, "unplugged"),
+            other => panic!("Expected the actuator to fail, got {:?}", other),
+        }
+        // it stopped partway there
+        assert!(sys.ticks() < 6);
+    }
+
+    #[test]
+    fn test_parse_step_delay() {
+        let delay = |ms: Option<&str>| parse_step_delay(ms.map(String::from));
+        assert_eq!(
+            delay(None).unwrap(),
+            Duration::from_millis(DEFAULT_STEP_DELAY_MS)
+        );
+        assert_eq!(delay(Some("1")).unwrap(), Duration::from_millis(1));
+        match delay(Some("fast")) {
+            Err(PuddleError::InvalidStepDelay(ms)) => assert_eq!(ms, "fast"),
+            other => panic!("Expected a bad step delay, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_pause_holds() {
+        let pause = PauseHandle::default();
+        let start = Instant::now();
+        pause.hold(Duration::from_millis(20));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // the paused time doesn't count toward the hold
+        pause.pause();
+        let resumer = pause.clone();
+        let start = Instant::now();
+        let thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(30));
+            resumer.resume();
+        });
+        pause.hold(Duration::from_millis(10));
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert!(!pause.is_paused());
+        thread.join().unwrap();
+    }
+}
@@ -4,9 +4,9 @@ use std::sync::atomic::Ordering::Relaxed;
 
 use serde::{Deserialize, Serialize};
 
-use super::{Location, Rectangle};
+use super::{location::yx, Location, Rectangle};
 use crate::process::ProcessId;
-use indexmap::IndexSet;
+use indexmap::{IndexMap, IndexSet};
 
 static NEXT_COLLISION_GROUP: AtomicUsize = AtomicUsize::new(0);
 
@@ -27,6 +27,54 @@ impl fmt::Debug for DropletId {
     }
 }
 
+/// Written like `p2.d5`, for droplet 5 of process 2. `FromStr` reads the
+/// same thing back.
+impl fmt::Display for DropletId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "p{}.d{}", self.process_id, self.id)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DropletIdParseError(pub String);
+
+impl fmt::Display for DropletIdParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Expected a droplet id like 'p0.d1', got '{}'", self.0)
+    }
+}
+
+impl std::error::Error for DropletIdParseError {}
+
+impl std::str::FromStr for DropletId {
+    type Err = DropletIdParseError;
+
+    fn from_str(s: &str) -> Result<DropletId, DropletIdParseError> {
+        let err = || DropletIdParseError(s.into());
+        if !s.starts_with('p') {
+            return Err(err());
+        }
+        let rest = &s[1..];
+        let split = rest.find(".d").ok_or_else(err)?;
+        let (process_id, id) = (&rest[..split], &rest[split + 2..]);
+        Ok(DropletId {
+            id: id.parse().map_err(|_| err())?,
+            process_id: process_id.parse().map_err(|_| err())?,
+        })
+    }
+}
+
+/// Makes sure freshly created droplets won't reuse `group` or anything below
+/// it, e.g. after loading droplets from somewhere else.
+pub(crate) fn reserve_collision_groups(group: usize) {
+    crate::util::fetch_max(&NEXT_COLLISION_GROUP, group + 1);
+}
+
+/// A collision group no droplet has used yet.
+pub(crate) fn new_collision_group() -> usize {
+    NEXT_COLLISION_GROUP.fetch_add(1, Relaxed)
+}
+
 #[cfg(test)]
 impl From<usize> for DropletId {
     fn from(id: usize) -> DropletId {
@@ -34,7 +82,7 @@ impl From<usize> for DropletId {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Droplet {
     // The droplet's id should never be modified once it has been created. They
     // are globally unique by construction.
@@ -43,6 +91,29 @@ pub struct Droplet {
     pub dimensions: Location,
     pub volume: f64,
 
+    // The exact cells this droplet covers, relative to `location`. Merged
+    // droplets aren't always rectangular, so when this is `None` the droplet
+    // is assumed to fill its whole bounding rectangle.
+    pub cells: Option<IndexSet<Location>>,
+
+    // The concentration of each tagged substance, as a fraction of the volume.
+    #[serde(default)]
+    pub tags: IndexMap<String, f64>,
+
+    // The hottest this droplet, or anything mixed into it, has been heated to.
+    #[serde(default)]
+    pub max_temperature: Option<f32>,
+    // Heating past this is an error, see `Process::set_max_temperature`.
+    #[serde(default)]
+    pub temperature_limit: Option<f32>,
+
+    // For people, not the planner: a name like the input it came from, and
+    // an RGB color to draw it with.
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub color: Option<[u8; 3]>,
+
     // all this stuff is used for routing
     pub collision_group: usize,
     pub pinned: bool,
@@ -56,6 +127,21 @@ pub struct DropletInfo {
     pub location: Location,
     pub volume: f64,
     pub dimensions: Location,
+    #[serde(default)]
+    pub tags: IndexMap<String, f64>,
+    #[serde(default)]
+    pub max_temperature: Option<f32>,
+    #[serde(default)]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub color: Option<[u8; 3]>,
+}
+
+impl DropletInfo {
+    /// See `Droplet::concentration`.
+    pub fn concentration(&self, tag: &str) -> f64 {
+        self.tags.get(tag).cloned().unwrap_or(0.0)
+    }
 }
 
 impl Droplet {
@@ -69,6 +155,12 @@ impl Droplet {
             location,
             dimensions,
             volume: volume,
+            cells: None,
+            tags: IndexMap::new(),
+            max_temperature: None,
+            temperature_limit: None,
+            label: None,
+            color: None,
             collision_group: NEXT_COLLISION_GROUP.fetch_add(1, Relaxed),
             pinned: false,
         }
@@ -81,8 +173,42 @@ impl Droplet {
         }
     }
 
+    /// Returns the cells this droplet covers, relative to its location.
+    pub fn relative_cells(&self) -> IndexSet<Location> {
+        match &self.cells {
+            Some(cells) => cells.clone(),
+            None => Rectangle::new(yx(0, 0), self.dimensions)
+                .locations()
+                .collect(),
+        }
+    }
+
+    /// Returns the grid locations this droplet actually covers.
+    pub fn locations(&self) -> Vec<Location> {
+        match &self.cells {
+            Some(cells) => cells.iter().map(|&c| self.location + c).collect(),
+            None => self.rectangle().locations().collect(),
+        }
+    }
+
+    fn footprint(&self) -> Vec<Rectangle> {
+        match &self.cells {
+            Some(_) => self
+                .locations()
+                .into_iter()
+                .map(|loc| Rectangle::new(loc, yx(1, 1)))
+                .collect(),
+            None => vec![self.rectangle()],
+        }
+    }
+
     pub fn collision_distance(&self, other: &Droplet) -> i32 {
-        self.rectangle().collision_distance(&other.rectangle())
+        let mine = self.footprint();
+        let theirs = other.footprint();
+        mine.iter()
+            .flat_map(|r1| theirs.iter().map(move |r2| r1.collision_distance(r2)))
+            .min()
+            .expect("droplet has no cells")
     }
 
     pub fn info(&self) -> DropletInfo {
@@ -91,9 +217,61 @@ impl Droplet {
             location: self.location,
             dimensions: self.dimensions,
             volume: self.volume,
+            tags: self.tags.clone(),
+            max_temperature: self.max_temperature,
+            label: self.label.clone(),
+            color: self.color,
+        }
+    }
+
+    /// Carries the thermal history of `parents` over to this droplet. It's
+    /// been as hot as the hottest of them, and it's as sensitive as the most
+    /// sensitive of them.
+    pub fn inherit_temperatures(&mut self, parents: &[&Droplet]) {
+        fn pick(a: Option<f32>, b: Option<f32>, f: fn(f32, f32) -> f32) -> Option<f32> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(f(a, b)),
+                (a, b) => a.or(b),
+            }
+        }
+        for p in parents {
+            self.max_temperature = pick(self.max_temperature, p.max_temperature, f32::max);
+            self.temperature_limit = pick(self.temperature_limit, p.temperature_limit, f32::min);
+        }
+    }
+
+    /// Keeps the label and color that all of `parents` agree on.
+    pub fn inherit_label(&mut self, parents: &[&Droplet]) {
+        let first = match parents.first() {
+            Some(d) => d,
+            None => return,
+        };
+        if parents.iter().all(|d| d.label == first.label) {
+            self.label = first.label.clone();
+        }
+        if parents.iter().all(|d| d.color == first.color) {
+            self.color = first.color;
         }
     }
 
+    /// The concentration of `tag`, as a fraction of the volume. Anything
+    /// that was never tagged is at 0.
+    pub fn concentration(&self, tag: &str) -> f64 {
+        self.tags.get(tag).cloned().unwrap_or(0.0)
+    }
+
+    /// Returns the tags of this droplet mixed with `other`, weighted by volume.
+    pub fn mixed_tags(&self, other: &Droplet) -> IndexMap<String, f64> {
+        let total = self.volume + other.volume;
+        let mut tags = IndexMap::new();
+        for d in &[self, other] {
+            for (tag, conc) in &d.tags {
+                *tags.entry(tag.clone()).or_insert(0.0) += conc * d.volume / total;
+            }
+        }
+        tags
+    }
+
     pub fn to_blob(&self) -> SimpleBlob {
         SimpleBlob {
             location: self.location,
@@ -117,6 +295,12 @@ impl Default for Droplet {
             dimensions: bad_loc,
             pinned: false,
             volume: 1.0,
+            cells: None,
+            tags: IndexMap::new(),
+            max_temperature: None,
+            temperature_limit: None,
+            label: None,
+            color: None,
             collision_group: NEXT_COLLISION_GROUP.fetch_add(1, Relaxed),
         }
     }
@@ -197,7 +381,8 @@ impl Blob for SimpleBlob {
 
 #[cfg(test)]
 pub mod tests {
-    use super::{Droplet, DropletId, Location};
+    use super::{yx, Droplet, DropletId, Location};
+    use indexmap::IndexSet;
 
     #[test]
     #[should_panic]
@@ -237,4 +422,63 @@ pub mod tests {
         let b = droplet_with_shape((0, 8), (3, 1));
         assert_eq!(a.collision_distance(&b), 0);
     }
+
+    #[test]
+    fn test_exact_cells_collision() {
+        // a is an L-shape inside a 3x3 bounding box, b sits in the notch
+        // a.b
+        // a..
+        // aaa
+        let mut a = droplet_with_shape((0, 0), (3, 3));
+        let b = droplet_with_shape((0, 2), (1, 1));
+
+        // the bounding rectangle swallows b
+        assert!(a.collision_distance(&b) <= 0);
+
+        let cells: IndexSet<Location> = vec![yx(0, 0), yx(1, 0), yx(2, 0), yx(2, 1), yx(2, 2)]
+            .into_iter()
+            .collect();
+        a.cells = Some(cells);
+
+        // but the exact cells leave a gap
+        assert_eq!(a.collision_distance(&b), 1);
+        assert_eq!(b.collision_distance(&a), 1);
+        assert_eq!(a.locations().len(), 5);
+    }
+
+    #[test]
+    fn test_mixed_concentration() {
+        let mut dye = droplet_with_shape((0, 0), (1, 1));
+        dye.tags.insert("dye".into(), 1.0);
+        let water = droplet_with_shape((0, 2), (1, 1));
+        assert_eq!(water.concentration("dye"), 0.0);
+
+        let mut mixed = droplet_with_shape((0, 0), (2, 1));
+        mixed.volume = 2.0;
+        mixed.tags = dye.mixed_tags(&water);
+        assert_eq!(mixed.concentration("dye"), 0.5);
+
+        // weighted by volume, 2 parts at 0.5 and 1 part at 0
+        let thirds = mixed.mixed_tags(&water);
+        assert!((thirds["dye"] - 1.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_droplet_id_round_trip() {
+        let id = DropletId {
+            id: 5,
+            process_id: 12,
+        };
+        assert_eq!(id.to_string(), "p12.d5");
+        assert_eq!("p12.d5".parse::<DropletId>(), Ok(id));
+
+        for bad in &[
+            "", "p12", "12.d5", "p12.5", "p.d5", "p12.d", "p-1.d5", "p1.d2x",
+        ] {
+            assert_eq!(
+                bad.parse::<DropletId>(),
+                Err(super::DropletIdParseError(bad.to_string()))
+            );
+        }
+    }
 }
@@ -1,7 +1,9 @@
+use std::fmt;
+
 use serde::{Deserialize, Serialize};
 
-use super::Location;
-use indexmap::IndexSet;
+use super::{GridBounds, Location, PinMap, Rectangle};
+use indexmap::{IndexMap, IndexSet};
 
 use crate::grid::{location::yx, parse::ParsedGrid};
 
@@ -9,6 +11,13 @@ use crate::grid::{location::yx, parse::ParsedGrid};
 pub struct Electrode {
     pub pin: u32,
     pub peripheral: Option<Peripheral>,
+    // wired up, but droplets shouldn't go here and it's never actuated
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Clone)]
@@ -37,11 +46,89 @@ impl Electrode {
     }
 }
 
-#[derive(Debug, Default, PartialEq, Eq, Clone, Deserialize)]
-#[serde(from = "ParsedGrid")]
+/// How to drive one electrode, when it shouldn't get the board's usual
+/// voltage or polarity frequency. Unset fields are left as they are.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Drive {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voltage: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frequency: Option<f64>,
+}
+
+// compared bit for bit, so even a NaN override equals itself and a Grid
+// can still be Eq
+impl PartialEq for Drive {
+    fn eq(&self, other: &Drive) -> bool {
+        let bits = |v: Option<f64>| v.map(f64::to_bits);
+        bits(self.voltage) == bits(other.voltage) && bits(self.frequency) == bits(other.frequency)
+    }
+}
+
+impl Eq for Drive {}
+
+/// The axis to mirror a grid across.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Axis {
+    /// Flips the rows, so the top row becomes the bottom one.
+    Horizontal,
+    /// Flips the columns, so the left column becomes the right one.
+    Vertical,
+}
+
+/// Something wrong with a grid, as found by `Grid::validate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GridError {
+    /// There are no electrodes at all.
+    Empty,
+    /// The pin, and the two locations wired to it.
+    DuplicatePin(u32, Location, Location),
+    /// A location the grid refers to, like one end of a one-way transition
+    /// or a drive override, that has no electrode.
+    OutOfRange(Location),
+    /// Two peripherals on the same electrode.
+    DuplicatePeripheral(Location),
+    /// A drive override that can't be right, like a negative voltage or a
+    /// frequency of 0.
+    InvalidDrive(Location, Drive),
+    /// There's no path between these two electrodes. Each island that's cut
+    /// off from the first electrode gets reported once.
+    Disconnected(Location, Location),
+}
+
+impl fmt::Display for GridError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GridError::Empty => write!(f, "Grid has no electrodes"),
+            GridError::DuplicatePin(pin, loc1, loc2) => {
+                write!(f, "Pin {} is used by both {} and {}", pin, loc1, loc2)
+            }
+            GridError::OutOfRange(loc) => write!(f, "No electrode at {}", loc),
+            GridError::DuplicatePeripheral(loc) => {
+                write!(f, "More than one peripheral at {}", loc)
+            }
+            GridError::InvalidDrive(loc, drive) => {
+                write!(f, "Bad override at {}: {:?}", loc, drive)
+            }
+            GridError::Disconnected(loc1, loc2) => {
+                write!(f, "No path between electrodes {} and {}", loc1, loc2)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GridError {}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[serde(try_from = "ParsedGrid")]
 #[serde(into = "ParsedGrid")]
 pub struct Grid {
     pub vec: Vec<Vec<Option<Electrode>>>,
+    // whether a droplet may move from one cell to the other; anything not in
+    // here is allowed
+    pub transitions: IndexMap<(Location, Location), bool>,
+    // cells that need a different voltage or frequency than the rest
+    pub drive: IndexMap<Location, Drive>,
 }
 
 #[rustfmt::skip]
@@ -91,6 +178,7 @@ impl Grid {
             let cell = Some(Electrode {
                 pin: pin,
                 peripheral: None,
+                enabled: true,
             });
             pin += 1;
             cell
@@ -106,6 +194,98 @@ impl Grid {
         self.vec.iter().map(Vec::len).max().unwrap_or(0)
     }
 
+    /// The smallest box containing every row and column of the grid.
+    pub fn bounds(&self) -> GridBounds {
+        let max = yx(self.max_height() as i32 - 1, self.max_width() as i32 - 1);
+        GridBounds::new(yx(0, 0), max)
+    }
+
+    /// How many rows and columns the grid spans. Grids always start at
+    /// (0, 0), so this is one past the biggest location in each direction.
+    pub fn dimensions(&self) -> Location {
+        yx(self.max_height() as i32, self.max_width() as i32)
+    }
+
+    /// Same extent as `bounds`, as a `Rectangle`.
+    pub fn bounding_rectangle(&self) -> Rectangle {
+        Rectangle::new(yx(0, 0), self.dimensions())
+    }
+
+    /// Whether `loc` is within the grid's extent. There may not be a usable
+    /// electrode there, see `is_enabled` for that.
+    pub fn in_bounds(&self, loc: Location) -> bool {
+        self.bounds().contains(loc)
+    }
+
+    /// Whether `loc` is in one of the outer `n` rings of the grid.
+    pub fn in_border(&self, loc: Location, n: u32) -> bool {
+        let n = n as i32;
+        loc.y < n
+            || loc.x < n
+            || loc.y >= self.max_height() as i32 - n
+            || loc.x >= self.max_width() as i32 - n
+    }
+
+    /// Returns the mirror image of this grid across `axis`. The electrodes
+    /// (and their pins) move with the flip, so a user `Location` on the
+    /// mirrored grid drives the mirrored electrode without any translation.
+    pub fn mirror(mut self, axis: Axis) -> Grid {
+        let width = self.max_width();
+        self.transitions = self
+            .transitions
+            .iter()
+            .map(|(&(from, to), &ok)| {
+                let m = |loc| self.mirror_location(loc, axis);
+                ((m(from), m(to)), ok)
+            })
+            .collect();
+        self.drive = self
+            .drive
+            .iter()
+            .map(|(&loc, &drive)| (self.mirror_location(loc, axis), drive))
+            .collect();
+        match axis {
+            Axis::Horizontal => self.vec.reverse(),
+            Axis::Vertical => {
+                for row in self.vec.iter_mut() {
+                    // pad ragged rows so everything flips around the same column
+                    row.resize(width, None);
+                    row.reverse();
+                }
+            }
+        }
+        self
+    }
+
+    /// Where `loc` ends up after mirroring this grid across `axis`.
+    pub fn mirror_location(&self, loc: Location, axis: Axis) -> Location {
+        match axis {
+            Axis::Horizontal => yx(self.max_height() as i32 - 1 - loc.y, loc.x),
+            Axis::Vertical => yx(loc.y, self.max_width() as i32 - 1 - loc.x),
+        }
+    }
+
+    /// Only lets droplets move from `from` to `to`, not back.
+    pub fn add_one_way(&mut self, from: Location, to: Location) {
+        self.transitions.insert((from, to), true);
+        self.transitions.insert((to, from), false);
+    }
+
+    pub fn can_move(&self, from: Location, to: Location) -> bool {
+        self.transitions.get(&(from, to)).cloned().unwrap_or(true)
+    }
+
+    /// The voltage the electrode at `loc` should be driven at, if it's
+    /// different from the rest of the board.
+    pub fn voltage_at(&self, loc: Location) -> Option<f64> {
+        self.drive.get(&loc).and_then(|d| d.voltage)
+    }
+
+    /// Like `voltage_at`, for the polarity frequency.
+    pub fn frequency_at(&self, loc: Location) -> Option<f64> {
+        self.drive.get(&loc).and_then(|d| d.frequency)
+    }
+
     pub fn max_pin(&self) -> u32 {
         self.vec
             .iter()
@@ -148,7 +328,11 @@ impl Grid {
             })
             .collect();
 
-        Grid { vec }
+        Grid {
+            vec,
+            transitions: IndexMap::new(),
+            drive: IndexMap::new(),
+        }
     }
 
     // from here on out, functions only return valid locations
@@ -165,6 +349,11 @@ impl Grid {
             .and_then(Option::as_ref)
     }
 
+    /// Whether there's a working electrode at `loc`.
+    pub fn is_enabled(&self, loc: Location) -> bool {
+        self.get_cell(loc).map_or(false, |e| e.enabled)
+    }
+
     pub fn get_cell_mut(&mut self, loc: Location) -> Option<&mut Electrode> {
         if loc.x < 0 || loc.y < 0 {
             return None;
@@ -177,13 +366,23 @@ impl Grid {
             .and_then(Option::as_mut)
     }
 
+    /// Where the output (collection port) named `name` is, if there is one.
+    pub fn output_port(&self, name: &str) -> Option<Location> {
+        self.locations()
+            .find(|(_, cell)| match &cell.peripheral {
+                Some(Peripheral::Output { name: n, .. }) => n == name,
+                _ => false,
+            })
+            .map(|(loc, _)| loc)
+    }
+
     fn locations_from_offsets<'a, I>(&self, loc: Location, offsets: I) -> Vec<Location>
     where
         I: Iterator<Item = &'a Location>,
     {
         offsets
             .map(|off| loc + *off)
-            .filter(|loc| self.get_cell(*loc).is_some())
+            .filter(|loc| self.is_enabled(*loc))
             .collect()
     }
 
@@ -215,6 +414,79 @@ impl Grid {
     }
 }
 
+impl Grid {
+    /// Checks the grid for mistakes that are easy to make writing a grid file
+    /// by hand, returning all of them rather than just the first.
+    pub fn validate(&self) -> Result<(), Vec<GridError>> {
+        let mut errors = Vec::new();
+
+        if let Err(duplicates) = PinMap::from_grid(self) {
+            errors.extend(duplicates);
+        }
+
+        let mut off_grid: IndexSet<Location> = IndexSet::new();
+        for &(from, to) in self.transitions.keys() {
+            for loc in &[from, to] {
+                if self.get_cell(*loc).is_none() && off_grid.insert(*loc) {
+                    errors.push(GridError::OutOfRange(*loc));
+                }
+            }
+        }
+
+        for (&loc, drive) in &self.drive {
+            if self.get_cell(loc).is_none() {
+                if off_grid.insert(loc) {
+                    errors.push(GridError::OutOfRange(loc));
+                }
+                continue;
+            }
+            let bad_voltage = drive
+                .voltage
+                .map_or(false, |v| !(v.is_finite() && v >= 0.0));
+            let bad_frequency = drive
+                .frequency
+                .map_or(false, |f| !(f.is_finite() && f > 0.0));
+            if bad_voltage || bad_frequency {
+                errors.push(GridError::InvalidDrive(loc, *drive));
+            }
+        }
+
+        // flood fill from each electrode we haven't seen yet; every fill
+        // after the first is an island
+        let mut first = None;
+        let mut seen = IndexSet::new();
+        for (start, _) in self.locations() {
+            if !seen.insert(start) {
+                continue;
+            }
+            match first {
+                None => first = Some(start),
+                Some(first) => errors.push(GridError::Disconnected(first, start)),
+            }
+            let mut todo = vec![start];
+            while let Some(loc) = todo.pop() {
+                for off in &NEIGHBORS_4 {
+                    let n = loc + *off;
+                    // disabled electrodes are still part of the board
+                    if self.get_cell(n).is_some() && seen.insert(n) {
+                        todo.push(n);
+                    }
+                }
+            }
+        }
+
+        if first.is_none() {
+            errors.push(GridError::Empty);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
 #[cfg(test)]
 use petgraph::{graphmap::GraphMap, Undirected};
 
@@ -316,3 +588,156 @@ impl Grid {
 //         assert_eq!(&identity_locs, &map);
 //     }
 // }
+
+#[cfg(test)]
+mod mirror_tests {
+    use super::*;
+
+    #[test]
+    fn test_mirror_vertical() {
+        let grid = Grid::rectangle(2, 3);
+        let loc = yx(1, 0);
+        let pin = grid.get_cell(loc).unwrap().pin;
+
+        let mirrored_loc = grid.mirror_location(loc, Axis::Vertical);
+        assert_eq!(mirrored_loc, yx(1, 2));
+
+        let mirrored = grid.clone().mirror(Axis::Vertical);
+        assert_eq!(mirrored.get_cell(mirrored_loc).unwrap().pin, pin);
+        assert_eq!(mirrored.clone().mirror(Axis::Vertical), grid);
+    }
+}
+
+#[cfg(test)]
+mod validate_tests {
+    use super::*;
+
+    // like the board in a grid file, with pins numbered in reading order
+    fn board(rows: &[&str]) -> Grid {
+        let mut pin = 0;
+        let vec = rows
+            .iter()
+            .map(|row| {
+                row.chars()
+                    .map(|c| {
+                        if c == ' ' {
+                            return None;
+                        }
+                        pin += 1;
+                        Some(Electrode {
+                            pin,
+                            peripheral: None,
+                            enabled: c != 'x',
+                        })
+                    })
+                    .collect()
+            })
+            .collect();
+        Grid {
+            vec,
+            transitions: IndexMap::new(),
+            drive: IndexMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_validate_ok() {
+        assert_eq!(Grid::rectangle(3, 4).validate(), Ok(()));
+        // disabled electrodes still hold the board together
+        assert_eq!(board(&["..", " x", " ."]).validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_empty() {
+        assert_eq!(
+            Grid::rectangle(0, 0).validate(),
+            Err(vec![GridError::Empty])
+        );
+        assert_eq!(board(&["  ", " "]).validate(), Err(vec![GridError::Empty]));
+    }
+
+    #[test]
+    fn test_validate_duplicate_pins() {
+        let mut grid = board(&["...", "..."]);
+        grid.get_cell_mut(yx(0, 2)).unwrap().pin = 1;
+        grid.get_cell_mut(yx(1, 2)).unwrap().pin = 1;
+        assert_eq!(
+            grid.validate(),
+            Err(vec![
+                GridError::DuplicatePin(1, yx(0, 0), yx(0, 2)),
+                GridError::DuplicatePin(1, yx(0, 0), yx(1, 2)),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_validate_out_of_range() {
+        let mut grid = board(&["..", " ."]);
+        grid.add_one_way(yx(0, 1), yx(1, 1));
+        grid.add_one_way(yx(1, 0), yx(0, 0));
+        grid.add_one_way(yx(5, 5), yx(5, 6));
+        assert_eq!(
+            grid.validate(),
+            Err(vec![
+                GridError::OutOfRange(yx(1, 0)),
+                GridError::OutOfRange(yx(5, 5)),
+                GridError::OutOfRange(yx(5, 6)),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_validate_drive() {
+        let mut grid = board(&["..."]);
+        let drive = |voltage, frequency| Drive { voltage, frequency };
+        grid.drive.insert(yx(0, 0), drive(Some(2.5), Some(100.0)));
+        grid.drive.insert(yx(0, 1), drive(Some(-1.0), None));
+        grid.drive.insert(yx(0, 2), drive(None, Some(0.0)));
+        grid.drive.insert(yx(1, 0), drive(Some(1.0), None));
+        assert_eq!(grid.voltage_at(yx(0, 0)), Some(2.5));
+        assert_eq!(grid.frequency_at(yx(0, 1)), None);
+        assert_eq!(
+            drive(Some(std::f64::NAN), None),
+            drive(Some(std::f64::NAN), None)
+        );
+        assert_eq!(
+            grid.validate(),
+            Err(vec![
+                GridError::InvalidDrive(yx(0, 1), drive(Some(-1.0), None)),
+                GridError::InvalidDrive(yx(0, 2), drive(None, Some(0.0))),
+                GridError::OutOfRange(yx(1, 0)),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_validate_disconnected() {
+        // diagonals don't count
+        let grid = board(&[".. .", "  . ", ".  ."]);
+        assert_eq!(
+            grid.validate(),
+            Err(vec![
+                GridError::Disconnected(yx(0, 0), yx(0, 3)),
+                GridError::Disconnected(yx(0, 0), yx(1, 2)),
+                GridError::Disconnected(yx(0, 0), yx(2, 0)),
+                GridError::Disconnected(yx(0, 0), yx(2, 3)),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_validate_everything_at_once() {
+        let mut grid = board(&[". ."]);
+        grid.get_cell_mut(yx(0, 2)).unwrap().pin = 1;
+        grid.add_one_way(yx(0, 0), yx(0, 1));
+        let errors = grid.validate().unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                GridError::DuplicatePin(1, yx(0, 0), yx(0, 2)),
+                GridError::OutOfRange(yx(0, 1)),
+                GridError::Disconnected(yx(0, 0), yx(0, 2)),
+            ]
+        );
+    }
+}
@@ -1,12 +1,314 @@
-use crate::grid::{Droplet, DropletId, DropletInfo, Electrode, Grid, Location};
-use crate::plan::place::Placement;
-use crate::process::ProcessId;
+use std::io::{Read, Write};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::command::{Command, Split, SplitAxis};
+use crate::grid::droplet::{new_collision_group, reserve_collision_groups};
+use crate::grid::{
+    bounding_box, location::yx, Droplet, DropletId, DropletInfo, Electrode, Grid, Location,
+    Rectangle,
+};
+use crate::plan::place::{Placement, PlacementRequest, Placer};
+use crate::plan::{Metrics, PlanError};
+use crate::process::{ProcessId, PuddleError, PuddleResult};
+use crate::util::iso8601;
 use indexmap::{IndexMap, IndexSet};
 
 #[derive(Default, Clone)]
 pub struct GridView {
     pub grid: Grid,
     pub droplets: IndexMap<DropletId, Droplet>,
+    // only used to pace animations, this has nothing to do with actuation
+    render_interval: Option<Duration>,
+    // the outer rings that droplets should stay out of
+    keepout_border: u32,
+    // time from each command being queued to its first actuation
+    latencies: Vec<Duration>,
+    provenance: Provenance,
+    // droplets that didn't end up where they were sent, where they are
+    // instead, and for how many moves in a row
+    stalled: IndexMap<DropletId, (Location, u32)>,
+    stuck_threshold: Option<u32>,
+    // how much is left in each named input, for the ones we're tracking
+    reservoirs: IndexMap<String, f64>,
+    // wall clock start and end of each finished command
+    timeline: Vec<(String, SystemTime, SystemTime)>,
+    // whether the router may move droplets diagonally
+    allow_diagonal: bool,
+    // radius and extra cost for routing near other droplets
+    proximity_penalty: (u32, u32),
+    // gpios to pulse right before this state is actuated
+    sync_pins: Vec<u32>,
+    // how long planning took, see `plan::Metrics`
+    metrics: Metrics,
+    // splits can't make droplets smaller than this, DEFAULT_MIN_VOLUME
+    // unless set otherwise, however the view was made
+    min_volume: Option<f64>,
+    // if set, droplets created without dimensions are sized by volume
+    cell_volume: Option<f64>,
+}
+
+/// A tenth of what one electrode holds (see `sizing::DEFAULT_CELL_VOLUME`).
+/// Much smaller than that and a droplet won't cover enough of an electrode
+/// to get pulled along, let alone pinched off.
+pub const DEFAULT_MIN_VOLUME: f64 = 0.1;
+
+/// A finished command with absolute timestamps, see `export_timeline`.
+#[derive(Debug, Serialize)]
+pub struct TimelineRecord {
+    pub command: String,
+    pub start: String,
+    pub end: String,
+}
+
+/// Which droplets were made from which, see `export_provenance`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Provenance {
+    pub nodes: Vec<ProvenanceNode>,
+    pub edges: Vec<ProvenanceEdge>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProvenanceNode {
+    pub id: DropletId,
+    pub volume: f64,
+    pub tags: IndexMap<String, f64>,
+}
+
+/// A single operation, taking some droplets and making some others.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProvenanceEdge {
+    pub operation: String,
+    pub inputs: Vec<DropletId>,
+    pub outputs: Vec<DropletId>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyStats {
+    pub count: usize,
+    pub min: Duration,
+    pub mean: Duration,
+    pub max: Duration,
+}
+
+/// The droplets on the grid at a single point in time.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub droplets: IndexMap<DropletId, Droplet>,
+}
+
+impl Snapshot {
+    /// Every pair of droplets that are touching or overlapping, the same
+    /// thing the `GridView` refuses to execute. See `collisions_within`.
+    pub fn collisions(&self) -> Vec<(DropletId, DropletId)> {
+        self.collisions_within(1)
+    }
+
+    /// The smallest rectangle covering every droplet, or `None` if the grid
+    /// is empty. Handy for framing just the part of the grid in use.
+    pub fn bounding_box(&self) -> Option<Rectangle> {
+        bounding_box(self.droplets.values().flat_map(|d| d.locations()))
+    }
+
+    /// Every pair of droplets whose footprints are closer than `margin`.
+    /// Distance is counted in empty cells between them, so adjacent
+    /// droplets are 0 apart and overlapping ones are negative. Droplets in
+    /// the same collision group are allowed to touch, so they're skipped.
+    /// Each pair shows up once, in snapshot order.
+    pub fn collisions_within(&self, margin: i32) -> Vec<(DropletId, DropletId)> {
+        let droplets: Vec<&Droplet> = self.droplets.values().collect();
+        let mut pairs = Vec::new();
+        for (i, d1) in droplets.iter().enumerate() {
+            for d2 in &droplets[i + 1..] {
+                if d1.collision_group == d2.collision_group {
+                    continue;
+                }
+                if d1.collision_distance(d2) < margin {
+                    pairs.push((d1.id, d2.id));
+                }
+            }
+        }
+        pairs
+    }
+
+    /// What happened between `prev` and this snapshot, see `SnapshotDiff`.
+    pub fn diff(&self, prev: &Snapshot) -> SnapshotDiff {
+        SnapshotDiff::between(&prev.droplets, &self.droplets)
+    }
+
+    /// Recomputes every collision group from scratch: droplets that touch or
+    /// overlap, directly or through a chain of others, end up in one group,
+    /// and everything else gets a group of its own.
+    pub fn reassign_collision_groups(&mut self) {
+        let ids: Vec<DropletId> = self.droplets.keys().cloned().collect();
+        regroup(&mut self.droplets, &ids);
+    }
+}
+
+/// Splits `ids` into connected components of touching or overlapping
+/// droplets and gives each component one group. A component keeps the group
+/// of its first droplet unless an earlier component already took it, so
+/// groups only change where they have to.
+fn regroup(droplets: &mut IndexMap<DropletId, Droplet>, ids: &[DropletId]) {
+    let mut component: Vec<Option<usize>> = vec![None; ids.len()];
+    let mut n_components = 0;
+    for start in 0..ids.len() {
+        if component[start].is_some() {
+            continue;
+        }
+        component[start] = Some(n_components);
+        let mut todo = vec![start];
+        while let Some(i) = todo.pop() {
+            for j in 0..ids.len() {
+                let touching = droplets[&ids[i]].collision_distance(&droplets[&ids[j]]) <= 0;
+                if component[j].is_none() && touching {
+                    component[j] = Some(n_components);
+                    todo.push(j);
+                }
+            }
+        }
+        n_components += 1;
+    }
+
+    let mut taken = IndexSet::new();
+    let mut groups = vec![None; n_components];
+    for (i, c) in component.iter().enumerate() {
+        let c = c.unwrap();
+        if groups[c].is_none() {
+            let first = droplets[&ids[i]].collision_group;
+            let group = if taken.insert(first) {
+                first
+            } else {
+                new_collision_group()
+            };
+            groups[c] = Some(group);
+        }
+        droplets.get_mut(&ids[i]).unwrap().collision_group = groups[c].unwrap();
+    }
+}
+
+/// Everything about the droplets that changed from one snapshot to the
+/// next. Droplets that only moved are in `moved`, and ones that changed in
+/// any other way, like their volume or dimensions, are in `changed` in full,
+/// wherever they are now. Empty lists are left out when serialized.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SnapshotDiff {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub added: Vec<Droplet>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<DropletId>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub moved: Vec<Moved>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changed: Vec<Droplet>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Moved {
+    pub id: DropletId,
+    pub from: Location,
+    pub to: Location,
+}
+
+impl SnapshotDiff {
+    fn between(
+        prev: &IndexMap<DropletId, Droplet>,
+        now: &IndexMap<DropletId, Droplet>,
+    ) -> SnapshotDiff {
+        let mut diff = SnapshotDiff::default();
+        for (id, d) in now {
+            let old = match prev.get(id) {
+                Some(old) => old,
+                None => {
+                    diff.added.push(d.clone());
+                    continue;
+                }
+            };
+            // everything but where it is, and the routing bookkeeping
+            let mut old_here = old.info();
+            old_here.location = d.location;
+            if old_here != d.info() || old.cells != d.cells {
+                diff.changed.push(d.clone());
+            } else if old.location != d.location {
+                diff.moved.push(Moved {
+                    id: *id,
+                    from: old.location,
+                    to: d.location,
+                });
+            }
+        }
+        diff.removed = prev
+            .keys()
+            .filter(|id| !now.contains_key(*id))
+            .cloned()
+            .collect();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.moved.is_empty()
+            && self.changed.is_empty()
+    }
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    // the droplet covers a location with no (usable) electrode
+    OutOfBounds(DropletId, Location),
+    Collision(DropletId, DropletId),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::OutOfBounds(id, loc) => {
+                write!(
+                    f,
+                    "Droplet {} is at {}, where there's no usable electrode",
+                    id, loc
+                )
+            }
+            SnapshotError::Collision(id1, id2) => {
+                write!(f, "Droplets {} and {} collide", id1, id2)
+            }
+        }
+    }
+}
+
+// everything needed to rebuild a GridView, see `export_state`
+#[derive(Serialize, Deserialize)]
+struct ExportedState {
+    grid: Grid,
+    droplets: Vec<Droplet>,
+    // droplets that didn't make it where they were sent, see `record_move`
+    #[serde(default)]
+    stalled: Vec<Stalled>,
+    #[serde(default)]
+    reservoirs: IndexMap<String, f64>,
+    #[serde(default)]
+    config: ExportedConfig,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Stalled {
+    id: DropletId,
+    location: Location,
+    moves: u32,
+}
+
+// how the view was set up, as opposed to what's on it
+#[derive(Default, Serialize, Deserialize)]
+struct ExportedConfig {
+    render_interval: Option<Duration>,
+    keepout_border: u32,
+    stuck_threshold: Option<u32>,
+    allow_diagonal: bool,
+    proximity_penalty: (u32, u32),
+    min_volume: Option<f64>,
+    cell_volume: Option<f64>,
 }
 
 use std::fmt;
@@ -35,6 +337,63 @@ impl GridView {
             .collect()
     }
 
+    /// Writes the grid, all the droplets on it, the ones that are stalled,
+    /// the reservoir levels, and the routing and sizing settings as a single
+    /// JSON document, so a misbehaving state can be reproduced later.
+    pub fn export_state(&self, w: impl Write) -> serde_json::Result<()> {
+        let stalled = self
+            .stalled
+            .iter()
+            .map(|(&id, &(location, moves))| Stalled {
+                id,
+                location,
+                moves,
+            })
+            .collect();
+        let config = ExportedConfig {
+            render_interval: self.render_interval,
+            keepout_border: self.keepout_border,
+            stuck_threshold: self.stuck_threshold,
+            allow_diagonal: self.allow_diagonal,
+            proximity_penalty: self.proximity_penalty,
+            min_volume: self.min_volume,
+            cell_volume: self.cell_volume,
+        };
+        let state = ExportedState {
+            grid: self.grid.clone(),
+            droplets: self.droplets.values().cloned().collect(),
+            stalled,
+            reservoirs: self.reservoirs.clone(),
+            config,
+        };
+        serde_json::to_writer_pretty(w, &state)
+    }
+
+    /// Rebuilds a GridView written by `export_state`.
+    pub fn import_state(r: impl Read) -> serde_json::Result<GridView> {
+        let state: ExportedState = serde_json::from_reader(r)?;
+        let config = state.config;
+        let mut gv = GridView {
+            render_interval: config.render_interval,
+            keepout_border: config.keepout_border,
+            stuck_threshold: config.stuck_threshold,
+            allow_diagonal: config.allow_diagonal,
+            proximity_penalty: config.proximity_penalty,
+            min_volume: config.min_volume,
+            cell_volume: config.cell_volume,
+            reservoirs: state.reservoirs,
+            ..GridView::new(state.grid)
+        };
+        for droplet in state.droplets {
+            reserve_collision_groups(droplet.collision_group);
+            gv.droplets.insert(droplet.id, droplet);
+        }
+        for s in state.stalled {
+            gv.stalled.insert(s.id, (s.location, s.moves));
+        }
+        Ok(gv)
+    }
+
     /// Returns an invalid droplet, if any.
     fn get_collision(&self) -> Option<(i32, Droplet, Droplet)> {
         for (id1, droplet1) in &self.droplets {
@@ -60,6 +419,351 @@ impl GridView {
         }
     }
 
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            droplets: self.droplets.clone(),
+        }
+    }
+
+    /// What's changed since `prev`, without taking a whole new snapshot.
+    pub fn diff(&self, prev: &Snapshot) -> SnapshotDiff {
+        SnapshotDiff::between(&prev.droplets, &self.droplets)
+    }
+
+    /// Replaces all the droplets with the ones in `snap`. If the snapshot
+    /// isn't valid on this grid, nothing is changed.
+    pub fn apply_snapshot(&mut self, snap: Snapshot) -> PuddleResult<()> {
+        let invalid = |e| Err(PuddleError::InvalidSnapshot(e));
+
+        for d in snap.droplets.values() {
+            for loc in d.locations() {
+                if !self.grid.is_enabled(loc) || self.in_keepout(loc) {
+                    return invalid(SnapshotError::OutOfBounds(d.id, loc));
+                }
+            }
+        }
+
+        if let Some(&(id1, id2)) = snap.collisions().first() {
+            return invalid(SnapshotError::Collision(id1, id2));
+        }
+        self.droplets = snap.droplets;
+
+        Ok(())
+    }
+
+    /// Sets how long each step should be shown when rendering or replaying.
+    /// This is independent of how long the electrodes are actually held.
+    pub fn set_render_interval(&mut self, interval: Duration) {
+        self.render_interval = Some(interval);
+    }
+
+    pub fn render_interval(&self) -> Option<Duration> {
+        self.render_interval
+    }
+
+    pub(crate) fn record_latency(&mut self, latency: Duration) {
+        self.latencies.push(latency);
+    }
+
+    /// Summarizes how long commands waited between being queued and first
+    /// actuating the electrodes. Returns `None` if nothing has run yet.
+    pub fn actuation_latency_stats(&self) -> Option<LatencyStats> {
+        let count = self.latencies.len();
+        let total: Duration = self.latencies.iter().sum();
+        Some(LatencyStats {
+            count,
+            min: *self.latencies.iter().min()?,
+            mean: total / count as u32,
+            max: *self.latencies.iter().max()?,
+        })
+    }
+
+    pub(crate) fn record_plan_time(&mut self, type_name: &'static str, time: Duration) {
+        self.metrics.record(type_name, time);
+    }
+
+    /// How long the planner has spent on each type of command so far.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub(crate) fn into_metrics(self) -> Metrics {
+        self.metrics
+    }
+
+    pub(crate) fn set_metrics(&mut self, metrics: Metrics) {
+        self.metrics = metrics;
+    }
+
+    /// Records that `operation` turned `inputs` into `outputs`. The outputs
+    /// should already be on the grid.
+    pub(crate) fn record_provenance(
+        &mut self,
+        operation: &str,
+        inputs: Vec<DropletId>,
+        outputs: Vec<DropletId>,
+    ) {
+        for id in &outputs {
+            let d = &self.droplets[id];
+            self.provenance.nodes.push(ProvenanceNode {
+                id: *id,
+                volume: d.volume,
+                tags: d.tags.clone(),
+            });
+        }
+        self.provenance.edges.push(ProvenanceEdge {
+            operation: operation.into(),
+            inputs,
+            outputs,
+        });
+    }
+
+    pub fn provenance(&self) -> &Provenance {
+        &self.provenance
+    }
+
+    /// Writes the provenance graph as JSON: the nodes are droplets, and the
+    /// edges are the operations that made them.
+    pub fn export_provenance(&self, w: impl Write) -> serde_json::Result<()> {
+        serde_json::to_writer_pretty(w, &self.provenance)
+    }
+
+    pub(crate) fn record_timeline(&mut self, command: &str, start: SystemTime, end: SystemTime) {
+        self.timeline.push((command.to_string(), start, end));
+    }
+
+    /// Writes when each finished command started and ended as JSON records
+    /// with ISO-8601 timestamps, ordered by start, for lining up with other
+    /// instruments.
+    pub fn export_timeline(&self, w: impl Write) -> serde_json::Result<()> {
+        let mut timeline = self.timeline.clone();
+        timeline.sort_by_key(|&(_, start, _)| start);
+        let records: Vec<_> = timeline
+            .into_iter()
+            .map(|(command, start, end)| TimelineRecord {
+                command,
+                start: iso8601(start),
+                end: iso8601(end),
+            })
+            .collect();
+        serde_json::to_writer_pretty(w, &records)
+    }
+
+    /// Keeps droplets out of the outer `n` rings of the grid. Those electrodes
+    /// are still there, they just won't be routed through or placed on.
+    pub fn set_keepout_border(&mut self, n: u32) {
+        self.keepout_border = n;
+    }
+
+    pub fn keepout_border(&self) -> u32 {
+        self.keepout_border
+    }
+
+    /// Records a droplet being sent to `commanded` and actually showing up at
+    /// `observed`. Without sensors, those are the same thing.
+    pub fn record_move(&mut self, id: DropletId, commanded: Location, observed: Location) {
+        if commanded == observed {
+            self.stalled.swap_remove(&id);
+            return;
+        }
+        let entry = self.stalled.entry(id).or_insert((observed, 0));
+        if entry.0 != observed {
+            // it moved, just not where we wanted
+            *entry = (observed, 0);
+        }
+        entry.1 += 1;
+    }
+
+    /// Droplets that haven't moved over their last `threshold` commanded moves.
+    pub fn stuck_droplets(&self, threshold: u32) -> Vec<DropletId> {
+        self.stalled
+            .iter()
+            .filter(|(id, &(_, count))| count >= threshold && self.droplets.contains_key(*id))
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    /// Makes the executor give up once a droplet is stuck for `threshold`
+    /// moves, see `stuck_droplets`.
+    pub fn set_stuck_threshold(&mut self, threshold: Option<u32>) {
+        self.stuck_threshold = threshold;
+    }
+
+    pub fn stuck_threshold(&self) -> Option<u32> {
+        self.stuck_threshold
+    }
+
+    /// Lets the router take diagonal steps (8-connectivity) instead of only
+    /// moving up, down, left, and right. Off by default.
+    pub fn set_allow_diagonal(&mut self, allow: bool) {
+        self.allow_diagonal = allow;
+    }
+
+    pub fn allow_diagonal(&self) -> bool {
+        self.allow_diagonal
+    }
+
+    /// Makes the router charge `cost` extra for every step a droplet spends
+    /// within `radius` empty cells of another droplet, so routes keep their
+    /// distance when they can. A cost of 0 (the default) turns this off.
+    pub fn set_proximity_penalty(&mut self, radius: u32, cost: u32) {
+        self.proximity_penalty = (radius, cost);
+    }
+
+    pub fn proximity_penalty(&self) -> (u32, u32) {
+        self.proximity_penalty
+    }
+
+    /// Fails planning any split that would make a droplet with less than
+    /// `volume`, since the chip can't reliably pinch off anything smaller.
+    /// Defaults to `DEFAULT_MIN_VOLUME`.
+    pub fn set_min_volume(&mut self, volume: f64) {
+        self.min_volume = Some(volume);
+    }
+
+    pub fn min_volume(&self) -> f64 {
+        self.min_volume.unwrap_or(DEFAULT_MIN_VOLUME)
+    }
+
+    /// Sizes droplets created without dimensions to fit their volume, with
+    /// each electrode holding `volume` (see `sizing::dimensions_for_volume`).
+    /// Off by default, so those droplets get a single cell.
+    pub fn set_cell_volume(&mut self, volume: Option<f64>) {
+        self.cell_volume = volume;
+    }
+
+    pub fn cell_volume(&self) -> Option<f64> {
+        self.cell_volume
+    }
+
+    /// GPIOs that should be pulsed just before this state goes out to the
+    /// electrodes, for lining up with external recordings.
+    pub fn sync_pins(&self) -> &[u32] {
+        &self.sync_pins
+    }
+
+    pub(crate) fn add_sync_pin(&mut self, pin: u32) {
+        self.sync_pins.push(pin);
+    }
+
+    pub(crate) fn clear_sync_pins(&mut self) {
+        self.sync_pins.clear();
+    }
+
+    /// Starts tracking how much is left in the input named `name`.
+    pub fn set_reservoir(&mut self, name: impl Into<String>, volume: f64) {
+        self.reservoirs.insert(name.into(), volume);
+    }
+
+    pub fn reservoir_levels(&self) -> &IndexMap<String, f64> {
+        &self.reservoirs
+    }
+
+    fn draw_from_reservoir(&mut self, name: &str, volume: f64) {
+        if let Some(level) = self.reservoirs.get_mut(name) {
+            if *level < volume {
+                warn!(
+                    "Reservoir '{}' ran dry: wanted {}, had {}",
+                    name, volume, level
+                );
+            }
+            *level = (*level - volume).max(0.0);
+        }
+    }
+
+    fn return_to_reservoir(&mut self, name: &str, volume: f64) {
+        if let Some(level) = self.reservoirs.get_mut(name) {
+            *level += volume;
+        }
+    }
+
+    pub fn in_keepout(&self, loc: Location) -> bool {
+        self.keepout_border > 0 && self.grid.in_border(loc, self.keepout_border)
+    }
+
+    /// Where the two daughters would land if `d` were split along `axis`
+    /// right now. Nothing gets planned or changed, this just asks the placer
+    /// the same question the planner would.
+    pub fn preview_split(
+        &self,
+        d: DropletId,
+        axis: SplitAxis,
+    ) -> PuddleResult<(Location, Location)> {
+        let droplet = self
+            .droplets
+            .get(&d)
+            .ok_or(PuddleError::NonExistentDropletId(d.id))?;
+
+        // the outputs don't matter, they never get made
+        let split = Split::new(d, d, d)?.with_axis(axis);
+        let cmd_req = split.request(self);
+        let stored: Vec<_> = self
+            .droplets
+            .keys()
+            .filter(|&&id| id != d)
+            .cloned()
+            .collect();
+
+        let req = PlacementRequest {
+            gridview: self,
+            fixed_commands: vec![],
+            commands: std::slice::from_ref(&cmd_req),
+            stored_droplets: &stored,
+        };
+        let resp = Placer::default()
+            .place(req)
+            .map_err(|e| PuddleError::PlanError(PlanError::PlaceError(e)))?;
+
+        let offset = resp.commands[0].mapping[&yx(0, 0)];
+        let (loc0, loc1, _) = split.daughters(droplet.dimensions);
+        Ok((loc0 + offset, loc1 + offset))
+    }
+
+    /// Returns the droplets in each collision group, keyed by group.
+    pub fn groups(&self) -> IndexMap<usize, Vec<DropletId>> {
+        let mut groups: IndexMap<usize, Vec<DropletId>> = IndexMap::default();
+        for droplet in self.droplets.values() {
+            groups
+                .entry(droplet.collision_group)
+                .or_insert_with(Vec::new)
+                .push(droplet.id);
+        }
+        groups
+    }
+
+    /// Draws the grid with each droplet as a letter. If `by_group` is set,
+    /// droplets in the same collision group share a letter.
+    pub fn render_ascii(&self, by_group: bool) -> Vec<String> {
+        let mut rows: Vec<Vec<char>> = self
+            .grid
+            .to_strs()
+            .iter()
+            .map(|row| row.chars().collect())
+            .collect();
+
+        let groups = self.groups();
+        for (i, droplet) in self.droplets.values().enumerate() {
+            let n = if by_group {
+                groups.get_full(&droplet.collision_group).unwrap().0
+            } else {
+                i
+            };
+            let ch = (b'a' + (n % 26) as u8) as char;
+            for loc in droplet.locations() {
+                let cell = rows
+                    .get_mut(loc.y as usize)
+                    .and_then(|row| row.get_mut(loc.x as usize));
+                if let Some(cell) = cell {
+                    *cell = ch;
+                }
+            }
+        }
+
+        rows.into_iter()
+            .map(|row| row.into_iter().collect())
+            .collect()
+    }
+
     pub fn subview<'a>(&'a mut self, placement: &'a Placement) -> GridSubView<'a> {
         GridSubView {
             backing_gridview: self,
@@ -79,6 +783,16 @@ impl<'a> GridSubView<'a> {
         self.backing_gridview.grid.get_cell(*actual_loc)
     }
 
+    /// Takes `volume` out of the named input, if we're tracking it.
+    pub fn draw_from_reservoir(&mut self, name: &str, volume: f64) {
+        self.backing_gridview.draw_from_reservoir(name, volume)
+    }
+
+    /// Puts `volume` back into the named input, if we're tracking it.
+    pub fn return_to_reservoir(&mut self, name: &str, volume: f64) {
+        self.backing_gridview.return_to_reservoir(name, volume)
+    }
+
     // TODO: translate or somehow hide the untranslated location of this
     pub fn get(&self, id: &DropletId) -> &Droplet {
         // assert!(self.ids.contains(&id));
@@ -115,6 +829,22 @@ impl<'a> GridSubView<'a> {
         }
     }
 
+    /// After `merged` was made out of droplets in `parent_groups`, whatever
+    /// else was sharing those groups gets sorted out again: droplets still
+    /// touching the merged one join its group, and the rest are grouped
+    /// among themselves like `Snapshot::reassign_collision_groups`.
+    pub fn regroup_merged(&mut self, merged: DropletId, parent_groups: &[usize]) {
+        let droplets = &mut self.backing_gridview.droplets;
+        let mut ids = vec![merged];
+        ids.extend(
+            droplets
+                .values()
+                .filter(|d| d.id != merged && parent_groups.contains(&d.collision_group))
+                .map(|d| d.id),
+        );
+        regroup(droplets, &ids);
+    }
+
     pub fn remove(&mut self, id: &DropletId) -> Droplet {
         // let was_there = self.ids.remove(id);
         // assert!(was_there);
@@ -143,15 +873,9 @@ impl<'a> GridSubView<'a> {
         let droplet = self.get(id);
         let mapped_to: IndexSet<_> = self.placement.mapping.values().collect();
         // TODO this is pretty slow
-        for i in 0..droplet.dimensions.y {
-            for j in 0..droplet.dimensions.x {
-                let loc = Location {
-                    y: droplet.location.y + i,
-                    x: droplet.location.x + j,
-                };
-                if !mapped_to.contains(&loc) {
-                    panic!("{} was unmapped!, placement: {:#?}", loc, self.placement);
-                }
+        for loc in droplet.locations() {
+            if !mapped_to.contains(&loc) {
+                panic!("{} was unmapped!, placement: {:#?}", loc, self.placement);
             }
         }
     }
@@ -197,7 +921,9 @@ impl<'a> GridSubView<'a> {
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use crate::command::Combine;
     use crate::grid::{droplet::Blob, location::yx, parse::tests::parse_strings};
+    use matches::assert_matches;
 
     pub fn id2c(id: &DropletId) -> char {
         assert!(id.id < 255);
@@ -267,6 +993,147 @@ pub mod tests {
         // TODO this is a weak test because we don't really do anything
     }
 
+    #[test]
+    fn test_min_volume_default() {
+        // however the view is made, it starts with the same minimum
+        let gv = GridView::new(Grid::rectangle(2, 2));
+        assert_eq!(gv.min_volume(), DEFAULT_MIN_VOLUME);
+        let mut gv = GridView::default();
+        assert_eq!(gv.min_volume(), DEFAULT_MIN_VOLUME);
+        gv.set_min_volume(0.0);
+        assert_eq!(gv.min_volume(), 0.0);
+    }
+
+    #[test]
+    fn test_groups() {
+        let mut gv = parse_gridview(&["a.b.c", ".....", "d...."]);
+        for (ch, group) in &[('a', 0), ('b', 1), ('c', 0), ('d', 1)] {
+            gv.droplets.get_mut(&c2id(*ch)).unwrap().collision_group = *group;
+        }
+
+        let groups = gv.groups();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[&0], vec![c2id('a'), c2id('c')]);
+        assert_eq!(groups[&1], vec![c2id('b'), c2id('d')]);
+
+        assert_eq!(gv.render_ascii(false), vec!["a.b.c", ".....", "d...."]);
+        assert_eq!(gv.render_ascii(true), vec!["a.b.a", ".....", "b...."]);
+    }
+
+    #[test]
+    fn test_reassign_collision_groups() {
+        let mut snapshot = parse_gridview(&["aab..", "....c", "....c", "d...."]).snapshot();
+        for (ch, group) in &[('a', 0), ('b', 1), ('c', 1), ('d', 0)] {
+            snapshot
+                .droplets
+                .get_mut(&c2id(*ch))
+                .unwrap()
+                .collision_group = *group;
+        }
+
+        snapshot.reassign_collision_groups();
+        let group = |ch| snapshot.droplets[&c2id(ch)].collision_group;
+        // a and b are touching, so they share a's group and c takes over b's
+        assert_eq!(group('a'), 0);
+        assert_eq!(group('b'), 0);
+        assert_eq!(group('c'), 1);
+        // d isn't touching a anymore, so it's on its own
+        assert_ne!(group('d'), 0);
+        assert_ne!(group('d'), 1);
+        assert!(snapshot.collisions().is_empty());
+    }
+
+    #[test]
+    fn test_combine_regroups() {
+        let mut gv = parse_gridview(&["a....", "b....", "c....", ".....", "...d."]);
+        // routing a and b together left everything in one group
+        for d in gv.droplets.values_mut() {
+            d.collision_group = 7;
+        }
+
+        let out = c2id('m');
+        // the first input is the bottom one
+        let mut combine = Combine::new(c2id('b'), c2id('a'), out).unwrap();
+        let placement = placement_rect(yx(0, 0), yx(5, 5));
+        combine.run(&mut gv.subview(&placement));
+
+        let group = |ch| gv.droplets[&c2id(ch)].collision_group;
+        assert_ne!(group('m'), 7);
+        // c is touching the merged droplet, d isn't
+        assert_eq!(group('c'), group('m'));
+        assert_ne!(group('d'), group('m'));
+        assert!(gv.snapshot().collisions().is_empty());
+    }
+
+    #[test]
+    fn test_apply_snapshot() {
+        let mut gv = parse_gridview(&["a....", ".....", "....b"]);
+
+        let mut snap = gv.snapshot();
+        snap.droplets[&c2id('a')].location = yx(1, 1);
+        gv.apply_snapshot(snap).unwrap();
+        assert_eq!(gv.droplets[&c2id('a')].location, yx(1, 1));
+
+        // moving b right next to a is a collision, so nothing should change
+        let before = gv.droplet_info(None);
+        let mut snap = gv.snapshot();
+        snap.droplets[&c2id('b')].location = yx(1, 2);
+        assert_matches!(
+            gv.apply_snapshot(snap),
+            Err(PuddleError::InvalidSnapshot(SnapshotError::Collision(..)))
+        );
+        assert_eq!(gv.droplet_info(None), before);
+
+        let mut snap = gv.snapshot();
+        snap.droplets[&c2id('b')].location = yx(3, 4);
+        assert_matches!(
+            gv.apply_snapshot(snap),
+            Err(PuddleError::InvalidSnapshot(SnapshotError::OutOfBounds(..)))
+        );
+    }
+
+    #[test]
+    fn test_export_import_state() {
+        let mut gv = parse_gridview(&["aa..b", "aa...", "     ", "c...."]);
+        gv.droplets.get_mut(&c2id('b')).unwrap().collision_group = 7;
+        gv.droplets.get_mut(&c2id('c')).unwrap().collision_group = 7;
+        gv.grid.get_cell_mut(yx(1, 4)).unwrap().enabled = false;
+        gv.record_move(c2id('c'), yx(3, 1), yx(3, 0));
+        gv.record_move(c2id('c'), yx(3, 1), yx(3, 0));
+        gv.set_reservoir("water", 4.5);
+        gv.set_render_interval(Duration::from_millis(250));
+        gv.set_keepout_border(1);
+        gv.set_stuck_threshold(Some(2));
+        gv.set_allow_diagonal(true);
+        gv.set_proximity_penalty(2, 5);
+        gv.set_min_volume(0.25);
+        gv.set_cell_volume(Some(1.5));
+
+        let mut buf = Vec::new();
+        gv.export_state(&mut buf).unwrap();
+        let gv2 = GridView::import_state(buf.as_slice()).unwrap();
+
+        assert_eq!(gv.grid, gv2.grid);
+        assert!(!gv2.grid.is_enabled(yx(1, 4)));
+        assert_eq!(gv.droplet_info(None), gv2.droplet_info(None));
+        assert_eq!(gv.groups(), gv2.groups());
+        assert_eq!(gv2.stuck_droplets(2), vec![c2id('c')]);
+        assert_eq!(gv.reservoir_levels(), gv2.reservoir_levels());
+        assert_eq!(gv2.render_interval(), Some(Duration::from_millis(250)));
+        assert_eq!(gv2.keepout_border(), 1);
+        assert_eq!(gv2.stuck_threshold(), Some(2));
+        assert!(gv2.allow_diagonal());
+        assert_eq!(gv2.proximity_penalty(), (2, 5));
+        assert_eq!(gv2.min_volume(), 0.25);
+        assert_eq!(gv2.cell_volume(), Some(1.5));
+
+        // older exports without any of that still load
+        let json = r#"{"grid": {"board": [[0, 1]]}, "droplets": []}"#;
+        let gv3 = GridView::import_state(json.as_bytes()).unwrap();
+        assert_eq!(gv3.min_volume(), DEFAULT_MIN_VOLUME);
+        assert!(gv3.stuck_droplets(0).is_empty());
+    }
+
     #[test]
     #[should_panic]
     fn test_subview_check() {
@@ -280,4 +1147,124 @@ pub mod tests {
         sub.update(&c2id('b'), |b| b.location = yx(0, 2))
     }
 
+    #[test]
+    fn test_stuck_droplets() {
+        let mut gv = parse_gridview(&["a...", "...."]);
+        let a = c2id('a');
+
+        // a keeps getting sent right, but never leaves
+        for _ in 0..2 {
+            gv.record_move(a, yx(0, 1), yx(0, 0));
+        }
+        assert!(gv.stuck_droplets(3).is_empty());
+        gv.record_move(a, yx(0, 1), yx(0, 0));
+        assert_eq!(gv.stuck_droplets(3), vec![a]);
+
+        // finally making it resets things
+        gv.record_move(a, yx(0, 1), yx(0, 1));
+        assert!(gv.stuck_droplets(1).is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_collisions() {
+        // a and b overlap, c is right next to d, e is off on its own
+        let gv = parse_gridview(&["aa.....e", ".bb.....", "....cd.."]);
+        let mut snap = gv.snapshot();
+        snap.droplets.get_mut(&c2id('b')).unwrap().location = yx(0, 1);
+        let (a, b, c, d, e) = (c2id('a'), c2id('b'), c2id('c'), c2id('d'), c2id('e'));
+
+        assert_eq!(snap.collisions(), vec![(a, b), (c, d)]);
+        assert_eq!(snap.collisions_within(0), vec![(a, b)]);
+        // e is 5 empty cells away from a
+        assert!(!snap.collisions_within(5).contains(&(a, e)));
+        assert!(snap.collisions_within(6).contains(&(a, e)));
+
+        // the same group is allowed to touch
+        let group = snap.droplets[&c].collision_group;
+        snap.droplets.get_mut(&d).unwrap().collision_group = group;
+        assert_eq!(snap.collisions(), vec![(a, b)]);
+    }
+
+    #[test]
+    fn test_snapshot_bounding_box() {
+        let gv = parse_gridview(&["......", "..aa..", "....b."]);
+        let snap = gv.snapshot();
+        let expected = Rectangle::new(yx(1, 2), yx(2, 3));
+        assert_eq!(snap.bounding_box(), Some(expected));
+        assert_eq!(Snapshot::default().bounding_box(), None);
+    }
+
+    #[test]
+    fn test_diff_created() {
+        let mut gv = parse_gridview(&["a...", "...."]);
+        let prev = gv.snapshot();
+        assert!(gv.diff(&prev).is_empty());
+
+        let b = parse_gridview(&["....", "..b."]).droplets[&c2id('b')].clone();
+        gv.droplets.insert(b.id, b);
+        let diff = gv.diff(&prev);
+        let added: Vec<_> = diff.added.iter().map(|d| d.id).collect();
+        assert_eq!(added, vec![c2id('b')]);
+        assert!(diff.removed.is_empty());
+        assert!(diff.moved.is_empty());
+    }
+
+    #[test]
+    fn test_diff_removed() {
+        let mut gv = parse_gridview(&["a...", "..b."]);
+        let prev = gv.snapshot();
+        gv.droplets.shift_remove(&c2id('a'));
+        let diff = gv.diff(&prev);
+        assert!(diff.added.is_empty());
+        assert_eq!(diff.removed, vec![c2id('a')]);
+        assert!(diff.moved.is_empty());
+    }
+
+    #[test]
+    fn test_diff_moved() {
+        let prev = parse_gridview(&["a...", "..b."]).snapshot();
+        let now = parse_gridview(&["....", "a.b."]).snapshot();
+        let diff = now.diff(&prev);
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+        let moved = Moved {
+            id: c2id('a'),
+            from: yx(0, 0),
+            to: yx(1, 0),
+        };
+        assert_eq!(diff.moved, vec![moved]);
+
+        // the empty lists don't get sent
+        let json = serde_json::to_value(&diff).unwrap();
+        assert_eq!(json.as_object().unwrap().len(), 1);
+        assert_eq!(json["moved"][0]["to"], serde_json::json!({"y": 1, "x": 0}));
+    }
+
+    #[test]
+    fn test_diff_changed() {
+        let mut gv = parse_gridview(&["a...", "..b."]);
+        let prev = gv.snapshot();
+
+        // a grew where it was, and b moved and shrank
+        let a = gv.droplets.get_mut(&c2id('a')).unwrap();
+        a.dimensions = yx(1, 2);
+        let b = gv.droplets.get_mut(&c2id('b')).unwrap();
+        b.location = yx(1, 3);
+        b.volume /= 2.0;
+
+        let diff = gv.diff(&prev);
+        assert!(diff.added.is_empty() && diff.removed.is_empty());
+        assert!(diff.moved.is_empty());
+        let changed: Vec<_> = diff.changed.iter().map(|d| d.info()).collect();
+        assert_eq!(changed[0].dimensions, yx(1, 2));
+        assert_eq!(changed[1].location, yx(1, 3));
+        assert_eq!(changed[1].volume, prev.droplets[&c2id('b')].volume / 2.0);
+        assert!(!diff.is_empty());
+
+        // routing bookkeeping isn't a change anyone needs to see
+        let mut gv = parse_gridview(&["a..."]);
+        let prev = gv.snapshot();
+        gv.droplets.get_mut(&c2id('a')).unwrap().pinned = true;
+        assert!(gv.diff(&prev).is_empty());
+    }
 }
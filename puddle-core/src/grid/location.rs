@@ -66,31 +66,165 @@ impl Location {
     pub fn east(self) -> Location {
         self + yx(0, 1)
     }
+
+    pub fn north_east(self) -> Location {
+        self + yx(-1, 1)
+    }
+
+    pub fn north_west(self) -> Location {
+        self + yx(-1, -1)
+    }
+
+    pub fn south_east(self) -> Location {
+        self + yx(1, 1)
+    }
+
+    pub fn south_west(self) -> Location {
+        self + yx(1, -1)
+    }
+
+    /// The four locations that touch this one only at a corner.
+    pub fn diagonals(self) -> [Location; 4] {
+        [
+            self.north_west(),
+            self.north_east(),
+            self.south_west(),
+            self.south_east(),
+        ]
+    }
+
+    /// The four locations sharing an edge with this one. Unlike
+    /// `Grid::neighbors4`, these may be off the grid.
+    pub fn neighbors4(self) -> [Location; 4] {
+        [self.north(), self.west(), self.south(), self.east()]
+    }
+
+    /// Everything touching this one, edges first, then corners.
+    pub fn neighbors8(self) -> [Location; 8] {
+        let [n, w, s, e] = self.neighbors4();
+        let [nw, ne, sw, se] = self.diagonals();
+        [n, w, s, e, nw, ne, sw, se]
+    }
+
+    /// Number of steps to `other` when diagonal steps are allowed.
+    pub fn chebyshev_distance_to(self, other: Self) -> u32 {
+        let d = self - other;
+        d.y.abs().max(d.x.abs()) as u32
+    }
+
+    /// Mirrors the x coordinate across the vertical line through column `x`.
+    pub fn reflect_x(self, x: i32) -> Location {
+        yx(self.y, 2 * x - self.x)
+    }
+
+    /// Mirrors the y coordinate across the horizontal line through row `y`.
+    pub fn reflect_y(self, y: i32) -> Location {
+        yx(2 * y - self.y, self.x)
+    }
+
+    /// Turns clockwise around the origin by `times` quarter turns, so
+    /// east becomes south.
+    pub fn rotate90(self, times: u32) -> Location {
+        (0..times % 4).fold(self, |loc, _| yx(loc.x, -loc.y))
+    }
+}
+
+/// What can go wrong parsing a `Location` or `Location3`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocationParseError {
+    Int(ParseIntError),
+    /// The wrong number of coordinates, this many were given.
+    Arity(usize),
+}
+
+impl fmt::Display for LocationParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LocationParseError::Int(err) => write!(f, "Bad coordinate: {}", err),
+            LocationParseError::Arity(n) => write!(f, "Wrong number of coordinates: {}", n),
+        }
+    }
+}
+
+impl std::error::Error for LocationParseError {}
+
+impl From<ParseIntError> for LocationParseError {
+    fn from(err: ParseIntError) -> Self {
+        LocationParseError::Int(err)
+    }
+}
+
+// the comma-separated ints in something like "(1, 2)"
+fn parse_coords(s: &str) -> Result<Vec<i32>, LocationParseError> {
+    let coords = s
+        .trim()
+        .trim_matches(|p| p == '(' || p == ')')
+        .split(',')
+        .map(|c| c.trim().parse())
+        .collect::<Result<_, _>>()?;
+    Ok(coords)
 }
 
 impl FromStr for Location {
-    type Err = ParseIntError;
+    type Err = LocationParseError;
 
+    /// Takes exactly 2 ints, like `"(1, 2)"` or `"1,2"`.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let coords: Vec<&str> = s
-            .trim()
-            .trim_matches(|p| p == '(' || p == ')')
-            .split(',')
-            .map(str::trim)
-            .collect();
+        match parse_coords(s)?[..] {
+            [y, x] => Ok(yx(y, x)),
+            ref coords => Err(LocationParseError::Arity(coords.len())),
+        }
+    }
+}
+
+/// A location on a chip with more than one layer. `z` is the layer, and
+/// the bottom one is 0.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy, Debug)] // std
+#[derive(Serialize, Deserialize)] // serde
+pub struct Location3 {
+    pub y: i32,
+    pub x: i32,
+    #[serde(default)]
+    pub z: i32,
+}
+
+impl Location3 {
+    /// Where this is within its layer.
+    pub fn location(self) -> Location {
+        yx(self.y, self.x)
+    }
+}
 
-        if coords.len() != 2 {
-            panic!("A location is 2 comma-separated ints. Given: '{}'", s)
+impl From<Location> for Location3 {
+    fn from(loc: Location) -> Location3 {
+        Location3 {
+            y: loc.y,
+            x: loc.x,
+            z: 0,
         }
+    }
+}
 
-        let y = coords[0].parse()?;
-        let x = coords[1].parse()?;
+impl fmt::Display for Location3 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({}, {}, {})", self.y, self.x, self.z)
+    }
+}
 
-        Ok(yx(y, x))
+impl FromStr for Location3 {
+    type Err = LocationParseError;
+
+    /// Takes 2 or 3 ints, the missing `z` is 0.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_coords(s)?[..] {
+            [y, x] => Ok(Location3 { y, x, z: 0 }),
+            [y, x, z] => Ok(Location3 { y, x, z }),
+            ref coords => Err(LocationParseError::Arity(coords.len())),
+        }
     }
 }
 
-#[derive(Clone)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Rectangle {
     pub location: Location,
     pub dimensions: Location,
@@ -140,6 +274,59 @@ impl Rectangle {
         y_dist.max(x_dist)
     }
 
+    fn is_empty(&self) -> bool {
+        self.dimensions.y <= 0 || self.dimensions.x <= 0
+    }
+
+    /// The region covered by both rectangles, if there is one. Rectangles
+    /// that only touch along an edge don't intersect.
+    pub fn intersection(&self, other: &Rectangle) -> Option<Rectangle> {
+        let top = self.top_edge().max(other.top_edge());
+        let bottom = self.bottom_edge().min(other.bottom_edge());
+        let left = self.left_edge().max(other.left_edge());
+        let right = self.right_edge().min(other.right_edge());
+        if top >= bottom || left >= right {
+            return None;
+        }
+        Some(Rectangle::new(
+            yx(top, left),
+            yx(bottom - top, right - left),
+        ))
+    }
+
+    pub fn contains_location(&self, loc: Location) -> bool {
+        self.top_edge() <= loc.y
+            && loc.y < self.bottom_edge()
+            && self.left_edge() <= loc.x
+            && loc.x < self.right_edge()
+    }
+
+    /// Whether every cell of `other` is in this rectangle. Empty rectangles
+    /// never contain anything, and aren't contained by anything either.
+    pub fn contains_rect(&self, other: &Rectangle) -> bool {
+        !self.is_empty()
+            && !other.is_empty()
+            && self.top_edge() <= other.top_edge()
+            && other.bottom_edge() <= self.bottom_edge()
+            && self.left_edge() <= other.left_edge()
+            && other.right_edge() <= self.right_edge()
+    }
+
+    /// Turns clockwise around the origin by `times` quarter turns, like
+    /// `Location::rotate90`. The result covers exactly the rotated cells,
+    /// so odd turns swap the dimensions.
+    pub fn rotate90(self, times: u32) -> Rectangle {
+        if self.is_empty() {
+            return self;
+        }
+        // rotate opposite corner cells, and the new top left is between them
+        let a = self.location.rotate90(times);
+        let b = (self.location + self.dimensions - yx(1, 1)).rotate90(times);
+        let top_left = yx(a.y.min(b.y), a.x.min(b.x));
+        let dimensions = yx((a.y - b.y).abs() + 1, (a.x - b.x).abs() + 1);
+        Rectangle::new(top_left, dimensions)
+    }
+
     pub fn locations(self) -> impl Iterator<Item = Location> {
         let ys = 0..(self.dimensions.y);
         ys.flat_map(move |y| {
@@ -148,12 +335,238 @@ impl Rectangle {
             xs.map(move |x| base + yx(y, x))
         })
     }
+
+    /// Like `locations`, but every other row goes right to left, so each
+    /// location is next to the one before it.
+    pub fn locations_serpentine(self) -> impl Iterator<Item = Location> {
+        let ys = 0..(self.dimensions.y);
+        ys.flat_map(move |y| {
+            let width = self.dimensions.x;
+            let base = self.location;
+            (0..width).map(move |i| {
+                let x = if y % 2 == 0 { i } else { width - 1 - i };
+                base + yx(y, x)
+            })
+        })
+    }
+
+    /// Walks clockwise around the edge starting at the top left, then keeps
+    /// going around each ring inside it until it hits the middle.
+    pub fn locations_spiral(self) -> impl Iterator<Item = Location> {
+        let mut locs = Vec::new();
+        let (mut top, mut left) = (0, 0);
+        let (mut bottom, mut right) = (self.dimensions.y - 1, self.dimensions.x - 1);
+
+        while top <= bottom && left <= right {
+            locs.extend((left..=right).map(|x| yx(top, x)));
+            locs.extend((top + 1..=bottom).map(|y| yx(y, right)));
+            // a single row or column has no way back
+            if top < bottom {
+                locs.extend((left..right).rev().map(|x| yx(bottom, x)));
+            }
+            if left < right {
+                locs.extend((top + 1..bottom).rev().map(|y| yx(y, left)));
+            }
+            top += 1;
+            left += 1;
+            bottom -= 1;
+            right -= 1;
+        }
+
+        let base = self.location;
+        locs.into_iter().map(move |loc| base + loc)
+    }
+}
+
+/// An inclusive box of locations, from `min` to `max`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct GridBounds {
+    pub min: Location,
+    pub max: Location,
+}
+
+impl GridBounds {
+    pub fn new(min: Location, max: Location) -> GridBounds {
+        GridBounds { min, max }
+    }
+
+    pub fn contains(&self, loc: Location) -> bool {
+        self.min.y <= loc.y && loc.y <= self.max.y && self.min.x <= loc.x && loc.x <= self.max.x
+    }
+
+    /// Iterates over every location in the box, row by row.
+    pub fn iter(&self) -> impl Iterator<Item = Location> {
+        let GridBounds { min, max } = *self;
+        (min.y..=max.y).flat_map(move |y| (min.x..=max.x).map(move |x| yx(y, x)))
+    }
+
+    /// Returns the location in the box closest to `loc`.
+    pub fn clamp(&self, loc: Location) -> Location {
+        yx(
+            loc.y.max(self.min.y).min(self.max.y),
+            loc.x.max(self.min.x).min(self.max.x),
+        )
+    }
+}
+
+/// The smallest rectangle covering every one of `locations`, or `None` if
+/// there aren't any.
+pub fn bounding_box(locations: impl Iterator<Item = Location>) -> Option<Rectangle> {
+    let mut locations = locations;
+    let first = locations.next()?;
+    let (min, max) = locations.fold((first, first), |(min, max), loc| {
+        let min = yx(min.y.min(loc.y), min.x.min(loc.x));
+        let max = yx(max.y.max(loc.y), max.x.max(loc.x));
+        (min, max)
+    });
+    Some(Rectangle::new(min, max - min + yx(1, 1)))
+}
+
+/// The average of `locations` as `(y, x)`, or `None` if there aren't any.
+/// It's usually between cells, so it isn't rounded to a `Location`.
+pub fn centroid(locations: impl Iterator<Item = Location>) -> Option<(f64, f64)> {
+    let (mut y, mut x, mut n) = (0.0, 0.0, 0);
+    for loc in locations {
+        y += f64::from(loc.y);
+        x += f64::from(loc.x);
+        n += 1;
+    }
+    if n == 0 {
+        return None;
+    }
+    Some((y / f64::from(n), x / f64::from(n)))
 }
 
 #[cfg(test)]
 pub mod tests {
     use super::*;
 
+    #[test]
+    fn test_rotate_rectangle() {
+        let rect = Rectangle::new(yx(1, 2), yx(2, 3));
+        let mut rotated = rect.clone();
+        for turn in 1..=4 {
+            rotated = rotated.rotate90(1);
+            assert_eq!(rotated, rect.clone().rotate90(turn));
+
+            // covers exactly the rotated cells
+            let mut cells: Vec<_> = rect.clone().locations().map(|l| l.rotate90(turn)).collect();
+            let mut expected: Vec<_> = rotated.clone().locations().collect();
+            cells.sort();
+            expected.sort();
+            assert_eq!(cells, expected);
+        }
+        assert_eq!(rect.clone().rotate90(1).dimensions, yx(3, 2));
+        assert_eq!(rect.clone().rotate90(2).dimensions, yx(2, 3));
+        assert_eq!(rotated, rect);
+    }
+
+    #[test]
+    fn test_reflect() {
+        let loc = yx(3, 1);
+        assert_eq!(loc.reflect_x(4), yx(3, 7));
+        assert_eq!(loc.reflect_x(4).reflect_x(4), loc);
+        // a point on the axis stays put
+        assert_eq!(yx(0, 4).reflect_x(4), yx(0, 4));
+        assert_eq!(loc.reflect_y(1), yx(-1, 1));
+    }
+
+    #[test]
+    fn test_bounds() {
+        let bounds = GridBounds::new(yx(1, 2), yx(3, 5));
+
+        assert!(bounds.contains(yx(1, 2)));
+        assert!(bounds.contains(yx(3, 5)));
+        assert!(bounds.contains(yx(2, 4)));
+        assert!(!bounds.contains(yx(0, 3)));
+        assert!(!bounds.contains(yx(2, 6)));
+
+        let locs: Vec<_> = bounds.iter().collect();
+        assert_eq!(locs.len(), 3 * 4);
+        assert_eq!(locs[0], yx(1, 2));
+        assert_eq!(locs[1], yx(1, 3));
+        assert_eq!(locs[11], yx(3, 5));
+        assert!(locs.iter().all(|&loc| bounds.contains(loc)));
+
+        assert_eq!(bounds.clamp(yx(2, 3)), yx(2, 3));
+        assert_eq!(bounds.clamp(yx(-4, 3)), yx(1, 3));
+        assert_eq!(bounds.clamp(yx(9, 9)), yx(3, 5));
+
+        let grid = crate::grid::Grid::rectangle(4, 6);
+        assert_eq!(grid.bounds(), GridBounds::new(yx(0, 0), yx(3, 5)));
+        assert_eq!(grid.bounds().iter().count(), grid.locations().count());
+    }
+
+    #[test]
+    fn test_bounding_box() {
+        assert_eq!(bounding_box(std::iter::empty()), None);
+        assert_eq!(centroid(std::iter::empty()), None);
+
+        let one = vec![yx(2, 3)];
+        assert_eq!(
+            bounding_box(one.iter().cloned()),
+            Some(Rectangle::new(yx(2, 3), yx(1, 1)))
+        );
+        assert_eq!(centroid(one.into_iter()), Some((2.0, 3.0)));
+
+        let many = vec![yx(4, 1), yx(1, 5), yx(2, -1), yx(3, 3)];
+        assert_eq!(
+            bounding_box(many.iter().cloned()),
+            Some(Rectangle::new(yx(1, -1), yx(4, 7)))
+        );
+        assert_eq!(centroid(many.into_iter()), Some((2.5, 2.0)));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        use matches::assert_matches;
+        use LocationParseError::*;
+        assert_eq!("(1)".parse::<Location>(), Err(Arity(1)));
+        assert_eq!("(1,2,3)".parse::<Location>(), Err(Arity(3)));
+        assert_matches!("(a,b)".parse::<Location>(), Err(Int(_)));
+        assert_matches!("()".parse::<Location>(), Err(Int(_)));
+    }
+
+    #[test]
+    fn test_parse_location3() {
+        let flat: Location3 = "(1,2)".parse().unwrap();
+        assert_eq!(flat, Location3 { y: 1, x: 2, z: 0 });
+        assert_eq!(flat, yx(1, 2).into());
+        let layered: Location3 = " (1, 2, 3) ".parse().unwrap();
+        assert_eq!(layered, Location3 { y: 1, x: 2, z: 3 });
+        assert_eq!(layered.location(), yx(1, 2));
+
+        assert_eq!(
+            "(1,2,3,4)".parse::<Location3>(),
+            Err(LocationParseError::Arity(4))
+        );
+        // plain locations are still 2d only
+        assert_eq!("(1,2)".parse(), Ok(yx(1, 2)));
+        assert_eq!(
+            "(1,2,3)".parse::<Location>(),
+            Err(LocationParseError::Arity(3))
+        );
+
+        // z can be left out of json too
+        let json: Location3 = serde_json::from_str(r#"{"y": 4, "x": 5}"#).unwrap();
+        assert_eq!(json, Location3 { y: 4, x: 5, z: 0 });
+    }
+
+    #[test]
+    fn test_neighbors() {
+        let loc = yx(2, 2);
+        assert_eq!(loc.north_east(), yx(1, 3));
+        assert_eq!(loc.south_west(), yx(3, 1));
+
+        let n8 = loc.neighbors8();
+        assert_eq!(n8[..4], loc.neighbors4());
+        assert!(n8.iter().all(|n| n.chebyshev_distance_to(loc) == 1));
+        assert_eq!(n8.iter().filter(|n| n.distance_to(loc) == 1).count(), 4);
+
+        assert_eq!(yx(0, 0).chebyshev_distance_to(yx(3, 2)), 3);
+        assert_eq!(yx(0, 0).distance_to(yx(3, 2)), 5);
+    }
+
     fn check_dist(r1: Rectangle, r2: Rectangle, expected: i32) {
         let actual1 = r1.collision_distance(&r2);
         let actual2 = r2.collision_distance(&r1);
@@ -202,4 +615,106 @@ pub mod tests {
             -1,
         );
     }
+
+    #[test]
+    fn test_rectangle_intersection() {
+        // overlap
+        // ....aa....
+        // ....aXbb..
+        let a = Rectangle::new(yx(0, 4), yx(2, 2));
+        let b = Rectangle::new(yx(1, 5), yx(1, 3));
+        let expected = Rectangle::new(yx(1, 5), yx(1, 1));
+        assert_eq!(a.intersection(&b), Some(expected.clone()));
+        assert_eq!(b.intersection(&a), Some(expected));
+
+        // touching isn't overlapping
+        // aabb
+        let a = Rectangle::new(yx(0, 0), yx(1, 2));
+        let b = Rectangle::new(yx(0, 2), yx(1, 2));
+        assert_eq!(a.intersection(&b), None);
+
+        // diagonals don't overlap either
+        // a.
+        // .b
+        let a = Rectangle::new(yx(0, 0), yx(1, 1));
+        let b = Rectangle::new(yx(1, 1), yx(1, 1));
+        assert_eq!(a.intersection(&b), None);
+    }
+
+    #[test]
+    fn test_rectangle_contains() {
+        // aaaa
+        // aXXa
+        // aaaa
+        let a = Rectangle::new(yx(0, 0), yx(3, 4));
+        let b = Rectangle::new(yx(1, 1), yx(1, 2));
+        assert!(a.contains_rect(&b));
+        assert!(!b.contains_rect(&a));
+        assert!(a.contains_rect(&a));
+        assert!(a.contains_location(yx(2, 3)));
+        assert!(!a.contains_location(yx(3, 3)));
+        assert!(!a.contains_location(yx(0, -1)));
+
+        // sticking out the side
+        // aaaa
+        // aaXXb
+        let b = Rectangle::new(yx(1, 2), yx(1, 3));
+        assert!(!a.contains_rect(&b));
+
+        let empty = Rectangle::new(yx(1, 1), yx(0, 2));
+        assert!(!empty.contains_location(yx(1, 1)));
+        assert!(!empty.contains_rect(&empty));
+        assert!(!a.contains_rect(&empty));
+    }
+
+    #[test]
+    fn test_rectangle_serpentine() {
+        let rect = Rectangle::new(yx(1, 1), yx(3, 2));
+        let locs: Vec<_> = rect.clone().locations_serpentine().collect();
+        let expected = vec![yx(1, 1), yx(1, 2), yx(2, 2), yx(2, 1), yx(3, 1), yx(3, 2)];
+        assert_eq!(locs, expected);
+
+        // same cells as the normal order
+        let mut sorted = locs.clone();
+        sorted.sort();
+        assert_eq!(sorted, rect.locations().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_rectangle_spiral() {
+        let spiral = |y, x| -> Vec<Location> {
+            Rectangle::new(yx(0, 0), yx(y, x))
+                .locations_spiral()
+                .collect()
+        };
+
+        // 012
+        // 783
+        // 654
+        #[rustfmt::skip]
+        let expected = vec![
+            yx(0, 0), yx(0, 1), yx(0, 2), yx(1, 2), yx(2, 2),
+            yx(2, 1), yx(2, 0), yx(1, 0), yx(1, 1),
+        ];
+        assert_eq!(spiral(3, 3), expected);
+
+        // 0123
+        // 9AB4
+        // 8765
+        #[rustfmt::skip]
+        let expected = vec![
+            yx(0, 0), yx(0, 1), yx(0, 2), yx(0, 3), yx(1, 3), yx(2, 3),
+            yx(2, 2), yx(2, 1), yx(2, 0), yx(1, 0), yx(1, 1), yx(1, 2),
+        ];
+        assert_eq!(spiral(3, 4), expected);
+
+        assert_eq!(spiral(1, 3), vec![yx(0, 0), yx(0, 1), yx(0, 2)]);
+        assert_eq!(spiral(3, 1), vec![yx(0, 0), yx(1, 0), yx(2, 0)]);
+        assert_eq!(spiral(0, 3), vec![]);
+
+        let offset: Vec<_> = Rectangle::new(yx(2, 3), yx(2, 2))
+            .locations_spiral()
+            .collect();
+        assert_eq!(offset, vec![yx(2, 3), yx(2, 4), yx(3, 4), yx(3, 3)]);
+    }
 }
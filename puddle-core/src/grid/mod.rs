@@ -3,8 +3,16 @@ pub mod grid;
 pub mod gridview;
 pub mod location;
 pub mod parse;
+pub mod pinmap;
+pub mod render;
+pub mod route;
+pub mod sizing;
 
 pub use self::droplet::*;
-pub use self::grid::{Electrode, Grid, Peripheral};
-pub use self::gridview::GridView;
-pub use self::location::{Location, Rectangle};
+pub use self::grid::{Axis, Drive, Electrode, Grid, GridError, Peripheral};
+pub use self::gridview::{GridView, Moved, Snapshot, SnapshotDiff, DEFAULT_MIN_VOLUME};
+pub use self::location::{
+    bounding_box, centroid, GridBounds, Location, Location3, LocationParseError, Rectangle,
+};
+pub use self::parse::{GridFormat, GridFormatError};
+pub use self::pinmap::PinMap;
@@ -1,3 +1,7 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Read;
+
 use serde::{Deserialize, Serialize};
 
 use crate::grid::grid::*;
@@ -25,6 +29,39 @@ pub struct ParsedGrid {
     pub board: Vec<Vec<ParsedElectrode>>,
     #[serde(default)]
     pub peripherals: Vec<LocatedPeripheral>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub one_way: Vec<OneWay>,
+    // electrodes that are there but shouldn't be used
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub disabled: Vec<Location>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub overrides: Vec<DriveOverride>,
+}
+
+/// A pair of cells droplets can only move between in one direction.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OneWay {
+    from: Location,
+    to: Location,
+}
+
+/// A different voltage or frequency for a rectangle of cells, one cell by
+/// default.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DriveOverride {
+    location: Location,
+    #[serde(default = "one_cell", skip_serializing_if = "is_one_cell")]
+    dimensions: Location,
+    #[serde(flatten)]
+    drive: Drive,
+}
+
+fn one_cell() -> Location {
+    Location { y: 1, x: 1 }
+}
+
+fn is_one_cell(dim: &Location) -> bool {
+    *dim == one_cell()
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -34,13 +71,19 @@ pub struct LocatedPeripheral {
     peripheral: Peripheral,
 }
 
-impl From<ParsedGrid> for Grid {
-    fn from(pg: ParsedGrid) -> Grid {
+/// Fails if a peripheral or disabled electrode isn't on an electrode, or if
+/// two peripherals are in the same place. Everything else is left to
+/// `Grid::validate`.
+impl TryFrom<ParsedGrid> for Grid {
+    type Error = GridError;
+
+    fn try_from(pg: ParsedGrid) -> Result<Grid, GridError> {
         let mut f = |pe: &ParsedElectrode| match pe {
             Marked(Empty) => None,
             Index(n) => Some(Electrode {
                 pin: *n,
                 peripheral: None,
+                enabled: true,
             }),
         };
 
@@ -50,21 +93,46 @@ impl From<ParsedGrid> for Grid {
                 .iter()
                 .map(|row| row.iter().map(&mut f).collect())
                 .collect(),
+            transitions: Default::default(),
+            drive: Default::default(),
         };
 
+        for one_way in &pg.one_way {
+            grid.add_one_way(one_way.from, one_way.to);
+        }
+
+        // later overrides win where they overlap
+        for o in &pg.overrides {
+            for y in 0..o.dimensions.y {
+                for x in 0..o.dimensions.x {
+                    let loc = o.location + Location { y, x };
+                    grid.drive.insert(loc, o.drive);
+                }
+            }
+        }
+
+        for &loc in &pg.disabled {
+            let electrode = grid.get_cell_mut(loc).ok_or(GridError::OutOfRange(loc))?;
+            electrode.enabled = false;
+        }
+
         for loc_periph in pg.peripherals.iter() {
-            let electrode = grid.get_cell_mut(loc_periph.location).unwrap();
-            assert_eq!(electrode.peripheral, None);
+            let loc = loc_periph.location;
+            let electrode = grid.get_cell_mut(loc).ok_or(GridError::OutOfRange(loc))?;
+            if electrode.peripheral.is_some() {
+                return Err(GridError::DuplicatePeripheral(loc));
+            }
             electrode.peripheral = Some(loc_periph.peripheral.clone());
         }
 
-        grid
+        Ok(grid)
     }
 }
 
 impl From<Grid> for ParsedGrid {
     fn from(grid: Grid) -> ParsedGrid {
         let mut peripherals = Vec::default();
+        let mut disabled = Vec::default();
         let board = grid
             .vec
             .iter()
@@ -75,11 +143,14 @@ impl From<Grid> for ParsedGrid {
                     .map(|(j, e_opt)| match e_opt {
                         None => ParsedElectrode::Marked(Mark::Empty),
                         Some(e) => {
+                            let loc = Location {
+                                y: i as i32,
+                                x: j as i32,
+                            };
+                            if !e.enabled {
+                                disabled.push(loc);
+                            }
                             if let Some(ref peripheral) = e.peripheral {
-                                let loc = Location {
-                                    y: i as i32,
-                                    x: j as i32,
-                                };
                                 peripherals.push(LocatedPeripheral {
                                     location: loc,
                                     peripheral: peripheral.clone(),
@@ -91,7 +162,134 @@ impl From<Grid> for ParsedGrid {
                     .collect()
             })
             .collect();
-        ParsedGrid { board, peripherals }
+        let one_way = grid
+            .transitions
+            .iter()
+            .filter(|(_, &ok)| ok)
+            .map(|(&(from, to), _)| OneWay { from, to })
+            .collect();
+        let overrides = grid
+            .drive
+            .iter()
+            .map(|(&location, &drive)| DriveOverride {
+                location,
+                dimensions: one_cell(),
+                drive,
+            })
+            .collect();
+        ParsedGrid {
+            board,
+            peripherals,
+            one_way,
+            disabled,
+            overrides,
+        }
+    }
+}
+
+/// The formats a grid file can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GridFormat {
+    Json,
+    /// TOML arrays can't mix numbers and strings, so this only works for
+    /// boards without gaps.
+    Toml,
+    Yaml,
+}
+
+impl GridFormat {
+    /// Guesses the format of a grid file. Anything starting with `{` or `[`
+    /// is JSON, a first line like `board = ...` is TOML, and anything else
+    /// is YAML. Note that this means a TOML file can't start with a table.
+    pub fn sniff(s: &str) -> GridFormat {
+        match s.trim_start().chars().next() {
+            Some('{') | Some('[') => return GridFormat::Json,
+            _ => (),
+        }
+        let first_line = s
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'))
+            .unwrap_or("");
+        match (first_line.find('='), first_line.find(':')) {
+            (Some(eq), Some(colon)) if eq < colon => GridFormat::Toml,
+            (Some(_), None) => GridFormat::Toml,
+            _ => GridFormat::Yaml,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GridFormatError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Toml(toml::de::Error),
+    TomlWrite(toml::ser::Error),
+    Yaml(serde_yaml::Error),
+    /// It parsed, but `Grid::validate` found these problems with it.
+    Invalid(Vec<GridError>),
+}
+
+impl fmt::Display for GridFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GridFormatError::Io(err) => write!(f, "Couldn't read grid: {}", err),
+            GridFormatError::Json(err) => write!(f, "Bad JSON grid: {}", err),
+            GridFormatError::Toml(err) => write!(f, "Bad TOML grid: {}", err),
+            GridFormatError::TomlWrite(err) => write!(f, "Couldn't write TOML grid: {}", err),
+            GridFormatError::Yaml(err) => write!(f, "Bad YAML grid: {}", err),
+            GridFormatError::Invalid(errors) => {
+                write!(f, "Invalid grid:")?;
+                for err in errors {
+                    write!(f, "\n  {}", err)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for GridFormatError {}
+
+impl Grid {
+    /// Reads a grid, guessing the format with `GridFormat::sniff`.
+    pub fn from_reader(mut reader: impl Read) -> Result<Grid, GridFormatError> {
+        let mut s = String::new();
+        reader.read_to_string(&mut s).map_err(GridFormatError::Io)?;
+        Grid::from_str_with_format(&s, GridFormat::sniff(&s))
+    }
+
+    pub fn from_reader_with_format(
+        mut reader: impl Read,
+        format: GridFormat,
+    ) -> Result<Grid, GridFormatError> {
+        let mut s = String::new();
+        reader.read_to_string(&mut s).map_err(GridFormatError::Io)?;
+        Grid::from_str_with_format(&s, format)
+    }
+
+    pub fn to_string_with_format(&self, format: GridFormat) -> Result<String, GridFormatError> {
+        let pg = ParsedGrid::from(self.clone());
+        match format {
+            GridFormat::Json => serde_json::to_string_pretty(&pg).map_err(GridFormatError::Json),
+            GridFormat::Toml => {
+                // going through a Value puts the tables after the plain
+                // values, which TOML insists on
+                let value = toml::Value::try_from(&pg).map_err(GridFormatError::TomlWrite)?;
+                toml::to_string(&value).map_err(GridFormatError::TomlWrite)
+            }
+            GridFormat::Yaml => serde_yaml::to_string(&pg).map_err(GridFormatError::Yaml),
+        }
+    }
+
+    fn from_str_with_format(s: &str, format: GridFormat) -> Result<Grid, GridFormatError> {
+        let grid: Grid = match format {
+            GridFormat::Json => serde_json::from_str(s).map_err(GridFormatError::Json),
+            GridFormat::Toml => toml::from_str(s).map_err(GridFormatError::Toml),
+            GridFormat::Yaml => serde_yaml::from_str(s).map_err(GridFormatError::Yaml),
+        }?;
+        grid.validate().map_err(GridFormatError::Invalid)?;
+        Ok(grid)
     }
 }
 
@@ -102,9 +300,10 @@ pub mod tests {
     use crate::tests::project_path;
 
     use glob::glob;
+    use matches::assert_matches;
     use std::fs::File;
 
-    use crate::grid::{droplet::SimpleBlob, location::yx, Grid, Location};
+    use crate::grid::{droplet::SimpleBlob, location::yx, Grid, Location, Rectangle};
     use indexmap::{IndexMap, IndexSet};
     use std::env;
 
@@ -175,6 +374,7 @@ pub mod tests {
                 Some(Electrode {
                     pin,
                     peripheral: None,
+                    enabled: true,
                 })
             } else {
                 None
@@ -224,12 +424,126 @@ pub mod tests {
             let reader = File::open(path.clone()).expect("file not found");
             let grid = serde_yaml::from_reader(reader).expect("parse failed");
             check_round_trip(grid, path.to_str().unwrap());
+            // the boards we ship should load through validation too
+            let reader = File::open(path.clone()).expect("file not found");
+            if let Err(err) = Grid::from_reader_with_format(reader, GridFormat::Yaml) {
+                panic!("{} failed to load: {}", path.display(), err);
+            }
             successes += 1;
         }
         debug!("Tested {} parsing round trips", successes);
         assert!(successes >= 4);
     }
 
+    #[test]
+    fn test_from_reader_errors() {
+        let json = Grid::rectangle(2, 2)
+            .to_string_with_format(GridFormat::Json)
+            .unwrap();
+        let truncated = &json[..json.len() / 2];
+        assert_matches!(
+            Grid::from_reader(truncated.as_bytes()),
+            Err(GridFormatError::Json(_))
+        );
+
+        // these used to panic while building the grid
+        let off_grid = "board:\n  - [0, 1]\ndisabled:\n  - {y: 3, x: 0}\n";
+        let err = Grid::from_reader(off_grid.as_bytes()).unwrap_err();
+        assert!(
+            err.to_string().contains("No electrode at (3, 0)"),
+            "{}",
+            err
+        );
+
+        let doubled = "board:\n  - [0, 1]\nperipherals:\n  \
+                       - {location: {y: 0, x: 1}, type: Output, pwm_channel: 0, name: a}\n  \
+                       - {location: {y: 0, x: 1}, type: Output, pwm_channel: 1, name: b}\n";
+        let err = Grid::from_reader(doubled.as_bytes()).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("More than one peripheral at (0, 1)"),
+            "{}",
+            err
+        );
+
+        let mut unreadable: &[u8] = &[0xff, 0xfe];
+        assert_matches!(
+            Grid::from_reader(&mut unreadable),
+            Err(GridFormatError::Io(_))
+        );
+    }
+
+    #[test]
+    fn test_from_reader_validates() {
+        let yaml =
+            "board:\n  - [0, 1, _, 1]\none_way:\n  - {from: {y: 0, x: 1}, to: {y: 0, x: 2}}\n";
+        match Grid::from_reader(yaml.as_bytes()) {
+            Err(GridFormatError::Invalid(errors)) => assert_eq!(
+                errors,
+                vec![
+                    GridError::DuplicatePin(1, yx(0, 1), yx(0, 3)),
+                    GridError::OutOfRange(yx(0, 2)),
+                    GridError::Disconnected(yx(0, 0), yx(0, 3)),
+                ]
+            ),
+            other => panic!("expected an invalid grid, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_overrides() {
+        let yaml = "board:\n  - [0, 1, 2]\n  - [3, 4, 5]\noverrides:\n  \
+                    - {location: {y: 0, x: 1}, dimensions: {y: 2, x: 2}, voltage: 2.0}\n  \
+                    - {location: {y: 1, x: 2}, frequency: 500}\n";
+        let grid = Grid::from_reader(yaml.as_bytes()).unwrap();
+        assert_eq!(grid.voltage_at(yx(0, 0)), None);
+        assert_eq!(grid.voltage_at(yx(1, 1)), Some(2.0));
+        // the second override replaces the first on its cell
+        assert_eq!(grid.voltage_at(yx(1, 2)), None);
+        assert_eq!(grid.frequency_at(yx(1, 2)), Some(500.0));
+
+        let s = grid.to_string_with_format(GridFormat::Json).unwrap();
+        assert_eq!(Grid::from_reader(s.as_bytes()).unwrap(), grid);
+    }
+
+    #[test]
+    fn test_formats_round_trip() {
+        let mut grid = Grid::rectangle(2, 3);
+        grid.get_cell_mut(yx(1, 2)).unwrap().enabled = false;
+        grid.get_cell_mut(yx(0, 0)).unwrap().peripheral = Some(Peripheral::Input {
+            pwm_channel: 1,
+            name: "in".into(),
+        });
+
+        for &format in &[GridFormat::Json, GridFormat::Toml, GridFormat::Yaml] {
+            let s = grid.to_string_with_format(format).unwrap();
+            assert_eq!(GridFormat::sniff(&s), format, "{}", s);
+            let read = Grid::from_reader_with_format(s.as_bytes(), format).unwrap();
+            assert_eq!(read, grid);
+            assert_eq!(Grid::from_reader(s.as_bytes()).unwrap(), grid);
+        }
+    }
+
+    #[test]
+    fn test_parse_disabled() {
+        let yaml = r#"
+board: [[0, 1, 2], [3, _, 4]]
+disabled: [{y: 0, x: 1}, {y: 1, x: 2}]
+"#;
+        let grid: Grid = serde_yaml::from_str(yaml).expect("parse failed");
+        assert!(grid.is_enabled(yx(0, 0)));
+        assert!(!grid.is_enabled(yx(0, 1)));
+        assert!(!grid.is_enabled(yx(1, 2)));
+        // gaps and off-grid locations aren't enabled either
+        assert!(!grid.is_enabled(yx(1, 1)));
+        assert!(!grid.is_enabled(yx(5, 5)));
+        // but the pins are still there
+        assert_eq!(grid.get_cell(yx(0, 1)).unwrap().pin, 1);
+        assert_eq!(grid.neighbors4(yx(0, 0)), vec![yx(1, 0)]);
+
+        check_round_trip(grid, "disabled");
+    }
+
     #[test]
     fn test_parse() {
         // test uneven string lengths with gaps
@@ -251,4 +565,21 @@ pub mod tests {
         assert_eq!(grid.max_height(), 4);
         assert_eq!(grid.max_width(), 12);
     }
+
+    #[test]
+    fn test_loaded_dimensions() {
+        let yaml = include_str!("../../../tests/arches/purpledrop.yaml");
+        let grid: Grid = serde_yaml::from_str(yaml).unwrap();
+
+        // the last few columns are all empty, but they still count
+        assert_eq!(grid.dimensions(), yx(15, 14));
+        let rect = Rectangle::new(yx(0, 0), yx(15, 14));
+        assert_eq!(grid.bounding_rectangle(), rect);
+        assert!(grid.in_bounds(yx(14, 13)));
+        // in bounds, but there's no electrode there
+        assert!(grid.in_bounds(yx(0, 0)) && !grid.is_enabled(yx(0, 0)));
+        for &loc in &[yx(15, 0), yx(0, 14), yx(-1, 3), yx(3, -1)] {
+            assert!(!grid.in_bounds(loc), "{} should be out", loc);
+        }
+    }
 }
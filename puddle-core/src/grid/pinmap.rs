@@ -0,0 +1,85 @@
+use indexmap::IndexMap;
+
+use super::{Grid, GridError, Location};
+
+/// Which physical output drives each electrode, and the other way around.
+/// Every electrode in the grid gets one, disabled ones included, since
+/// they're still wired up.
+#[derive(Debug, Clone, Default)]
+pub struct PinMap {
+    pins: IndexMap<Location, usize>,
+    locations: IndexMap<usize, Location>,
+}
+
+impl PinMap {
+    /// Builds the map from the pins in `grid`. Two electrodes on the same
+    /// pin would always switch together, so each pin that's used more than
+    /// once is an error. `Grid::validate` reports these too.
+    pub fn from_grid(grid: &Grid) -> Result<PinMap, Vec<GridError>> {
+        let mut map = PinMap::default();
+        let mut errors = Vec::new();
+        for (loc, electrode) in grid.locations() {
+            let pin = electrode.pin as usize;
+            if let Some(&other) = map.locations.get(&pin) {
+                errors.push(GridError::DuplicatePin(electrode.pin, other, loc));
+                continue;
+            }
+            map.pins.insert(loc, pin);
+            map.locations.insert(pin, loc);
+        }
+        if errors.is_empty() {
+            Ok(map)
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn pin_of(&self, loc: Location) -> Option<usize> {
+        self.pins.get(&loc).cloned()
+    }
+
+    pub fn location_of(&self, pin: usize) -> Option<Location> {
+        self.locations.get(&pin).cloned()
+    }
+
+    /// One more than the highest pin, so a pin pattern this long has room
+    /// for all of them.
+    pub fn n_pins(&self) -> usize {
+        self.locations.keys().max().map_or(0, |&max| max + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::location::yx;
+
+    #[test]
+    fn test_pin_map() {
+        let mut grid = Grid::rectangle(2, 2);
+        grid.get_cell_mut(yx(1, 1)).unwrap().pin = 7;
+        grid.vec[0][1] = None;
+
+        let map = PinMap::from_grid(&grid).unwrap();
+        assert_eq!(map.pin_of(yx(0, 0)), Some(0));
+        assert_eq!(map.pin_of(yx(1, 1)), Some(7));
+        assert_eq!(map.pin_of(yx(0, 1)), None);
+        assert_eq!(map.location_of(7), Some(yx(1, 1)));
+        assert_eq!(map.location_of(1), None);
+        assert_eq!(map.n_pins(), 8);
+    }
+
+    #[test]
+    fn test_duplicate_pin() {
+        let mut grid = Grid::rectangle(1, 4);
+        grid.get_cell_mut(yx(0, 2)).unwrap().pin = 0;
+        grid.get_cell_mut(yx(0, 3)).unwrap().pin = 0;
+        assert_eq!(
+            PinMap::from_grid(&grid).unwrap_err(),
+            vec![
+                GridError::DuplicatePin(0, yx(0, 0), yx(0, 2)),
+                GridError::DuplicatePin(0, yx(0, 0), yx(0, 3)),
+            ]
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use std::fmt::Write;
+
+use super::{Grid, Snapshot};
+
+// pixels per electrode
+const CELL: i32 = 20;
+// room between a droplet and the edge of its cells
+const INSET: i32 = 2;
+
+const PALETTE: &[&str] = &[
+    "#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b", "#e377c2", "#17becf",
+];
+
+/// Draws the grid with the droplets in `snapshot` on top, as a standalone
+/// SVG document. Each droplet gets a rounded rectangle, in its own color if
+/// it has one, labeled with its label or else its id. Disabled electrodes
+/// are gray.
+pub fn to_svg(grid: &Grid, snapshot: &Snapshot) -> String {
+    let width = grid.max_width() as i32 * CELL;
+    let height = grid.max_height() as i32 * CELL;
+
+    // writing to a String can't fail, so the unwraps are fine
+    let mut svg = String::new();
+    writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}">"#,
+        w = width,
+        h = height,
+    )
+    .unwrap();
+
+    for (loc, electrode) in grid.locations() {
+        let fill = if electrode.enabled {
+            "#f4f4f4"
+        } else {
+            "#999999"
+        };
+        writeln!(
+            svg,
+            r##"  <rect class="electrode" x="{}" y="{}" width="{c}" height="{c}" fill="{}" stroke="#cccccc"/>"##,
+            loc.x * CELL,
+            loc.y * CELL,
+            fill,
+            c = CELL,
+        )
+        .unwrap();
+    }
+
+    for d in snapshot.droplets.values() {
+        let color = match d.color {
+            Some([r, g, b]) => format!("#{:02x}{:02x}{:02x}", r, g, b),
+            None => PALETTE[d.id.id % PALETTE.len()].to_string(),
+        };
+        let label = match &d.label {
+            Some(label) => escape(label),
+            None => format!("{:?}", d.id),
+        };
+        let x = d.location.x * CELL + INSET;
+        let y = d.location.y * CELL + INSET;
+        let w = d.dimensions.x * CELL - 2 * INSET;
+        let h = d.dimensions.y * CELL - 2 * INSET;
+        writeln!(
+            svg,
+            r#"  <rect class="droplet" x="{}" y="{}" width="{}" height="{}" rx="{r}" ry="{r}" fill="{}" fill-opacity="0.8"/>"#,
+            x,
+            y,
+            w,
+            h,
+            color,
+            r = CELL / 3,
+        )
+        .unwrap();
+        writeln!(
+            svg,
+            r#"  <text x="{}" y="{}" font-size="{}" text-anchor="middle" dominant-baseline="central">{}</text>"#,
+            x + w / 2,
+            y + h / 2,
+            CELL / 2,
+            label,
+        )
+        .unwrap();
+    }
+
+    svg.push_str("</svg>\n");
+    svg
+}
+
+// labels come from users, so keep them from breaking the markup
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::{location::yx, Droplet, DropletId};
+
+    #[test]
+    fn test_to_svg() {
+        let mut grid = Grid::rectangle(4, 5);
+        grid.get_cell_mut(yx(3, 4)).unwrap().enabled = false;
+
+        let mut snap = Snapshot::default();
+        for (i, &(loc, dim)) in [(yx(0, 0), yx(1, 1)), (yx(2, 1), yx(2, 3))]
+            .iter()
+            .enumerate()
+        {
+            let id = DropletId {
+                id: i,
+                process_id: 0,
+            };
+            snap.droplets.insert(id, Droplet::new(id, 1.0, loc, dim));
+        }
+
+        let svg = to_svg(&grid, &snap);
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        assert_eq!(svg.matches("<rect class=\"droplet\"").count(), 2);
+        assert_eq!(svg.matches("<rect class=\"electrode\"").count(), 20);
+        assert_eq!(svg.matches("#999999").count(), 1);
+        // the big droplet covers cells (2,1) to (3,3)
+        assert!(svg.contains(r#"x="22" y="42" width="56" height="36""#));
+    }
+
+    #[test]
+    fn test_to_svg_label_and_color() {
+        let grid = Grid::rectangle(2, 2);
+        let mut snap = Snapshot::default();
+        let id = DropletId {
+            id: 0,
+            process_id: 0,
+        };
+        let mut d = Droplet::new(id, 1.0, yx(0, 0), yx(1, 1));
+        d.label = Some("a<b".into());
+        d.color = Some([255, 0, 16]);
+        snap.droplets.insert(id, d);
+
+        let svg = to_svg(&grid, &snap);
+        assert!(svg.contains(r##"fill="#ff0010""##));
+        assert!(svg.contains(">a&lt;b</text>"));
+    }
+}
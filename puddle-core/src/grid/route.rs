@@ -0,0 +1,121 @@
+use indexmap::IndexSet;
+
+use super::{Grid, Location};
+
+/// Finds a shortest path for a single 1x1 droplet from `start` to `goal`
+/// with 4-way steps, avoiding dead or disabled electrodes and anything in
+/// `blocked`. The path includes both ends.
+pub fn shortest_path(
+    grid: &Grid,
+    start: Location,
+    goal: Location,
+    blocked: &IndexSet<Location>,
+) -> Option<Vec<Location>> {
+    shortest_path_with(grid, start, goal, blocked, false)
+}
+
+/// Like `shortest_path`, but diagonal steps can be allowed too. A diagonal
+/// step costs the same as any other.
+pub fn shortest_path_with(
+    grid: &Grid,
+    start: Location,
+    goal: Location,
+    blocked: &IndexSet<Location>,
+    allow_diagonal: bool,
+) -> Option<Vec<Location>> {
+    let open = |loc: &Location| grid.is_enabled(*loc) && !blocked.contains(loc);
+    if !open(&start) || !open(&goal) {
+        return None;
+    }
+
+    let successors = |loc: &Location| {
+        let nbrs = if allow_diagonal {
+            grid.neighbors8(*loc)
+        } else {
+            grid.neighbors4(*loc)
+        };
+        nbrs.into_iter()
+            .filter(|n| open(n))
+            .map(|n| (n, 1))
+            .collect::<Vec<_>>()
+    };
+
+    let heuristic = |loc: &Location| {
+        if allow_diagonal {
+            loc.chebyshev_distance_to(goal)
+        } else {
+            loc.distance_to(goal)
+        }
+    };
+
+    let success = |loc: &Location| *loc == goal;
+
+    pathfinding::directed::astar::astar(&start, successors, heuristic, success)
+        .map(|(path, _cost)| path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::location::yx;
+
+    // .....
+    // .#...
+    // .#.#.
+    // .#...
+    // .....
+    fn walled_grid() -> (Grid, IndexSet<Location>) {
+        let mut grid = Grid::rectangle(5, 5);
+        grid.vec[2][3] = None;
+        let blocked = vec![yx(1, 1), yx(2, 1), yx(3, 1)].into_iter().collect();
+        (grid, blocked)
+    }
+
+    fn check_path(path: &[Location], start: Location, goal: Location, diagonal: bool) {
+        assert_eq!(path.first(), Some(&start));
+        assert_eq!(path.last(), Some(&goal));
+        for w in path.windows(2) {
+            let step = if diagonal {
+                w[0].chebyshev_distance_to(w[1])
+            } else {
+                w[0].distance_to(w[1])
+            };
+            assert_eq!(step, 1);
+        }
+    }
+
+    #[test]
+    fn test_route_around_wall() {
+        let (grid, blocked) = walled_grid();
+        let (start, goal) = (yx(2, 0), yx(2, 2));
+
+        let path = shortest_path(&grid, start, goal, &blocked).unwrap();
+        check_path(&path, start, goal, false);
+        assert!(path.iter().all(|loc| !blocked.contains(loc)));
+        // up and over the wall
+        assert_eq!(path.len(), 7);
+
+        let path = shortest_path_with(&grid, start, goal, &blocked, true).unwrap();
+        check_path(&path, start, goal, true);
+        assert_eq!(path.len(), 5);
+
+        // the dead electrode is never stepped on
+        let path = shortest_path(&grid, yx(2, 2), yx(2, 4), &blocked).unwrap();
+        assert!(!path.contains(&yx(2, 3)));
+        assert_eq!(path.len(), 5);
+    }
+
+    #[test]
+    fn test_route_unreachable() {
+        let (grid, mut blocked) = walled_grid();
+        blocked.insert(yx(0, 1));
+        blocked.insert(yx(4, 1));
+
+        assert_eq!(shortest_path(&grid, yx(2, 0), yx(2, 2), &blocked), None);
+        assert_eq!(shortest_path(&grid, yx(2, 2), yx(2, 3), &blocked), None);
+        assert_eq!(shortest_path(&grid, yx(2, 2), yx(9, 9), &blocked), None);
+
+        let path = shortest_path(&grid, yx(0, 0), yx(0, 0), &blocked).unwrap();
+        assert_eq!(path, vec![yx(0, 0)]);
+    }
+}
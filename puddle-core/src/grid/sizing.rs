@@ -0,0 +1,65 @@
+use super::{location::yx, Location};
+
+/// How much volume one electrode holds, in the same units as droplet
+/// volumes. A good start for `GridView::set_cell_volume`.
+pub const DEFAULT_CELL_VOLUME: f64 = 1.0;
+
+/// The smallest footprint that holds `volume`, when each cell holds
+/// `cell_volume`. Footprints are as square as possible, and wider than
+/// tall when they can't be square. Anything that doesn't make sense, like a
+/// zero or negative volume, gets a single cell. Absurdly big volumes are
+/// capped at `i32::MAX` cells.
+pub fn dimensions_for_volume(volume: f64, cell_volume: f64) -> Location {
+    let cells = (volume / cell_volume).ceil();
+    // NaN fails this too, and the cap keeps the cast in range
+    let cells = if cells >= 1.0 {
+        cells.min(f64::from(std::i32::MAX)) as i64
+    } else {
+        1
+    };
+
+    // the square root gets close, but might be off by one either way
+    let mut side = (cells as f64).sqrt() as i64;
+    while side * side > cells {
+        side -= 1;
+    }
+    while side * side < cells {
+        side += 1;
+    }
+    let (y, x) = if (side - 1) * side >= cells {
+        (side - 1, side)
+    } else {
+        (side, side)
+    };
+    yx(y as i32, x as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dimensions_for_volume() {
+        let dims = |vol| dimensions_for_volume(vol, 1.0);
+        assert_eq!(dims(1.0), yx(1, 1));
+        assert_eq!(dims(0.3), yx(1, 1));
+        assert_eq!(dims(1.5), yx(1, 2));
+        assert_eq!(dims(2.0), yx(1, 2));
+        assert_eq!(dims(3.0), yx(2, 2));
+        assert_eq!(dims(4.0), yx(2, 2));
+        assert_eq!(dims(5.0), yx(2, 3));
+        assert_eq!(dims(7.0), yx(3, 3));
+        assert_eq!(dims(12.0), yx(3, 4));
+        assert_eq!(dims(13.0), yx(4, 4));
+
+        assert_eq!(dimensions_for_volume(10.0, 2.5), yx(2, 2));
+        assert_eq!(dimensions_for_volume(0.0, 1.0), yx(1, 1));
+        assert_eq!(dimensions_for_volume(-4.0, 1.0), yx(1, 1));
+        assert_eq!(dimensions_for_volume(std::f64::NAN, 1.0), yx(1, 1));
+        assert_eq!(
+            dimensions_for_volume(std::f64::INFINITY, 1.0),
+            yx(46341, 46341)
+        );
+        assert_eq!(dimensions_for_volume(1e300, 1e-300), yx(46341, 46341));
+    }
+}
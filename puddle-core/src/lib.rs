@@ -17,6 +17,7 @@ pub mod exec;
 pub mod grid;
 pub mod plan;
 pub mod process;
+pub mod sim;
 pub mod util;
 
 mod system;
@@ -26,6 +27,7 @@ pub mod prelude {
         exec::Executor,
         grid::{Blob, DropletId, DropletInfo, Grid, Location},
         process::{Manager, Process, ProcessId, PuddleError},
+        sim::SimExecutor,
     };
 }
 
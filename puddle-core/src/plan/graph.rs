@@ -101,6 +101,38 @@ impl Graph {
 
         Ok(cmd_id)
     }
+
+    /// Takes back a command, as if it was never added. This only works if
+    /// nothing has been added that uses its outputs yet, otherwise it's
+    /// `None` and the graph is left alone.
+    pub fn remove_command(&mut self, cmd_id: CmdIndex) -> Option<BoxedCommand> {
+        let cmd = self.graph.node_weight(cmd_id)?.as_ref()?;
+        let (inputs, outputs) = (cmd.input_droplets(), cmd.output_droplets());
+
+        for id in &outputs {
+            let (_src, tgt) = self.graph.edge_endpoints(self.droplet_idx[id])?;
+            if self.graph[tgt].is_some() {
+                return None;
+            }
+        }
+
+        // drop the unbound nodes hanging off the outputs, and the edges with them
+        for id in &outputs {
+            let e_idx = self.droplet_idx.shift_remove(id).unwrap();
+            let (_src, tgt) = self.graph.edge_endpoints(e_idx).unwrap();
+            self.graph.remove_node(tgt).unwrap();
+        }
+
+        // the inputs go back to being unbound
+        for id in &inputs {
+            let e_idx = self.droplet_idx.get_mut(id).unwrap();
+            let (src, _tgt) = self.graph.edge_endpoints(*e_idx).unwrap();
+            let unbound = self.graph.add_node(None);
+            *e_idx = self.graph.add_edge(src, unbound, *id);
+        }
+
+        self.graph.remove_node(cmd_id).unwrap()
+    }
 }
 
 #[cfg(test)]
@@ -142,4 +174,26 @@ mod tests {
         assert_matches!(r, Err(GraphError::AlreadyBound(_)));
     }
 
+    #[test]
+    fn test_remove_command() {
+        let mut graph = Graph::default();
+        for id in 0..3 {
+            graph.add_command(input(id)).unwrap();
+        }
+        let counts = |g: &Graph| (g.graph.node_count(), g.graph.edge_count());
+        let before = counts(&graph);
+
+        let mix_id = graph.add_command(mix(0, 1, 3)).unwrap();
+        let next_id = graph.add_command(mix(2, 3, 4)).unwrap();
+
+        // the mix can't go while something is using its output
+        assert!(graph.remove_command(mix_id).is_none());
+        assert!(graph.remove_command(next_id).is_some());
+        assert!(graph.remove_command(mix_id).is_some());
+        assert_eq!(counts(&graph), before);
+        assert!(!graph.droplet_idx.contains_key(&DropletId::from(3)));
+
+        // and the inputs are free to be used again
+        assert_matches!(graph.add_command(mix(0, 1, 3)), Ok(_));
+    }
 }
@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use indexmap::IndexMap;
+
+/// How much planning time went to one type of command.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CommandMetrics {
+    pub count: usize,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+/// Planning time per type of command, keyed by `Command::type_name`. A
+/// phase that plans several commands at once splits its time evenly
+/// between them.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Metrics {
+    commands: IndexMap<&'static str, CommandMetrics>,
+}
+
+impl Metrics {
+    pub fn record(&mut self, type_name: &'static str, time: Duration) {
+        let m = self.commands.entry(type_name).or_default();
+        m.count += 1;
+        m.total += time;
+        m.max = m.max.max(time);
+    }
+
+    pub fn get(&self, type_name: &str) -> Option<&CommandMetrics> {
+        self.commands.get(type_name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &CommandMetrics)> {
+        self.commands.iter().map(|(name, m)| (*name, m))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut metrics = Metrics::default();
+        metrics.record("Move", Duration::from_millis(3));
+        metrics.record("Move", Duration::from_millis(5));
+        metrics.record("Split", Duration::from_millis(1));
+
+        let expected = CommandMetrics {
+            count: 2,
+            total: Duration::from_millis(8),
+            max: Duration::from_millis(5),
+        };
+        assert_eq!(metrics.get("Move"), Some(&expected));
+        assert_eq!(metrics.get("Split").unwrap().count, 1);
+        assert_eq!(metrics.get("Mix"), None);
+        assert_eq!(metrics.iter().count(), 2);
+    }
+}
@@ -1,24 +1,131 @@
 // TODO move graph
 pub mod graph;
+mod metrics;
+mod multi;
 pub mod place;
 mod route;
 pub mod sched;
 
-use self::graph::{CmdIndex, Graph};
-use self::place::{Placement, PlacementRequest, Placer};
-use self::route::{Agent, Router, RoutingRequest};
+use self::graph::{CmdIndex, Graph, GraphError};
+use self::place::{Placement, PlacementError, PlacementRequest, Placer};
+use self::route::{Agent, Router, RoutingRequest, RoutingResponse};
 use self::sched::{SchedRequest, Scheduler};
 
+pub use self::metrics::{CommandMetrics, Metrics};
+pub use self::multi::route_all;
 pub use self::route::Path;
 
-use crate::grid::{droplet::DropletId, GridView};
+use std::fmt;
+use std::time::Instant;
+
+use crate::grid::{droplet::DropletId, GridView, Location};
 use indexmap::IndexMap;
+use petgraph::prelude::*;
 
 #[derive(Debug)]
 pub enum PlanError {
     RouteError(self::route::RoutingError),
     SchedError(self::sched::SchedError),
-    PlaceError(self::place::PlacementError),
+    PlaceError(PlacementError),
+    InKeepout(Location),
+    // a command was pinned somewhere that puts this location off the grid
+    OffGrid(Location),
+    DropletStuck(DropletId),
+    // heating this droplet to this temperature would go past its limit
+    OverTemperature(DropletId, f32),
+    // the droplet was expected at the first location but was at the second
+    AssertionFailed(DropletId, Location, Location),
+    // splitting this droplet would make one with only this much volume
+    VolumeMismatch(DropletId, f64),
+    // the heater only fits droplets one electrode wide, this one is wider
+    TooWideToHeat(DropletId, Location),
+    // the command doesn't fit in the graph, e.g. it uses a droplet that's gone
+    GraphError(GraphError),
+    // these droplets are in each other's way and none of them can get by
+    Deadlock { blocked: Vec<DropletId> },
+}
+
+// droplet ids as a readable list, like "p0.d1, p0.d2"
+fn id_list(ids: impl Iterator<Item = DropletId>) -> String {
+    let ids: Vec<String> = ids.map(|id| id.to_string()).collect();
+    ids.join(", ")
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use self::PlanError::*;
+        match self {
+            RouteError(route::RoutingError::NoRoute { agents }) => write!(
+                f,
+                "Couldn't route droplets {}",
+                id_list(agents.iter().map(|a| a.id))
+            ),
+            SchedError(sched::SchedError::NothingToSchedule) => {
+                write!(f, "There was nothing to schedule")
+            }
+            PlaceError(PlacementError::Bad) => write!(f, "There's no room on the grid"),
+            PlaceError(PlacementError::Collision(id)) => {
+                write!(f, "Couldn't move droplet {} out of the way", id)
+            }
+            InKeepout(loc) => write!(f, "Location {} is in the keepout border", loc),
+            OffGrid(loc) => write!(f, "Location {} is off the grid", loc),
+            DropletStuck(id) => write!(f, "Droplet {} is stuck", id),
+            OverTemperature(id, temp) => write!(
+                f,
+                "Heating droplet {} to {}*C would go past its limit",
+                id, temp
+            ),
+            AssertionFailed(id, expected, actual) => write!(
+                f,
+                "Droplet {} should be at {}, but it's at {}",
+                id, expected, actual
+            ),
+            VolumeMismatch(id, vol) => write!(
+                f,
+                "Splitting droplet {} would leave a droplet of only {}",
+                id, vol
+            ),
+            TooWideToHeat(id, dim) => write!(
+                f,
+                "Droplet {} is {} electrodes wide, only 1 can be heated",
+                id, dim.x
+            ),
+            GraphError(graph::GraphError::AlreadyExists(id)) => {
+                write!(f, "Droplet {} already exists", id)
+            }
+            GraphError(graph::GraphError::AlreadyBound(id)) => {
+                write!(f, "Droplet {} is already used by another command", id)
+            }
+            GraphError(graph::GraphError::DoesNotExist(id)) => {
+                write!(f, "Droplet {} does not exist", id)
+            }
+            GraphError(graph::GraphError::Duplicate(id)) => {
+                write!(f, "Droplet {} is used twice in one command", id)
+            }
+            Deadlock { blocked } => {
+                write!(
+                    f,
+                    "Droplets {} are deadlocked",
+                    id_list(blocked.iter().cloned())
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for PlanError {}
+
+/// Why a command can't currently proceed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockReason {
+    /// an input is produced by a command that hasn't run yet
+    WaitingForDroplet(DropletId),
+    /// the router couldn't get the droplets where they need to go
+    NoRoute,
+    /// placing the command would run into this droplet
+    Collision(DropletId),
+    /// there's no room on the grid for the command
+    NoPlacement,
 }
 
 pub struct PlannedCommand {
@@ -34,11 +141,48 @@ pub struct PlanPhase {
 
 type PlanResult = Result<PlanPhase, PlanError>;
 
+/// Fails if running `cmd` on a droplet of `volume` would split off one with
+/// less than `min_volume`. Commands that don't split always pass.
+pub fn check_split_volume(
+    cmd: &dyn crate::command::Command,
+    volume: f64,
+    min_volume: f64,
+) -> Result<(), PlanError> {
+    // NaN doesn't compare, so leave it out
+    let smallest = cmd
+        .split_fractions()
+        .into_iter()
+        .filter(|f| !f.is_nan())
+        .min_by(|a, b| a.partial_cmp(b).unwrap());
+    if let Some(smallest) = smallest {
+        let id = cmd.input_droplets()[0];
+        if volume * smallest < min_volume {
+            return Err(PlanError::VolumeMismatch(id, volume * smallest));
+        }
+    }
+    Ok(())
+}
+
+/// Fails if `cmd` heats a droplet of `dimensions` that won't fit on the
+/// heater. Commands that don't heat always pass.
+pub fn check_heatable(
+    cmd: &dyn crate::command::Command,
+    dimensions: Location,
+) -> Result<(), PlanError> {
+    if cmd.temperature().is_some() && dimensions.x != 1 {
+        let id = cmd.input_droplets()[0];
+        return Err(PlanError::TooWideToHeat(id, dimensions));
+    }
+    Ok(())
+}
+
 pub struct Planner {
     pub gridview: GridView,
     scheduler: Scheduler,
     placer: Placer,
     router: Router,
+    // commands that failed to plan on the last call to plan
+    blocked: IndexMap<CmdIndex, BlockReason>,
 }
 
 impl Planner {
@@ -48,12 +192,18 @@ impl Planner {
             scheduler: Scheduler::default(),
             placer: Placer::default(),
             router: Router::default(),
+            blocked: IndexMap::default(),
         }
     }
 
     pub fn plan(&mut self, graph: &Graph, _droplets: &[DropletId]) -> PlanResult {
+        let start = Instant::now();
         debug!("Planning GV: {:#?}", self.gridview.droplets);
         self.gridview.check_no_collision();
+        self.blocked.clear();
+
+        // the placer and router both stay out of the keepout border
+        let gridview = &self.gridview;
 
         let mut sched_limit = None;
         let (sched_resp, command_requests, place_resp) = loop {
@@ -74,12 +224,21 @@ impl Planner {
                 resp
             };
 
+            // the heater's request can't describe anything wider than 1
+            for cmd_id in &sched_resp.commands_to_run {
+                let cmd = graph.graph[*cmd_id].as_ref().expect("Command was unbound!");
+                let inputs = cmd.input_droplets();
+                if let Some(d) = inputs.first().and_then(|id| gridview.droplets.get(id)) {
+                    check_heatable(&**cmd, d.dimensions)?;
+                }
+            }
+
             let command_requests: Vec<_> = sched_resp
                 .commands_to_run
                 .iter()
                 .map(|cmd_id: &CmdIndex| {
                     let cmd = graph.graph[*cmd_id].as_ref().expect("Command was unbound!");
-                    cmd.request(&self.gridview)
+                    cmd.request(gridview)
                     // TODO update the outputs
                     // for out in cmd_req.outputs {
                     //     self.droplets.insert(out.id, out);
@@ -92,8 +251,46 @@ impl Planner {
                 command_requests.iter().map(|r| &r.name).collect::<Vec<_>>()
             );
 
+            // don't cook anything past its limit
+            for cmd_id in &sched_resp.commands_to_run {
+                let cmd = graph.graph[*cmd_id].as_ref().expect("Command was unbound!");
+                if let Some(temp) = cmd.temperature() {
+                    for id in cmd.input_droplets() {
+                        if let Some(limit) = gridview.droplets[&id].temperature_limit {
+                            if temp > limit {
+                                return Err(PlanError::OverTemperature(id, temp));
+                            }
+                        }
+                    }
+                }
+                // or split anything too small to handle
+                let inputs = cmd.input_droplets();
+                if let Some(d) = inputs.first().and_then(|id| gridview.droplets.get(id)) {
+                    check_split_volume(&**cmd, d.volume, gridview.min_volume())?;
+                }
+            }
+
+            // commands that force their placement can't put it off the grid
+            // or in the keepout
+            for req in &command_requests {
+                if let Some(offset) = req.offset {
+                    let mut cells = req.shape.locations().map(|(loc, _)| loc + offset);
+                    if let Some(loc) = cells.find(|&loc| !self.gridview.grid.in_bounds(loc)) {
+                        return Err(PlanError::OffGrid(loc));
+                    }
+                    let in_keepout = req
+                        .shape
+                        .locations()
+                        .map(|(loc, _)| loc + offset)
+                        .find(|&loc| self.gridview.in_keepout(loc));
+                    if let Some(loc) = in_keepout {
+                        return Err(PlanError::InKeepout(loc));
+                    }
+                }
+            }
+
             let req = PlacementRequest {
-                gridview: &self.gridview,
+                gridview,
                 fixed_commands: vec![],
                 commands: command_requests.as_slice(),
                 stored_droplets: sched_resp.droplets_to_store.as_slice(),
@@ -105,6 +302,13 @@ impl Planner {
                 Err(e) => {
                     if command_requests.len() <= 1 {
                         error!("Actually failing to place for real");
+                        let reason = match e {
+                            PlacementError::Collision(id) => BlockReason::Collision(id),
+                            PlacementError::Bad => BlockReason::NoPlacement,
+                        };
+                        for cmd_id in &sched_resp.commands_to_run {
+                            self.blocked.insert(*cmd_id, reason.clone());
+                        }
                         return Err(PlanError::PlaceError(e));
                     } else {
                         let n_cmds = sched_resp.commands_to_run.len();
@@ -124,7 +328,7 @@ impl Planner {
                 .droplets_to_store
                 .iter()
                 .zip(place_resp.stored_droplets)
-                .map(|(id, loc)| Agent::from_droplet(&self.gridview.droplets[id], loc))
+                .map(|(id, loc)| Agent::from_droplet(&gridview.droplets[id], loc))
                 .collect();
 
             // TODO getting these input droplets is pretty painful
@@ -136,24 +340,45 @@ impl Planner {
             for ((cmd_id, req), placement) in placed {
                 let cmd = graph.graph[*cmd_id].as_ref().expect("Command was unbound!");
                 let in_ids = cmd.input_droplets();
+                // droplets about to be merged share a collision group just for
+                // this route, so they're allowed to approach each other
+                let shared_group = match in_ids.first() {
+                    Some(id) if cmd.merges_inputs() => Some(gridview.droplets[id].collision_group),
+                    _ => None,
+                };
                 let ins = in_ids.iter().zip(&req.input_locations);
                 for (&droplet_id, location) in ins {
-                    agents.push(self::route::Agent {
-                        id: droplet_id,
-                        source: self.gridview.droplets[&droplet_id].location,
-                        dimensions: self.gridview.droplets[&droplet_id].dimensions,
-                        destination: placement.mapping[location],
-                    });
+                    let droplet = &gridview.droplets[&droplet_id];
+                    let mut agent = Agent::from_droplet(droplet, placement.mapping[location]);
+                    if let Some(group) = shared_group {
+                        agent.collision_group = group;
+                    }
+                    agents.push(agent);
                 }
             }
 
             let req = RoutingRequest {
                 agents,
-                gridview: &self.gridview,
+                gridview,
                 blockages: vec![],
             };
             // debug!("{:?}", req);
-            let resp = self.router.route(&req).map_err(PlanError::RouteError)?;
+            let resp = match self.router.route(&req) {
+                Ok(resp) => resp,
+                // the router moves everyone at once, so droplets that have
+                // to take turns through a tight spot need route_all's waits
+                // and detours
+                Err(e) => match multi::route_agents(gridview, &req.agents) {
+                    Ok(routes) => RoutingResponse { routes },
+                    Err(multi_err) => {
+                        debug!("route_all couldn't route either: {}", multi_err);
+                        for cmd_id in &sched_resp.commands_to_run {
+                            self.blocked.insert(*cmd_id, BlockReason::NoRoute);
+                        }
+                        return Err(PlanError::RouteError(e));
+                    }
+                },
+            };
             debug!("{:?}", resp);
 
             resp
@@ -175,9 +400,128 @@ impl Planner {
         // now commit to the schedule
         self.scheduler.commit(&sched_resp);
 
+        let n_cmds = sched_resp.commands_to_run.len() as u32;
+        let share = start.elapsed() / n_cmds.max(1);
+        for cmd_id in &sched_resp.commands_to_run {
+            let cmd = graph.graph[*cmd_id].as_ref().expect("Command was unbound!");
+            self.gridview.record_plan_time(cmd.type_name(), share);
+        }
+
         Ok(PlanPhase {
             routes,
             planned_commands,
         })
     }
+
+    /// Returns each unfinished command that can't currently proceed, and why.
+    pub fn blocked(&self, graph: &Graph) -> Vec<(CmdIndex, BlockReason)> {
+        let mut blocked = Vec::new();
+        for cmd_id in graph.graph.node_indices() {
+            if graph.graph[cmd_id].is_none() || self.scheduler.is_scheduled(cmd_id) {
+                continue;
+            }
+
+            if let Some(reason) = self.blocked.get(&cmd_id) {
+                blocked.push((cmd_id, reason.clone()));
+                continue;
+            }
+
+            let waiting_on = graph
+                .graph
+                .edges_directed(cmd_id, Incoming)
+                .find(|e| !self.scheduler.is_scheduled(e.source()));
+            if let Some(e) = waiting_on {
+                blocked.push((cmd_id, BlockReason::WaitingForDroplet(*e.weight())));
+            }
+        }
+        blocked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::location::yx;
+
+    #[test]
+    fn test_error_messages() {
+        let id = |id| DropletId { id, process_id: 0 };
+        let agent = |i| route::Agent {
+            id: id(i),
+            source: yx(0, 0),
+            destination: yx(1, 1),
+            dimensions: yx(1, 1),
+            collision_group: i,
+        };
+        let cases = vec![
+            (
+                PlanError::RouteError(route::RoutingError::NoRoute {
+                    agents: vec![agent(1), agent(2)],
+                }),
+                "Couldn't route droplets p0.d1, p0.d2",
+            ),
+            (
+                PlanError::SchedError(sched::SchedError::NothingToSchedule),
+                "There was nothing to schedule",
+            ),
+            (
+                PlanError::PlaceError(PlacementError::Bad),
+                "There's no room on the grid",
+            ),
+            (
+                PlanError::PlaceError(PlacementError::Collision(id(3))),
+                "Couldn't move droplet p0.d3 out of the way",
+            ),
+            (
+                PlanError::InKeepout(yx(0, 1)),
+                "Location (0, 1) is in the keepout border",
+            ),
+            (
+                PlanError::OffGrid(yx(-1, 0)),
+                "Location (-1, 0) is off the grid",
+            ),
+            (PlanError::DropletStuck(id(1)), "Droplet p0.d1 is stuck"),
+            (
+                PlanError::OverTemperature(id(1), 95.5),
+                "Heating droplet p0.d1 to 95.5*C would go past its limit",
+            ),
+            (
+                PlanError::AssertionFailed(id(1), yx(1, 1), yx(2, 2)),
+                "Droplet p0.d1 should be at (1, 1), but it's at (2, 2)",
+            ),
+            (
+                PlanError::VolumeMismatch(id(1), 0.25),
+                "Splitting droplet p0.d1 would leave a droplet of only 0.25",
+            ),
+            (
+                PlanError::TooWideToHeat(id(1), yx(1, 2)),
+                "Droplet p0.d1 is 2 electrodes wide, only 1 can be heated",
+            ),
+            (
+                PlanError::GraphError(GraphError::AlreadyExists(id(1))),
+                "Droplet p0.d1 already exists",
+            ),
+            (
+                PlanError::GraphError(GraphError::AlreadyBound(id(1))),
+                "Droplet p0.d1 is already used by another command",
+            ),
+            (
+                PlanError::GraphError(GraphError::DoesNotExist(id(1))),
+                "Droplet p0.d1 does not exist",
+            ),
+            (
+                PlanError::GraphError(GraphError::Duplicate(id(1))),
+                "Droplet p0.d1 is used twice in one command",
+            ),
+            (
+                PlanError::Deadlock {
+                    blocked: vec![id(1), id(2)],
+                },
+                "Droplets p0.d1, p0.d2 are deadlocked",
+            ),
+        ];
+        for (err, message) in cases {
+            assert_eq!(err.to_string(), message);
+        }
+    }
 }
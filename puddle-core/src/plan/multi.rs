@@ -0,0 +1,362 @@
+use indexmap::{IndexMap, IndexSet};
+
+use super::route::{Agent, Path, RoutingError};
+use super::PlanError;
+use crate::grid::{
+    grid::NEIGHBORS_5, gridview::SnapshotError, route::shortest_path, DropletId, Grid, GridView,
+    Location, Snapshot,
+};
+use crate::process::{PuddleError, PuddleResult};
+
+struct Mover {
+    id: DropletId,
+    // relative to the droplet's location
+    cells: Vec<Location>,
+    collision_group: usize,
+    start: Location,
+    goal: Location,
+}
+
+impl Mover {
+    fn fits(&self, grid: &Grid, loc: Location) -> bool {
+        self.cells.iter().all(|&c| grid.is_enabled(loc + c))
+    }
+
+    // same rule as `Snapshot::collisions`, touching counts
+    fn too_close(&self, loc: Location, other: &Mover, other_loc: Location) -> bool {
+        if self.collision_group == other.collision_group {
+            return false;
+        }
+        self.cells.iter().any(|&c1| {
+            other
+                .cells
+                .iter()
+                .any(|&c2| (loc + c1).chebyshev_distance_to(other_loc + c2) <= 1)
+        })
+    }
+}
+
+// where a droplet is at step `t`, they stay put once they're done
+fn at(path: &[Location], t: usize) -> Location {
+    path[t.min(path.len() - 1)]
+}
+
+/// Drives every droplet in `goals` to its goal, one step at a time, without
+/// any two droplets getting close enough to collide. Droplets without a
+/// goal stay where they are. The first snapshot is `snapshot` itself, and
+/// the last has everyone in place.
+///
+/// Droplets are planned one at a time, each waiting or detouring around
+/// the ones planned before it. If someone can't get through, the planning
+/// starts over with the next droplet going first. If nobody going first
+/// works, that's a `PlanError::Deadlock` naming whoever got stuck.
+pub fn route_all(
+    grid: &Grid,
+    snapshot: &Snapshot,
+    goals: &IndexMap<DropletId, Location>,
+) -> PuddleResult<Vec<Snapshot>> {
+    if let Some(&(id1, id2)) = snapshot.collisions().first() {
+        let err = SnapshotError::Collision(id1, id2);
+        return Err(PuddleError::InvalidSnapshot(err));
+    }
+
+    let mut movers = Vec::new();
+    let mut statics = Vec::new();
+    for d in snapshot.droplets.values() {
+        let goal = goals.get(&d.id).cloned();
+        let mover = Mover {
+            id: d.id,
+            cells: d.relative_cells().into_iter().collect(),
+            collision_group: d.collision_group,
+            start: d.location,
+            goal: goal.unwrap_or(d.location),
+        };
+        if goal.is_some() {
+            movers.push(mover);
+        } else {
+            statics.push(mover);
+        }
+    }
+    if let Some(id) = goals.keys().find(|&id| !snapshot.droplets.contains_key(id)) {
+        return Err(PuddleError::NonExistentDropletId(id.id));
+    }
+
+    let no_route = |ids: &[DropletId]| {
+        let agents = ids
+            .iter()
+            .map(|id| Agent::from_droplet(&snapshot.droplets[id], goals[id]))
+            .collect();
+        PuddleError::PlanError(PlanError::RouteError(RoutingError::NoRoute { agents }))
+    };
+
+    // even on an empty board, everyone has to be able to get there
+    for m in &movers {
+        let alone = shortest_path(grid, m.start, m.goal, &IndexSet::new());
+        if !m.fits(grid, m.goal) || alone.is_none() {
+            return Err(no_route(&[m.id]));
+        }
+    }
+
+    let mut blocked = Vec::new();
+    let mut paths = None;
+    for first in 0..movers.len() {
+        let mut order: Vec<&Mover> = movers.iter().collect();
+        order.rotate_left(first);
+        match plan_in_order(grid, &order, &statics) {
+            Ok(p) => {
+                paths = Some(p);
+                break;
+            }
+            Err(id) => blocked.push(id),
+        }
+    }
+    let paths = paths.ok_or_else(|| {
+        blocked.sort();
+        blocked.dedup();
+        PuddleError::PlanError(PlanError::Deadlock { blocked })
+    })?;
+
+    let len = paths.values().map(Vec::len).max().unwrap_or(1);
+    let snapshots: Vec<Snapshot> = (0..len)
+        .map(|t| {
+            let mut snap = snapshot.clone();
+            for (id, path) in &paths {
+                snap.droplets[id].location = at(path, t);
+            }
+            snap
+        })
+        .collect();
+
+    debug_assert!(snapshots.iter().all(|s| s.collisions().is_empty()));
+    Ok(snapshots)
+}
+
+/// Routes `agents` with `route_all`, for when the `Router` couldn't get
+/// them through all at once. Like the router, this stays out of the
+/// keepout border and lets agents in the same collision group touch.
+pub(crate) fn route_agents(
+    gridview: &GridView,
+    agents: &[Agent],
+) -> PuddleResult<IndexMap<DropletId, Path>> {
+    let mut grid = gridview.grid.clone();
+    let keepout: Vec<Location> = grid
+        .locations()
+        .map(|(loc, _)| loc)
+        .filter(|&loc| gridview.in_keepout(loc))
+        .collect();
+    for loc in keepout {
+        grid.get_cell_mut(loc).unwrap().enabled = false;
+    }
+
+    let mut snapshot = gridview.snapshot();
+    let mut goals = IndexMap::new();
+    for agent in agents {
+        if let Some(d) = snapshot.droplets.get_mut(&agent.id) {
+            d.collision_group = agent.collision_group;
+        }
+        goals.insert(agent.id, agent.destination);
+    }
+
+    let steps = route_all(&grid, &snapshot, &goals)?;
+    Ok(goals
+        .keys()
+        .map(|id| (*id, steps.iter().map(|s| s.droplets[id].location).collect()))
+        .collect())
+}
+
+// on failure, returns whoever couldn't get through
+fn plan_in_order(
+    grid: &Grid,
+    order: &[&Mover],
+    statics: &[Mover],
+) -> Result<IndexMap<DropletId, Vec<Location>>, DropletId> {
+    let mut planned: Vec<(&Mover, Vec<Location>)> =
+        statics.iter().map(|m| (m, vec![m.start])).collect();
+    for &mover in order {
+        let path = plan_one(grid, mover, &planned).ok_or(mover.id)?;
+        planned.push((mover, path));
+    }
+    Ok(planned
+        .into_iter()
+        .skip(statics.len())
+        .map(|(m, path)| (m.id, path))
+        .collect())
+}
+
+// space-time A*, where waiting in place is a step like any other
+fn plan_one(
+    grid: &Grid,
+    mover: &Mover,
+    planned: &[(&Mover, Vec<Location>)],
+) -> Option<Vec<Location>> {
+    // after this everyone else has stopped moving, so the board won't
+    // change no matter how long we wait. Time stops there too, which keeps
+    // the search finite: a droplet that can't make progress once everyone
+    // else is done never will.
+    let horizon = planned.iter().map(|(_, p)| p.len() - 1).max().unwrap_or(0);
+
+    let is_clear = |loc: Location, t: usize| {
+        mover.fits(grid, loc)
+            && planned
+                .iter()
+                .all(|(other, path)| !mover.too_close(loc, other, at(path, t)))
+    };
+
+    let successors = |&(loc, t): &(Location, usize)| {
+        let t = (t + 1).min(horizon);
+        NEIGHBORS_5
+            .iter()
+            .map(|&n| loc + n)
+            .filter(|&next| {
+                mover
+                    .cells
+                    .iter()
+                    .all(|&c| grid.can_move(loc + c, next + c))
+            })
+            .filter(|&next| is_clear(next, t))
+            .map(|next| ((next, t), 1))
+            .collect::<Vec<_>>()
+    };
+    let heuristic = |&(loc, _): &(Location, usize)| loc.distance_to(mover.goal);
+    let success = |&(loc, t): &(Location, usize)| loc == mover.goal && t >= horizon;
+
+    let (path, _cost) =
+        pathfinding::directed::astar::astar(&(mover.start, 0), successors, heuristic, success)?;
+    Some(path.into_iter().map(|(loc, _)| loc).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::{location::yx, Droplet};
+    use matches::assert_matches;
+
+    // a one-wide channel with a dead-end siding hanging off of it
+    // .........
+    // ..#
+    // ..#
+    fn channel() -> Grid {
+        let mut grid = Grid::rectangle(3, 9);
+        for y in 1..3 {
+            for x in 0..9 {
+                if x != 2 {
+                    grid.vec[y][x] = None;
+                }
+            }
+        }
+        grid
+    }
+
+    fn snapshot(droplets: &[(usize, Location)]) -> Snapshot {
+        let mut snap = Snapshot::default();
+        for &(i, loc) in droplets {
+            let id = i.into();
+            let mut d = Droplet::new(id, 1.0, loc, yx(1, 1));
+            d.collision_group = i;
+            snap.droplets.insert(id, d);
+        }
+        snap
+    }
+
+    #[test]
+    fn test_swap_in_channel() {
+        let grid = channel();
+        let (a, b) = (0.into(), 1.into());
+        let snap = snapshot(&[(0, yx(0, 0)), (1, yx(0, 8))]);
+        let goals = vec![(a, yx(0, 8)), (b, yx(0, 0))].into_iter().collect();
+
+        let steps = route_all(&grid, &snap, &goals).unwrap();
+        assert_eq!(steps[0].droplets[&a].location, yx(0, 0));
+        let last = steps.last().unwrap();
+        assert_eq!(last.droplets[&a].location, yx(0, 8));
+        assert_eq!(last.droplets[&b].location, yx(0, 0));
+
+        for (s1, s2) in steps.iter().zip(&steps[1..]) {
+            assert!(s2.collisions().is_empty());
+            for id in &[a, b] {
+                let (from, to) = (s1.droplets[id].location, s2.droplets[id].location);
+                assert!(from.distance_to(to) <= 1);
+                assert!(grid.is_enabled(to));
+            }
+        }
+        // someone had to pull into the siding to let the other by
+        assert!(steps
+            .iter()
+            .any(|s| s.droplets.values().any(|d| d.location == yx(2, 2))));
+    }
+
+    #[test]
+    fn test_route_agents() {
+        let mut gv = GridView::new(channel());
+        let (a, b): (DropletId, DropletId) = (0.into(), 1.into());
+        let snap = snapshot(&[(0, yx(0, 0)), (1, yx(0, 8))]);
+        gv.droplets = snap.droplets.clone();
+        let agents = vec![
+            Agent::from_droplet(&snap.droplets[&a], yx(0, 8)),
+            Agent::from_droplet(&snap.droplets[&b], yx(0, 0)),
+        ];
+
+        let paths = route_agents(&gv, &agents).unwrap();
+        assert_eq!(paths[&a].first(), Some(&yx(0, 0)));
+        assert_eq!(paths[&a].last(), Some(&yx(0, 8)));
+        assert_eq!(paths[&b].last(), Some(&yx(0, 0)));
+        assert_eq!(paths[&a].len(), paths[&b].len());
+
+        // with the channel in the keepout, nobody can use it
+        gv.set_keepout_border(1);
+        assert!(route_agents(&gv, &agents).is_err());
+    }
+
+    #[test]
+    fn test_route_all_errors() {
+        let grid = channel();
+        let snap = snapshot(&[(0, yx(0, 0)), (1, yx(0, 1))]);
+        let goals = vec![(0.into(), yx(0, 5))].into_iter().collect();
+        assert_matches!(
+            route_all(&grid, &snap, &goals),
+            Err(PuddleError::InvalidSnapshot(SnapshotError::Collision(_, _)))
+        );
+
+        // off in the dead space under the channel
+        let snap = snapshot(&[(0, yx(0, 0))]);
+        let goals = vec![(0.into(), yx(2, 5))].into_iter().collect();
+        assert_matches!(
+            route_all(&grid, &snap, &goals),
+            Err(PuddleError::PlanError(PlanError::RouteError(_)))
+        );
+
+        let goals = vec![(7.into(), yx(0, 5))].into_iter().collect();
+        assert_matches!(
+            route_all(&grid, &snap, &goals),
+            Err(PuddleError::NonExistentDropletId(7))
+        );
+    }
+
+    #[test]
+    fn test_deadlock() {
+        // a and b want to swap in a corridor with nowhere to pull over, c is
+        // on its own track and shouldn't get blamed
+        // .......
+        // xxxxxxx
+        // .......
+        let mut grid = Grid::rectangle(3, 7);
+        for x in 0..7 {
+            grid.vec[1][x] = None;
+        }
+        let (a, b, c) = (0.into(), 1.into(), 2.into());
+        let snap = snapshot(&[(0, yx(0, 0)), (1, yx(0, 6)), (2, yx(2, 0))]);
+        let goals = vec![(a, yx(0, 6)), (b, yx(0, 0)), (c, yx(2, 6))]
+            .into_iter()
+            .collect();
+
+        assert_matches!(
+            route_all(&grid, &snap, &goals),
+            Err(PuddleError::PlanError(PlanError::Deadlock { ref blocked })) if *blocked == vec![a, b]
+        );
+
+        // c alone is fine
+        let goals = vec![(c, yx(2, 6))].into_iter().collect();
+        let steps = route_all(&grid, &snap, &goals).unwrap();
+        assert_eq!(steps.len(), 7);
+    }
+}
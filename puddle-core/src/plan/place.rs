@@ -25,6 +25,8 @@ pub struct PlacementRequest<'a> {
 #[derive(Debug)]
 pub enum PlacementError {
     Bad,
+    // a stored droplet couldn't be moved out of the way of the commands
+    Collision(DropletId),
 }
 
 type PlacementResult = Result<PlacementResponse, PlacementError>;
@@ -127,7 +129,14 @@ impl<'a> Context<'a> {
     }
 
     fn is_compatible(&self, smaller: &Grid, offset: Location) -> bool {
-        is_compatible(&self.req.gridview.grid, smaller, offset, &self.bad_locs)
+        let gv = self.req.gridview;
+        is_compatible(
+            &gv.grid,
+            gv.keepout_border(),
+            smaller,
+            offset,
+            &self.bad_locs,
+        )
     }
 
     fn place(mut self) -> PlacementResult {
@@ -143,7 +152,9 @@ impl<'a> Context<'a> {
 
         // iteratively place the droplets
         for id in self.req.stored_droplets {
-            let offset = self.place_droplet(*id)?;
+            let offset = self
+                .place_droplet(*id)
+                .map_err(|_| PlacementError::Collision(*id))?;
             self.bad_locs.extend(
                 Rectangle {
                     location: offset,
@@ -170,6 +181,7 @@ impl Placer {
 
 fn is_compatible(
     bigger: &Grid,
+    keepout: u32,
     smaller: &Grid,
     offset: Location,
     bad_locs: &IndexSet<Location>,
@@ -185,10 +197,14 @@ fn is_compatible(
             return false;
         };
 
+        if keepout > 0 && bigger.in_border(big_loc, keepout) {
+            return false;
+        }
+
         // return the compatibility
-        bigger
-            .get_cell(big_loc)
-            .map_or(false, |big_cell| small_cell.is_compatible(&big_cell))
+        bigger.get_cell(big_loc).map_or(false, |big_cell| {
+            big_cell.enabled && small_cell.is_compatible(&big_cell)
+        })
     })
 }
 
@@ -204,7 +220,7 @@ mod tests {
         let offset = Location { y: 0, x: 0 };
         let bad_locs = IndexSet::default();
 
-        assert!(is_compatible(&grid, &shape, offset, &bad_locs))
+        assert!(is_compatible(&grid, 0, &shape, offset, &bad_locs))
     }
 
     // #[test]
@@ -251,5 +267,4 @@ mod tests {
     //         Some(&heater_loc)
     //     );
     // }
-
 }
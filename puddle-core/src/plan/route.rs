@@ -1,6 +1,8 @@
 use std::rc::Rc;
 
-use crate::grid::{grid::NEIGHBORS_5, Droplet, DropletId, Grid, GridView, Location, Rectangle};
+use crate::grid::{
+    grid::NEIGHBORS_5, location::yx, Droplet, DropletId, Grid, GridView, Location, Rectangle,
+};
 use indexmap::IndexMap;
 
 pub type Path = Vec<Location>;
@@ -49,6 +51,8 @@ pub struct Agent {
     pub source: Location,
     pub destination: Location,
     pub dimensions: Location,
+    // agents in the same group don't have to keep their distance
+    pub collision_group: usize,
 }
 
 impl Agent {
@@ -58,12 +62,25 @@ impl Agent {
             source: d.location,
             dimensions: d.dimensions,
             destination,
+            collision_group: d.collision_group,
         }
     }
 
     fn rectangle(&self, loc: Location) -> Rectangle {
         Rectangle::new(loc, self.dimensions)
     }
+
+    /// How many empty cells have to be kept between this agent at `loc` and
+    /// `other` at `other_loc`. Agents in the same group can touch once
+    /// they've both arrived, they just can't overlap.
+    fn spacing(&self, loc: Location, other: &Agent, other_loc: Location) -> i32 {
+        let arrived = loc == self.destination && other_loc == other.destination;
+        if arrived && self.collision_group == other.collision_group {
+            0
+        } else {
+            1
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -120,10 +137,16 @@ impl Node {
         self.locations.iter().zip(&group.agents)
     }
 
-    fn heuristic(&self, group: &Group) -> u32 {
+    fn heuristic(&self, ctx: &Context, group: &Group) -> u32 {
         let n_steps: u32 = self
             .with_group(group)
-            .map(|(l, a)| l.distance_to(a.destination))
+            .map(|(&l, a)| {
+                if ctx.allow_diagonal {
+                    l.chebyshev_distance_to(a.destination)
+                } else {
+                    l.distance_to(a.destination)
+                }
+            })
             .sum();
         MOVE_COST * n_steps
     }
@@ -138,7 +161,7 @@ impl Node {
         for (&loc, agent) in self.with_group(group) {
             let rect = agent.rectangle(loc);
             for rloc in rect.locations() {
-                if ctx.grid.get_cell(rloc).is_none() {
+                if !ctx.is_usable(rloc) {
                     return false;
                 }
             }
@@ -152,7 +175,7 @@ impl Node {
                 let dist = r1.collision_distance(&r2);
                 // collision distance is the number of spaces between, so
                 // anything above 0 is good
-                if dist <= 0 {
+                if dist < a1.spacing(loc1, a2, loc2) {
                     return false;
                 }
             }
@@ -169,6 +192,14 @@ impl Node {
     ) -> Option<(EdgeCost, Node)> {
         assert_eq!(self.locations.len(), offsets.len());
 
+        // every cell of every agent has to be allowed to make its move
+        for ((&loc, &offset), agent) in self.locations.iter().zip(offsets).zip(&group.agents) {
+            let cells = agent.rectangle(loc).locations();
+            if !cells.into_iter().all(|c| ctx.grid.can_move(c, c + offset)) {
+                return None;
+            }
+        }
+
         let new_locs: Vec<_> = self
             .locations
             .iter()
@@ -183,6 +214,26 @@ impl Node {
             time: self.time + 1,
         };
 
+        // a diagonal step cuts across the corner, so the whole box it sweeps
+        // has to be on the grid and clear of everyone else
+        for (i, (&loc, agent)) in self.with_group(group).enumerate() {
+            let offset = offsets[i];
+            if offset.y == 0 || offset.x == 0 {
+                continue;
+            }
+            let swept = swept_rectangle(&[loc, loc + offset], 1, agent.dimensions);
+            let mut cells = swept.clone().locations();
+            if cells.any(|c| !ctx.is_usable(c)) {
+                return None;
+            }
+            for (j, (&other_loc, other)) in node.with_group(group).enumerate() {
+                let spacing = agent.spacing(node.locations[i], other, other_loc);
+                if i != j && swept.collision_distance(&other.rectangle(other_loc)) < spacing {
+                    return None;
+                }
+            }
+        }
+
         if node.is_valid(ctx, group) {
             Some((edge_cost, node))
         } else {
@@ -193,7 +244,10 @@ impl Node {
     // This is rather naive for now, it pretty much always generates
     // exponentially many new agents
     fn open(&self, ctx: &Context, group: &Group, new_nodes: &mut Vec<(EdgeCost, Node)>) {
-        let nbrs = NEIGHBORS_5;
+        let mut nbrs = NEIGHBORS_5.to_vec();
+        if ctx.allow_diagonal {
+            nbrs.extend(&yx(0, 0).diagonals());
+        }
         let mut assignments = vec![0; self.locations.len()];
         let mut new_locations = Vec::with_capacity(nbrs.len());
 
@@ -228,6 +282,19 @@ fn path_nth(path: &[Location], i: usize) -> Location {
     *path.get(i).unwrap_or_else(|| path.last().unwrap())
 }
 
+// The space an agent takes up at time `i`. During a diagonal step it brushes
+// the two cells it cuts across, so that covers the box around both ends.
+fn swept_rectangle(path: &[Location], i: usize, dimensions: Location) -> Rectangle {
+    let loc = path_nth(path, i);
+    let prev = path_nth(path, i.saturating_sub(1));
+    if loc.y != prev.y && loc.x != prev.x {
+        let corner = yx(loc.y.min(prev.y), loc.x.min(prev.x));
+        Rectangle::new(corner, dimensions + yx(1, 1))
+    } else {
+        Rectangle::new(loc, dimensions)
+    }
+}
+
 #[derive(Debug)]
 struct Collision {
     id1: DropletId,
@@ -238,6 +305,10 @@ struct Collision {
 // borrows from request
 struct Context<'req> {
     grid: &'req Grid,
+    keepout: u32,
+    allow_diagonal: bool,
+    proximity_radius: i32,
+    proximity_cost: EdgeCost,
     agents: IndexMap<DropletId, Agent>,
     groups: IndexMap<DropletId, Rc<Group>>,
 }
@@ -250,6 +321,10 @@ impl Context<'_> {
 
         Context {
             grid: &req.gridview.grid,
+            keepout: req.gridview.keepout_border(),
+            allow_diagonal: req.gridview.allow_diagonal(),
+            proximity_radius: req.gridview.proximity_penalty().0 as i32,
+            proximity_cost: req.gridview.proximity_penalty().1,
             // TODO we can make agents ourselves instead of the request doing it
             agents: agents().map(|a| (a.id, a)).collect(),
             // each group is a singleton node for now,
@@ -259,6 +334,11 @@ impl Context<'_> {
         }
     }
 
+    // enabled and outside the keepout border
+    fn is_usable(&self, loc: Location) -> bool {
+        self.grid.is_enabled(loc) && !(self.keepout > 0 && self.grid.in_border(loc, self.keepout))
+    }
+
     fn find_collisions(&self, paths: &PathMap) -> Vec<Collision> {
         let mut collisions = Vec::new();
 
@@ -283,8 +363,9 @@ impl Context<'_> {
                     let a2 = &self.agents[&id2];
                     let p2 = p2.as_ref();
                     let loc2 = path_nth(p2, time);
-                    let rect2 = Rectangle::new(loc2, a2.dimensions);
-                    if rect1.collision_distance(&rect2) <= 0 {
+                    let swept1 = swept_rectangle(p1, time, a1.dimensions);
+                    let swept2 = swept_rectangle(p2, time, a2.dimensions);
+                    if swept1.collision_distance(&swept2) < a1.spacing(loc1, a2, loc2) {
                         let c = Collision { id1, id2, time };
                         collisions.push(c)
                     }
@@ -315,7 +396,8 @@ impl Context<'_> {
                     location,
                     dimensions,
                 };
-                if rect.collision_distance(&path_rect) <= 0 {
+                let spacing = a.spacing(location, &self.agents[id], path_rect.location);
+                if rect.collision_distance(&path_rect) < spacing {
                     return Some(*id);
                 }
             }
@@ -323,6 +405,42 @@ impl Context<'_> {
         None
     }
 
+    // Extra cost for agents in `node` being close to droplets they aren't
+    // about to merge with. Agents that haven't been routed yet count as
+    // sitting where they started.
+    fn proximity_cost(&self, paths: &PathMap, group: &Group, node: &Node) -> EdgeCost {
+        if self.proximity_cost == 0 {
+            return 0;
+        }
+
+        let time = node.time as usize;
+        let in_group = node.with_group(group).map(|(&loc, a)| (loc, a));
+        let outside = self
+            .agents
+            .values()
+            .filter(|o| group.agents.iter().all(|a| a.id != o.id))
+            .map(|o| match paths.get(&o.id) {
+                Some(path) => (path_nth(path, time), o),
+                None => (o.source, o),
+            });
+        let others: Vec<_> = in_group.chain(outside).collect();
+
+        let mut cost = 0;
+        for (&loc, a) in node.with_group(group) {
+            let rect = a.rectangle(loc);
+            for &(other_loc, other) in &others {
+                if other.collision_group == a.collision_group {
+                    continue;
+                }
+                let dist = rect.collision_distance(&other.rectangle(other_loc));
+                if dist <= self.proximity_radius {
+                    cost += self.proximity_cost;
+                }
+            }
+        }
+        cost
+    }
+
     fn merge_groups(&mut self, id1: &DropletId, id2: &DropletId) -> Rc<Group> {
         let group1 = &self.groups[id1];
         let group2 = &self.groups[id2];
@@ -421,11 +539,14 @@ impl Context<'_> {
         let successors = |n: &Node| {
             let mut buf = Vec::new();
             n.open(self, group, &mut buf);
-            buf.into_iter().map(|(c, n)| (n, c))
+            buf.into_iter().map(|(c, n)| {
+                let c = c + self.proximity_cost(paths, group, &n);
+                (n, c)
+            })
         };
 
         let heuristic = |n: &Node| {
-            let mut h = n.heuristic(group);
+            let mut h = n.heuristic(self, group);
             if self.find_collisions_with(paths, group, n).is_some() {
                 h += COLLISION_COST;
             }
@@ -489,6 +610,7 @@ mod tests {
 
     use super::*;
     use crate::grid::gridview::tests::{c2id, id2c, parse_gridview};
+    use crate::grid::location::yx;
     use indexmap::IndexSet;
 
     fn draw_path(path: &[Location], ch: char, gridview: &GridView) -> Vec<String> {
@@ -791,4 +913,130 @@ mod tests {
         // sorting
         check_groups(&ctx, &["ab", "c", "d", "e", "f"]);
     }
+
+    #[test]
+    fn test_merging_droplets_can_touch() {
+        let gv0 = parse_gridview(&["a...b...c"]);
+
+        // a and b are about to be combined, so they can end up adjacent
+        let gv1 = parse_gridview(&["..ab....c"]);
+        let mut req = mk_route_request(&gv0, &gv1);
+        share_group(&mut req, 'a', 'b');
+        let mut ctx = Context::from_request(&req);
+        assert!(ctx.route().is_some());
+
+        // but c still has to keep its distance from both of them
+        let gv1 = parse_gridview(&["..abc...."]);
+        let mut req = mk_route_request(&gv0, &gv1);
+        share_group(&mut req, 'a', 'b');
+        let mut ctx = Context::from_request(&req);
+        assert_eq!(ctx.route(), None);
+    }
+
+    fn share_group(req: &mut RoutingRequest, ch1: char, ch2: char) {
+        let find = |ch| req.agents.iter().find(|a| a.id == c2id(ch));
+        let group = find(ch1).unwrap().collision_group;
+        for a in req.agents.iter_mut().filter(|a| a.id == c2id(ch2)) {
+            a.collision_group = group;
+        }
+    }
+
+    #[test]
+    fn test_one_way_route() {
+        #[rustfmt::skip]
+        let mut gv0 = parse_gridview(&[
+            "....a",
+            ".   .",
+            ".....",
+        ]);
+        #[rustfmt::skip]
+        let mut gv1 = parse_gridview(&[
+            "a....",
+            ".   .",
+            ".....",
+        ]);
+
+        // the top row only flows east, so a has to go around the bottom
+        for gv in &mut [&mut gv0, &mut gv1] {
+            gv.grid.add_one_way(yx(0, 1), yx(0, 2));
+        }
+
+        let req = &mk_route_request(&gv0, &gv1);
+        let mut ctx = Context::from_request(req);
+        let paths = ctx.route().unwrap();
+
+        let path = &paths[&c2id('a')];
+        assert_eq!(path.len(), 9);
+        assert!(path.contains(&yx(2, 2)));
+    }
+
+    #[test]
+    fn test_diagonal_route_is_shorter() {
+        #[rustfmt::skip]
+        let mut gv0 = parse_gridview(&[
+            "a....",
+            ".....",
+            ".....",
+            ".....",
+        ]);
+        #[rustfmt::skip]
+        let mut gv1 = parse_gridview(&[
+            ".....",
+            ".....",
+            ".....",
+            "...a.",
+        ]);
+
+        let route_len = |gv0: &GridView, gv1: &GridView| {
+            let req = &mk_route_request(gv0, gv1);
+            let mut ctx = Context::from_request(req);
+            ctx.route().unwrap()[&c2id('a')].len()
+        };
+
+        // 3 down and 3 over, plus the starting location
+        assert_eq!(route_len(&gv0, &gv1), 7);
+
+        for gv in &mut [&mut gv0, &mut gv1] {
+            gv.set_allow_diagonal(true);
+        }
+        assert_eq!(route_len(&gv0, &gv1), 4);
+    }
+
+    #[test]
+    fn test_proximity_penalty() {
+        #[rustfmt::skip]
+        let mut gv0 = parse_gridview(&[
+            ".......",
+            ".......",
+            "a......",
+            ".......",
+            "...b...",
+        ]);
+        #[rustfmt::skip]
+        let mut gv1 = parse_gridview(&[
+            ".......",
+            ".......",
+            "......a",
+            ".......",
+            "...b...",
+        ]);
+
+        let route = |gv0: &GridView, gv1: &GridView| {
+            let req = &mk_route_request(gv0, gv1);
+            let mut ctx = Context::from_request(req);
+            ctx.route().unwrap()[&c2id('a')].clone()
+        };
+
+        // the straight line only has one empty row between a and b
+        let path = route(&gv0, &gv1);
+        assert_eq!(path.len(), 7);
+
+        // with a penalty, a goes up a row to keep its distance
+        for gv in &mut [&mut gv0, &mut gv1] {
+            gv.set_proximity_penalty(1, 5);
+        }
+        let path = route(&gv0, &gv1);
+        assert_eq!(path.len(), 9);
+        assert!(path.contains(&yx(1, 3)));
+    }
 }
@@ -137,6 +137,10 @@ impl Scheduler {
         assert_eq!(was_there, None);
     }
 
+    pub fn is_scheduled(&self, cmd_id: CmdIndex) -> bool {
+        self.node_sched.contains_key(&cmd_id)
+    }
+
     fn is_ready(&self, req: &SchedRequest, cmd: CmdIndex) -> bool {
         let graph = &req.graph.graph;
         graph
@@ -1,8 +1,10 @@
+use std::io::Write;
 use std::ops::{Deref, DerefMut, Drop};
 use std::sync::{Arc, Mutex};
 
-use crate::grid::{DropletInfo, Grid};
-use crate::process::{Process, ProcessId, PuddleError, PuddleResult};
+use crate::exec::Execute;
+use crate::grid::{DropletInfo, Grid, Snapshot};
+use crate::process::{recorder::Recorder, Process, ProcessId, PuddleError, PuddleResult};
 use crate::system::System;
 
 use indexmap::IndexMap;
@@ -47,9 +49,19 @@ pub struct Manager {
 }
 
 impl Manager {
-    pub fn new(blocking: bool, grid: Grid) -> Manager {
-        let system = Arc::new(Mutex::new(System::new(grid)));
+    /// Runs on a plain `Executor`, see `Executor::new` for how this fails.
+    pub fn new(blocking: bool, grid: Grid) -> PuddleResult<Manager> {
+        Ok(Manager::from_system(blocking, System::new(grid)?))
+    }
+
+    /// Like `new`, but with a different executor, say a `SimExecutor` to
+    /// run without hardware.
+    pub fn with_executor(blocking: bool, grid: Grid, executor: Box<dyn Execute>) -> Manager {
+        Manager::from_system(blocking, System::with_executor(grid, executor))
+    }
 
+    fn from_system(blocking: bool, system: System) -> Manager {
+        let system = Arc::new(Mutex::new(system));
         Manager {
             system,
             blocking,
@@ -61,6 +73,77 @@ impl Manager {
         self.system.lock().unwrap().get_logs().to_vec()
     }
 
+    /// Writes out which droplets were made from which, as JSON.
+    pub fn export_provenance(&self, w: impl Write) -> serde_json::Result<()> {
+        self.system.lock().unwrap().export_provenance(w)
+    }
+
+    /// Writes out when each command started and ended, as JSON records with
+    /// ISO-8601 timestamps.
+    pub fn export_timeline(&self, w: impl Write) -> serde_json::Result<()> {
+        self.system.lock().unwrap().export_timeline(w)
+    }
+
+    /// Logs every command from here on, so the run can be replayed later.
+    /// Pass `None` to stop.
+    pub fn set_recorder(&self, recorder: Option<Recorder>) {
+        self.system.lock().unwrap().set_recorder(recorder)
+    }
+
+    /// Keeps droplets out of the outer `n` rings of the grid.
+    pub fn set_keepout_border(&self, n: u32) {
+        self.system.lock().unwrap().set_keepout_border(n)
+    }
+
+    /// Tracks how much is left in the input named `name`, starting at `volume`.
+    pub fn set_reservoir(&self, name: &str, volume: f64) {
+        self.system.lock().unwrap().set_reservoir(name, volume)
+    }
+
+    /// Fails the flush once a droplet hasn't moved for `threshold` commanded
+    /// moves in a row. Only useful when something is sensing the droplets.
+    pub fn set_stuck_threshold(&self, threshold: Option<u32>) {
+        self.system.lock().unwrap().set_stuck_threshold(threshold)
+    }
+
+    /// See `GridView::set_min_volume`.
+    pub fn set_min_volume(&self, volume: f64) {
+        self.system.lock().unwrap().set_min_volume(volume)
+    }
+
+    /// See `GridView::set_cell_volume`.
+    pub fn set_cell_volume(&self, volume: Option<f64>) {
+        self.system.lock().unwrap().set_cell_volume(volume)
+    }
+
+    /// How long planning has taken so far, per type of command.
+    pub fn plan_metrics(&self) -> crate::plan::Metrics {
+        self.system.lock().unwrap().plan_metrics()
+    }
+
+    /// See `GridView::set_proximity_penalty`.
+    pub fn set_proximity_penalty(&self, radius: u32, cost: u32) {
+        self.system
+            .lock()
+            .unwrap()
+            .set_proximity_penalty(radius, cost)
+    }
+
+    /// Lets droplets be routed diagonally, see `GridView::set_allow_diagonal`.
+    pub fn set_allow_diagonal(&self, allow: bool) {
+        self.system.lock().unwrap().set_allow_diagonal(allow)
+    }
+
+    /// The droplets as of the last flush. Unlike `Process::flush`, this
+    /// doesn't wait for anything to run, so it's cheap to poll.
+    pub fn snapshot(&self) -> Snapshot {
+        self.system.lock().unwrap().snapshot()
+    }
+
+    pub fn grid(&self) -> Grid {
+        self.system.lock().unwrap().grid().clone()
+    }
+
     // pub fn gridview(&self) -> MutexGuard<GridView> {
     //     self.gridview.lock().unwrap()
     // }
@@ -98,6 +181,27 @@ impl Manager {
         Ok(pid)
     }
 
+    /// Like `new_process`, but with the ids given, see
+    /// `Process::new_with_ids`. Fails if there's already a process `pid`.
+    pub fn restore_process<S>(
+        &self,
+        name: S,
+        pid: ProcessId,
+        start_droplet_id: usize,
+    ) -> PuddleResult<ProcessId>
+    where
+        S: Into<String>,
+    {
+        let mut procs = self.processes.lock().unwrap();
+        if procs.contains_key(&pid) {
+            return Err(PuddleError::ProcessIdTaken(pid));
+        }
+        let system = Arc::clone(&self.system);
+        let process = Process::new_with_ids(name.into(), system, pid, start_droplet_id);
+        procs.insert(pid, process);
+        Ok(pid)
+    }
+
     pub fn close_process(&self, pid: ProcessId) -> PuddleResult<()> {
         let p = self.take_process(pid)?;
         p.flush()?;
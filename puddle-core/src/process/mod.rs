@@ -1,5 +1,6 @@
 mod manager;
 mod process;
+pub mod recorder;
 
 pub use self::manager::*;
 pub use self::process::*;
@@ -2,31 +2,114 @@ use std::fmt;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::Relaxed;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use crate::util::seconds_duration;
+use crate::util::{fetch_max, seconds_duration};
 
-use crate::grid::{DropletId, DropletInfo, Location};
+use crate::grid::{
+    gridview::SnapshotError, location::yx, sizing::dimensions_for_volume, DropletId, DropletInfo,
+    Location, Rectangle,
+};
 use crate::system::System;
 
 use crate::command;
-use crate::command::BoxedCommand;
+use crate::command::{BoxedCommand, SplitAxis};
+use crate::exec::CommandTiming;
 
-use crate::plan::PlanError;
+use crate::plan::{self, BlockReason, PlanError};
+
+use indexmap::IndexMap;
 
 #[derive(Debug)]
 pub enum PuddleError {
     PlanError(PlanError),
     NonExistentDropletId(usize),
     NonExistentProcess(ProcessId),
+    InvalidFraction(f64),
+    InvalidRatios(Vec<f64>),
+    InvalidPath(Vec<Location>),
+    // only single electrode droplets can be dragged along a path, and this
+    // one had these dimensions
+    InvalidWashBuffer(DropletId, Location),
+    InvalidHeatProfile(Vec<(f32, f64)>),
+    InvalidSnapshot(SnapshotError),
+    OutOfBounds(Location),
+    NonExistentOutput(String),
+    RegionOccupied(DropletId),
+    InvalidDilution(usize, f64),
+    // the droplet needed to have at least this much volume
+    NotEnoughVolume(DropletId, f64),
+    // intensity, then cycles
+    InvalidAgitation(u32, u32),
+    ProcessIdTaken(ProcessId),
+    // the droplet sensor couldn't be read, with why
+    SensorFailed(String),
+    // the electrodes couldn't be set, with why
+    ActuationFailed(String),
+    // in seconds
+    InvalidDuration(f64),
+    // what PUDDLE_STEP_DELAY_MS was set to
+    InvalidStepDelay(String),
+    // a simulated command didn't conserve volume: its name, then the volume
+    // going in and coming out
+    VolumeChanged(String, f64, f64),
 }
 
 impl fmt::Display for PuddleError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use PuddleError::*;
         match self {
-            PlanError(err) => write!(f, "Plan error {:#?}", err),
+            PlanError(err) => write!(f, "Couldn't plan: {}", err),
             NonExistentProcess(pid) => write!(f, "Process {} does not exist", pid),
             NonExistentDropletId(id) => write!(f, "Droplet {} does not exist", id),
+            InvalidFraction(x) => write!(f, "Fraction {} must be between 0 and 1", x),
+            InvalidRatios(r) => write!(f, "Ratios {:?} must be non-empty and positive", r),
+            InvalidPath(p) => write!(
+                f,
+                "Path {:?} must be non-empty and go one electrode at a time",
+                p
+            ),
+            InvalidWashBuffer(id, dims) => write!(
+                f,
+                "Droplet {} is {}x{}, but only 1x1 droplets can wash a path",
+                id, dims.y, dims.x
+            ),
+            InvalidHeatProfile(segs) => write!(
+                f,
+                "Heat profile {:?} must be non-empty, with temperatures from {} to {}*C",
+                segs,
+                command::MIN_HEAT_TEMPERATURE,
+                command::MAX_HEAT_TEMPERATURE
+            ),
+            InvalidSnapshot(err) => write!(f, "Invalid snapshot: {}", err),
+            OutOfBounds(loc) => write!(f, "Location {} is off the grid", loc),
+            NonExistentOutput(name) => write!(f, "There's no output named '{}'", name),
+            RegionOccupied(id) => write!(f, "Droplet {} is in the way", id),
+            InvalidDilution(steps, factor) => write!(
+                f,
+                "Can't dilute {} times by {}, need at least one step and a factor over 1",
+                steps, factor
+            ),
+            NotEnoughVolume(id, vol) => write!(f, "Droplet {} needs a volume of {}", id, vol),
+            InvalidAgitation(intensity, cycles) => write!(
+                f,
+                "Can't agitate {} times with intensity {}, both need to be at least 1",
+                cycles, intensity
+            ),
+            ProcessIdTaken(pid) => write!(f, "Process {} already exists", pid),
+            SensorFailed(why) => write!(f, "Couldn't read the droplet sensor: {}", why),
+            ActuationFailed(why) => write!(f, "Couldn't set the electrodes: {}", why),
+            InvalidDuration(secs) => write!(f, "Duration of {} seconds can't be negative", secs),
+            InvalidStepDelay(ms) => write!(
+                f,
+                "PUDDLE_STEP_DELAY_MS should be a whole number of milliseconds, not '{}'",
+                ms
+            ),
+            VolumeChanged(name, before, after) => write!(
+                f,
+                "{} changed the volume from {} to {}",
+                name, before, after
+            ),
         }
     }
 }
@@ -37,6 +120,12 @@ pub type PuddleResult<T> = Result<T, PuddleError>;
 
 pub type ProcessId = usize;
 
+// volumes closer than this are the same as far as dilution is concerned
+const VOLUME_EPSILON: f64 = 1e-9;
+
+// where `wash` dumps its buffer
+const WASTE_OUTPUT: &str = "waste";
+
 pub struct Process {
     id: ProcessId,
     #[allow(dead_code)]
@@ -59,11 +148,30 @@ impl Process {
         }
     }
 
+    /// Picks up where an earlier run left off: the process gets id
+    /// `process_id`, and its droplets are numbered from `start_droplet_id`.
+    /// Processes made after this one get higher ids, so they won't collide
+    /// with it.
+    pub fn new_with_ids(
+        name: String,
+        system: Arc<Mutex<System>>,
+        process_id: ProcessId,
+        start_droplet_id: usize,
+    ) -> Process {
+        fetch_max(&NEXT_PROCESS_ID, process_id + 1);
+        Process {
+            id: process_id,
+            name: name,
+            next_droplet_id: AtomicUsize::new(start_droplet_id),
+            system,
+        }
+    }
+
     pub fn id(&self) -> ProcessId {
         self.id
     }
 
-    fn new_droplet_id(&self) -> DropletId {
+    pub(crate) fn new_droplet_id(&self) -> DropletId {
         DropletId {
             id: self.next_droplet_id.fetch_add(1, Relaxed),
             process_id: self.id,
@@ -71,18 +179,64 @@ impl Process {
     }
 
     fn plan(&self, cmd: BoxedCommand) -> PuddleResult<()> {
+        self.try_plan(cmd).map_err(|(err, _cmd)| err)
+    }
+
+    /// Queues up a command built by hand. If it can't be added, say because
+    /// one of its inputs doesn't exist yet, the command comes back with the
+    /// error so it can be retried later.
+    pub fn try_plan(&self, cmd: BoxedCommand) -> Result<(), (PuddleError, BoxedCommand)> {
         let mut sys = self.system.lock().unwrap();
-        sys.add(cmd)
+        sys.try_add(cmd)
+    }
+
+    /// Queues up several commands built by hand, all or nothing. The system
+    /// is locked once for the whole batch, so no other process can slip its
+    /// own commands in between these. If any command can't be added, none of
+    /// them are and the first error comes back.
+    ///
+    /// This is only about queueing. Nothing runs until a flush, and then
+    /// these are scheduled like any others, so they can still run alongside
+    /// other processes' commands that don't touch the same droplets.
+    pub fn plan_batch(&self, cmds: Vec<BoxedCommand>) -> PuddleResult<()> {
+        self.try_plan_batch(cmds).map_err(|(err, _cmds)| err)
+    }
+
+    /// Like `plan_batch`, but if the batch can't be added it comes back
+    /// with the error, in order and untouched, like `try_plan`.
+    pub fn try_plan_batch(
+        &self,
+        cmds: Vec<BoxedCommand>,
+    ) -> Result<(), (PuddleError, Vec<BoxedCommand>)> {
+        let mut sys = self.system.lock().unwrap();
+        sys.try_add_batch(cmds)
     }
 }
 
 impl Process {
     pub fn flush(&self) -> PuddleResult<Vec<DropletInfo>> {
         let mut sys = self.system.lock().unwrap();
-        sys.flush(&[]).unwrap();
+        sys.flush(&[])?;
         Ok(sys.info(Some(self.id)))
     }
 
+    /// Like `flush`, but also returns the timing of every command finalized
+    /// during the flush. Flushing runs everything, so that includes other
+    /// processes' commands too.
+    pub fn flush_with_timing(&self) -> PuddleResult<(Vec<DropletInfo>, Vec<CommandTiming>)> {
+        let mut sys = self.system.lock().unwrap();
+        // throw away anything from before this flush
+        sys.take_timings();
+        sys.flush(&[])?;
+        Ok((sys.info(Some(self.id)), sys.take_timings()))
+    }
+
+    /// Returns this process's commands that can't currently proceed, and why.
+    pub fn blocked(&self) -> Vec<(String, BlockReason)> {
+        let sys = self.system.lock().unwrap();
+        sys.blocked(Some(self.id))
+    }
+
     pub fn create(
         &self,
         loc: Option<Location>,
@@ -90,11 +244,37 @@ impl Process {
         dim: Option<Location>,
     ) -> PuddleResult<DropletId> {
         let output = self.new_droplet_id();
+        let dim = dim.or_else(|| {
+            let cell_volume = self.system.lock().unwrap().cell_volume();
+            cell_volume.map(|cv| dimensions_for_volume(vol, cv))
+        });
         let create_cmd = command::Create::new(loc, vol, dim, output)?;
         self.plan(Box::new(create_cmd))?;
         Ok(output)
     }
 
+    /// Floods `region`, then pulls it back in so a droplet of `vol` forms at
+    /// the region's center. The region has to be on the grid and clear of
+    /// other droplets, so this flushes to check.
+    pub fn create_from_region(&self, region: Rectangle, vol: f64) -> PuddleResult<DropletId> {
+        let output = self.new_droplet_id();
+        let mut sys = self.system.lock().unwrap();
+        sys.flush(&[])?;
+        if !sys.in_bounds(region.location, region.dimensions) {
+            return Err(PuddleError::OutOfBounds(region.location));
+        }
+        let in_the_way = sys.info(None).into_iter().find(|info| {
+            let rect = Rectangle::new(info.location, info.dimensions);
+            rect.collision_distance(&region) < 1
+        });
+        if let Some(info) = in_the_way {
+            return Err(PuddleError::RegionOccupied(info.id));
+        }
+        let create_cmd = command::CreateFromRegion::new(region, vol, output)?;
+        sys.add(Box::new(create_cmd))?;
+        Ok(output)
+    }
+
     pub fn input(
         &self,
         name: impl Into<String>,
@@ -107,12 +287,69 @@ impl Process {
         Ok(output)
     }
 
+    /// Moves `d` back onto the input named `source` and absorbs it, crediting
+    /// the reservoir with its volume. This consumes `d`.
+    pub fn return_to_reservoir(&self, d: DropletId, source: impl Into<String>) -> PuddleResult<()> {
+        let return_cmd = command::ReturnToReservoir::new(source.into(), d)?;
+        self.plan(Box::new(return_cmd))?;
+        Ok(())
+    }
+
+    /// How much is left in each tracked input. This only counts inputs
+    /// that have actually run, so flush first to include everything queued.
+    pub fn reservoir_levels(&self) -> IndexMap<String, f64> {
+        self.system.lock().unwrap().reservoir_levels()
+    }
+
+    pub fn reservoir_level(&self, name: &str) -> Option<f64> {
+        self.reservoir_levels().get(name).cloned()
+    }
+
+    /// Routes `d` to the output named `name` and consumes it there.
     pub fn output(&self, name: impl Into<String>, d: DropletId) -> PuddleResult<()> {
-        let output_cmd = command::Output::new(name.into(), d)?;
+        let name = name.into();
+        if self.system.lock().unwrap().output_port(&name).is_none() {
+            return Err(PuddleError::NonExistentOutput(name));
+        }
+        let output_cmd = command::Output::new(name, d)?;
         self.plan(Box::new(output_cmd))?;
         Ok(())
     }
 
+    /// Drags `buffer` over every electrode in `path`, in order, and then
+    /// sends it to the output named "waste". The path has to be on the grid
+    /// and go one (non-diagonal) step at a time. This consumes `buffer`.
+    pub fn wash(&self, path: Vec<Location>, buffer: DropletId) -> PuddleResult<()> {
+        let contiguous = path.windows(2).all(|w| w[0].distance_to(w[1]) == 1);
+        if path.is_empty() || !contiguous {
+            return Err(PuddleError::InvalidPath(path));
+        }
+        {
+            let sys = self.system.lock().unwrap();
+            if let Some(&loc) = path.iter().find(|&&l| !sys.in_bounds(l, yx(1, 1))) {
+                return Err(PuddleError::OutOfBounds(loc));
+            }
+            if sys.output_port(WASTE_OUTPUT).is_none() {
+                return Err(PuddleError::NonExistentOutput(WASTE_OUTPUT.into()));
+            }
+        }
+        // FIXME only single electrodes can be dragged along a path for now
+        let info = self.flush()?;
+        let dims = info
+            .iter()
+            .find(|d| d.id == buffer)
+            .map(|d| d.dimensions)
+            .ok_or(PuddleError::NonExistentDropletId(buffer.id))?;
+        if dims != yx(1, 1) {
+            return Err(PuddleError::InvalidWashBuffer(buffer, dims));
+        }
+
+        let washed = self.new_droplet_id();
+        let wash_cmd = command::Wash::new(buffer, path, washed)?;
+        self.plan(Box::new(wash_cmd))?;
+        self.output(WASTE_OUTPUT, washed)
+    }
+
     pub fn move_droplet(&self, d1: DropletId, loc: Location) -> PuddleResult<DropletId> {
         let output = self.new_droplet_id();
         let move_cmd = command::Move::new(d1, loc, output)?;
@@ -120,13 +357,88 @@ impl Process {
         Ok(output)
     }
 
+    /// Like `move_droplet`, but pulses the GPIO `sync_pin` right before the
+    /// first step of the move is actuated, e.g. to line up a camera.
+    pub fn move_with_sync(
+        &self,
+        d: DropletId,
+        loc: Location,
+        sync_pin: u32,
+    ) -> PuddleResult<DropletId> {
+        let output = self.new_droplet_id();
+        let move_cmd = command::Move::new(d, loc, output)?.with_sync(sync_pin);
+        self.plan(Box::new(move_cmd))?;
+        Ok(output)
+    }
+
+    /// Moves `d` by `offset` from wherever it is now. This has to flush to
+    /// find out where that is.
+    pub fn move_relative(&self, d: DropletId, offset: Location) -> PuddleResult<DropletId> {
+        let destination = {
+            let mut sys = self.system.lock().unwrap();
+            sys.flush(&[])?;
+            let info = sys
+                .info(Some(self.id))
+                .into_iter()
+                .find(|info| info.id == d)
+                .ok_or(PuddleError::NonExistentDropletId(d.id))?;
+            let destination = info.location + offset;
+            if !sys.in_bounds(destination, info.dimensions) {
+                return Err(PuddleError::OutOfBounds(destination));
+            }
+            destination
+        };
+        self.move_droplet(d, destination)
+    }
+
+    /// Checks that `d` really is at `loc` once everything before this has
+    /// run. With a sensor attached, that's where the sensor last saw it.
+    pub fn assert_at(&self, d: DropletId, loc: Location) -> PuddleResult<()> {
+        let mut sys = self.system.lock().unwrap();
+        sys.flush(&[])?;
+        let actual = sys
+            .info(Some(self.id))
+            .into_iter()
+            .find(|info| info.id == d)
+            .ok_or(PuddleError::NonExistentDropletId(d.id))?
+            .location;
+        if actual != loc {
+            let err = PlanError::AssertionFailed(d, loc, actual);
+            return Err(PuddleError::PlanError(err));
+        }
+        Ok(())
+    }
+
+    /// Whether there's really a droplet at `loc`, once everything before
+    /// this has run. On hardware that's up to the chip's sensing, otherwise
+    /// it's whatever the simulation says. A `loc` off the grid is an error.
+    pub fn sense(&self, loc: Location) -> PuddleResult<bool> {
+        let mut sys = self.system.lock().unwrap();
+        sys.sense(loc)
+    }
+
     pub fn mix(&self, d1: DropletId, d2: DropletId) -> PuddleResult<DropletId> {
+        self.mix_with(d1, d2, 1, 1)
+    }
+
+    /// Like `mix`, but the combined droplet gets moved `intensity`
+    /// electrodes back and forth, `cycles` times over. Thicker samples need
+    /// more of both.
+    pub fn mix_with(
+        &self,
+        d1: DropletId,
+        d2: DropletId,
+        intensity: u32,
+        cycles: u32,
+    ) -> PuddleResult<DropletId> {
         let combine_out = self.new_droplet_id();
+        let agitate_out = self.new_droplet_id();
+
+        // check the agitation before planning anything
         let combine_cmd = command::Combine::new(d1, d2, combine_out)?;
-        self.plan(Box::new(combine_cmd))?;
+        let agitate_cmd = command::Agitate::new(combine_out, agitate_out, intensity, cycles)?;
 
-        let agitate_out = self.new_droplet_id();
-        let agitate_cmd = command::Agitate::new(combine_out, agitate_out)?;
+        self.plan(Box::new(combine_cmd))?;
         self.plan(Box::new(agitate_cmd))?;
 
         Ok(agitate_out)
@@ -143,24 +455,468 @@ impl Process {
         let out1 = self.new_droplet_id();
         let out2 = self.new_droplet_id();
         let split_cmd = command::Split::new(d, out1, out2)?;
+        self.check_split(&split_cmd)?;
+        self.plan(Box::new(split_cmd))?;
+        Ok((out1, out2))
+    }
+
+    /// Like `split`, but lets you pick which way the daughters go.
+    pub fn split_along(
+        &self,
+        d: DropletId,
+        axis: SplitAxis,
+    ) -> PuddleResult<(DropletId, DropletId)> {
+        let out1 = self.new_droplet_id();
+        let out2 = self.new_droplet_id();
+        let split_cmd = command::Split::new(d, out1, out2)?.with_axis(axis);
+        self.check_split(&split_cmd)?;
         self.plan(Box::new(split_cmd))?;
         Ok((out1, out2))
     }
 
+    // If the droplet being split is already on the grid we know its volume,
+    // so a split that's too small can fail right here instead of at the next
+    // flush, the same way it would there.
+    fn check_split(&self, split: &dyn command::Command) -> PuddleResult<()> {
+        let sys = self.system.lock().unwrap();
+        let d = split.input_droplets()[0];
+        if let Some(volume) = sys.volume(d) {
+            plan::check_split_volume(split, volume, sys.min_volume())
+                .map_err(PuddleError::PlanError)?;
+        }
+        Ok(())
+    }
+
+    fn check_heat(&self, heat: &dyn command::Command) -> PuddleResult<()> {
+        let sys = self.system.lock().unwrap();
+        let d = heat.input_droplets()[0];
+        if let Some(dimensions) = sys.dimensions(d) {
+            plan::check_heatable(heat, dimensions).map_err(PuddleError::PlanError)?;
+        }
+        Ok(())
+    }
+
+    /// Where the daughters of splitting `d` along `axis` would end up,
+    /// without actually splitting. This flushes so `d` is on the grid.
+    pub fn preview_split(
+        &self,
+        d: DropletId,
+        axis: SplitAxis,
+    ) -> PuddleResult<(Location, Location)> {
+        let mut sys = self.system.lock().unwrap();
+        sys.flush(&[])?;
+        sys.preview_split(d, axis)
+    }
+
+    /// Splits `d` into one daughter per ratio, each getting that share of the
+    /// volume. The ratios don't have to add up to 1, they get normalized.
+    pub fn split_ratios(&self, d: DropletId, ratios: &[f64]) -> PuddleResult<Vec<DropletId>> {
+        let outs: Vec<_> = ratios.iter().map(|_| self.new_droplet_id()).collect();
+        let split_cmd = command::SplitN::new(d, outs.clone(), ratios)?;
+        self.check_split(&split_cmd)?;
+        self.plan(Box::new(split_cmd))?;
+        Ok(outs)
+    }
+
+    /// Mixes `from` into `into`, then splits so that `fraction` of the
+    /// combined volume continues as `into`. Returns the new `(from, into)`.
+    pub fn transfer(
+        &self,
+        from: DropletId,
+        into: DropletId,
+        fraction: f64,
+    ) -> PuddleResult<(DropletId, DropletId)> {
+        let combine_out = self.new_droplet_id();
+        let agitate_out = self.new_droplet_id();
+        let from_out = self.new_droplet_id();
+        let into_out = self.new_droplet_id();
+
+        // build everything first so a bad fraction doesn't leave a half-planned transfer
+        let combine_cmd = command::Combine::new(from, into, combine_out)?;
+        let agitate_cmd = command::Agitate::new(combine_out, agitate_out, 1, 1)?;
+        let split_cmd = command::Split::with_ratio(agitate_out, into_out, from_out, fraction)?;
+
+        self.plan(Box::new(combine_cmd))?;
+        self.plan(Box::new(agitate_cmd))?;
+        self.plan(Box::new(split_cmd))?;
+
+        Ok((from_out, into_out))
+    }
+
+    /// Dilutes `sample` by `factor` with `buffer`, `steps` times over, and
+    /// returns the series from most to least concentrated. Every step mixes
+    /// in `factor - 1` times the sample's volume of buffer, then splits off
+    /// the sample's volume to carry on to the next step; the last step
+    /// keeps all of it. Any buffer that isn't needed is left on the grid.
+    /// This needs to know the volumes, so it flushes first.
+    pub fn serial_dilute(
+        &self,
+        sample: DropletId,
+        buffer: DropletId,
+        steps: usize,
+        factor: f64,
+    ) -> PuddleResult<Vec<DropletId>> {
+        if steps == 0 || factor.is_nan() || factor <= 1.0 {
+            return Err(PuddleError::InvalidDilution(steps, factor));
+        }
+
+        let info = self.flush()?;
+        let volume = |id: DropletId| {
+            info.iter()
+                .find(|d| d.id == id)
+                .map(|d| d.volume)
+                .ok_or(PuddleError::NonExistentDropletId(id.id))
+        };
+        let portion = volume(sample)? * (factor - 1.0);
+        let needed = portion * steps as f64;
+        let leftover = volume(buffer)? - needed;
+        if leftover < -VOLUME_EPSILON {
+            return Err(PuddleError::NotEnoughVolume(buffer, needed));
+        }
+
+        let mut ratios = vec![portion; steps];
+        if leftover > VOLUME_EPSILON {
+            ratios.push(leftover);
+        }
+        let portions = if ratios.len() == 1 {
+            vec![buffer]
+        } else {
+            self.split_ratios(buffer, &ratios)?
+        };
+
+        let mut series = Vec::with_capacity(steps);
+        let mut carried = sample;
+        for (i, &portion) in portions[..steps].iter().enumerate() {
+            let mixed = self.mix(carried, portion)?;
+            if i + 1 == steps {
+                series.push(mixed);
+                break;
+            }
+            let next = self.new_droplet_id();
+            let kept = self.new_droplet_id();
+            let split_cmd = command::Split::with_ratio(mixed, next, kept, 1.0 / factor)?;
+            self.plan(Box::new(split_cmd))?;
+            series.push(kept);
+            carried = next;
+        }
+
+        Ok(series)
+    }
+
     pub fn heat(&self, d: DropletId, temperature: f32, seconds: f64) -> PuddleResult<DropletId> {
         let out = self.new_droplet_id();
         let duration = seconds_duration(seconds);
         let heat_cmd = command::Heat::new(d, out, temperature, duration)?;
+        self.check_heat(&heat_cmd)?;
         self.plan(Box::new(heat_cmd))?;
         Ok(out)
     }
 
+    /// Heats `d` through each `(temperature, seconds)` segment in order,
+    /// holding each temperature for that long once the droplet gets there.
+    pub fn heat_profile(&self, d: DropletId, segments: Vec<(f32, f64)>) -> PuddleResult<DropletId> {
+        let out = self.new_droplet_id();
+        let profile_cmd = command::HeatProfile::new(d, out, &segments)?;
+        self.check_heat(&profile_cmd)?;
+        self.plan(Box::new(profile_cmd))?;
+        Ok(out)
+    }
+
+    /// Makes any later attempt to heat `d` (or anything made from it) past
+    /// `temperature` fail with `PlanError::OverTemperature`. This flushes so
+    /// `d` is on the grid.
+    pub fn set_max_temperature(&self, d: DropletId, temperature: f32) -> PuddleResult<()> {
+        let mut sys = self.system.lock().unwrap();
+        sys.flush(&[])?;
+        sys.set_temperature_limit(d, temperature)
+    }
+
+    /// Holds `d` where it is for `seconds`, without moving it. Everything
+    /// else keeps going.
+    pub fn dwell(&self, d: DropletId, seconds: f64) -> PuddleResult<DropletId> {
+        let out = self.new_droplet_id();
+        let dwell_cmd = command::Dwell::new(d, out, seconds)?;
+        self.plan(Box::new(dwell_cmd))?;
+        Ok(out)
+    }
+
     pub fn ticks(&self) -> usize {
         self.system.lock().unwrap().ticks()
     }
+
+    pub fn elapsed(&self) -> Duration {
+        self.system.lock().unwrap().elapsed()
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
-    // TODO do we need tests here?
+    use super::*;
+    use crate::grid::{Grid, GridView, Peripheral};
+    use crate::plan::graph::GraphError;
+    use matches::assert_matches;
+
+    fn process() -> Process {
+        let system = Arc::new(Mutex::new(System::new(Grid::rectangle(5, 5)).unwrap()));
+        Process::new("test".into(), system)
+    }
+
+    #[test]
+    fn test_create_shows_up() {
+        let p = process();
+        let id = p.create(None, 1.0, None).unwrap();
+        p.flush().unwrap();
+
+        let snapshot = p.system.lock().unwrap().snapshot();
+        assert_eq!(snapshot.droplets.len(), 1);
+        assert_eq!(snapshot.droplets[&id].volume, 1.0);
+    }
+
+    #[test]
+    fn test_split_until_too_small() {
+        let p = process();
+        p.system.lock().unwrap().set_min_volume(0.25);
+        let mut d = p.create(None, 1.0, None).unwrap();
+
+        // 1.0 -> 0.5 -> 0.25 is fine, another split would be 0.125
+        for _ in 0..2 {
+            p.flush().unwrap();
+            d = p.split(d).unwrap().0;
+        }
+        p.flush().unwrap();
+        assert_matches!(
+            p.split(d),
+            Err(PuddleError::PlanError(PlanError::VolumeMismatch(id, vol))) if id == d && vol == 0.125
+        );
+    }
+
+    #[test]
+    fn test_restored_ids_dont_collide() {
+        let system = Arc::new(Mutex::new(System::new(Grid::rectangle(5, 5)).unwrap()));
+        let before = Process::new("before".into(), Arc::clone(&system));
+        let pid = before.id() + 100;
+
+        let restored = Process::new_with_ids("restored".into(), Arc::clone(&system), pid, 7);
+        let id = restored.create(None, 1.0, None).unwrap();
+        assert_eq!(id.to_string(), format!("p{}.d7", pid));
+        assert_eq!(restored.create(None, 1.0, None).unwrap().id, 8);
+
+        // later processes are numbered after the restored one
+        let after = Process::new("after".into(), Arc::clone(&system));
+        assert!(after.id() > pid);
+        let new_id = after.create(None, 1.0, None).unwrap();
+        assert_ne!(new_id, id);
+        after.flush().unwrap();
+        assert_eq!(system.lock().unwrap().snapshot().droplets.len(), 3);
+    }
+
+    #[test]
+    fn test_error_messages() {
+        let id = |id| DropletId { id, process_id: 3 };
+        let cases = vec![
+            (
+                PuddleError::PlanError(PlanError::DropletStuck(id(1))),
+                "Couldn't plan: Droplet p3.d1 is stuck",
+            ),
+            (
+                PuddleError::NonExistentDropletId(4),
+                "Droplet 4 does not exist",
+            ),
+            (
+                PuddleError::NonExistentProcess(3),
+                "Process 3 does not exist",
+            ),
+            (
+                PuddleError::InvalidFraction(1.5),
+                "Fraction 1.5 must be between 0 and 1",
+            ),
+            (
+                PuddleError::InvalidRatios(vec![1.0, -1.0]),
+                "Ratios [1.0, -1.0] must be non-empty and positive",
+            ),
+            (
+                PuddleError::InvalidPath(vec![yx(0, 0), yx(2, 2)]),
+                "Path [(0, 0), (2, 2)] must be non-empty and go one electrode at a time",
+            ),
+            (
+                PuddleError::InvalidWashBuffer(id(2), yx(1, 2)),
+                "Droplet p3.d2 is 1x2, but only 1x1 droplets can wash a path",
+            ),
+            (
+                PuddleError::InvalidHeatProfile(vec![]),
+                "Heat profile [] must be non-empty, with temperatures from 0 to 120*C",
+            ),
+            (
+                PuddleError::InvalidSnapshot(SnapshotError::Collision(id(1), id(2))),
+                "Invalid snapshot: Droplets p3.d1 and p3.d2 collide",
+            ),
+            (
+                PuddleError::InvalidSnapshot(SnapshotError::OutOfBounds(id(1), yx(9, 9))),
+                "Invalid snapshot: Droplet p3.d1 is at (9, 9), where there's no usable electrode",
+            ),
+            (
+                PuddleError::OutOfBounds(yx(9, 9)),
+                "Location (9, 9) is off the grid",
+            ),
+            (
+                PuddleError::NonExistentOutput("waste".into()),
+                "There's no output named 'waste'",
+            ),
+            (
+                PuddleError::RegionOccupied(id(5)),
+                "Droplet p3.d5 is in the way",
+            ),
+            (
+                PuddleError::InvalidDilution(0, 2.0),
+                "Can't dilute 0 times by 2, need at least one step and a factor over 1",
+            ),
+            (
+                PuddleError::NotEnoughVolume(id(5), 2.5),
+                "Droplet p3.d5 needs a volume of 2.5",
+            ),
+            (
+                PuddleError::InvalidAgitation(2, 0),
+                "Can't agitate 0 times with intensity 2, both need to be at least 1",
+            ),
+            (PuddleError::ProcessIdTaken(3), "Process 3 already exists"),
+            (
+                PuddleError::ActuationFailed("No mcp4725 is configured".into()),
+                "Couldn't set the electrodes: No mcp4725 is configured",
+            ),
+            (
+                PuddleError::VolumeChanged("mix(p3.d1)".into(), 2.0, 1.5),
+                "mix(p3.d1) changed the volume from 2 to 1.5",
+            ),
+        ];
+        for (err, message) in cases {
+            assert_eq!(err.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn test_input_label_round_trip() {
+        let mut grid = Grid::rectangle(5, 5);
+        let input = Peripheral::Input {
+            pwm_channel: 0,
+            name: "water".into(),
+        };
+        let loc = Location { y: 0, x: 0 };
+        grid.get_cell_mut(loc).unwrap().peripheral = Some(input);
+        let system = Arc::new(Mutex::new(System::new(grid.clone()).unwrap()));
+        let p = Process::new("test".into(), system);
+
+        let id = p.input("water", 1.0, Location { y: 1, x: 1 }).unwrap();
+        let info = p.flush().unwrap();
+        assert_eq!(info[0].label, Some("water".into()));
+        assert_eq!(info[0].color, None);
+
+        let snapshot = p.system.lock().unwrap().snapshot();
+        let mut gv = GridView::new(grid);
+        gv.apply_snapshot(snapshot).unwrap();
+        let mut buf = Vec::new();
+        gv.export_state(&mut buf).unwrap();
+        let gv = GridView::import_state(buf.as_slice()).unwrap();
+        assert_eq!(gv.snapshot().droplets[&id].label, Some("water".into()));
+    }
+
+    #[test]
+    fn test_try_plan_returns_command() {
+        let p = process();
+        // the droplet this moves doesn't exist yet
+        let input = p.new_droplet_id();
+        let mv = command::Move::new(input, Location { y: 3, x: 3 }, p.new_droplet_id()).unwrap();
+
+        let (err, mv) = p.try_plan(Box::new(mv)).unwrap_err();
+        assert_matches!(
+            err,
+            PuddleError::PlanError(PlanError::GraphError(GraphError::DoesNotExist(id))) if id == input
+        );
+
+        // once it does, the same command goes through
+        let create = command::Create::new(None, 1.0, None, input).unwrap();
+        p.plan(Box::new(create)).unwrap();
+        assert!(p.try_plan(mv).is_ok());
+        assert_eq!(p.flush().unwrap()[0].location, Location { y: 3, x: 3 });
+    }
+
+    #[test]
+    fn test_plan_batch_rolls_back() {
+        let p = process();
+        let id = p.create(Some(Location { y: 0, x: 0 }), 1.0, None).unwrap();
+        let before = p.flush().unwrap();
+
+        // the second move is of a droplet that doesn't exist
+        let (moved, ghost) = (p.new_droplet_id(), p.new_droplet_id());
+        let loc = Location { y: 2, x: 2 };
+        let batch: Vec<BoxedCommand> = vec![
+            Box::new(command::Move::new(id, loc, moved).unwrap()),
+            Box::new(command::Move::new(ghost, loc, p.new_droplet_id()).unwrap()),
+        ];
+        let err = p.plan_batch(batch).unwrap_err();
+        assert_matches!(
+            err,
+            PuddleError::PlanError(PlanError::GraphError(GraphError::DoesNotExist(i))) if i == ghost
+        );
+
+        // the first move didn't stick around either
+        assert_eq!(p.flush().unwrap(), before);
+        let mv = command::Move::new(id, Location { y: 3, x: 3 }, moved).unwrap();
+        p.plan_batch(vec![Box::new(mv)]).unwrap();
+        assert_eq!(p.flush().unwrap()[0].location, Location { y: 3, x: 3 });
+    }
+
+    #[test]
+    fn test_try_plan_batch_returns_commands() {
+        let p = process();
+        let (a, b) = (p.new_droplet_id(), p.new_droplet_id());
+        let (a_moved, b_moved) = (p.new_droplet_id(), p.new_droplet_id());
+        let (loc_a, loc_b) = (Location { y: 1, x: 1 }, Location { y: 3, x: 3 });
+        let create_a = command::Create::new(None, 1.0, None, a).unwrap();
+        p.plan(Box::new(create_a)).unwrap();
+
+        // b doesn't exist yet, so the whole thing comes back
+        let batch: Vec<BoxedCommand> = vec![
+            Box::new(command::Move::new(a, loc_a, a_moved).unwrap()),
+            Box::new(command::Move::new(b, loc_b, b_moved).unwrap()),
+        ];
+        let (err, batch) = p.try_plan_batch(batch).unwrap_err();
+        assert_matches!(
+            err,
+            PuddleError::PlanError(PlanError::GraphError(GraphError::DoesNotExist(id))) if id == b
+        );
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[0].output_droplets(), vec![a_moved]);
+        assert_eq!(batch[1].output_droplets(), vec![b_moved]);
+
+        // and goes through as-is once it can
+        let create_b = command::Create::new(None, 1.0, None, b).unwrap();
+        p.plan(Box::new(create_b)).unwrap();
+        p.try_plan_batch(batch).map_err(|(err, _)| err).unwrap();
+        let info = p.flush().unwrap();
+        let location = |id| info.iter().find(|d| d.id == id).unwrap().location;
+        assert_eq!(location(a_moved), loc_a);
+        assert_eq!(location(b_moved), loc_b);
+    }
+
+    #[test]
+    fn test_sense_in_sim() {
+        let grid = Grid::rectangle(5, 5);
+        let executor = crate::sim::SimExecutor::new(grid.clone());
+        let system = System::with_executor(grid, Box::new(executor));
+        let p = Process::new("test".into(), Arc::new(Mutex::new(system)));
+
+        let d = p.create(Some(yx(1, 1)), 1.0, Some(yx(1, 2))).unwrap();
+        // sensing runs the create first
+        assert!(p.sense(yx(1, 2)).unwrap());
+        assert!(!p.sense(yx(3, 3)).unwrap());
+
+        p.move_droplet(d, yx(3, 2)).unwrap();
+        assert!(!p.sense(yx(1, 2)).unwrap());
+        assert!(p.sense(yx(3, 3)).unwrap());
+
+        assert_matches!(
+            p.sense(yx(5, 0)),
+            Err(PuddleError::PlanError(PlanError::OffGrid(_)))
+        );
+    }
 }
@@ -0,0 +1,268 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::command::{self, BoxedCommand, Command};
+use crate::grid::DropletId;
+use crate::process::{Process, PuddleError};
+use crate::util::duration_seconds;
+
+use indexmap::IndexMap;
+
+macro_rules! recorded_commands {
+    ($($name:ident),*) => {
+        /// The commands that can be recorded, as JSON tagged by `type`.
+        #[derive(Debug, Clone, Serialize, Deserialize)]
+        #[serde(tag = "type")]
+        pub enum RecordedCommand {
+            $($name(command::$name)),*
+        }
+
+        impl RecordedCommand {
+            pub fn into_command(self) -> BoxedCommand {
+                match self {
+                    $(RecordedCommand::$name(cmd) => Box::new(cmd)),*
+                }
+            }
+        }
+
+        $(
+            impl From<command::$name> for RecordedCommand {
+                fn from(cmd: command::$name) -> RecordedCommand {
+                    RecordedCommand::$name(cmd)
+                }
+            }
+        )*
+    };
+}
+
+recorded_commands!(
+    Create,
+    CreateFromRegion,
+    Move,
+    Wash,
+    Combine,
+    Agitate,
+    Split,
+    SplitN,
+    Heat,
+    HeatProfile,
+    Dwell,
+    Input,
+    Output,
+    ReturnToReservoir
+);
+
+/// One line of a command log.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// When the command was queued, in seconds since the recording started.
+    pub seconds: f64,
+    pub command: RecordedCommand,
+}
+
+/// Writes every command the system accepts to a log, one JSON `Entry` per
+/// line, so a run can be replayed later with `replay`.
+///
+/// Recording a command that doesn't support `Command::record` is an error,
+/// rather than a hole in the log that a replay would quietly skip over.
+pub struct Recorder {
+    out: Box<dyn Write + Send>,
+    start: Instant,
+}
+
+impl Recorder {
+    pub fn new(out: impl Write + Send + 'static) -> Recorder {
+        Recorder {
+            out: Box::new(out),
+            start: Instant::now(),
+        }
+    }
+
+    /// Records to a new file at `path`, replacing whatever was there.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Recorder> {
+        let file = File::create(path)?;
+        Ok(Recorder::new(BufWriter::new(file)))
+    }
+
+    pub fn record(&mut self, cmd: &dyn Command) -> io::Result<()> {
+        let command = cmd.record().ok_or_else(|| {
+            let msg = format!("{} commands can't be recorded", cmd.type_name());
+            io::Error::new(io::ErrorKind::InvalidInput, msg)
+        })?;
+        let entry = Entry {
+            seconds: duration_seconds(&self.start.elapsed()),
+            command,
+        };
+        serde_json::to_writer(&mut self.out, &entry)?;
+        writeln!(self.out)?;
+        // flush every time, so the log is good up to the last command even
+        // if we crash
+        self.out.flush()
+    }
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    /// The line number, counting from 1, and what was wrong with it.
+    Parse(usize, serde_json::Error),
+    Puddle(PuddleError),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(err) => write!(f, "Couldn't read command log: {}", err),
+            ReplayError::Parse(line, err) => write!(f, "Bad command on line {}: {}", line, err),
+            ReplayError::Puddle(err) => write!(f, "Couldn't replay command: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+/// Queues up every command in the log at `path` on `process`, in order. The
+/// timestamps are ignored; the commands are just planned like any others
+/// and run at the next flush.
+///
+/// The log's droplet ids belong to whatever process recorded them, so each
+/// one gets a new id from `process` the first time it shows up. The returned
+/// map goes from the recorded ids to the new ones.
+pub fn replay(
+    path: impl AsRef<Path>,
+    process: &Process,
+) -> Result<IndexMap<DropletId, DropletId>, ReplayError> {
+    let file = File::open(path).map_err(ReplayError::Io)?;
+    let mut ids = IndexMap::new();
+
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(ReplayError::Io)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let parse_err = |err| ReplayError::Parse(i + 1, err);
+
+        let mut entry: Value = serde_json::from_str(&line).map_err(parse_err)?;
+        let command = entry
+            .get_mut("command")
+            .ok_or_else(|| parse_err(serde::de::Error::missing_field("command")))?;
+        renumber(command, &mut ids, process);
+        let command: RecordedCommand = serde_json::from_value(command.take()).map_err(parse_err)?;
+
+        debug!("Replaying {:?}", command);
+        process
+            .try_plan(command.into_command())
+            .map_err(|(err, _cmd)| ReplayError::Puddle(err))?;
+    }
+
+    Ok(ids)
+}
+
+// droplet ids are the only objects with exactly these fields
+fn as_droplet_id(value: &Value) -> Option<DropletId> {
+    match value {
+        Value::Object(map) if map.len() == 2 => serde_json::from_value(value.clone()).ok(),
+        _ => None,
+    }
+}
+
+fn renumber(value: &mut Value, ids: &mut IndexMap<DropletId, DropletId>, process: &Process) {
+    if let Some(old) = as_droplet_id(value) {
+        let new = *ids.entry(old).or_insert_with(|| process.new_droplet_id());
+        *value = serde_json::to_value(new).expect("droplet ids serialize");
+        return;
+    }
+    match value {
+        Value::Array(values) => values.iter_mut().for_each(|v| renumber(v, ids, process)),
+        Value::Object(map) => map.values_mut().for_each(|v| renumber(v, ids, process)),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::command::tests::Dummy;
+    use crate::grid::{location::yx, Grid, Rectangle};
+    use crate::process::Manager;
+
+    #[test]
+    fn test_record_then_replay() {
+        let path =
+            std::env::temp_dir().join(format!("puddle-replay-{}.ndjson", std::process::id()));
+
+        let manager = Manager::new(false, Grid::rectangle(8, 8)).unwrap();
+        manager.set_recorder(Some(Recorder::create(&path).unwrap()));
+        let p = manager.get_new_process("recorded");
+        let a = p.create(Some(yx(0, 0)), 1.0, None).unwrap();
+        let b = p.create(Some(yx(5, 5)), 1.0, None).unwrap();
+        let a = p.move_droplet(a, yx(3, 0)).unwrap();
+        let ab = p.mix(a, b).unwrap();
+        let recorded = p.flush().unwrap();
+        manager.set_recorder(None);
+
+        let manager = Manager::new(false, Grid::rectangle(8, 8)).unwrap();
+        let p = manager.get_new_process("replayed");
+        let ids = replay(&path, &p).unwrap();
+        let replayed = p.flush().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(replayed[0].id, ids[&ab]);
+        assert_eq!(replayed[0].id.process_id, p.id());
+        assert_eq!(replayed[0].location, recorded[0].location);
+        assert_eq!(replayed[0].dimensions, recorded[0].dimensions);
+        assert_eq!(replayed[0].volume, recorded[0].volume);
+
+        // new droplets in the replaying process don't collide with replayed ones
+        let c = p.create(None, 1.0, None).unwrap();
+        assert!(!ids.values().any(|&id| id == c));
+    }
+
+    #[test]
+    fn test_record_everything() {
+        let path =
+            std::env::temp_dir().join(format!("puddle-record-{}.ndjson", std::process::id()));
+        let id = |id| DropletId { id, process_id: 0 };
+
+        let region = Rectangle::new(yx(1, 1), yx(3, 3));
+        let path_locs = vec![yx(0, 0), yx(0, 1), yx(1, 1)];
+        let cmds: Vec<BoxedCommand> = vec![
+            Box::new(command::CreateFromRegion::new(region, 1.0, id(0)).unwrap()),
+            Box::new(command::Wash::new(id(0), path_locs, id(1)).unwrap()),
+            Box::new(command::ReturnToReservoir::new("buffer".into(), id(1)).unwrap()),
+        ];
+
+        let mut recorder = Recorder::create(&path).unwrap();
+        for cmd in &cmds {
+            recorder.record(&**cmd).unwrap();
+        }
+        // commands that can't be written out are an error, not a gap
+        let err = recorder.record(&Dummy::new(&[2], &[3])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        drop(recorder);
+
+        let file = File::open(&path).unwrap();
+        let entries: Vec<Entry> = BufReader::new(file)
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(entries.len(), cmds.len());
+        for (entry, cmd) in entries.into_iter().zip(&cmds) {
+            let replayed = entry.command.into_command();
+            assert_eq!(replayed.type_name(), cmd.type_name());
+            assert_eq!(replayed.input_droplets(), cmd.input_droplets());
+            assert_eq!(replayed.output_droplets(), cmd.output_droplets());
+        }
+    }
+}
@@ -0,0 +1,517 @@
+use std::time::Duration;
+
+use crate::command::RunStatus;
+use crate::exec::{ExecResponse, Execute};
+use crate::grid::{DropletId, Grid, GridView, Snapshot};
+use crate::plan::{
+    graph::{CmdIndex, Graph},
+    Path, PlanPhase, PlannedCommand,
+};
+use crate::process::{PuddleError, PuddleResult};
+use crate::util::duration_seconds;
+
+use indexmap::IndexMap;
+
+/// Where droplets start out, and what they cool back down to, in celsius.
+pub const AMBIENT_TEMPERATURE: f32 = 25.0;
+
+/// How fast droplets warm up or cool down, in degrees per second.
+pub const RAMP_RATE: f32 = 10.0;
+
+const DEFAULT_STEP_DURATION: Duration = Duration::from_millis(100);
+
+// the most volume a command can gain or lose before we call it a bug
+const VOLUME_EPSILON: f64 = 1e-9;
+// close enough to a setpoint to count as there
+const TEMPERATURE_EPSILON: f32 = 0.01;
+
+/// An executor with no hardware behind it, for trying out protocols.
+///
+/// Droplets go exactly where they're routed, and commands act on an
+/// in-memory `GridView` like usual, with some simple physics on top.
+/// Anything that takes droplets in and puts droplets out has to conserve
+/// volume, and droplets warm up and cool down at `RAMP_RATE` instead of
+/// jumping to temperature, so a heat waits until its droplet gets there.
+/// Time is simulated as well, so a phase always plays out the same way.
+pub struct SimExecutor {
+    gridview: GridView,
+    // the loaded phase, its commands start once the routes are done
+    routes: IndexMap<DropletId, Path>,
+    route_step: usize,
+    pending: Vec<PlannedCommand>,
+    running: IndexMap<CmdIndex, PlannedCommand>,
+    // volume and temperature of each running command's inputs, last we saw
+    inputs: IndexMap<CmdIndex, IndexMap<DropletId, (f64, f32)>>,
+    temperatures: IndexMap<DropletId, f32>,
+    step_duration: Duration,
+    ticks: usize,
+    elapsed: Duration,
+}
+
+impl SimExecutor {
+    pub fn new(grid: Grid) -> SimExecutor {
+        info!("Creating a SimExecutor");
+        SimExecutor {
+            gridview: GridView::new(grid),
+            routes: IndexMap::default(),
+            route_step: 0,
+            pending: Vec::new(),
+            running: IndexMap::default(),
+            inputs: IndexMap::default(),
+            temperatures: IndexMap::default(),
+            step_duration: DEFAULT_STEP_DURATION,
+            ticks: 0,
+            elapsed: Duration::default(),
+        }
+    }
+
+    /// Sets how much simulated time a normal step takes.
+    pub fn set_step_duration(&mut self, duration: Duration) {
+        self.step_duration = duration;
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.gridview.snapshot()
+    }
+
+    /// The simulated temperature of a droplet, if it exists.
+    pub fn temperature(&self, id: DropletId) -> Option<f32> {
+        self.gridview.droplets.get(&id)?;
+        Some(temperature(&self.temperatures, id))
+    }
+
+    /// Whether everything that's been loaded has finished.
+    pub fn is_idle(&self) -> bool {
+        self.route_step >= self.route_len() && self.pending.is_empty() && self.running.is_empty()
+    }
+
+    /// Sets up a phase to be stepped through. Anything loaded before has to
+    /// be finished first.
+    pub fn load(&mut self, phase: PlanPhase) {
+        assert!(self.is_idle(), "Loaded a phase while another was running");
+
+        // make sure that all droplets start where they are at this time step
+        for (id, path) in &phase.routes {
+            assert_eq!(self.gridview.droplets[id].location, path[0]);
+        }
+
+        self.routes = phase.routes;
+        self.route_step = 1;
+        self.pending = phase.planned_commands;
+    }
+
+    /// Runs a single tick of the loaded phase. While routing, every droplet
+    /// takes one step; after that, every command runs once. Returns whether
+    /// there's anything left to do. If a command doesn't conserve volume,
+    /// the rest of the phase is dropped and this fails.
+    pub fn step(&mut self, graph: &mut Graph) -> PuddleResult<bool> {
+        if self.is_idle() {
+            return Ok(false);
+        }
+
+        if self.route_step < self.route_len() {
+            self.take_route_step();
+            self.route_step += 1;
+        } else {
+            for planned in self.pending.drain(..) {
+                let was_there = self.running.insert(planned.cmd_id, planned);
+                assert!(was_there.is_none());
+            }
+        }
+
+        let (hold, fresh) = match self.run_commands(graph) {
+            Ok(ran) => ran,
+            Err(err) => {
+                self.abandon();
+                return Err(err);
+            }
+        };
+        self.ramp_temperatures(hold, graph, &fresh);
+        self.ticks += 1;
+        self.elapsed += hold;
+
+        Ok(!self.is_idle())
+    }
+
+    // forgets the rest of the loaded phase, so another can be loaded
+    fn abandon(&mut self) {
+        self.routes.clear();
+        self.route_step = 0;
+        self.pending.clear();
+        self.running.clear();
+        self.inputs.clear();
+    }
+
+    fn route_len(&self) -> usize {
+        self.routes.values().map(Vec::len).max().unwrap_or(0)
+    }
+
+    fn take_route_step(&mut self) {
+        let i = self.route_step;
+        for (&id, path) in self.routes.iter().filter(|(_, path)| i < path.len()) {
+            let loc = path[i];
+            self.gridview.droplets.get_mut(&id).unwrap().location = loc;
+            self.gridview.record_move(id, loc, loc);
+        }
+    }
+
+    // returns how long the step is held, and the droplets made during it
+    fn run_commands(&mut self, graph: &mut Graph) -> PuddleResult<(Duration, Vec<DropletId>)> {
+        let mut done = Vec::new();
+        let mut fresh = Vec::new();
+        let mut hold = self.step_duration;
+
+        for (&cmd_id, planned) in &self.running {
+            let cmd = graph
+                .graph
+                .node_weight_mut(cmd_id)
+                .expect("node not in graph")
+                .as_mut()
+                .expect("node unbound");
+
+            let seen = self.inputs.entry(cmd_id).or_default();
+            for id in cmd.input_droplets() {
+                if let Some(d) = self.gridview.droplets.get(&id) {
+                    seen.insert(id, (d.volume, temperature(&self.temperatures, id)));
+                }
+            }
+
+            // heating (or cooling) takes as long as it takes
+            if let Some(target) = cmd.setpoint() {
+                let temps = &self.temperatures;
+                let off = |id| (temperature(temps, id) - target).abs() > TEMPERATURE_EPSILON;
+                if cmd.input_droplets().into_iter().any(off) {
+                    continue;
+                }
+            }
+
+            let subview = &mut self.gridview.subview(&planned.placement);
+            let status = cmd.run(subview);
+            if let RunStatus::Hold(duration) | RunStatus::Wait(duration) = status {
+                hold = hold.max(duration);
+            }
+            match status {
+                RunStatus::Done | RunStatus::Hold(_) => {
+                    cmd.finalize(subview);
+                    done.push(cmd_id);
+                }
+                RunStatus::KeepGoing | RunStatus::Wait(_) => (),
+            }
+        }
+
+        for cmd_id in done {
+            let planned = self.running.swap_remove(&cmd_id).unwrap();
+            let seen = self.inputs.swap_remove(&cmd_id).unwrap_or_default();
+            let cmd = graph.graph[cmd_id].as_ref().expect("node unbound");
+            let outputs = cmd.output_droplets();
+            self.mix_outputs(&planned.request.name, &seen, &outputs)?;
+            fresh.extend(&outputs);
+            self.gridview
+                .record_provenance(&planned.request.name, cmd.input_droplets(), outputs);
+        }
+
+        Ok((hold, fresh))
+    }
+
+    // outputs split the inputs' volume and share their temperature
+    fn mix_outputs(
+        &mut self,
+        name: &str,
+        inputs: &IndexMap<DropletId, (f64, f32)>,
+        outputs: &[DropletId],
+    ) -> PuddleResult<()> {
+        for id in inputs.keys() {
+            self.temperatures.swap_remove(id);
+        }
+
+        // things like create and output are allowed to add or remove volume
+        let out_volume: f64 = outputs
+            .iter()
+            .filter_map(|id| self.gridview.droplets.get(id))
+            .map(|d| d.volume)
+            .sum();
+        let in_volume: f64 = inputs.values().map(|&(v, _)| v).sum();
+        if inputs.is_empty() || out_volume == 0.0 {
+            return Ok(());
+        }
+        // NaN counts as a change too
+        let conserved = (in_volume - out_volume).abs() < VOLUME_EPSILON;
+        if !conserved {
+            let err = PuddleError::VolumeChanged(name.into(), in_volume, out_volume);
+            error!("{}", err);
+            return Err(err);
+        }
+
+        let heat: f64 = inputs.values().map(|&(v, t)| v * f64::from(t)).sum();
+        let mixed = (heat / in_volume) as f32;
+        for &id in outputs {
+            self.temperatures.insert(id, mixed);
+        }
+        Ok(())
+    }
+
+    // fresh droplets already got their temperature from their inputs
+    fn ramp_temperatures(&mut self, dt: Duration, graph: &Graph, fresh: &[DropletId]) {
+        let mut targets = IndexMap::new();
+        for &cmd_id in self.running.keys() {
+            let cmd = graph.graph[cmd_id].as_ref().expect("node unbound");
+            if let Some(target) = cmd.setpoint() {
+                for id in cmd.input_droplets() {
+                    targets.insert(id, target);
+                }
+            }
+        }
+
+        let max_change = RAMP_RATE * duration_seconds(&dt) as f32;
+        let droplets = &self.gridview.droplets;
+        self.temperatures.retain(|id, _| droplets.contains_key(id));
+        for &id in droplets.keys().filter(|id| !fresh.contains(id)) {
+            let target = targets.get(&id).cloned().unwrap_or(AMBIENT_TEMPERATURE);
+            let temp = self.temperatures.entry(id).or_insert(AMBIENT_TEMPERATURE);
+            // move toward the target, but no faster than the ramp rate
+            let change = target - *temp;
+            if change.abs() <= max_change {
+                *temp = target;
+            } else {
+                *temp += max_change * change.signum();
+            }
+        }
+    }
+}
+
+fn temperature(temperatures: &IndexMap<DropletId, f32>, id: DropletId) -> f32 {
+    temperatures
+        .get(&id)
+        .cloned()
+        .unwrap_or(AMBIENT_TEMPERATURE)
+}
+
+impl Execute for SimExecutor {
+    fn run(&mut self, phase: PlanPhase, graph: &mut Graph) -> ExecResponse {
+        self.load(phase);
+        loop {
+            match self.step(graph) {
+                Ok(true) => (),
+                Ok(false) => return ExecResponse::Ok,
+                Err(err) => return ExecResponse::Failed(err),
+            }
+        }
+    }
+
+    fn gridview(&self) -> &GridView {
+        &self.gridview
+    }
+
+    fn gridview_mut(&mut self) -> &mut GridView {
+        &mut self.gridview
+    }
+
+    fn ticks(&self) -> usize {
+        self.ticks
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{BoxedCommand, Command, CommandRequest, Create, Heat, HeatProfile, Move};
+    use crate::grid::{gridview::GridSubView, location::yx, Peripheral};
+    use crate::plan::Planner;
+
+    struct Bench {
+        graph: Graph,
+        planner: Planner,
+        sim: SimExecutor,
+    }
+
+    impl Bench {
+        fn new(grid: Grid) -> Bench {
+            Bench {
+                graph: Graph::default(),
+                planner: Planner::new(GridView::new(grid.clone())),
+                sim: SimExecutor::new(grid),
+            }
+        }
+
+        fn load(&mut self, cmd: BoxedCommand) {
+            self.graph.add_command(cmd).unwrap();
+            let phase = self.planner.plan(&self.graph, &[]).unwrap();
+            self.sim.load(phase);
+        }
+
+        fn finish(&mut self) {
+            while self.sim.step(&mut self.graph).unwrap() {}
+            self.planner.gridview = self.sim.gridview.clone();
+        }
+    }
+
+    // doubles its droplet out of nowhere
+    #[derive(Debug)]
+    struct Leaky {
+        input: DropletId,
+        output: DropletId,
+    }
+
+    impl Command for Leaky {
+        fn type_name(&self) -> &'static str {
+            "Leaky"
+        }
+
+        fn input_droplets(&self) -> Vec<DropletId> {
+            vec![self.input]
+        }
+
+        fn output_droplets(&self) -> Vec<DropletId> {
+            vec![self.output]
+        }
+
+        fn request(&self, _gridview: &GridView) -> CommandRequest {
+            CommandRequest {
+                name: "leaky".into(),
+                shape: Grid::rectangle(1, 1),
+                input_locations: vec![yx(0, 0)],
+                offset: None,
+            }
+        }
+
+        fn run(&mut self, gridview: &mut GridSubView) -> RunStatus {
+            let mut d = gridview.remove(&self.input);
+            d.id = self.output;
+            d.volume *= 2.0;
+            gridview.insert(d);
+            RunStatus::Done
+        }
+    }
+
+    #[test]
+    fn test_volume_must_be_conserved() {
+        let mut bench = Bench::new(Grid::rectangle(1, 3));
+        let id = 0.into();
+        let create = Create::new(Some(yx(0, 0)), 1.0, None, id).unwrap();
+        bench.load(Box::new(create));
+        bench.finish();
+
+        let leaked = 1.into();
+        bench.load(Box::new(Leaky {
+            input: id,
+            output: leaked,
+        }));
+        let result = loop {
+            match bench.sim.step(&mut bench.graph) {
+                Ok(true) => (),
+                other => break other,
+            }
+        };
+        match result {
+            Err(PuddleError::VolumeChanged(name, before, after)) => {
+                assert_eq!(name, "leaky");
+                assert_eq!((before, after), (1.0, 2.0));
+            }
+            r => panic!("Expected a volume change, got {:?}", r),
+        }
+        // what's left of the phase is dropped
+        assert!(bench.sim.is_idle());
+    }
+
+    #[test]
+    fn test_step_by_step() {
+        let mut bench = Bench::new(Grid::rectangle(1, 5));
+        let id = 0.into();
+        let create = Create::new(Some(yx(0, 0)), 1.0, None, id).unwrap();
+        bench.load(Box::new(create));
+        bench.finish();
+
+        let moved = 1.into();
+        bench.load(Box::new(Move::new(id, yx(0, 3), moved).unwrap()));
+        for x in 1..=3 {
+            assert!(bench.sim.step(&mut bench.graph).unwrap());
+            assert_eq!(bench.sim.snapshot().droplets[&id].location, yx(0, x));
+        }
+
+        // the move itself takes one more step
+        assert!(!bench.sim.step(&mut bench.graph).unwrap());
+        assert!(bench.sim.is_idle());
+        let snap = bench.sim.snapshot();
+        assert_eq!(snap.droplets.len(), 1);
+        assert_eq!(snap.droplets[&moved].location, yx(0, 3));
+        assert_eq!(bench.sim.ticks(), 5);
+        assert_eq!(bench.sim.elapsed(), 5 * DEFAULT_STEP_DURATION);
+    }
+
+    #[test]
+    fn test_heat_ramps() {
+        let mut grid = Grid::rectangle(1, 3);
+        grid.get_cell_mut(yx(0, 2)).unwrap().peripheral = Some(Peripheral::Heater {
+            pwm_channel: 0,
+            spi_channel: 0,
+        });
+        let mut bench = Bench::new(grid);
+
+        let id = 0.into();
+        let create = Create::new(Some(yx(0, 0)), 1.0, None, id).unwrap();
+        bench.load(Box::new(create));
+        bench.finish();
+        assert_eq!(bench.sim.temperature(id), Some(AMBIENT_TEMPERATURE));
+
+        // a degree a step, so 10 steps of heating on top of the routing
+        let heated = 1.into();
+        let start = bench.sim.ticks();
+        bench.load(Box::new(
+            Heat::new(id, heated, 35.0, Duration::default()).unwrap(),
+        ));
+        bench.finish();
+        assert!(bench.sim.ticks() - start >= 10);
+        assert_eq!(bench.sim.temperature(id), None);
+        assert_eq!(bench.sim.temperature(heated), Some(35.0));
+
+        // and back down once it's done
+        let moved = 2.into();
+        bench.load(Box::new(Move::new(heated, yx(0, 0), moved).unwrap()));
+        bench.finish();
+        let temp = bench.sim.temperature(moved).unwrap();
+        assert!(AMBIENT_TEMPERATURE < temp && temp < 35.0);
+    }
+
+    #[test]
+    fn test_heat_profile() {
+        let mut grid = Grid::rectangle(1, 3);
+        grid.get_cell_mut(yx(0, 2)).unwrap().peripheral = Some(Peripheral::Heater {
+            pwm_channel: 0,
+            spi_channel: 0,
+        });
+        let mut bench = Bench::new(grid);
+
+        let id = 0.into();
+        let create = Create::new(Some(yx(0, 0)), 1.0, None, id).unwrap();
+        bench.load(Box::new(create));
+        bench.finish();
+
+        // up, down past where it started, then up again
+        let segments = [(35.0, 0.5), (30.0, 0.0), (40.0, 0.2)];
+        let heated = 1.into();
+        let profile = HeatProfile::new(id, heated, &segments).unwrap();
+        bench.load(Box::new(profile));
+
+        let mut temps = Vec::new();
+        while bench.sim.step(&mut bench.graph).unwrap() {
+            temps.extend(bench.sim.temperature(id));
+        }
+        temps.extend(bench.sim.temperature(heated));
+
+        let mut visited = segments.iter().map(|&(t, _)| t).peekable();
+        for &temp in &temps {
+            if visited.peek() == Some(&temp) {
+                visited.next();
+            }
+        }
+        assert!(visited.next().is_none(), "off profile: {:?}", temps);
+
+        let snap = bench.sim.snapshot();
+        assert_eq!(snap.droplets[&heated].max_temperature, Some(40.0));
+        assert_eq!(bench.sim.temperature(heated), Some(40.0));
+    }
+}
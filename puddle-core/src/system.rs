@@ -1,21 +1,34 @@
-use crate::command::BoxedCommand;
-use crate::exec::{Executor, StepInfo};
-use crate::grid::{droplet::DropletInfo, DropletId, Grid, GridView};
-use crate::process::{ProcessId, PuddleResult};
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::command::{BoxedCommand, SplitAxis};
+use crate::exec::{CommandTiming, ExecResponse, Execute, Executor, StepInfo};
+use crate::grid::{droplet::DropletInfo, DropletId, Grid, GridView, Location, Rectangle, Snapshot};
+use crate::process::{recorder::Recorder, ProcessId, PuddleError, PuddleResult};
 
 use crate::plan::graph::Graph;
-use crate::plan::{sched::SchedError, PlanError, Planner};
+use crate::plan::{sched::SchedError, BlockReason, Metrics, PlanError, Planner};
+
+use indexmap::IndexMap;
 
 pub struct System {
-    #[allow(dead_code)]
     grid: Grid,
     graph: Graph,
     planner: Planner,
-    executor: Executor,
+    executor: Box<dyn Execute>,
+    recorder: Option<Recorder>,
 }
 
 impl System {
-    pub fn new(grid: Grid) -> System {
+    /// Runs on a plain `Executor`, see `Executor::new` for how this fails.
+    pub fn new(grid: Grid) -> PuddleResult<System> {
+        let executor = Executor::new(grid.clone())?;
+        Ok(System::with_executor(grid, Box::new(executor)))
+    }
+
+    /// Like `new`, but phases are carried out by `executor`, which should be
+    /// set up for the same grid.
+    pub fn with_executor(grid: Grid, executor: Box<dyn Execute>) -> System {
         info!("Creating a system");
         let planner = {
             let gv = GridView::new(grid.clone());
@@ -25,17 +38,127 @@ impl System {
             grid: grid.clone(),
             graph: Graph::default(),
             planner,
-            executor: Executor::new(grid.clone()),
+            executor,
+            recorder: None,
         }
     }
 
     pub fn add(&mut self, cmd: BoxedCommand) -> PuddleResult<()> {
-        // TODO unwrap
+        self.try_add(cmd).map_err(|(err, _cmd)| err)
+    }
+
+    /// Like `add`, but hands the command back if it couldn't be added.
+    pub fn try_add(&mut self, cmd: BoxedCommand) -> Result<(), (PuddleError, BoxedCommand)> {
         info!("Adding command {:?}", cmd);
-        let _cmd_id = self.graph.add_command(cmd).unwrap();
+        if let Err(err) = self.graph.check_add_command(&cmd) {
+            warn!("Couldn't add command {:?}: {:?}", cmd, err);
+            let err = PuddleError::PlanError(PlanError::GraphError(err));
+            return Err((err, cmd));
+        }
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(err) = recorder.record(&*cmd) {
+                error!("Failed to record {:?}: {}", cmd, err);
+            }
+        }
+        let cmd_id = self
+            .graph
+            .add_command(cmd)
+            .expect("command was already checked");
+        self.executor.command_queued(cmd_id, Instant::now());
         Ok(())
     }
 
+    /// Adds all of `cmds` in order, or none of them. If one can't be added,
+    /// the ones before it are taken back out and that command's error is
+    /// returned.
+    pub fn add_batch(&mut self, cmds: Vec<BoxedCommand>) -> PuddleResult<()> {
+        self.try_add_batch(cmds).map_err(|(err, _cmds)| err)
+    }
+
+    /// Like `add_batch`, but hands the whole batch back, in order, if it
+    /// couldn't be added.
+    pub fn try_add_batch(
+        &mut self,
+        cmds: Vec<BoxedCommand>,
+    ) -> Result<(), (PuddleError, Vec<BoxedCommand>)> {
+        let mut added = Vec::with_capacity(cmds.len());
+        let mut cmds = cmds.into_iter();
+        while let Some(cmd) = cmds.next() {
+            info!("Adding command {:?}", cmd);
+            if let Err(err) = self.graph.check_add_command(&cmd) {
+                warn!("Rolling back batch of {} commands: {:?}", added.len(), err);
+                let mut batch: Vec<BoxedCommand> = added
+                    .iter()
+                    .rev()
+                    .map(|&cmd_id| {
+                        self.graph
+                            .remove_command(cmd_id)
+                            .expect("nothing else could have used it yet")
+                    })
+                    .collect();
+                batch.reverse();
+                batch.push(cmd);
+                batch.extend(cmds);
+                let err = PuddleError::PlanError(PlanError::GraphError(err));
+                return Err((err, batch));
+            }
+            let cmd_id = self
+                .graph
+                .add_command(cmd)
+                .expect("command was already checked");
+            added.push(cmd_id);
+        }
+        if let Some(recorder) = &mut self.recorder {
+            for &cmd_id in &added {
+                let cmd = self.graph.graph[cmd_id].as_ref().expect("just added");
+                if let Err(err) = recorder.record(&**cmd) {
+                    error!("Failed to record {:?}: {}", cmd, err);
+                }
+            }
+        }
+        for cmd_id in added {
+            self.executor.command_queued(cmd_id, Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Runs everything queued, then checks for a droplet at `loc`, see
+    /// `Execute::sense`.
+    pub fn sense(&mut self, loc: Location) -> PuddleResult<bool> {
+        if !self.grid.in_bounds(loc) {
+            return Err(PuddleError::PlanError(PlanError::OffGrid(loc)));
+        }
+        self.flush(&[])?;
+        self.executor.sense(loc)
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.planner.gridview.snapshot()
+    }
+
+    pub fn grid(&self) -> &Grid {
+        &self.grid
+    }
+
+    /// Whether a droplet of the given dimensions fits on the grid at `loc`.
+    pub fn in_bounds(&self, loc: Location, dimensions: Location) -> bool {
+        Rectangle::new(loc, dimensions)
+            .locations()
+            .all(|l| self.grid.is_enabled(l))
+    }
+
+    pub fn preview_split(
+        &self,
+        d: DropletId,
+        axis: SplitAxis,
+    ) -> PuddleResult<(Location, Location)> {
+        self.planner.gridview.preview_split(d, axis)
+    }
+
+    pub fn output_port(&self, name: &str) -> Option<Location> {
+        self.grid.output_port(name)
+    }
+
     pub fn info(&self, pid: Option<ProcessId>) -> Vec<DropletInfo> {
         self.planner.gridview.droplet_info(pid)
     }
@@ -44,6 +167,14 @@ impl System {
         self.executor.get_logs()
     }
 
+    pub fn export_provenance(&self, w: impl Write) -> serde_json::Result<()> {
+        self.executor.gridview().export_provenance(w)
+    }
+
+    pub fn export_timeline(&self, w: impl Write) -> serde_json::Result<()> {
+        self.executor.gridview().export_timeline(w)
+    }
+
     // TODO switch to event loop here
     pub fn flush(&mut self, droplets: &[DropletId]) -> PuddleResult<()> {
         info!("Flushing...");
@@ -51,18 +182,41 @@ impl System {
             let phase = match self.planner.plan(&self.graph, droplets) {
                 Ok(phase) => phase,
                 Err(PlanError::SchedError(SchedError::NothingToSchedule)) => break,
-                Err(e) => panic!("{:?}", e),
+                Err(e) => {
+                    error!("Failed to plan: {:?}", e);
+                    return Err(PuddleError::PlanError(e));
+                }
             };
 
             // TODO For now this is blocking
-            self.executor.run(phase, &mut self.graph);
+            let resp = self.executor.run(phase, &mut self.graph);
 
             // TODO this is a little hacky
-            self.planner.gridview = self.executor.gridview.clone();
+            // the planner keeps the planning metrics, so they carry over
+            let gridview = self.executor.gridview().clone();
+            let old = std::mem::replace(&mut self.planner.gridview, gridview);
+            self.planner.gridview.set_metrics(old.into_metrics());
             debug!(
                 "Updated planner droplets: {:#?}",
                 self.planner.gridview.droplets
             );
+
+            // the planner already knows where a stuck droplet really is
+            let gv = &self.planner.gridview;
+            let stuck = match resp {
+                ExecResponse::Stuck(id) => Some(id),
+                ExecResponse::Failed(err) => {
+                    error!("Execution failed: {}", err);
+                    return Err(err);
+                }
+                ExecResponse::Ok => gv
+                    .stuck_threshold()
+                    .and_then(|threshold| gv.stuck_droplets(threshold).first().cloned()),
+            };
+            if let Some(id) = stuck {
+                error!("Droplet {:?} is stuck", id);
+                return Err(PuddleError::PlanError(PlanError::DropletStuck(id)));
+            }
         }
 
         info!("Flushed!");
@@ -73,4 +227,284 @@ impl System {
     pub fn ticks(&self) -> usize {
         self.executor.ticks()
     }
+
+    /// Starts or stops recording the commands that get added, see
+    /// `process::recorder`.
+    pub fn set_recorder(&mut self, recorder: Option<Recorder>) {
+        self.recorder = recorder;
+    }
+
+    pub fn set_keepout_border(&mut self, n: u32) {
+        self.planner.gridview.set_keepout_border(n);
+        self.executor.gridview_mut().set_keepout_border(n);
+    }
+
+    pub fn set_reservoir(&mut self, name: &str, volume: f64) {
+        self.planner.gridview.set_reservoir(name, volume);
+        self.executor.gridview_mut().set_reservoir(name, volume);
+    }
+
+    pub fn reservoir_levels(&self) -> IndexMap<String, f64> {
+        self.executor.gridview().reservoir_levels().clone()
+    }
+
+    pub fn set_temperature_limit(&mut self, d: DropletId, limit: f32) -> PuddleResult<()> {
+        for gv in &mut [&mut self.planner.gridview, self.executor.gridview_mut()] {
+            let droplet = gv
+                .droplets
+                .get_mut(&d)
+                .ok_or(PuddleError::NonExistentDropletId(d.id))?;
+            droplet.temperature_limit = Some(limit);
+        }
+        Ok(())
+    }
+
+    pub fn set_proximity_penalty(&mut self, radius: u32, cost: u32) {
+        self.planner.gridview.set_proximity_penalty(radius, cost);
+        self.executor
+            .gridview_mut()
+            .set_proximity_penalty(radius, cost);
+    }
+
+    pub fn set_min_volume(&mut self, volume: f64) {
+        self.planner.gridview.set_min_volume(volume);
+        self.executor.gridview_mut().set_min_volume(volume);
+    }
+
+    pub fn set_cell_volume(&mut self, volume: Option<f64>) {
+        self.planner.gridview.set_cell_volume(volume);
+        self.executor.gridview_mut().set_cell_volume(volume);
+    }
+
+    pub fn cell_volume(&self) -> Option<f64> {
+        self.planner.gridview.cell_volume()
+    }
+
+    pub fn plan_metrics(&self) -> Metrics {
+        self.planner.gridview.metrics().clone()
+    }
+
+    pub fn min_volume(&self) -> f64 {
+        self.planner.gridview.min_volume()
+    }
+
+    /// The volume of `d`, if it's on the grid as of the last flush.
+    pub fn volume(&self, d: DropletId) -> Option<f64> {
+        self.planner.gridview.droplets.get(&d).map(|d| d.volume)
+    }
+
+    pub fn dimensions(&self, d: DropletId) -> Option<Location> {
+        self.planner.gridview.droplets.get(&d).map(|d| d.dimensions)
+    }
+
+    pub fn set_stuck_threshold(&mut self, threshold: Option<u32>) {
+        self.planner.gridview.set_stuck_threshold(threshold);
+        self.executor.gridview_mut().set_stuck_threshold(threshold);
+    }
+
+    pub fn set_allow_diagonal(&mut self, allow: bool) {
+        self.planner.gridview.set_allow_diagonal(allow);
+        self.executor.gridview_mut().set_allow_diagonal(allow);
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.executor.elapsed()
+    }
+
+    pub fn take_timings(&mut self) -> Vec<CommandTiming> {
+        self.executor.take_timings()
+    }
+
+    pub fn blocked(&self, pid: Option<ProcessId>) -> Vec<(String, BlockReason)> {
+        self.planner
+            .blocked(&self.graph)
+            .into_iter()
+            .filter_map(|(cmd_id, reason)| {
+                let cmd = self.graph.graph[cmd_id].as_ref()?;
+                let mut ids = cmd.input_droplets();
+                ids.extend(cmd.output_droplets());
+                if let Some(pid) = pid {
+                    if ids.iter().all(|id| id.process_id != pid) {
+                        return None;
+                    }
+                }
+                Some((format!("{:?}", cmd), reason))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::{Create, HeatProfile, Move};
+    use crate::grid::location::yx;
+    use crate::grid::Peripheral;
+    use matches::assert_matches;
+
+    fn run_move(render_interval: Option<Duration>) -> (usize, usize) {
+        let mut sys = System::new(Grid::rectangle(1, 5)).unwrap();
+        if let Some(interval) = render_interval {
+            sys.executor.gridview_mut().set_render_interval(interval);
+        }
+
+        let create = Create::new(Some(yx(0, 0)), 1.0, None, 0.into()).unwrap();
+        let mv = Move::new(0.into(), yx(0, 4), 1.into()).unwrap();
+        sys.add(Box::new(create)).unwrap();
+        sys.add(Box::new(mv)).unwrap();
+        sys.flush(&[]).unwrap();
+
+        (sys.ticks(), sys.get_logs().len())
+    }
+
+    #[test]
+    fn test_keepout_border() {
+        let mut sys = System::new(Grid::rectangle(5, 5)).unwrap();
+        sys.set_keepout_border(1);
+
+        let create = Create::new(Some(yx(1, 1)), 1.0, None, 0.into()).unwrap();
+        let mv = Move::new(0.into(), yx(3, 3), 1.into()).unwrap();
+        sys.add(Box::new(create)).unwrap();
+        sys.add(Box::new(mv)).unwrap();
+        sys.flush(&[]).unwrap();
+
+        // every step of the route should stay in the interior
+        let steps = serde_json::to_value(sys.get_logs()).unwrap();
+        let inside = |v: i64| (1..=3).contains(&v);
+        for step in steps.as_array().unwrap() {
+            for d in step["droplets"].as_array().unwrap() {
+                let (y, x) = (&d["location"]["y"], &d["location"]["x"]);
+                let (y, x) = (y.as_i64().unwrap(), x.as_i64().unwrap());
+                assert!(inside(y) && inside(x), "({}, {})", y, x);
+            }
+        }
+
+        let mv = Move::new(1.into(), yx(0, 2), 2.into()).unwrap();
+        sys.add(Box::new(mv)).unwrap();
+        assert_matches!(
+            sys.flush(&[]),
+            Err(PuddleError::PlanError(PlanError::InKeepout(loc))) if loc == yx(0, 2)
+        );
+    }
+
+    #[test]
+    fn test_plan_metrics() {
+        let mut sys = System::new(Grid::rectangle(5, 5)).unwrap();
+        let create = Create::new(Some(yx(0, 0)), 1.0, None, 0.into()).unwrap();
+        sys.add(Box::new(create)).unwrap();
+
+        let n = 4;
+        for i in 0..n {
+            let mv = Move::new(i.into(), yx(i as i32 + 1, 0), (i + 1).into()).unwrap();
+            sys.add(Box::new(mv)).unwrap();
+        }
+        sys.flush(&[]).unwrap();
+
+        let metrics = sys.plan_metrics();
+        let moves = metrics.get("Move").unwrap();
+        assert_eq!(moves.count, n);
+        assert!(moves.total > Duration::from_secs(0));
+        assert!(moves.max <= moves.total);
+        assert_eq!(metrics.get("Create").unwrap().count, 1);
+    }
+
+    #[test]
+    fn test_actuation_latency() {
+        let mut sys = System::new(Grid::rectangle(3, 3)).unwrap();
+        assert_eq!(sys.executor.gridview().actuation_latency_stats(), None);
+
+        let create = Create::new(None, 1.0, None, 0.into()).unwrap();
+        sys.add(Box::new(create)).unwrap();
+
+        // pretend the command sat in the queue for a while before the flush
+        let delay = Duration::from_millis(30);
+        let cmd_id = sys.graph.graph.node_indices().next().unwrap();
+        sys.executor.command_queued(cmd_id, Instant::now() - delay);
+        sys.flush(&[]).unwrap();
+
+        let stats = sys.executor.gridview().actuation_latency_stats().unwrap();
+        assert_eq!(stats.count, 1);
+        assert!(stats.min >= delay);
+        assert_eq!(stats.min, stats.max);
+    }
+
+    /// Only ever sees droplets in the first two columns, like there's
+    /// something in the way of the third
+    struct Wall;
+
+    impl crate::exec::Sensor for Wall {
+        fn droplet_present(&mut self, loc: Location) -> PuddleResult<bool> {
+            Ok(loc.x < 2)
+        }
+    }
+
+    #[test]
+    fn test_blocked_droplet_is_stuck() {
+        let grid = Grid::rectangle(1, 5);
+        let mut executor = Executor::new(grid.clone()).unwrap();
+        executor.set_sensor(Some(Box::new(Wall)));
+        let mut sys = System::with_executor(grid, Box::new(executor));
+        sys.set_stuck_threshold(Some(2));
+
+        let create = Create::new(Some(yx(0, 0)), 1.0, Some(yx(1, 1)), 0.into()).unwrap();
+        let mv = Move::new(0.into(), yx(0, 4), 1.into()).unwrap();
+        sys.add(Box::new(create)).unwrap();
+        sys.add(Box::new(mv)).unwrap();
+        assert_matches!(
+            sys.flush(&[]),
+            Err(PuddleError::PlanError(PlanError::DropletStuck(id))) if id == 0.into()
+        );
+
+        // the planner sees it where it got stuck, not where it was sent
+        let stuck = &sys.planner.gridview.droplets[&DropletId::from(0)];
+        assert_eq!(stuck.location, yx(0, 1));
+    }
+
+    #[test]
+    fn test_realtime_holds_steps() {
+        let grid = Grid::rectangle(1, 3);
+        let mut executor = Executor::new(grid.clone()).unwrap();
+        executor.set_realtime(true);
+        let mut sys = System::with_executor(grid, Box::new(executor));
+
+        let create = Create::new(Some(yx(0, 0)), 1.0, Some(yx(1, 1)), 0.into()).unwrap();
+        sys.add(Box::new(create)).unwrap();
+        let start = Instant::now();
+        sys.flush(&[]).unwrap();
+        assert!(sys.elapsed() > Duration::from_secs(0));
+        assert!(start.elapsed() >= sys.elapsed());
+    }
+
+    #[test]
+    fn test_realtime_holds_heat_profile() {
+        let mut grid = Grid::rectangle(2, 3);
+        grid.get_cell_mut(yx(1, 1)).unwrap().peripheral = Some(Peripheral::Heater {
+            pwm_channel: 0,
+            spi_channel: 0,
+        });
+        let mut executor = Executor::new(grid.clone()).unwrap();
+        executor.set_realtime(true);
+        let mut sys = System::with_executor(grid, Box::new(executor));
+
+        let create = Create::new(Some(yx(0, 0)), 1.0, Some(yx(1, 1)), 0.into()).unwrap();
+        let segments = [(95.0, 0.05), (55.0, 0.0), (72.0, 0.05)];
+        let profile = HeatProfile::new(0.into(), 1.into(), &segments).unwrap();
+        sys.add(Box::new(create)).unwrap();
+        sys.add(Box::new(profile)).unwrap();
+        let start = Instant::now();
+        sys.flush(&[]).unwrap();
+
+        // every segment was really held
+        assert!(sys.elapsed() >= Duration::from_millis(100));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_render_interval_does_not_change_steps() {
+        let default = run_move(None);
+        let slow = run_move(Some(Duration::from_millis(500)));
+        let fast = run_move(Some(Duration::from_millis(1)));
+        assert_eq!(default, slow);
+        assert_eq!(default, fast);
+    }
 }
@@ -1,11 +1,15 @@
 pub mod pid;
 
 use std::env;
-use std::time::{Duration, Instant};
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use rand::{Rng, SeedableRng};
 use rand_pcg::Pcg32;
 
+use crate::grid::Location;
+
 pub fn mk_rng() -> impl Rng {
     Pcg32::seed_from_u64(
         env::var("PUDDLE_SEED")
@@ -59,6 +63,48 @@ pub fn seconds_duration(seconds: f64) -> Duration {
     Duration::new(secs as u64, nanos as u32)
 }
 
+/// Formats a time as an ISO-8601 UTC timestamp with milliseconds.
+pub fn iso8601(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // days to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    // days can't be negative here, so plain division is enough for the era
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Raises `atomic` to `value` if it's lower, like `AtomicUsize::fetch_max`
+/// does on newer compilers.
+pub fn fetch_max(atomic: &AtomicUsize, value: usize) {
+    let mut current = atomic.load(Ordering::Relaxed);
+    while current < value {
+        match atomic.compare_exchange_weak(current, value, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => break,
+            Err(actual) => current = actual,
+        }
+    }
+}
+
 pub fn find_duplicate<T>(items: &[T]) -> Option<(usize, usize)>
 where
     T: PartialEq,
@@ -74,6 +120,31 @@ where
     None
 }
 
+#[derive(Debug, PartialEq)]
+pub enum DimError {
+    Malformed(String),
+    NotPositive(Location),
+}
+
+impl fmt::Display for DimError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DimError::Malformed(s) => write!(f, "Dimensions should look like 'y,x', got '{}'", s),
+            DimError::NotPositive(dims) => write!(f, "Dimensions must be positive, got {}", dims),
+        }
+    }
+}
+
+/// Parses droplet dimensions like `2,3` or `(2,3)`, making sure both are
+/// positive.
+pub fn parse_dimensions(s: &str) -> Result<Location, DimError> {
+    let dims: Location = s.parse().map_err(|_| DimError::Malformed(s.to_string()))?;
+    if dims.y <= 0 || dims.x <= 0 {
+        return Err(DimError::NotPositive(dims));
+    }
+    Ok(dims)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -92,4 +163,45 @@ mod tests {
         let a4 = &[1, 2, 3, 4];
         assert_eq!(find_duplicate(a4), None);
     }
+
+    #[test]
+    fn test_fetch_max() {
+        let atomic = AtomicUsize::new(5);
+        fetch_max(&atomic, 3);
+        assert_eq!(atomic.load(Ordering::Relaxed), 5);
+        fetch_max(&atomic, 8);
+        assert_eq!(atomic.load(Ordering::Relaxed), 8);
+    }
+
+    #[test]
+    fn test_parse_dimensions() {
+        use crate::grid::location::yx;
+
+        assert_eq!(parse_dimensions("2,3"), Ok(yx(2, 3)));
+        assert_eq!(parse_dimensions(" (1, 1) "), Ok(yx(1, 1)));
+
+        assert_eq!(
+            parse_dimensions("(0,0)"),
+            Err(DimError::NotPositive(yx(0, 0)))
+        );
+        assert_eq!(
+            parse_dimensions("2,-1"),
+            Err(DimError::NotPositive(yx(2, -1)))
+        );
+
+        for bad in &["", "2", "1,2,3", "a,b", "2.5,1"] {
+            assert_eq!(
+                parse_dimensions(bad),
+                Err(DimError::Malformed(bad.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_iso8601() {
+        let at = |secs: u64, millis: u64| UNIX_EPOCH + Duration::from_millis(secs * 1000 + millis);
+        assert_eq!(iso8601(at(0, 0)), "1970-01-01T00:00:00.000Z");
+        assert_eq!(iso8601(at(1_000_000_000, 5)), "2001-09-09T01:46:40.005Z");
+        assert_eq!(iso8601(at(951_782_400, 0)), "2000-02-29T00:00:00.000Z");
+    }
 }
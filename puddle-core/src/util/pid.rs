@@ -2,6 +2,7 @@ use crate::util::duration_seconds;
 use std::f64::{INFINITY, NAN};
 use std::time::Duration;
 
+#[derive(Debug, Clone)]
 pub struct PidController {
     pub p_gain: f64,
     pub i_gain: f64,
@@ -29,20 +30,45 @@ impl PidController {
         }
     }
 
+    /// Clamps the output to between `min` and `max`, like a duty cycle
+    /// between 0 and 1.
+    pub fn with_output_limits(mut self, min: f64, max: f64) -> Self {
+        self.out_min = min;
+        self.out_max = max;
+        self
+    }
+
     pub fn set_target(&mut self, target: f64) {
         self.target = target;
     }
 
+    /// Forgets the history, like it was just made.
+    pub fn reset(&mut self) {
+        self.total_error = 0.0;
+        self.prev_error = NAN;
+    }
+
     pub fn update(&mut self, measured: f64, dt: &Duration) -> f64 {
-        let dt_seconds = duration_seconds(dt);
+        self.update_seconds(measured, duration_seconds(dt))
+    }
+
+    /// Like `update`, for a temperature reading and `dt` in seconds.
+    pub fn step(&mut self, measured: f32, dt: f64) -> f64 {
+        self.update_seconds(f64::from(measured), dt)
+    }
 
+    // The integral stops building up once the output is pinned at a limit,
+    // so a long climb to a far-off target doesn't leave it way overshooting
+    // once it gets there.
+    fn update_seconds(&mut self, measured: f64, dt_seconds: f64) -> f64 {
         let error = self.target - measured;
 
         let p = self.p_gain * error;
 
-        let new_i = self.i_gain * error * dt_seconds;
-        self.total_error = (self.total_error + new_i).min(self.i_max).max(self.i_min);
-        let i = self.total_error;
+        // nothing to integrate or differentiate over
+        if dt_seconds <= 0.0 {
+            return self.clamp_output(p + self.total_error);
+        }
 
         let d = if self.prev_error.is_nan() {
             0.0
@@ -52,8 +78,26 @@ impl PidController {
 
         self.prev_error = error;
 
+        let new_i = self.i_gain * error * dt_seconds;
+        let i = (self.total_error + new_i).min(self.i_max).max(self.i_min);
+
+        // past the output limits, the integral can only grow as far as it
+        // takes to get there, but it doesn't give back what it already had
+        let out = p + i + d;
+        self.total_error = if out > self.out_max && new_i > 0.0 {
+            i.min(self.total_error.max(self.out_max - p - d))
+        } else if out < self.out_min && new_i < 0.0 {
+            i.max(self.total_error.min(self.out_min - p - d))
+        } else {
+            i
+        };
+
         // return the sum of the PID components, clamping the output
-        (p + i + d).max(self.out_min).min(self.out_max)
+        self.clamp_output(p + self.total_error + d)
+    }
+
+    fn clamp_output(&self, out: f64) -> f64 {
+        out.max(self.out_min).min(self.out_max)
     }
 }
 
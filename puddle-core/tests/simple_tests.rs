@@ -1,8 +1,14 @@
 use std::collections::{HashMap, HashSet};
 use std::env;
+use std::time::Duration;
 
 use matches::assert_matches;
-use puddle_core::{grid::location::yx, prelude::*, process::ProcessHandle};
+use puddle_core::{
+    grid::location::yx,
+    plan::{BlockReason, PlanError},
+    prelude::*,
+    process::ProcessHandle,
+};
 
 fn manager_from_str(s: &str) -> Manager {
     let _ = env_logger::builder().is_test(true).try_init();
@@ -11,7 +17,7 @@ fn manager_from_str(s: &str) -> Manager {
     env::set_var("PUDDLE_STEP_DELAY_MS", "1");
 
     let blocking = false;
-    Manager::new(blocking, grid)
+    Manager::new(blocking, grid).unwrap()
 }
 
 fn manager_from_rect(rows: usize, cols: usize) -> Manager {
@@ -22,7 +28,7 @@ fn manager_from_rect(rows: usize, cols: usize) -> Manager {
     env::set_var("PUDDLE_STEP_DELAY_MS", "1");
 
     let blocking = false;
-    Manager::new(blocking, grid)
+    Manager::new(blocking, grid).unwrap()
 }
 
 fn info_dict(p: &ProcessHandle) -> HashMap<DropletId, DropletInfo> {
@@ -93,6 +99,65 @@ fn mix2() {
     assert!(float_epsilon_equal(droplets[&id12].volume, 2.0));
 }
 
+#[test]
+fn mix_with_more_cycles() {
+    // how many steps it takes to mix, and how far the droplet went
+    let mix_ticks = |intensity, cycles| {
+        let man = manager_from_rect(20, 20);
+        let p = man.get_new_process("test");
+        let id1 = p.create(Some(yx(1, 1)), 1.0, None).unwrap();
+        let id2 = p.create(Some(yx(1, 5)), 1.0, None).unwrap();
+        let _ = info_dict(&p);
+        let before = p.ticks();
+
+        let id12 = p.mix_with(id1, id2, intensity, cycles).unwrap();
+        let droplets = info_dict(&p);
+        assert!(float_epsilon_equal(droplets[&id12].volume, 2.0));
+        p.ticks() - before
+    };
+
+    let once = mix_ticks(1, 1);
+    assert!(mix_ticks(1, 3) > once);
+    // each extra cycle goes around the square once more
+    assert_eq!(mix_ticks(1, 3), once + 2 * 4);
+    assert_eq!(mix_ticks(2, 1), once + 4);
+
+    let man = manager_from_rect(20, 20);
+    let p = man.get_new_process("test");
+    let id1 = p.create(None, 1.0, None).unwrap();
+    let id2 = p.create(None, 1.0, None).unwrap();
+    assert_matches!(
+        p.mix_with(id1, id2, 1, 0),
+        Err(PuddleError::InvalidAgitation(1, 0))
+    );
+    assert_matches!(
+        p.mix_with(id1, id2, 0, 1),
+        Err(PuddleError::InvalidAgitation(0, 1))
+    );
+    // nothing got planned, so they can still be mixed
+    p.mix(id1, id2).unwrap();
+}
+
+#[test]
+fn create_sizes_by_volume() {
+    let man = manager_from_rect(10, 10);
+    let p = man.get_new_process("test");
+
+    // off by default
+    let unsized_id = p.create(None, 5.0, None).unwrap();
+    man.set_cell_volume(Some(1.0));
+
+    let small = p.create(None, 1.0, None).unwrap();
+    let big = p.create(None, 5.0, None).unwrap();
+    let given = p.create(None, 5.0, Some(yx(1, 1))).unwrap();
+    let droplets = info_dict(&p);
+
+    assert_eq!(droplets[&unsized_id].dimensions, yx(1, 1));
+    assert_eq!(droplets[&small].dimensions, yx(1, 1));
+    assert_eq!(droplets[&big].dimensions, yx(2, 3));
+    assert_eq!(droplets[&given].dimensions, yx(1, 1));
+}
+
 #[test]
 fn mix3() {
     let man = manager_from_rect(20, 20);
@@ -213,6 +278,92 @@ fn mix_split() {
     assert!(float_epsilon_equal(droplets[&id5].volume, 0.5));
 }
 
+#[test]
+fn volume_conservation() {
+    let man = manager_from_rect(9, 9);
+    let p = man.get_new_process("test");
+    man.set_min_volume(0.5);
+
+    let id1 = p.create(None, 1.5, None).unwrap();
+    let id2 = p.create(None, 2.5, None).unwrap();
+    let id12 = p.mix(id1, id2).unwrap();
+    let outs = p.split_ratios(id12, &[1.0, 3.0]).unwrap();
+    let (id3, id4) = p.split(outs[1]).unwrap();
+
+    let droplets = info_dict(&p);
+    let total: f64 = droplets.values().map(|d| d.volume).sum();
+    assert!(float_epsilon_equal(total, 4.0));
+    assert!(float_epsilon_equal(droplets[&outs[0]].volume, 1.0));
+    assert!(float_epsilon_equal(droplets[&id3].volume, 1.5));
+    assert!(float_epsilon_equal(droplets[&id4].volume, 1.5));
+
+    // a fifth of 1.0 is under the minimum, and it's on the grid, so we
+    // find out right away
+    assert_matches!(
+        p.split_ratios(outs[0], &[1.0, 4.0]),
+        Err(PuddleError::PlanError(
+            puddle_core::plan::PlanError::VolumeMismatch(id, vol)
+        )) if id == outs[0] && float_epsilon_equal(vol, 0.2)
+    );
+}
+
+#[test]
+fn serial_dilute() {
+    let man = manager_from_rect(12, 12);
+    let p = man.get_new_process("test");
+
+    let sample = p.create(None, 1.0, None).unwrap();
+    let buffer = p.create(None, 4.0, None).unwrap();
+    assert_matches!(
+        p.serial_dilute(sample, buffer, 0, 2.0),
+        Err(PuddleError::InvalidDilution(0, _))
+    );
+    assert_matches!(
+        p.serial_dilute(sample, buffer, 3, 1.0),
+        Err(PuddleError::InvalidDilution(3, _))
+    );
+    assert_matches!(
+        p.serial_dilute(sample, buffer, 5, 2.0),
+        Err(PuddleError::NotEnoughVolume(id, _)) if id == buffer
+    );
+
+    let series = p.serial_dilute(sample, buffer, 3, 2.0).unwrap();
+    assert_eq!(series.len(), 3);
+
+    let droplets = info_dict(&p);
+    let volumes: Vec<_> = series.iter().map(|id| droplets[id].volume).collect();
+    assert!(float_epsilon_equal(volumes[0], 1.0));
+    assert!(float_epsilon_equal(volumes[1], 1.0));
+    assert!(float_epsilon_equal(volumes[2], 2.0));
+    // the series plus the leftover buffer
+    assert_eq!(droplets.len(), 4);
+}
+
+#[test]
+fn serial_dilute_in_sim() {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let grid = Grid::rectangle(12, 12);
+    let sim = SimExecutor::new(grid.clone());
+    let man = Manager::with_executor(false, grid, Box::new(sim));
+    let p = man.get_new_process("test");
+
+    let sample = p.create(None, 1.0, None).unwrap();
+    let buffer = p.create(None, 7.0, None).unwrap();
+    let series = p.serial_dilute(sample, buffer, 3, 3.0).unwrap();
+
+    let droplets = info_dict(&p);
+    let volumes: Vec<_> = series.iter().map(|id| droplets[id].volume).collect();
+    assert!(float_epsilon_equal(volumes[0], 2.0));
+    assert!(float_epsilon_equal(volumes[1], 2.0));
+    assert!(float_epsilon_equal(volumes[2], 3.0));
+    // the leftover buffer, and nothing went missing along the way
+    assert_eq!(droplets.len(), 4);
+    let total: f64 = droplets.values().map(|d| d.volume).sum();
+    assert!(float_epsilon_equal(total, 8.0));
+    // simulated time only, nothing actually waited
+    assert!(p.elapsed() > Duration::from_secs(1));
+}
+
 #[test]
 fn process_isolation() {
     let num_processes = 10;
@@ -418,6 +569,55 @@ fn heat_droplet() {
     assert_eq!(droplets[&id1].location, expected_loc);
 }
 
+#[test]
+fn move_off_grid() {
+    let man = manager_from_rect(5, 5);
+    let p = man.get_new_process("test");
+    let id = p.create(None, 1.0, None).unwrap();
+    p.move_droplet(id, yx(2, 7)).unwrap();
+
+    assert_matches!(
+        p.flush(),
+        Err(PuddleError::PlanError(PlanError::OffGrid(loc))) if loc == yx(2, 7)
+    );
+}
+
+#[test]
+fn heat_profile_droplet() {
+    let board_str = r#"
+        board: [
+          [  0,  1,  2,  3,  4 ],
+          [  _,  _,  5,  _,  _ ],
+        ]
+        peripherals:
+          - location: {y: 1, x: 2}
+            type: Heater
+            pwm_channel: 0
+            spi_channel: 0
+    "#;
+
+    let man = manager_from_str(board_str);
+    let p = man.get_new_process("test");
+    let id0 = p.create(None, 1.0, Some(yx(1, 1))).unwrap();
+
+    assert_matches!(
+        p.heat_profile(id0, vec![]),
+        Err(PuddleError::InvalidHeatProfile(_))
+    );
+    assert_matches!(
+        p.heat_profile(id0, vec![(95.0, 0.0), (500.0, 0.0)]),
+        Err(PuddleError::InvalidHeatProfile(_))
+    );
+
+    let id1 = p
+        .heat_profile(id0, vec![(95.0, 0.0), (55.0, 0.0), (72.0, 0.0)])
+        .unwrap();
+    let droplets = info_dict(&p);
+    assert_eq!(droplets.len(), 1);
+    assert_eq!(droplets[&id1].location, yx(1, 2));
+    assert_eq!(droplets[&id1].max_temperature, Some(95.0));
+}
+
 #[test]
 #[ignore = "We don't support combine into yet"]
 fn combine_into() {
@@ -442,3 +642,659 @@ fn combine_into() {
     assert_eq!(droplets[&ab].location, loc_a - y1);
     assert_eq!(droplets[&cd].location, loc_d - y1);
 }
+
+#[test]
+fn move_blocked_by_droplet() {
+    let man = manager_from_rect(1, 3);
+    let p = man.get_new_process("test");
+
+    let a = p.create(Some(yx(0, 0)), 1.0, None).unwrap();
+    let b = p.create(Some(yx(0, 2)), 1.0, None).unwrap();
+    p.flush().unwrap();
+    assert!(p.blocked().is_empty());
+
+    // there's nowhere to put b once a moves next to it
+    let a2 = p.move_droplet(a, yx(0, 1)).unwrap();
+    let _a3 = p.move_droplet(a2, yx(0, 0)).unwrap();
+    assert!(p.flush().is_err());
+
+    let blocked = p.blocked();
+    assert_eq!(blocked.len(), 2);
+    assert!(blocked[0].0.starts_with("Move"));
+    assert_eq!(blocked[0].1, BlockReason::Collision(b));
+    assert_eq!(blocked[1].1, BlockReason::WaitingForDroplet(a2));
+}
+
+#[test]
+fn transfer_fraction() {
+    let board_str = r#"
+        board: [
+          [  0,  1,  2,  3,  4,  5,  6,  7,  8,  9 ],
+          [ 10, 11, 12, 13, 14, 15, 16, 17, 18, 19 ],
+          [ 20, 21, 22, 23, 24, 25, 26, 27, 28, 29 ],
+          [ 30, 31, 32, 33, 34, 35, 36, 37, 38, 39 ],
+          [ 40, 41, 42, 43, 44, 45, 46, 47, 48, 49 ],
+          [ 50, 51, 52, 53, 54, 55, 56, 57, 58, 59 ],
+          [ 60, 61, 62, 63, 64, 65, 66, 67, 68, 69 ],
+          [ 70, 71, 72, 73, 74, 75, 76, 77, 78, 79 ],
+        ]
+        peripherals:
+          - location: {y: 0, x: 9}
+            type: Input
+            pwm_channel: 0
+            name: dye
+    "#;
+
+    let man = manager_from_str(board_str);
+    let p = man.get_new_process("test");
+
+    let dye = p.input("dye", 1.0, yx(1, 1)).unwrap();
+    let water = p.create(None, 3.0, None).unwrap();
+    let (water2, dye2) = p.transfer(water, dye, 0.25).unwrap();
+    assert_matches!(
+        p.transfer(water2, dye2, 1.5),
+        Err(PuddleError::InvalidFraction(_))
+    );
+
+    let droplets = info_dict(&p);
+    assert_eq!(droplets.len(), 2);
+
+    let d = &droplets[&dye2];
+    let w = &droplets[&water2];
+    assert!(float_epsilon_equal(d.volume, 1.0));
+    assert!(float_epsilon_equal(w.volume, 3.0));
+    // both halves come from the same well-mixed droplet
+    for info in &[d, w] {
+        assert_eq!(info.tags.len(), 1);
+        assert!(float_epsilon_equal(info.tags["dye"], 0.25));
+    }
+}
+
+#[test]
+fn mix_tracks_concentration() {
+    let board_str = r#"
+        board: [
+          [  0,  1,  2,  3,  4,  5,  6,  7,  8,  9 ],
+          [ 10, 11, 12, 13, 14, 15, 16, 17, 18, 19 ],
+          [ 20, 21, 22, 23, 24, 25, 26, 27, 28, 29 ],
+          [ 30, 31, 32, 33, 34, 35, 36, 37, 38, 39 ],
+          [ 40, 41, 42, 43, 44, 45, 46, 47, 48, 49 ],
+          [ 50, 51, 52, 53, 54, 55, 56, 57, 58, 59 ],
+          [ 60, 61, 62, 63, 64, 65, 66, 67, 68, 69 ],
+          [ 70, 71, 72, 73, 74, 75, 76, 77, 78, 79 ],
+        ]
+        peripherals:
+          - location: {y: 0, x: 9}
+            type: Input
+            pwm_channel: 0
+            name: dye
+    "#;
+
+    let man = manager_from_str(board_str);
+    let p = man.get_new_process("test");
+
+    // each round doubles the volume with plain water, halving the dye
+    let mut d = p.input("dye", 1.0, yx(1, 1)).unwrap();
+    let mut expected = 1.0;
+    for _ in 0..3 {
+        let vol = info_dict(&p)[&d].volume;
+        let water = p.create(None, vol, None).unwrap();
+        d = p.mix(d, water).unwrap();
+        expected /= 2.0;
+
+        let mixed = &info_dict(&p)[&d];
+        assert!(float_epsilon_equal(mixed.concentration("dye"), expected));
+        assert!(float_epsilon_equal(mixed.concentration("salt"), 0.0));
+    }
+    assert!(float_epsilon_equal(expected, 0.125));
+
+    // splitting doesn't change it
+    let (a, b) = p.split(d).unwrap();
+    let droplets = info_dict(&p);
+    for id in &[a, b] {
+        let conc = droplets[id].concentration("dye");
+        assert!(float_epsilon_equal(conc, 0.125));
+    }
+}
+
+#[test]
+fn wash_path() {
+    let board_str = r#"
+        board: [
+          [  0,  1,  2,  3,  4,  5 ],
+          [  6,  7,  8,  9, 10, 11 ],
+          [ 12, 13, 14, 15, 16, 17 ],
+          [ 18, 19, 20, 21, 22, 23 ],
+          [ 24, 25, 26, 27, 28, 29 ],
+          [ 30, 31, 32, 33, 34, 35 ],
+        ]
+        peripherals:
+          - location: {y: 5, x: 5}
+            type: Output
+            pwm_channel: 0
+            name: waste
+    "#;
+
+    let man = manager_from_str(board_str);
+    let p = man.get_new_process("test");
+
+    let bystander = p.create(Some(yx(4, 0)), 1.0, None).unwrap();
+    let buffer = p.create(Some(yx(0, 5)), 1.0, None).unwrap();
+    let path = vec![yx(0, 0), yx(0, 1), yx(0, 2), yx(1, 2), yx(2, 2)];
+
+    assert_matches!(
+        p.wash(vec![yx(0, 0), yx(0, 2)], buffer),
+        Err(PuddleError::InvalidPath(_))
+    );
+    assert_matches!(p.wash(vec![], buffer), Err(PuddleError::InvalidPath(_)));
+    assert_matches!(
+        p.wash(vec![yx(0, 5), yx(0, 6)], buffer),
+        Err(PuddleError::OutOfBounds(loc)) if loc == yx(0, 6)
+    );
+
+    p.wash(path.clone(), buffer).unwrap();
+    let droplets = info_dict(&p);
+
+    // every electrode on the path got a visit
+    let steps = serde_json::to_value(man.get_logs()).unwrap();
+    let visited: HashSet<_> = steps
+        .as_array()
+        .unwrap()
+        .iter()
+        .flat_map(|step| step["droplets"].as_array().unwrap().clone())
+        .map(|d| {
+            (
+                d["location"]["y"].as_i64().unwrap(),
+                d["location"]["x"].as_i64().unwrap(),
+            )
+        })
+        .collect();
+    for loc in &path {
+        assert!(visited.contains(&(loc.y as i64, loc.x as i64)), "{}", loc);
+    }
+    // the buffer ended up in the waste, and nothing else moved
+    assert_eq!(droplets.len(), 1);
+    assert_eq!(droplets[&bystander].location, yx(4, 0));
+
+    // only single electrodes can be dragged along
+    let wide = p.create(Some(yx(2, 4)), 2.0, Some(yx(1, 2))).unwrap();
+    assert_matches!(
+        p.wash(path, wide),
+        Err(PuddleError::InvalidWashBuffer(id, dims)) if id == wide && dims == yx(1, 2)
+    );
+}
+
+#[test]
+fn dwell_holds_state() {
+    let man = manager_from_rect(5, 5);
+    let p = man.get_new_process("test");
+
+    let id = p.create(None, 1.0, None).unwrap();
+    let before = info_dict(&p).remove(&id).unwrap();
+    let elapsed = p.elapsed();
+    let ticks = p.ticks();
+
+    let id = p.dwell(id, 2.0).unwrap();
+
+    let after = info_dict(&p).remove(&id).unwrap();
+    assert_eq!(after.location, before.location);
+    assert_eq!(after.volume, before.volume);
+    // the whole dwell is a single held step
+    assert_eq!(p.ticks(), ticks + 1);
+    assert_eq!(p.elapsed() - elapsed, Duration::from_secs(2));
+
+    assert_matches!(p.dwell(id, -1.0), Err(PuddleError::InvalidDuration(_)));
+}
+
+#[test]
+fn provenance_links_inputs_to_output() {
+    let board_str = r#"
+        board: [
+          [  0,  1,  2,  3,  4,  5,  6,  7,  8,  9 ],
+          [ 10, 11, 12, 13, 14, 15, 16, 17, 18, 19 ],
+          [ 20, 21, 22, 23, 24, 25, 26, 27, 28, 29 ],
+          [ 30, 31, 32, 33, 34, 35, 36, 37, 38, 39 ],
+          [ 40, 41, 42, 43, 44, 45, 46, 47, 48, 49 ],
+          [ 50, 51, 52, 53, 54, 55, 56, 57, 58, 59 ],
+          [ 60, 61, 62, 63, 64, 65, 66, 67, 68, 69 ],
+          [ 70, 71, 72, 73, 74, 75, 76, 77, 78, 79 ],
+        ]
+        peripherals:
+          - location: {y: 0, x: 9}
+            type: Input
+            pwm_channel: 0
+            name: dye
+          - location: {y: 7, x: 0}
+            type: Output
+            pwm_channel: 1
+            name: waste
+    "#;
+
+    let man = manager_from_str(board_str);
+    let p = man.get_new_process("test");
+
+    let dye = p.input("dye", 1.0, yx(1, 1)).unwrap();
+    let water = p.create(None, 1.0, None).unwrap();
+    let mixed = p.mix(dye, water).unwrap();
+    let (half, _other_half) = p.split(mixed).unwrap();
+    p.output("waste", half).unwrap();
+    p.flush().unwrap();
+
+    let mut buf = Vec::new();
+    man.export_provenance(&mut buf).unwrap();
+    let prov: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+
+    // walk backwards from the output to everything that went into it
+    let edges = prov["edges"].as_array().unwrap();
+    let output = edges
+        .iter()
+        .find(|e| e["operation"].as_str().unwrap().starts_with("output"))
+        .unwrap();
+    let mut frontier: Vec<_> = output["inputs"].as_array().unwrap().clone();
+    let mut ancestors = HashSet::new();
+    while let Some(id) = frontier.pop() {
+        for e in edges {
+            if e["outputs"].as_array().unwrap().contains(&id) {
+                frontier.extend(e["inputs"].as_array().unwrap().iter().cloned());
+            }
+        }
+        ancestors.insert(id.to_string());
+    }
+
+    for original in &[dye, water] {
+        let id = serde_json::to_value(original).unwrap();
+        assert!(ancestors.contains(&id.to_string()), "{:?}", original);
+    }
+
+    let half_node = prov["nodes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|n| n["id"] == serde_json::to_value(half).unwrap())
+        .unwrap();
+    assert_eq!(half_node["tags"]["dye"], 0.5);
+}
+
+#[test]
+fn move_relative() {
+    let man = manager_from_rect(5, 5);
+    let p = man.get_new_process("test");
+
+    let id = p.create(Some(yx(1, 1)), 1.0, None).unwrap();
+    let id2 = p.move_relative(id, yx(0, 2)).unwrap();
+    let droplets = info_dict(&p);
+    assert_eq!(droplets[&id2].location, yx(1, 3));
+
+    assert_matches!(
+        p.move_relative(id2, yx(0, 2)),
+        Err(PuddleError::OutOfBounds(_))
+    );
+}
+
+#[test]
+fn flush_with_timing() {
+    let man = manager_from_rect(9, 9);
+    let p = man.get_new_process("test");
+
+    let a = p.create(Some(yx(1, 1)), 1.0, None).unwrap();
+    let b = p.create(Some(yx(5, 5)), 1.0, None).unwrap();
+    let a = p.move_droplet(a, yx(1, 4)).unwrap();
+    let _ = p.mix(a, b).unwrap();
+
+    let (droplets, timings) = p.flush_with_timing().unwrap();
+    assert_eq!(droplets.len(), 1);
+    // mix is a combine and an agitate
+    assert_eq!(timings.len(), 5);
+    for t in &timings {
+        assert!(t.start_tick <= t.end_tick);
+    }
+
+    // nothing left over for the next flush
+    let (_, timings) = p.flush_with_timing().unwrap();
+    assert!(timings.is_empty());
+}
+
+#[test]
+fn split_ratios() {
+    let man = manager_from_rect(9, 9);
+    let p = man.get_new_process("test");
+
+    let id = p.create(None, 1.0, None).unwrap();
+    let outs = p.split_ratios(id, &[0.5, 0.3, 0.2]).unwrap();
+    let droplets = info_dict(&p);
+
+    assert_eq!(droplets.len(), 3);
+    for (out, expected) in outs.iter().zip(&[0.5, 0.3, 0.2]) {
+        assert!((droplets[out].volume - expected).abs() < 1e-9);
+    }
+
+    let inf = std::f64::INFINITY;
+    let max = std::f64::MAX;
+    let nan = std::f64::NAN;
+    for bad in &[
+        &[][..],
+        &[0.5, 0.0],
+        &[1.0, -1.0],
+        &[inf, 1.0],
+        &[max, max],
+        &[nan],
+    ] {
+        let id = p.create(None, 1.0, None).unwrap();
+        assert_matches!(p.split_ratios(id, bad), Err(PuddleError::InvalidRatios(_)));
+    }
+
+    // the daughters get their share of the footprint
+    let id = p.create(Some(yx(0, 0)), 4.0, Some(yx(1, 4))).unwrap();
+    let outs = p.split_ratios(id, &[2.0, 1.0, 1.0]).unwrap();
+    let droplets = info_dict(&p);
+    let dims: Vec<_> = outs.iter().map(|id| droplets[id].dimensions).collect();
+    assert_eq!(dims, vec![yx(1, 2), yx(1, 1), yx(1, 1)]);
+}
+
+#[test]
+fn reservoir_levels() {
+    let board_str = r#"
+        board: [
+          [  0,  1,  2,  3,  4 ],
+        ]
+        peripherals:
+          - location: {y: 0, x: 0}
+            type: Input
+            name: water
+            pwm_channel: 0
+          - location: {y: 0, x: 4}
+            type: Output
+            name: trash
+            pwm_channel: 0
+    "#;
+
+    let man = manager_from_str(board_str);
+    man.set_reservoir("water", 5.0);
+    let p = man.get_new_process("test");
+
+    for _ in 0..3 {
+        let d = p.input("water", 1.5, yx(1, 1)).unwrap();
+        p.output("trash", d).unwrap();
+    }
+    p.flush().unwrap();
+
+    let level = p.reservoir_level("water").unwrap();
+    assert!(float_epsilon_equal(level, 0.5));
+    assert_eq!(p.reservoir_level("dye"), None);
+    assert_eq!(p.reservoir_levels().len(), 1);
+}
+
+#[test]
+fn timeline_timestamps_increase() {
+    let man = manager_from_rect(9, 9);
+    let p = man.get_new_process("test");
+
+    let a = p.create(None, 1.0, None).unwrap();
+    let b = p.create(None, 1.0, None).unwrap();
+    let ab = p.mix(a, b).unwrap();
+    let _ = p.split(ab).unwrap();
+    p.flush().unwrap();
+
+    let mut buf = Vec::new();
+    man.export_timeline(&mut buf).unwrap();
+    let records: Vec<HashMap<String, String>> = serde_json::from_slice(&buf).unwrap();
+    assert_eq!(records.len(), 5);
+
+    // ISO-8601 timestamps sort as strings
+    for r in &records {
+        assert!(r["start"] <= r["end"]);
+        assert!(r["start"].ends_with('Z'));
+    }
+    for pair in records.windows(2) {
+        assert!(pair[0]["start"] <= pair[1]["start"]);
+    }
+}
+
+#[test]
+fn return_to_reservoir() {
+    let board_str = r#"
+        board: [
+          [  0,  1,  2,  3,  4 ],
+          [  5,  6,  7,  8,  9 ],
+        ]
+        peripherals:
+          - location: {y: 0, x: 0}
+            type: Input
+            name: water
+            pwm_channel: 0
+    "#;
+
+    let man = manager_from_str(board_str);
+    man.set_reservoir("water", 5.0);
+    let p = man.get_new_process("test");
+
+    let d = p.input("water", 1.5, yx(1, 1)).unwrap();
+    let d = p.move_droplet(d, yx(1, 4)).unwrap();
+    p.flush().unwrap();
+    let before = p.reservoir_level("water").unwrap();
+
+    p.return_to_reservoir(d, "water").unwrap();
+    let droplets = info_dict(&p);
+    assert!(droplets.is_empty());
+
+    let after = p.reservoir_level("water").unwrap();
+    assert!(float_epsilon_equal(after - before, 1.5));
+}
+
+#[test]
+fn preview_split() {
+    use puddle_core::command::SplitAxis;
+
+    for &axis in &[SplitAxis::Vertical, SplitAxis::Horizontal] {
+        let man = manager_from_rect(9, 9);
+        let p = man.get_new_process("test");
+
+        let id = p.create(Some(yx(4, 4)), 1.0, None).unwrap();
+        // something else on the grid for the placer to work around
+        p.create(Some(yx(0, 0)), 1.0, None).unwrap();
+        let (loc0, loc1) = p.preview_split(id, axis).unwrap();
+
+        // previewing doesn't change anything
+        let before = info_dict(&p);
+        assert_eq!(before[&id].location, yx(4, 4));
+
+        let (out0, out1) = p.split_along(id, axis).unwrap();
+        let after = info_dict(&p);
+        assert_eq!(after[&out0].location, loc0);
+        assert_eq!(after[&out1].location, loc1);
+    }
+
+    // a 3x3 grid has no room for the split
+    let man = manager_from_rect(3, 3);
+    let p = man.get_new_process("test");
+    let id = p.create(None, 1.0, None).unwrap();
+    assert_matches!(
+        p.preview_split(id, SplitAxis::Vertical),
+        Err(PuddleError::PlanError(_))
+    );
+}
+
+#[test]
+fn max_temperature() {
+    let board_str = r#"
+        board: [
+          [  0,  1,  2,  3,  4 ],
+          [  5,  6,  7,  8,  9 ],
+          [  _,  _, 10,  _,  _ ],
+          [  _,  _, 11,  _,  _ ],
+        ]
+        peripherals:
+          - location: {y: 3, x: 2}
+            type: Heater
+            pwm_channel: 0
+            spi_channel: 0
+    "#;
+
+    let man = manager_from_str(board_str);
+    let p = man.get_new_process("test");
+
+    let id0 = p.create(None, 1.0, None).unwrap();
+    p.set_max_temperature(id0, 50.0).unwrap();
+
+    // staying under the limit is fine, and gets remembered
+    let id1 = p.heat(id0, 45.0, 0.0).unwrap();
+    let droplets = info_dict(&p);
+    assert_eq!(droplets[&id1].max_temperature, Some(45.0));
+
+    // the limit follows the droplet through the heat
+    p.heat(id1, 60.0, 0.0).unwrap();
+    assert_matches!(
+        p.flush(),
+        Err(PuddleError::PlanError(
+            puddle_core::plan::PlanError::OverTemperature(id, temp)
+        )) if id == id1 && temp == 60.0
+    );
+}
+
+#[test]
+fn heat_too_wide() {
+    let man = manager_from_rect(9, 9);
+    let p = man.get_new_process("test");
+
+    // not on the grid yet, so the planner catches it
+    let id = p.create(None, 1.0, Some(yx(1, 2))).unwrap();
+    p.heat_profile(id, vec![(50.0, 0.0)]).unwrap();
+    assert_matches!(
+        p.flush(),
+        Err(PuddleError::PlanError(
+            puddle_core::plan::PlanError::TooWideToHeat(d, dim)
+        )) if d == id && dim == yx(1, 2)
+    );
+
+    // on the grid, so we find out right away
+    let man = manager_from_rect(9, 9);
+    let p = man.get_new_process("test");
+    let id = p.create(None, 1.0, Some(yx(1, 2))).unwrap();
+    p.flush().unwrap();
+    assert_matches!(
+        p.heat(id, 50.0, 0.0),
+        Err(PuddleError::PlanError(
+            puddle_core::plan::PlanError::TooWideToHeat(..)
+        ))
+    );
+    assert_matches!(
+        p.heat_profile(id, vec![(50.0, 0.0)]),
+        Err(PuddleError::PlanError(
+            puddle_core::plan::PlanError::TooWideToHeat(..)
+        ))
+    );
+}
+
+#[test]
+fn output_routes_to_port() {
+    let board_str = r#"
+        board: [
+          [  0,  1,  2,  3,  4 ],
+          [  5,  6,  7,  8,  9 ],
+          [ 10, 11, 12, 13, 14 ],
+        ]
+        peripherals:
+          - location: {y: 0, x: 0}
+            type: Output
+            name: waste
+            pwm_channel: 0
+          - location: {y: 2, x: 4}
+            type: Output
+            name: collect
+            pwm_channel: 1
+    "#;
+
+    let man = manager_from_str(board_str);
+    let p = man.get_new_process("test");
+
+    let d = p.create(Some(yx(1, 2)), 1.0, None).unwrap();
+    p.output("collect", d).unwrap();
+    assert_eq!(info_dict(&p), HashMap::default());
+
+    // the last place we saw the droplet should be the port
+    let steps = serde_json::to_value(man.get_logs()).unwrap();
+    let id = serde_json::to_value(d).unwrap();
+    let last = steps
+        .as_array()
+        .unwrap()
+        .iter()
+        .flat_map(|s| s["droplets"].as_array().unwrap())
+        .rfind(|info| info["id"] == id)
+        .unwrap();
+    assert_eq!(last["location"], serde_json::json!({"y": 2, "x": 4}));
+
+    let d = p.create(None, 1.0, None).unwrap();
+    assert_matches!(
+        p.output("nowhere", d),
+        Err(PuddleError::NonExistentOutput(ref name)) if name == "nowhere"
+    );
+}
+
+#[test]
+fn assert_at() {
+    use puddle_core::plan::PlanError;
+
+    let man = manager_from_rect(5, 5);
+    let p = man.get_new_process("test");
+
+    let d0 = p.create(Some(yx(0, 0)), 1.0, None).unwrap();
+    let d1 = p.move_droplet(d0, yx(3, 3)).unwrap();
+    p.assert_at(d1, yx(3, 3)).unwrap();
+
+    assert_matches!(
+        p.assert_at(d1, yx(3, 4)),
+        Err(PuddleError::PlanError(PlanError::AssertionFailed(id, expected, actual)))
+            if id == d1 && expected == yx(3, 4) && actual == yx(3, 3)
+    );
+}
+
+#[test]
+fn create_from_region() {
+    use puddle_core::grid::Rectangle;
+
+    let man = manager_from_rect(9, 9);
+    let p = man.get_new_process("test");
+
+    let region = Rectangle::new(yx(2, 2), yx(3, 5));
+    let id = p.create_from_region(region, 2.5).unwrap();
+    let droplets = info_dict(&p);
+    assert_eq!(droplets[&id].location, yx(3, 4));
+    assert_eq!(droplets[&id].dimensions, yx(1, 1));
+    assert!((droplets[&id].volume - 2.5).abs() < 1e-9);
+
+    // can't flood right on top of a droplet
+    let region = Rectangle::new(yx(2, 2), yx(3, 3));
+    assert_matches!(
+        p.create_from_region(region, 1.0),
+        Err(PuddleError::RegionOccupied(other)) if other == id
+    );
+}
+
+#[test]
+fn move_with_sync() {
+    let man = manager_from_rect(3, 5);
+    let p = man.get_new_process("test");
+
+    let d0 = p.create(Some(yx(0, 0)), 1.0, None).unwrap();
+    p.flush().unwrap();
+    p.move_with_sync(d0, yx(0, 4), 7).unwrap();
+    p.flush().unwrap();
+
+    let steps = serde_json::to_value(man.get_logs()).unwrap();
+    let steps = steps.as_array().unwrap();
+    let moved = |step: &serde_json::Value| {
+        step["droplets"].as_array().unwrap()[0]["location"] != serde_json::json!({"y": 0, "x": 0})
+    };
+    let first_move = steps.iter().position(moved).unwrap();
+
+    // the pulse goes with the first step that actually moves the droplet,
+    // and nowhere else
+    for (i, step) in steps.iter().enumerate() {
+        let expected = if i == first_move {
+            serde_json::json!([7])
+        } else {
+            serde_json::Value::Null
+        };
+        assert_eq!(step["sync_pins"], expected, "step {}", i);
+    }
+}
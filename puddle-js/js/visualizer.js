@@ -62,7 +62,34 @@ function Board() {
         }
     }
 
+    // pace playback with the render interval from the log, not the dwell
+    const DEFAULT_RENDER_INTERVAL_MS = 200;
+    let timer = null;
+
+    function stop() {
+        clearTimeout(timer);
+        timer = null;
+    }
+
+    function play() {
+        if (timer !== null) {
+            return stop();
+        }
+        function tick() {
+            if (i >= data.length - 1) {
+                timer = null;
+                m.redraw();
+                return;
+            }
+            i += 1;
+            m.redraw();
+            timer = setTimeout(tick, data[i].render_interval_ms || DEFAULT_RENDER_INTERVAL_MS);
+        }
+        timer = setTimeout(tick, data[i].render_interval_ms || DEFAULT_RENDER_INTERVAL_MS);
+    }
+
     return {
+        onremove: stop,
         view: function (vnode) {
             data = vnode.attrs.data;
             if (!data || data.length == 0) {
@@ -90,6 +117,7 @@ function Board() {
                     m(".grid", droplets.concat(modules)),
                     m("button", {onclick: backward}, "backward"),
                     m("button", {onclick: forward}, "forward"),
+                    m("button", {onclick: play}, timer === null ? "play" : "pause"),
                 ]);
         }
     }
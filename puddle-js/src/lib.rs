@@ -35,7 +35,7 @@ impl System {
     pub fn new() -> System {
         let blocking = false;
         let grid = Grid::rectangle(10, 10);
-        let manager = Manager::new(blocking, grid);
+        let manager = Manager::new(blocking, grid).unwrap();
         let pid = manager.new_process("js").unwrap();
         System { manager, pid }
     }
@@ -0,0 +1,231 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use log::*;
+use serde::Deserialize;
+use serde_json::json;
+
+use puddle_core::grid::gridview::GridView;
+use puddle_core::grid::{DropletId, Location, PinMap};
+use puddle_core::util::iso8601;
+
+use crate::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// `time,voltage,energized,droplets,error`, with a header row when the
+    /// file is new. The lists are space separated.
+    Csv,
+    /// One JSON object per line, with the same fields as the CSV.
+    Ndjson,
+}
+
+/// Turns on the actuation log, under `[pi.actuation_log]`.
+#[derive(Debug, Deserialize)]
+pub struct Settings {
+    pub path: PathBuf,
+    pub format: Format,
+}
+
+impl Settings {
+    pub fn make(&self) -> Result<ActuationLog> {
+        ActuationLog::append(&self.path, self.format)
+    }
+}
+
+/// What the electrodes were doing after one `output_pins`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Actuation {
+    pub time: SystemTime,
+    /// The electrodes that were supposed to go on. If there's an `error`,
+    /// the HV507 was blanked instead.
+    pub energized: Vec<Location>,
+    /// What the DAC was set to, if we know.
+    pub voltage: Option<f64>,
+    pub droplets: Vec<DropletId>,
+    /// Why the electrodes couldn't be set, if they couldn't.
+    pub error: Option<String>,
+}
+
+impl Actuation {
+    /// The electrodes `pins` turns on, found with `map`, along with every
+    /// droplet in `gv`.
+    pub fn new(map: &PinMap, gv: &GridView, pins: &[bool], voltage: Option<f64>) -> Actuation {
+        let mut energized: Vec<Location> = (0..pins.len())
+            .filter(|&pin| pins[pin])
+            .filter_map(|pin| map.location_of(pin))
+            .collect();
+        energized.sort();
+        Actuation {
+            time: SystemTime::now(),
+            energized,
+            voltage,
+            droplets: gv.droplets.keys().cloned().collect(),
+            error: None,
+        }
+    }
+}
+
+/// A record of which electrodes were energized when, for audit trails.
+/// This is separate from the debug logging, which comes and goes with the
+/// log level; once an `ActuationLog` is set on the pi, every `output_pins`
+/// gets an entry, even if the pins didn't change.
+///
+/// Every entry is flushed as it's written, so the log is complete up to
+/// the last actuation even after a crash.
+pub struct ActuationLog {
+    out: Box<dyn Write + Send>,
+    format: Format,
+}
+
+impl fmt::Debug for ActuationLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ActuationLog({:?})", self.format)
+    }
+}
+
+impl ActuationLog {
+    /// Writes to `out`, with a CSV header first if that's the format.
+    pub fn new(out: impl Write + Send + 'static, format: Format) -> Result<ActuationLog> {
+        let mut log = ActuationLog::headless(out, format);
+        if format == Format::Csv {
+            writeln!(log.out, "time,voltage,energized,droplets,error")?;
+        }
+        Ok(log)
+    }
+
+    fn headless(out: impl Write + Send + 'static, format: Format) -> ActuationLog {
+        ActuationLog {
+            out: Box::new(out),
+            format,
+        }
+    }
+
+    /// Adds to the log at `path`. An audit trail shouldn't lose what's
+    /// already there, so this never truncates.
+    pub fn append(path: impl Into<PathBuf>, format: Format) -> Result<ActuationLog> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let is_new = file.metadata()?.len() == 0;
+        let out = BufWriter::new(file);
+        if is_new {
+            ActuationLog::new(out, format)
+        } else {
+            Ok(ActuationLog::headless(out, format))
+        }
+    }
+
+    pub fn write(&mut self, actuation: &Actuation) -> io::Result<()> {
+        let time = iso8601(actuation.time);
+        match self.format {
+            Format::Csv => {
+                let list = |items: Vec<String>| items.join(" ");
+                // quotes in a csv field are escaped by doubling them
+                let error = actuation.error.as_ref().map(|e| e.replace('"', "\"\""));
+                writeln!(
+                    self.out,
+                    "{},{},\"{}\",\"{}\",\"{}\"",
+                    time,
+                    actuation.voltage.map_or(String::new(), |v| v.to_string()),
+                    list(actuation.energized.iter().map(|l| l.to_string()).collect()),
+                    list(actuation.droplets.iter().map(|d| d.to_string()).collect()),
+                    error.unwrap_or_default(),
+                )?;
+            }
+            Format::Ndjson => {
+                let droplets: Vec<String> =
+                    actuation.droplets.iter().map(|d| d.to_string()).collect();
+                let entry = json!({
+                    "time": time,
+                    "voltage": actuation.voltage,
+                    "energized": actuation.energized,
+                    "droplets": droplets,
+                    "error": actuation.error,
+                });
+                serde_json::to_writer(&mut self.out, &entry)?;
+                writeln!(self.out)?;
+            }
+        }
+        self.out.flush()
+    }
+
+    /// Like `write`, but a failure only gets reported. By the time there's
+    /// something to log, the electrodes are already set, so it's too late
+    /// to fail the actuation over it.
+    pub fn record(&mut self, actuation: &Actuation) {
+        if let Err(err) = self.write(actuation) {
+            error!("Failed to write to the actuation log: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use puddle_core::grid::{location::yx, Droplet, Grid};
+
+    #[test]
+    fn test_csv_append() {
+        let path = std::env::temp_dir().join(format!("puddle-audit-{}.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut gv = GridView::new(Grid::rectangle(2, 2));
+        let id = DropletId {
+            id: 3,
+            process_id: 1,
+        };
+        gv.droplets
+            .insert(id, Droplet::new(id, 1.0, yx(0, 0), yx(1, 2)));
+        let map = PinMap::from_grid(&gv.grid).unwrap();
+        let mut actuation = Actuation::new(&map, &gv, &[true, true, false, false], None);
+        assert_eq!(actuation.energized, vec![yx(0, 0), yx(0, 1)]);
+
+        // opening it again keeps what's there, without another header
+        for _ in 0..2 {
+            let mut log = ActuationLog::append(&path, Format::Csv).unwrap();
+            log.write(&actuation).unwrap();
+        }
+        actuation.error = Some("it said \"no\"".into());
+        ActuationLog::append(&path, Format::Csv)
+            .unwrap()
+            .write(&actuation)
+            .unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "time,voltage,energized,droplets,error");
+        assert!(lines[1].ends_with(",,\"(0, 0) (0, 1)\",\"p1.d3\",\"\""));
+        assert_eq!(lines[1], lines[2]);
+        assert!(lines[3].ends_with(",\"p1.d3\",\"it said \"\"no\"\"\""));
+    }
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::Other.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_record_failure() {
+        let gv = GridView::new(Grid::rectangle(1, 1));
+        let map = PinMap::from_grid(&gv.grid).unwrap();
+        let actuation = Actuation::new(&map, &gv, &[true], None);
+        let mut log = ActuationLog::headless(Broken, Format::Ndjson);
+        assert!(log.write(&actuation).is_err());
+        // recording just complains about it
+        log.record(&actuation);
+    }
+}
@@ -1,3 +1,4 @@
+use std::convert::TryFrom;
 use std::error::Error;
 use std::time::Instant;
 
@@ -10,9 +11,9 @@ use puddle_core::{
     grid::location::yx,
     grid::parse::ParsedGrid,
     grid::{Grid, Location},
-    util::seconds_duration,
+    util::{parse_dimensions, seconds_duration},
 };
-use puddle_pi::{RaspberryPi, Settings};
+use puddle_pi::{percent_to_duty, PiDevice, RaspberryPi, Settings};
 
 #[derive(Debug, Clone, Copy)]
 struct MyDuration(std::time::Duration);
@@ -61,8 +62,9 @@ enum SubCommand {
     Split(Split),
     #[structopt(raw(usage = "CUSTOM_HELP"))]
     Custom(Custom),
+    #[structopt(name = "pi-pwm-pct")]
+    PwmPercent(PwmPercent),
     // Dac,
-    // Pwm,
     // Temp,
     // Heat,
     // Pins,
@@ -70,7 +72,15 @@ enum SubCommand {
 
 static SIGNALS: &[i32] = &[signal_hook::SIGINT];
 
-fn main() -> RunResult<()> {
+fn main() {
+    // print errors with Display, the Debug form main would use is hard to read
+    if let Err(err) = run() {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> RunResult<()> {
     // enable logging
     let _ = env_logger::try_init();
 
@@ -113,13 +123,13 @@ fn main() -> RunResult<()> {
     let settings = Settings::from_config(&mut conf)?;
     debug!("Settings made!");
 
-    let mut pi = RaspberryPi::new(settings)?;
-    debug!("Pi made!");
-
     let parsed_grid: ParsedGrid = conf.try_into()?;
-    let grid = parsed_grid.into();
+    let grid = Grid::try_from(parsed_grid)?;
     debug!("Grid made!");
 
+    let mut pi = RaspberryPi::new(settings, &grid)?;
+    debug!("Pi made!");
+
     use SubCommand::*;
     match sub {
         SetPolarity(x) => x.run(&grid, &mut pi, &sleep),
@@ -130,6 +140,7 @@ fn main() -> RunResult<()> {
         ToggleMask(x) => x.run(&grid, &mut pi, &sleep),
         Split(x) => x.run(&grid, &mut pi, &sleep),
         Custom(x) => x.run(&grid, &mut pi, &sleep),
+        PwmPercent(x) => x.run(&grid, &mut pi, &sleep),
     }
 }
 
@@ -188,24 +199,43 @@ impl SetPin {
             return Err(s.into());
         }
         pi.hv507.set_pin_hi(self.pin);
-        pi.hv507.shift_and_latch();
+        pi.hv507.shift_and_latch()?;
         sleep(self.seconds)
     }
 }
 
+#[derive(Debug, StructOpt)]
+struct PwmPercent {
+    gpio: u32,
+    frequency: u32,
+    /// duty cycle from 0 to 100
+    percent: f32,
+    #[structopt(default_value = "1")]
+    seconds: MyDuration,
+}
+
+impl PwmPercent {
+    fn run(&self, _: &Grid, pi: &mut impl PiDevice, sleep: &SleepFn) -> RunResult<()> {
+        pi.set_pwm(self.gpio, self.frequency, percent_to_duty(self.percent))?;
+        let result = sleep(self.seconds);
+        pi.set_pwm(self.gpio, self.frequency, 0)?;
+        result
+    }
+}
+
 #[derive(Debug, StructOpt)]
 struct SetLoc {
     location: Location,
-    #[structopt(default_value = "(1,1)")]
+    #[structopt(default_value = "(1,1)", parse(try_from_str = "parse_dimensions"))]
     dimensions: Location,
     #[structopt(default_value = "1")]
     seconds: MyDuration,
 }
 
 impl SetLoc {
-    fn run(&self, grid: &Grid, pi: &mut RaspberryPi, sleep: &SleepFn) -> RunResult<()> {
+    fn run(&self, grid: &Grid, pi: &mut impl PiDevice, sleep: &SleepFn) -> RunResult<()> {
         let gv = mk_gridview(grid.clone(), &[blob(self.location, self.dimensions)]);
-        pi.output_pins(&gv);
+        pi.output_pins(&gv)?;
         sleep(self.seconds)
     }
 }
@@ -213,10 +243,15 @@ impl SetLoc {
 #[derive(Debug, StructOpt)]
 struct Circle {
     location: Location,
+    #[structopt(parse(try_from_str = "parse_dimensions"))]
     dimensions: Location,
     circle_size: Location,
     #[structopt(default_value = "1")]
     seconds: MyDuration,
+    #[structopt(long, help = "check that the droplet followed, if there's a sensor")]
+    verify: bool,
+    #[structopt(long, help = "seconds to pause when the droplet is lost")]
+    pause_on_loss: Option<MyDuration>,
 }
 
 impl Circle {
@@ -230,8 +265,13 @@ impl Circle {
             let loc = self.location + yx(yo, xo);
             gv.droplets.get_mut(&id).unwrap().location = loc;
             let start = Instant::now();
-            pi.output_pins(&gv);
+            pi.output_pins(&gv)?;
             print!("Droplet at {}...", loc);
+            if self.verify && !pi.verify_droplet(loc)? {
+                if let Some(pause) = self.pause_on_loss {
+                    sleep(pause)?;
+                }
+            }
             let res = sleep(self.seconds);
             println!("{:?}", start.elapsed());
             res
@@ -257,7 +297,12 @@ impl Circle {
 #[derive(Debug, StructOpt)]
 #[structopt(rename_all = "kebab-case")]
 struct BackAndForth {
-    #[structopt(short, long, default_value = "2,2")]
+    #[structopt(
+        short,
+        long,
+        default_value = "2,2",
+        parse(try_from_str = "parse_dimensions")
+    )]
     dimensions: Location,
     #[structopt(short, long, default_value = "3")]
     x_distance: i32,
@@ -274,7 +319,7 @@ struct BackAndForth {
 }
 
 impl BackAndForth {
-    fn run(&self, grid: &Grid, pi: &mut RaspberryPi, sleep: &SleepFn) -> RunResult<()> {
+    fn run(&self, grid: &Grid, pi: &mut impl PiDevice, sleep: &SleepFn) -> RunResult<()> {
         let blobs: Vec<_> = (0..self.n_droplets)
             .map(|i| {
                 let y_offset = (self.dimensions.y + self.spacing as i32) * i as i32;
@@ -307,12 +352,12 @@ impl BackAndForth {
                 let droplet = gv.droplets.get_mut(id).unwrap();
                 droplet.location.x = x as i32;
                 if let Some(stagger) = self.stagger {
-                    pi.output_pins(&gv);
+                    pi.output_pins(&gv)?;
                     sleep(stagger)?;
                 }
             }
             let locs: Vec<_> = gv.droplets.values().map(|d| d.location).collect();
-            pi.output_pins(&gv);
+            pi.output_pins(&gv)?;
             println!("Droplets at {:?}", locs);
 
             sleep(self.seconds)?;
@@ -344,7 +389,7 @@ impl ToggleMask {
                 let bit = (self.mask >> (127 - pin)) & 1;
                 pi.hv507.set_pin(pin, bit as usize == flip);
             }
-            pi.hv507.shift_and_latch();
+            pi.hv507.shift_and_latch()?;
             sleep(self.delay)?;
         }
         Ok(())
@@ -356,6 +401,7 @@ struct Split {
     #[structopt(short, long, default_value = "1")]
     delay: MyDuration,
     location: Location,
+    #[structopt(parse(try_from_str = "parse_dimensions"))]
     dimensions: Location,
     #[structopt(subcommand)]
     kind: SplitKind,
@@ -369,7 +415,7 @@ enum SplitKind {
 }
 
 impl Split {
-    fn run(&self, grid: &Grid, pi: &mut RaspberryPi, sleep: &SleepFn) -> RunResult<()> {
+    fn run(&self, grid: &Grid, pi: &mut impl PiDevice, sleep: &SleepFn) -> RunResult<()> {
         let mut gv = mk_gridview(grid.clone(), &[]);
 
         let loc0 = self.location;
@@ -381,7 +427,7 @@ impl Split {
                 let id = mk_id(i);
                 gv.droplets.insert(id, blob.to_droplet(id));
             }
-            pi.output_pins(&gv);
+            pi.output_pins(&gv)?;
             let locs: Vec<_> = blobs.iter().map(|b| (b.location.y, b.location.x)).collect();
             println!("Droplets at {:?}", locs);
             sleep(self.delay)
@@ -430,7 +476,7 @@ struct Custom {
 }
 
 impl Custom {
-    fn run(&self, grid: &Grid, pi: &mut RaspberryPi, sleep: &SleepFn) -> RunResult<()> {
+    fn run(&self, grid: &Grid, pi: &mut impl PiDevice, sleep: &SleepFn) -> RunResult<()> {
         let mut gv = mk_gridview(grid.clone(), &[]);
         let mut y = 0;
         let mut locations = Vec::new();
@@ -445,7 +491,7 @@ impl Custom {
                 let droplet = blob(*loc, yx(1, 1)).to_droplet(id);
                 gv.droplets.insert(id, droplet);
             }
-            pi.output_pins(&gv);
+            pi.output_pins(&gv)?;
             let locs: Vec<_> = locations.iter().map(|l| (l.y, l.x)).collect();
             println!("Droplets at {:?}", locs);
             locations.clear();
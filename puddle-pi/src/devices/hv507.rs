@@ -1,3 +1,4 @@
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use log::*;
@@ -10,12 +11,20 @@ use crate::{Error, Result};
 
 const N_PINS: usize = 128;
 
+fn default_bit_delay_ns() -> u64 {
+    1000
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub frequency: f64,
     pub duty_cycle: f64,
     pub pins: Pins,
     pub default_polarity: DefaultLevel,
+    // how long to wait after each edge while shifting, some boards need more
+    // setup time than the 1us default
+    #[serde(default = "default_bit_delay_ns")]
+    pub bit_delay_ns: u64,
 }
 
 #[derive(Debug, Deserialize)]
@@ -59,12 +68,16 @@ impl Settings {
         let pwm = Pwm::with_frequency(chan, self.frequency, self.duty_cycle, pol, enabled)?;
 
         let mut hv = Hv507 {
-            blank: mk_output(self.pins.blank)?,
-            latch_enable: mk_output(self.pins.latch_enable)?,
-            clock: mk_output(self.pins.clock)?,
-            data: mk_output(self.pins.data)?,
+            lines: GpioLines {
+                blank: Arc::new(Mutex::new(mk_output(self.pins.blank)?)),
+                latch_enable: mk_output(self.pins.latch_enable)?,
+                clock: mk_output(self.pins.clock)?,
+                data: mk_output(self.pins.data)?,
+            },
             pins: [Level::Low; N_PINS],
             polarity: pwm,
+            duty_cycle: self.duty_cycle,
+            bit_delay: Duration::from_nanos(self.bit_delay_ns),
         };
 
         hv.init(self)?;
@@ -74,12 +87,70 @@ impl Settings {
     }
 }
 
-pub struct Hv507 {
-    blank: OutputPin,
+/// The HV507's control lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Line {
+    Blank,
+    LatchEnable,
+    Clock,
+    Data,
+}
+
+/// Whatever drives the HV507's control lines. This is split out so the
+/// clocking can be tested without the hardware.
+pub trait Lines {
+    fn write(&mut self, line: Line, level: Level) -> Result<()>;
+}
+
+struct GpioLines {
+    // shared with any `Blanker`s
+    blank: Arc<Mutex<OutputPin>>,
     latch_enable: OutputPin,
     clock: OutputPin,
     data: OutputPin,
+}
+
+// Writing a gpio can't fail by itself, so read the level back to catch a
+// line that's shorted or being driven by something else.
+fn write_checked(pin: &mut OutputPin, level: Level) -> Result<()> {
+    pin.write(level);
+    if pin.is_set_high() != (level == Level::High) {
+        return Err(Error::StuckLine(pin.pin()));
+    }
+    Ok(())
+}
+
+impl Lines for GpioLines {
+    fn write(&mut self, line: Line, level: Level) -> Result<()> {
+        match line {
+            Line::Blank => write_checked(&mut self.blank.lock().unwrap(), level),
+            Line::LatchEnable => write_checked(&mut self.latch_enable, level),
+            Line::Clock => write_checked(&mut self.clock, level),
+            Line::Data => write_checked(&mut self.data, level),
+        }
+    }
+}
+
+/// Just the HV507's blank line, so it can be blanked from another thread
+/// while the `Hv507` itself is busy elsewhere.
+#[derive(Clone)]
+pub struct Blanker {
+    blank: Arc<Mutex<OutputPin>>,
+}
+
+impl Blanker {
+    /// See `Hv507::set_blank`.
+    pub fn set_blank(&self, blanked: bool) {
+        let level = if blanked { Level::Low } else { Level::High };
+        self.blank.lock().unwrap().write(level);
+    }
+}
+
+pub struct Hv507 {
+    lines: GpioLines,
     polarity: Pwm,
+    duty_cycle: f64,
+    bit_delay: Duration,
 
     pins: [Level; N_PINS],
 }
@@ -94,10 +165,11 @@ impl Hv507 {
         // see row "LOAD S/R" in table 3-2 in
         // http://ww1.microchip.com/downloads/en/DeviceDoc/20005845A.pdf
 
-        self.blank.set_high();
-        self.latch_enable.set_low();
-        self.clock.set_low();
-        self.data.set_low();
+        use Level::*;
+        self.lines.write(Line::Blank, High)?;
+        self.lines.write(Line::LatchEnable, Low)?;
+        self.lines.write(Line::Clock, Low)?;
+        self.lines.write(Line::Data, Low)?;
 
         // now call the public function to set the HV507 polarity pin
         self.set_polarity(settings.frequency, settings.duty_cycle)?;
@@ -108,9 +180,15 @@ impl Hv507 {
     pub fn set_polarity(&mut self, frequency: f64, duty_cycle: f64) -> Result<()> {
         self.polarity.set_frequency(frequency, duty_cycle)?;
         self.polarity.enable()?;
+        self.duty_cycle = duty_cycle;
         Ok(())
     }
 
+    /// Changes the polarity frequency, keeping the duty cycle.
+    pub fn set_polarity_frequency(&mut self, frequency: f64) -> Result<()> {
+        self.set_polarity(frequency, self.duty_cycle)
+    }
+
     pub fn clear_pins(&mut self) {
         for pin in self.pins.iter_mut() {
             *pin = Level::Low;
@@ -130,26 +208,130 @@ impl Hv507 {
         self.set_pin(pin, false)
     }
 
-    pub fn shift_and_latch(&mut self) {
-        let spin_duration = Duration::from_micros(1);
-        let start = Instant::now();
-        for pin in self.pins.iter() {
-            // write and cycle the clock
-            self.data.write(*pin);
-            spin(spin_duration);
-            self.clock.set_high();
-            spin(spin_duration);
-            self.clock.set_low();
-            spin(spin_duration);
+    /// Turns all the outputs off (or back on) without touching the latched
+    /// pattern.
+    pub fn set_blank(&mut self, blanked: bool) -> Result<()> {
+        let level = if blanked { Level::Low } else { Level::High };
+        self.lines.write(Line::Blank, level)
+    }
+
+    /// A handle on the blank line, see `Blanker`.
+    pub fn blanker(&self) -> Blanker {
+        Blanker {
+            blank: Arc::clone(&self.lines.blank),
         }
-        let avg = start.elapsed() / self.pins.len() as u32;
-        debug!("Avg clock: {:?}", avg);
+    }
 
-        // commit the latch
-        self.latch_enable.set_high();
+    /// How long the clocking waits after each edge.
+    pub fn set_bit_delay(&mut self, delay: Duration) {
+        self.bit_delay = delay;
+    }
+
+    /// Clocks out and latches the pins. If a line doesn't take the level
+    /// written to it, the outputs are blanked so we don't leave a
+    /// half-written pattern energized.
+    pub fn shift_and_latch(&mut self) -> Result<()> {
+        shift_and_latch(&mut self.lines, &self.pins, self.bit_delay)
+    }
+
+    /// Clocks out and latches `bits` as is, first bit first, without
+    /// touching the pins set with `set_pin`. Failures blank the outputs
+    /// like `shift_and_latch`.
+    pub fn shift_out(&mut self, bits: &[bool]) -> Result<()> {
+        use Level::*;
+        let levels: Vec<Level> = bits.iter().map(|&b| if b { High } else { Low }).collect();
+        shift_and_latch(&mut self.lines, &levels, self.bit_delay)
+    }
+}
+
+/// Clocks `pins` out on `lines` and latches them. If any write fails, the
+/// outputs are blanked before the error is returned.
+pub fn shift_and_latch(lines: &mut impl Lines, pins: &[Level], bit_delay: Duration) -> Result<()> {
+    let result = clock_out(lines, pins, bit_delay);
+    if let Err(err) = &result {
+        error!("Failed to shift out pins, blanking the HV507: {}", err);
+        if let Err(err) = lines.write(Line::Blank, Level::Low) {
+            error!("Failed to blank the HV507!: {}", err);
+        }
+    }
+    result
+}
+
+/// Wraps some other lines, failing the nth write and any write matching
+/// `fail_if`, and passing everything else through. For testing what
+/// happens when the hardware acts up; build with `fault-injection` to use
+/// it outside this crate.
+#[cfg(any(test, feature = "fault-injection"))]
+pub struct FaultInjector<L> {
+    pub inner: L,
+    calls: usize,
+    fail_nth: Option<usize>,
+    fail_if: Option<Box<dyn Fn(Line, Level) -> bool + Send>>,
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+impl<L: Lines> FaultInjector<L> {
+    pub fn new(inner: L) -> FaultInjector<L> {
+        FaultInjector {
+            inner,
+            calls: 0,
+            fail_nth: None,
+            fail_if: None,
+        }
+    }
+
+    /// Fails the `n`th write from here on, counting from 0.
+    pub fn fail_nth(&mut self, n: usize) {
+        self.fail_nth = Some(self.calls + n);
+    }
+
+    /// Fails every write that `fail_if` says to.
+    pub fn fail_if(&mut self, fail_if: impl Fn(Line, Level) -> bool + Send + 'static) {
+        self.fail_if = Some(Box::new(fail_if));
+    }
+}
+
+#[cfg(any(test, feature = "fault-injection"))]
+impl<L: Lines> Lines for FaultInjector<L> {
+    fn write(&mut self, line: Line, level: Level) -> Result<()> {
+        let n = self.calls;
+        self.calls += 1;
+        let matches = match &self.fail_if {
+            Some(fail_if) => fail_if(line, level),
+            None => false,
+        };
+        if self.fail_nth == Some(n) || matches {
+            return Err(rppal::gpio::Error::PinNotAvailable(0).into());
+        }
+        self.inner.write(line, level)
+    }
+}
+
+// see the timing diagram, figure 3-1, in the datasheet linked in `init`
+fn clock_out(lines: &mut impl Lines, pins: &[Level], spin_duration: Duration) -> Result<()> {
+    use Level::*;
+    let start = Instant::now();
+    for pin in pins.iter() {
+        // write and cycle the clock
+        lines.write(Line::Data, *pin)?;
+        spin(spin_duration);
+        lines.write(Line::Clock, High)?;
         spin(spin_duration);
-        self.latch_enable.set_low();
+        lines.write(Line::Clock, Low)?;
+        spin(spin_duration);
+    }
+    if !pins.is_empty() {
+        let avg = start.elapsed() / pins.len() as u32;
+        debug!("Avg clock: {:?}", avg);
     }
+
+    // commit the latch
+    lines.write(Line::LatchEnable, High)?;
+    spin(spin_duration);
+    lines.write(Line::LatchEnable, Low)?;
+
+    // make sure the outputs are on, in case we blanked after a failure
+    lines.write(Line::Blank, High)
 }
 
 fn spin(duration: Duration) {
@@ -161,6 +343,76 @@ impl Drop for Hv507 {
     fn drop(&mut self) {
         debug!("Cleaning up HV507");
         self.clear_pins();
-        self.shift_and_latch();
+        if let Err(err) = self.shift_and_latch() {
+            error!("Failed to clear the HV507 {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELAY: Duration = Duration::from_nanos(0);
+
+    /// Records every write, and fails once on the nth one
+    struct FlakyLines {
+        writes: Vec<(Line, Level)>,
+        fail_at: Option<usize>,
+    }
+
+    impl Lines for FlakyLines {
+        fn write(&mut self, line: Line, level: Level) -> Result<()> {
+            if self.fail_at == Some(self.writes.len()) {
+                self.fail_at = None;
+                return Err(rppal::gpio::Error::PinNotAvailable(0).into());
+            }
+            self.writes.push((line, level));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_fault_injector() {
+        let pins = [Level::High; 8];
+        let recording = || FlakyLines {
+            writes: vec![],
+            fail_at: None,
+        };
+
+        // the latch failing still leaves the outputs blanked
+        let mut lines = FaultInjector::new(recording());
+        lines.fail_if(|line, level| line == Line::LatchEnable && level == Level::High);
+        assert!(shift_and_latch(&mut lines, &pins, DELAY).is_err());
+        assert_eq!(lines.inner.writes.last(), Some(&(Line::Blank, Level::Low)));
+
+        // and so does the very first write failing
+        let mut lines = FaultInjector::new(recording());
+        lines.fail_nth(0);
+        assert!(shift_and_latch(&mut lines, &pins, DELAY).is_err());
+        assert_eq!(lines.inner.writes, vec![(Line::Blank, Level::Low)]);
+    }
+
+    #[test]
+    fn test_blank_after_failed_shift() {
+        let pins = [Level::High; 8];
+
+        // die in the middle of clocking
+        let mut lines = FlakyLines {
+            writes: vec![],
+            fail_at: Some(10),
+        };
+        assert!(shift_and_latch(&mut lines, &pins, DELAY).is_err());
+        assert_eq!(lines.writes.len(), 11);
+        assert_eq!(lines.writes.last(), Some(&(Line::Blank, Level::Low)));
+        assert!(!lines.writes.contains(&(Line::LatchEnable, Level::High)));
+
+        // a good shift latches and leaves the outputs enabled
+        let mut lines = FlakyLines {
+            writes: vec![],
+            fail_at: None,
+        };
+        shift_and_latch(&mut lines, &pins, DELAY).unwrap();
+        assert_eq!(lines.writes.last(), Some(&(Line::Blank, Level::High)));
     }
 }
@@ -0,0 +1,183 @@
+use rppal::i2c::I2c;
+
+use crate::{Error, Result};
+
+/// The raw reads and writes the devices do on an I2C bus, plus the usual
+/// register access on top of them. Raw access stays available for devices
+/// like the MCP4725 that don't have register pointers.
+pub trait I2cBus {
+    fn write(&mut self, data: &[u8]) -> Result<usize>;
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+
+    /// Fills all of `buf`. A noisy bus can come up short, which is an
+    /// error the caller can retry rather than a panic.
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        let actual = self.read(buf)?;
+        if actual != buf.len() {
+            return Err(Error::ShortRead {
+                expected: buf.len(),
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    /// Writes all of `data`, where coming up short is an error like it is
+    /// for `read_exact`.
+    fn write_all(&mut self, data: &[u8]) -> Result<()> {
+        let actual = self.write(data)?;
+        if actual != data.len() {
+            return Err(Error::ShortWrite {
+                expected: data.len(),
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    /// Writes `data` starting at register `reg`.
+    fn write_register(&mut self, reg: u8, data: &[u8]) -> Result<()> {
+        let mut msg = Vec::with_capacity(data.len() + 1);
+        msg.push(reg);
+        msg.extend_from_slice(data);
+        self.write_all(&msg)
+    }
+
+    /// Points the device at register `reg`, then reads `count` bytes from it.
+    fn read_register(&mut self, reg: u8, count: usize) -> Result<Vec<u8>> {
+        self.write_all(&[reg])?;
+        let mut buf = vec![0; count];
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+pub const DEFAULT_BUS: u8 = 1;
+
+pub fn default_bus() -> u8 {
+    DEFAULT_BUS
+}
+
+/// Opens the device at `address` on `bus`, which is a 10-bit address if
+/// `addr_10bit` is set.
+pub fn open(bus: u8, address: u16, addr_10bit: bool) -> Result<I2c> {
+    let mut i2c = I2c::with_bus(bus)?;
+    if addr_10bit {
+        i2c.set_addr_10bit(true)?;
+    }
+    i2c.set_slave_address(address)?;
+    Ok(i2c)
+}
+
+impl I2cBus for I2c {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        Ok(I2c::write(self, data)?)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        Ok(I2c::read(self, buf)?)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// A device with 256 auto-incrementing registers
+    #[derive(Default)]
+    pub struct FakeBus {
+        pub registers: Vec<u8>,
+        pub pointer: usize,
+        pub writes: Vec<Vec<u8>>,
+        // how many bytes short the next read comes up
+        pub short_by: usize,
+        // and the same for the next write
+        pub write_short_by: usize,
+    }
+
+    impl FakeBus {
+        pub fn new() -> FakeBus {
+            FakeBus {
+                registers: vec![0; 256],
+                ..FakeBus::default()
+            }
+        }
+    }
+
+    impl I2cBus for FakeBus {
+        fn write(&mut self, data: &[u8]) -> Result<usize> {
+            let n = data.len().saturating_sub(self.write_short_by);
+            self.write_short_by = 0;
+            self.writes.push(data[..n].to_vec());
+            if n == 0 {
+                return Ok(0);
+            }
+            self.pointer = data[0] as usize;
+            for &byte in &data[1..n] {
+                self.registers[self.pointer] = byte;
+                self.pointer += 1;
+            }
+            Ok(n)
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let n = buf.len().saturating_sub(self.short_by);
+            self.short_by = 0;
+            for byte in &mut buf[..n] {
+                *byte = self.registers[self.pointer];
+                self.pointer += 1;
+            }
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_register_round_trip() {
+        let mut bus = FakeBus::new();
+        bus.write_register(6, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(bus.writes, vec![vec![6, 1, 2, 3, 4]]);
+
+        assert_eq!(bus.read_register(7, 2).unwrap(), vec![2, 3]);
+        assert_eq!(bus.writes.last(), Some(&vec![7]));
+        assert_eq!(bus.read_register(0, 1).unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn test_short_read() {
+        let mut bus = FakeBus::new();
+        bus.short_by = 2;
+        match bus.read_register(0, 3) {
+            Err(Error::ShortRead {
+                expected: 3,
+                actual: 1,
+            }) => (),
+            other => panic!("Expected a short read, got {:?}", other),
+        }
+        // the next one goes through
+        assert_eq!(bus.read_register(0, 3).unwrap(), vec![0, 0, 0]);
+    }
+
+    #[test]
+    fn test_short_write() {
+        let mut bus = FakeBus::new();
+        bus.write_short_by = 1;
+        match bus.write_register(4, &[9, 9]) {
+            Err(Error::ShortWrite {
+                expected: 3,
+                actual: 2,
+            }) => (),
+            other => panic!("Expected a short write, got {:?}", other),
+        }
+
+        // the register pointer not getting there is an error too
+        bus.write_short_by = 1;
+        match bus.read_register(4, 2) {
+            Err(Error::ShortWrite {
+                expected: 1,
+                actual: 0,
+            }) => (),
+            other => panic!("Expected a short write, got {:?}", other),
+        }
+        assert_eq!(bus.read_register(4, 2).unwrap(), vec![9, 0]);
+    }
+}
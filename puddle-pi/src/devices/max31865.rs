@@ -1,11 +1,14 @@
 #![allow(clippy::assertions_on_constants)]
 // https://datasheets.maximintegrated.com/en/ds/MAX31865.pdf
 
+use std::thread;
+use std::time::Duration;
+
 use log::*;
 use rppal::spi::Spi;
 use serde::Deserialize;
 
-use crate::Result;
+use crate::{Error, Result};
 
 // From Table 1
 #[allow(dead_code)]
@@ -52,6 +55,38 @@ pub enum Config {
     VBias                = 0b1000_0000,
 }
 
+/// The fault status register, decoded. See Table 8.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FaultStatus {
+    pub rtd_high_threshold: bool,
+    pub rtd_low_threshold: bool,
+    /// REFIN- > 0.85 x VBIAS
+    pub refin_high: bool,
+    /// REFIN- < 0.85 x VBIAS, FORCE- open
+    pub refin_low: bool,
+    /// RTDIN- < 0.85 x VBIAS, FORCE- open
+    pub rtdin_low: bool,
+    pub over_under_voltage: bool,
+}
+
+impl FaultStatus {
+    pub fn from_byte(status: u8) -> FaultStatus {
+        let bit = |n: u8| status & (1 << n) != 0;
+        FaultStatus {
+            rtd_high_threshold: bit(7),
+            rtd_low_threshold: bit(6),
+            refin_high: bit(5),
+            refin_low: bit(4),
+            rtdin_low: bit(3),
+            over_under_voltage: bit(2),
+        }
+    }
+
+    pub fn any(&self) -> bool {
+        *self != FaultStatus::default()
+    }
+}
+
 pub const CLOCK_SPEED: u32 = 40_000;
 // use min and max thresholds, we don't care about faults
 pub const LOW_THRESHOLD: u16 = 0;
@@ -60,6 +95,13 @@ pub const DEFAULT_CONFIG: u8 = {
     use self::Config::*;
     VBias as u8 | ConversionMode as u8
 };
+// From the Electrical Characteristics table, the first conversion in auto
+// mode takes 55ms with the 60Hz filter
+pub const DEFAULT_WARMUP_MS: u64 = 55;
+
+fn default_warmup_ms() -> u64 {
+    DEFAULT_WARMUP_MS
+}
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
@@ -68,6 +110,8 @@ pub struct Settings {
     pub n_samples: u32,
     pub resist_ref: f32,
     pub resist_zero: f32,
+    #[serde(default = "default_warmup_ms")]
+    pub warmup_ms: u64,
 }
 
 impl Settings {
@@ -83,14 +127,14 @@ impl Settings {
             0 => Bus::Spi0,
             1 => Bus::Spi1,
             2 => Bus::Spi2,
-            _ => panic!("Bad bus: {}", self.bus),
+            _ => return Err(crate::Error::InvalidSpiBus(self.bus)),
         };
 
         let select = match self.select {
             0 => SlaveSelect::Ss0,
             1 => SlaveSelect::Ss1,
             2 => SlaveSelect::Ss2,
-            _ => panic!("Bad select: {}", self.select),
+            _ => return Err(crate::Error::InvalidSpiSelect(self.select)),
         };
 
         let spi = Spi::new(bus, select, CLOCK_SPEED, Mode::Mode1)?;
@@ -100,6 +144,8 @@ impl Settings {
             n_samples: self.n_samples,
             resist_ref: self.resist_ref,
             resist_zero: self.resist_zero,
+            warmup: Warmup::new(Duration::from_millis(self.warmup_ms)),
+            config: DEFAULT_CONFIG,
         };
 
         max.initalize()?;
@@ -113,13 +159,43 @@ pub struct Max31865 {
     n_samples: u32,
     resist_ref: f32,
     resist_zero: f32,
+    warmup: Warmup,
+    // the last thing written to the configuration register
+    config: u8,
+}
+
+/// Tracks whether the chip needs time to settle before its conversion
+/// results can be trusted.
+struct Warmup {
+    delay: Duration,
+    pending: bool,
+}
+
+impl Warmup {
+    fn new(delay: Duration) -> Warmup {
+        Warmup {
+            delay,
+            pending: true,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.pending = true;
+    }
+
+    fn wait(&mut self, mut sleep: impl FnMut(Duration)) {
+        if self.pending {
+            debug!("Waiting {:?} for the max31865 to settle", self.delay);
+            sleep(self.delay);
+            self.pending = false;
+        }
+    }
 }
 
 impl Max31865 {
     fn initalize(&mut self) -> Result<()> {
         // first write out the config bits
-        self.spi
-            .write(&[Register::Configuration.write(), DEFAULT_CONFIG])?;
+        self.set_config(DEFAULT_CONFIG)?;
 
         // now write out the thresholds, knowing that it will auto-increment
         // starting from the HighFaultThresholdMsb register
@@ -136,6 +212,33 @@ impl Max31865 {
         Ok(())
     }
 
+    /// Writes the configuration register. Changing the mode means the next
+    /// read will wait for the conversion to settle again.
+    pub fn set_config(&mut self, config: u8) -> Result<()> {
+        self.spi.write(&[Register::Configuration.write(), config])?;
+        self.config = config;
+        self.warmup.reset();
+        Ok(())
+    }
+
+    pub fn read_faults(&mut self) -> Result<FaultStatus> {
+        let tx_buf = [Register::FaultStatus.read(), 0];
+        let mut rx_buf = [0; 2];
+        self.spi.transfer(&mut rx_buf, &tx_buf)?;
+        Ok(FaultStatus::from_byte(rx_buf[1]))
+    }
+
+    /// Clears the fault status register, leaving the mode alone.
+    pub fn clear_faults(&mut self) -> Result<()> {
+        use self::Config::*;
+        // the datasheet wants the one-shot and fault detection bits clear
+        // when clearing faults
+        let keep = !(OneShot as u8 | FaultDetectionCycle2 as u8 | FaultDetectionCycle3 as u8);
+        let config = (self.config & keep) | FaultStatusClear as u8;
+        self.spi.write(&[Register::Configuration.write(), config])?;
+        Ok(())
+    }
+
     pub fn read_one_resistance(&mut self) -> Result<f32> {
         // we are going to write 1 byte, then receive 8
         // but we have to use transfer instead of write/read because we need
@@ -148,14 +251,28 @@ impl Max31865 {
         // write out the config register, that's where we will start reading
         tx_buf[0] = Register::Configuration.read();
 
-        self.spi.transfer(&mut rx_buf, &tx_buf)?;
+        self.warmup.wait(thread::sleep);
+
+        let read = self.spi.transfer(&mut rx_buf, &tx_buf)?;
+        if read != count {
+            return Err(Error::ShortRead {
+                expected: count,
+                actual: read,
+            });
+        }
 
         // ignore the first byte, because that's when we were sending the
-        // register to read from
-        assert!(rx_buf[0] == 0);
+        // register to read from, so the chip should have been quiet
+        if rx_buf[0] != 0 {
+            warn!(
+                "MAX31865 sent {:#04x} while it was being addressed",
+                rx_buf[0]
+            );
+            return Err(Error::UnexpectedByte(rx_buf[0]));
+        }
 
         let config = rx_buf[1];
-        let (resistance_bits, _) = unpack_word(rx_buf[2], rx_buf[3]);
+        let (resistance_bits, fault) = unpack_word(rx_buf[2], rx_buf[3]);
         let (hi_threshold, _) = unpack_word(rx_buf[4], rx_buf[5]);
         let (lo_threshold, _) = unpack_word(rx_buf[6], rx_buf[7]);
         let status = rx_buf[8];
@@ -169,8 +286,12 @@ impl Max31865 {
         debug!("Lo Threshold:   {:04x}", lo_threshold);
         debug!("Status:         {:08b}", status);
 
-        // we don't handle status in anyway right now, so just make sure it's nothing
-        // assert_eq!(status, 0);
+        // a fault means the reading is garbage, so don't pass it along
+        let faults = FaultStatus::from_byte(status);
+        if fault || faults.any() {
+            warn!("MAX31865 fault: {:?}", faults);
+            return Err(Error::RtdFault(faults));
+        }
 
         let resistance = f32::from(resistance_bits) * self.resist_ref / ((1 << 15) as f32);
         debug!("Resistance:     {}", resistance);
@@ -235,3 +356,68 @@ fn unpack_word(msbs: u8, lsbs: u8) -> (u16, bool) {
     let low_bit = (word & 1) == 1;
     (word >> 1, low_bit)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fault_status() {
+        assert!(!FaultStatus::from_byte(0).any());
+        // the two unused bits don't count
+        assert!(!FaultStatus::from_byte(0b0000_0011).any());
+
+        // an open RTD trips the high threshold and REFIN-
+        let open = FaultStatus::from_byte(0b1010_0000);
+        assert!(open.any());
+        assert!(open.rtd_high_threshold && open.refin_high);
+        assert!(!open.rtd_low_threshold && !open.refin_low);
+
+        let expected = FaultStatus {
+            rtd_low_threshold: true,
+            rtdin_low: true,
+            over_under_voltage: true,
+            ..FaultStatus::default()
+        };
+        assert_eq!(FaultStatus::from_byte(0b0100_1100), expected);
+    }
+
+    #[test]
+    fn test_bad_spi_settings() {
+        let settings = |bus, select| Settings {
+            bus,
+            select,
+            n_samples: 1,
+            resist_ref: 430.0,
+            resist_zero: 100.0,
+            warmup_ms: DEFAULT_WARMUP_MS,
+        };
+        match settings(7, 0).make() {
+            Err(Error::InvalidSpiBus(7)) => (),
+            Err(err) => panic!("Expected a bad bus, got {}", err),
+            Ok(_) => panic!("Expected a bad bus"),
+        }
+        match settings(0, 3).make() {
+            Err(Error::InvalidSpiSelect(3)) => (),
+            Err(err) => panic!("Expected a bad select, got {}", err),
+            Ok(_) => panic!("Expected a bad select"),
+        }
+    }
+
+    #[test]
+    fn test_warmup_before_first_read() {
+        let delay = Duration::from_millis(DEFAULT_WARMUP_MS);
+        let mut warmup = Warmup::new(delay);
+        let mut slept = Vec::new();
+
+        // the first read waits, the ones after it don't
+        warmup.wait(|d| slept.push(d));
+        warmup.wait(|d| slept.push(d));
+        assert_eq!(slept, vec![delay]);
+
+        // a mode change makes us wait again
+        warmup.reset();
+        warmup.wait(|d| slept.push(d));
+        assert_eq!(slept, vec![delay, delay]);
+    }
+}
@@ -0,0 +1,92 @@
+// http://ww1.microchip.com/downloads/en/DeviceDoc/21295d.pdf
+
+use log::*;
+use rppal::spi::Spi;
+use serde::Deserialize;
+
+use crate::{Adc, Error, Result};
+
+pub const N_CHANNELS: u8 = 8;
+// From the Electrical Characteristics table, 1.35MHz is the fastest clock
+// at 2.7V, so this is safe on the pi's 3.3V
+pub const CLOCK_SPEED: u32 = 1_000_000;
+// 10-bit conversions
+pub const MAX_READING: u16 = 0x3ff;
+
+#[derive(Debug, Deserialize)]
+pub struct Settings {
+    pub bus: u8,
+    pub select: u8,
+}
+
+impl Settings {
+    pub fn make(&self) -> Result<Mcp3008> {
+        use rppal::spi::*;
+
+        let bus = match self.bus {
+            0 => Bus::Spi0,
+            1 => Bus::Spi1,
+            2 => Bus::Spi2,
+            _ => return Err(crate::Error::InvalidSpiBus(self.bus)),
+        };
+
+        let select = match self.select {
+            0 => SlaveSelect::Ss0,
+            1 => SlaveSelect::Ss1,
+            2 => SlaveSelect::Ss2,
+            _ => return Err(crate::Error::InvalidSpiSelect(self.select)),
+        };
+
+        let spi = Spi::new(bus, select, CLOCK_SPEED, Mode::Mode0)?;
+        Ok(Mcp3008 { spi })
+    }
+}
+
+pub struct Mcp3008 {
+    spi: Spi,
+}
+
+// See Figure 6-1: a start bit, then single-ended mode and the channel in
+// the top nibble of the second byte. The reading comes back in the low 10
+// bits of the last two.
+fn request(channel: u8) -> [u8; 3] {
+    [0x01, 0x80 | (channel << 4), 0x00]
+}
+
+fn decode(rx_buf: [u8; 3]) -> u16 {
+    (u16::from(rx_buf[1] & 0x03) << 8) | u16::from(rx_buf[2])
+}
+
+impl Adc for Mcp3008 {
+    fn read_channel(&mut self, channel: u8) -> Result<f64> {
+        if channel >= N_CHANNELS {
+            return Err(Error::InvalidAdcChannel(channel));
+        }
+        let mut rx_buf = [0; 3];
+        let read = self.spi.transfer(&mut rx_buf, &request(channel))?;
+        if read != rx_buf.len() {
+            return Err(Error::ShortRead {
+                expected: rx_buf.len(),
+                actual: read,
+            });
+        }
+        let reading = decode(rx_buf);
+        trace!("MCP3008 channel {}: {}", channel, reading);
+        Ok(f64::from(reading) / f64::from(MAX_READING))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_and_decode() {
+        assert_eq!(request(0), [0x01, 0x80, 0x00]);
+        assert_eq!(request(7), [0x01, 0xf0, 0x00]);
+
+        // junk in the high bits of the second byte is ignored
+        assert_eq!(decode([0xff, 0xfe, 0x34]), 0x234);
+        assert_eq!(decode([0x00, 0x03, 0xff]), MAX_READING);
+    }
+}
@@ -1,27 +1,85 @@
 // https://cdn-shop.adafruit.com/datasheets/mcp4725.pdf
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use log::*;
 use rppal::i2c::I2c;
 use serde::Deserialize;
 
-use crate::Result;
+use super::i2c::I2cBus;
+use crate::{Error, Result};
 
-// From Table 6.2
+// From Table 6.2, the C2 C1 C0 bits at the top of the first byte
 enum Command {
     WriteDac = 0b0100_0000,
-    WriteDacAndEeprom = 0b1100_0000,
+    WriteDacAndEeprom = 0b0110_0000,
+}
+
+pub const VALUE_MAX: u16 = (1 << 12) - 1;
+
+// the datasheet says EEPROM writes take 25ms typical, 50ms max
+const EEPROM_TIMEOUT: Duration = Duration::from_millis(50);
+const EEPROM_POLL: Duration = Duration::from_millis(5);
+
+/// Pulls the EEPROM ready bit and stored value out of the five bytes the
+/// chip sends back on a read (Figure 6-3).
+fn decode_read(buf: &[u8; 5]) -> (bool, u16) {
+    let ready = buf[0] & 0b1000_0000 != 0;
+    let value = (u16::from(buf[3] & 0x0f) << 8) | u16::from(buf[4]);
+    (ready, value)
+}
+
+pub const DEFAULT_ADDRESS: u16 = 0x60;
+
+fn default_address() -> u16 {
+    DEFAULT_ADDRESS
+}
+
+/// What `VALUE_MAX` comes out as with the chip running off the pi's 3.3V.
+pub const DEFAULT_FULL_SCALE_VOLTAGE: f64 = 3.3;
+
+fn default_full_scale_voltage() -> f64 {
+    DEFAULT_FULL_SCALE_VOLTAGE
+}
+
+/// The DAC value that gives `volts`, when `VALUE_MAX` gives `full_scale`.
+/// Anything the DAC can't reach is an error.
+pub fn voltage_to_value(volts: f64, full_scale: f64) -> Result<u16> {
+    let value = (volts / full_scale * f64::from(VALUE_MAX)).round();
+    if !(0.0..=f64::from(VALUE_MAX)).contains(&value) {
+        return Err(Error::InvalidVoltage(volts));
+    }
+    Ok(value as u16)
+}
+
+/// The volts `value` gives, the other way around from `voltage_to_value`.
+pub fn value_to_voltage(value: u16, full_scale: f64) -> f64 {
+    f64::from(value) / f64::from(VALUE_MAX) * full_scale
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
+    #[serde(default = "super::i2c::default_bus")]
     pub bus: u8,
+    #[serde(default = "default_address")]
     pub address: u16,
+    // for hats that put the dac on a 10-bit address
+    #[serde(default)]
+    pub addr_10bit: bool,
+    // what the DAC's max value works out to, in the same volts as the
+    // grid's voltage overrides; set this if the DAC drives a boost converter
+    #[serde(default = "default_full_scale_voltage")]
+    pub full_scale_voltage: f64,
 }
 
 impl Settings {
     pub fn make(&self) -> Result<Mcp4725> {
-        let mut i2c = rppal::i2c::I2c::with_bus(self.bus)?;
-        i2c.set_slave_address(self.address)?;
+        let i2c = super::i2c::open(self.bus, self.address, self.addr_10bit)?;
 
-        let mut mcp = Mcp4725 { i2c };
+        let mut mcp = Mcp4725 {
+            i2c,
+            full_scale_voltage: self.full_scale_voltage,
+        };
         // write to initialize, but also to make sure `new` fails if
         // something is wrong with the i2c
         mcp.write(0)?;
@@ -31,6 +89,7 @@ impl Settings {
 
 pub struct Mcp4725 {
     i2c: I2c,
+    pub full_scale_voltage: f64,
 }
 
 impl Mcp4725 {
@@ -42,13 +101,61 @@ impl Mcp4725 {
         self.do_write(data, Command::WriteDacAndEeprom)
     }
 
+    /// Writes `value` to the DAC and to the EEPROM, so it's also the output
+    /// at power up. The EEPROM write takes about 25ms, so this waits until
+    /// the chip says it's done, and fails if it still isn't after 50ms.
+    pub fn write_eeprom(&mut self, value: u16) -> Result<()> {
+        self.write_and_save(value)?;
+        let start = Instant::now();
+        loop {
+            sleep(EEPROM_POLL);
+            let (ready, _) = self.read()?;
+            if ready {
+                return Ok(());
+            }
+            if start.elapsed() > EEPROM_TIMEOUT {
+                warn!("MCP4725 EEPROM still busy after {:?}", EEPROM_TIMEOUT);
+                return Err(Error::DeviceTimeout("MCP4725 EEPROM write", EEPROM_TIMEOUT));
+            }
+        }
+    }
+
+    /// Reads back the value stored in the EEPROM.
+    pub fn read_eeprom(&mut self) -> Result<u16> {
+        let (_, value) = self.read()?;
+        Ok(value)
+    }
+
+    fn read(&mut self) -> Result<(bool, u16)> {
+        let mut buf = [0; 5];
+        self.i2c.read_exact(&mut buf)?;
+        Ok(decode_read(&buf))
+    }
+
     fn do_write(&mut self, value: u16, cmd: Command) -> Result<()> {
-        assert!(value < (1 << 12));
+        if value > VALUE_MAX {
+            return Err(Error::InvalidDacValue(value));
+        }
         let value_hi_8 = (value >> 4) as u8;
         let value_lo_4 = (value << 4) as u8;
 
-        let written = self.i2c.write(&[cmd as u8, value_hi_8, value_lo_4])?;
-        assert_eq!(written, 3);
-        Ok(())
+        self.i2c.write_all(&[cmd as u8, value_hi_8, value_lo_4])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_read() {
+        // busy, DAC at 0, EEPROM holding 0xabc with power down bits set
+        let (ready, value) = decode_read(&[0b0000_0000, 0, 0, 0b0110_1010, 0xbc]);
+        assert!(!ready);
+        assert_eq!(value, 0xabc);
+
+        let (ready, value) = decode_read(&[0b1100_0000, 0xff, 0xf0, 0x0f, 0xff]);
+        assert!(ready);
+        assert_eq!(value, VALUE_MAX);
     }
 }
@@ -1,4 +1,6 @@
 pub mod hv507;
+pub mod i2c;
 pub mod max31865;
+pub mod mcp3008;
 pub mod mcp4725;
 pub mod pca9685;
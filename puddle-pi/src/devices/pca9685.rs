@@ -1,11 +1,15 @@
-use std::thread::sleep;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::{self, sleep};
 use std::time::Duration;
 
 use log::*;
 use rppal::i2c::I2c;
 use serde::Deserialize;
 
-use crate::Result;
+use super::i2c::I2cBus;
+use crate::{Error, Result};
 
 // https://cdn-shop.adafruit.com/datasheets/PCA9685.pdf
 #[allow(dead_code)]
@@ -54,20 +58,37 @@ impl From<Mode1> for u8 {
     }
 }
 
+pub const DEFAULT_ADDRESS: u16 = 0x42;
+
+fn default_address() -> u16 {
+    DEFAULT_ADDRESS
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Settings {
+    #[serde(default = "super::i2c::default_bus")]
     pub bus: u8,
+    #[serde(default = "default_address")]
     pub address: u16,
+    #[serde(default)]
+    pub addr_10bit: bool,
+    // turn a channel back off if it's been on this long
+    #[serde(default)]
+    pub heater_watchdog_ms: Option<u64>,
 }
 
 impl Settings {
     pub fn make(&self) -> Result<Pca9685> {
-        let mut i2c = rppal::i2c::I2c::with_bus(self.bus)?;
-        i2c.set_slave_address(self.address)?;
+        let i2c = super::i2c::open(self.bus, self.address, self.addr_10bit)?;
         debug!("Creating pca9685...");
         let mut pca = Pca9685 {
             initialized: false,
             i2c,
+            bus: self.bus,
+            address: self.address,
+            addr_10bit: self.addr_10bit,
+            watchdog_timeout: self.heater_watchdog_ms.map(Duration::from_millis),
+            watchdogs: Default::default(),
         };
         pca.init()?;
         debug!("Created pca9685!");
@@ -78,6 +99,115 @@ impl Settings {
 pub struct Pca9685 {
     initialized: bool,
     i2c: I2c,
+    // the watchdogs open their own handle to the chip, so they need these
+    bus: u8,
+    address: u16,
+    addr_10bit: bool,
+    watchdog_timeout: Option<Duration>,
+    watchdogs: [Option<Watchdog>; NUM_LEDS as usize],
+}
+
+/// Runs `shutoff` on its own thread after `timeout`, so it fires even if
+/// whoever turned the channel on gets stuck. Dropping it disarms it.
+struct Watchdog {
+    _disarm: mpsc::Sender<()>,
+    fired: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    fn arm(timeout: Duration, shutoff: impl FnOnce() + Send + 'static) -> Watchdog {
+        let (tx, rx) = mpsc::channel::<()>();
+        let fired = Arc::new(AtomicBool::new(false));
+        let fired2 = Arc::clone(&fired);
+        thread::spawn(move || {
+            // the sender is only ever dropped, so a disconnect means disarmed
+            if let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(timeout) {
+                // mark it first, so a channel turned on while this runs
+                // gets a new watchdog rather than none at all
+                fired2.store(true, Ordering::SeqCst);
+                shutoff()
+            }
+        });
+        Watchdog { _disarm: tx, fired }
+    }
+
+    /// Whether this already ran its shutoff, so it's done watching.
+    fn fired(&self) -> bool {
+        self.fired.load(Ordering::SeqCst)
+    }
+}
+
+/// Updates a channel's watchdog after the channel is set. Turning it off
+/// disarms the watchdog. Turning it on arms one with `arm`, unless one is
+/// already counting down: only arming when the channel turns on means a
+/// busy control loop that keeps updating the duty cycle still gets cut
+/// off. A watchdog that already fired doesn't count, it has nothing left
+/// to watch.
+fn update_slot(slot: &mut Option<Watchdog>, on: bool, arm: impl FnOnce() -> Option<Watchdog>) {
+    if !on {
+        *slot = None;
+    } else if slot.as_ref().map_or(true, Watchdog::fired) {
+        *slot = arm();
+    }
+}
+
+fn check_channel(channel: u8) -> Result<()> {
+    if channel >= NUM_LEDS {
+        return Err(Error::InvalidPwmChannel(channel));
+    }
+    Ok(())
+}
+
+fn duty_cycle_message(channel: u8, on_fraction: u16) -> Result<[u8; 5]> {
+    check_channel(channel)?;
+    if on_fraction > DUTY_CYCLE_MAX {
+        let fraction = f64::from(on_fraction) / f64::from(DUTY_CYCLE_MAX);
+        return Err(Error::InvalidFraction(fraction));
+    }
+
+    // if on_fraction if min or max, use the special values of 0, DUTY_CYCLE_MAX + 1
+    let special = DUTY_CYCLE_MAX + 1;
+    let (on, off) = if on_fraction == 0 {
+        (0, special)
+    } else if on_fraction == DUTY_CYCLE_MAX {
+        (special, 0)
+    } else {
+        (0, on_fraction)
+    };
+
+    Ok(led_message(channel, on, off))
+}
+
+/// The ON and OFF counts for a channel that turns on `phase` of the way
+/// through the cycle and stays on for `duty` of it, wrapping around the end.
+fn phased_counts(duty: f64, phase: f64) -> (u16, u16) {
+    let counts = f64::from(DUTY_CYCLE_MAX + 1);
+    let special = DUTY_CYCLE_MAX + 1;
+    let width = (duty * counts).round() as u16;
+    if width == 0 {
+        return (0, special);
+    }
+    if width > DUTY_CYCLE_MAX {
+        return (special, 0);
+    }
+    let on = ((phase * counts).round() as u16) % special;
+    let off = (on + width) % special;
+    (on, off)
+}
+
+fn led_message(channel: u8, on: u16, off: u16) -> [u8; 5] {
+    let on_l = on as u8;
+    let on_h = (on >> 8) as u8;
+    let off_l = off as u8;
+    let off_h = (off >> 8) as u8;
+
+    [
+        Register::LedBase as u8 + (REGISTERS_PER_LED * channel),
+        on_l,
+        on_h,
+        off_l,
+        off_h,
+    ]
 }
 
 impl Pca9685 {
@@ -89,21 +219,15 @@ impl Pca9685 {
     }
 
     fn write(&mut self, data: &[u8]) -> Result<()> {
-        let written = self.i2c.write(data)?;
-        assert_eq!(written, data.len());
-        Ok(())
+        self.i2c.write_all(data)
     }
 
     fn write_reg(&mut self, reg: Register, data: impl Into<u8>) -> Result<()> {
-        self.write(&[reg as u8, data.into()])
+        self.i2c.write_register(reg as u8, &[data.into()])
     }
 
     fn read_reg(&mut self, reg: Register) -> Result<u8> {
-        self.write(&[reg as u8])?;
-        let mut buf = [0];
-        let n_read = self.i2c.read(&mut buf)?;
-        assert_eq!(n_read, 1);
-        Ok(buf[0])
+        Ok(self.i2c.read_register(reg as u8, 1)?[0])
     }
 
     pub fn reset(&mut self) -> Result<()> {
@@ -141,31 +265,46 @@ impl Pca9685 {
     }
 
     pub fn set_duty_cycle(&mut self, channel: u8, on_fraction: u16) -> Result<()> {
-        assert!(channel < NUM_LEDS);
-        assert!(on_fraction <= DUTY_CYCLE_MAX);
-
-        // if on_fraction if min or max, use the special values of 0, DUTY_CYCLE_MAX + 1
-        let special = DUTY_CYCLE_MAX + 1;
-        let (on, off) = if on_fraction == 0 {
-            (0, special)
-        } else if on_fraction == DUTY_CYCLE_MAX {
-            (special, 0)
-        } else {
-            (0, on_fraction)
-        };
+        self.write(&duty_cycle_message(channel, on_fraction)?)?;
+        self.update_watchdog(channel, on_fraction != 0);
+        Ok(())
+    }
+
+    /// Like `set_duty_cycle`, but the channel turns on `phase` of the way
+    /// through the PWM cycle instead of at the start. Staggering the phases
+    /// keeps every channel from switching on at once and spiking the
+    /// current. Both `duty` and `phase` go from 0 to 1.
+    pub fn set_duty_cycle_phased(&mut self, channel: u8, duty: f64, phase: f64) -> Result<()> {
+        check_channel(channel)?;
+        for &x in &[duty, phase] {
+            if !(0.0..=1.0).contains(&x) {
+                return Err(Error::InvalidFraction(x));
+            }
+        }
+        let (on, off) = phased_counts(duty, phase);
+        self.write(&led_message(channel, on, off))?;
+        self.update_watchdog(channel, (on, off) != phased_counts(0.0, 0.0));
+        Ok(())
+    }
 
-        let on_l = on as u8;
-        let on_h = (on >> 8) as u8;
-        let off_l = off as u8;
-        let off_h = (off >> 8) as u8;
+    fn update_watchdog(&mut self, channel: u8, on: bool) {
+        let timeout = self.watchdog_timeout;
+        let (bus, address, addr_10bit) = (self.bus, self.address, self.addr_10bit);
+        update_slot(&mut self.watchdogs[channel as usize], on, || {
+            let timeout = timeout?;
+            Some(Watchdog::arm(timeout, move || {
+                warn!("Watchdog turning off pwm channel {}", channel);
+                if let Err(err) = force_off(bus, address, addr_10bit, channel) {
+                    error!("Watchdog failed to turn off channel {}: {}", channel, err)
+                }
+            }))
+        });
+    }
 
-        self.write(&[
-            Register::LedBase as u8 + (REGISTERS_PER_LED * channel),
-            on_l,
-            on_h,
-            off_l,
-            off_h,
-        ])
+    /// Makes sure any channel that gets turned on is turned back off after
+    /// `timeout`, no matter what the code that turned it on is doing.
+    pub fn set_heater_watchdog(&mut self, timeout: Duration) {
+        self.watchdog_timeout = Some(timeout);
     }
 
     pub fn all_off(&mut self) -> Result<()> {
@@ -176,6 +315,12 @@ impl Pca9685 {
     }
 }
 
+fn force_off(bus: u8, address: u16, addr_10bit: bool, channel: u8) -> Result<()> {
+    let mut i2c = super::i2c::open(bus, address, addr_10bit)?;
+    i2c.write(&duty_cycle_message(channel, 0)?)?;
+    Ok(())
+}
+
 impl Drop for Pca9685 {
     fn drop(&mut self) {
         if self.initialized {
@@ -185,3 +330,101 @@ impl Drop for Pca9685 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn flag_watchdog(timeout: Duration) -> (Watchdog, Arc<AtomicBool>) {
+        let fired = Arc::new(AtomicBool::new(false));
+        let fired2 = fired.clone();
+        let watchdog = Watchdog::arm(timeout, move || fired2.store(true, Ordering::SeqCst));
+        (watchdog, fired)
+    }
+
+    #[test]
+    fn test_watchdog_cuts_stuck_heat() {
+        // a heat that never finishes, so nobody ever disarms the watchdog
+        let (_watchdog, fired) = flag_watchdog(Duration::from_millis(20));
+        sleep(Duration::from_millis(200));
+        assert!(fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_phased_counts() {
+        // a quarter duty starting halfway through
+        assert_eq!(phased_counts(0.25, 0.5), (2048, 3072));
+        assert_eq!(
+            led_message(3, 2048, 3072),
+            [Register::LedBase as u8 + 12, 0x00, 0x08, 0x00, 0x0c]
+        );
+        // wraps around the end of the cycle
+        assert_eq!(phased_counts(0.5, 0.75), (3072, 1024));
+        assert_eq!(phased_counts(0.5, 1.0), (0, 2048));
+        // full off and full on ignore the phase
+        assert_eq!(phased_counts(0.0, 0.3), (0, 4096));
+        assert_eq!(phased_counts(1.0, 0.3), (4096, 0));
+    }
+
+    #[test]
+    fn test_duty_cycle_message() {
+        assert_eq!(
+            duty_cycle_message(3, 1024).unwrap(),
+            [Register::LedBase as u8 + 12, 0x00, 0x00, 0x00, 0x04]
+        );
+        // same errors as set_duty_cycle_phased
+        match duty_cycle_message(NUM_LEDS, 0) {
+            Err(Error::InvalidPwmChannel(NUM_LEDS)) => (),
+            other => panic!("Expected a bad channel, got {:?}", other),
+        }
+        match duty_cycle_message(0, DUTY_CYCLE_MAX + 1) {
+            Err(Error::InvalidFraction(f)) => assert!(f > 1.0),
+            other => panic!("Expected a bad fraction, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_watchdog_rearms_after_firing() {
+        let shutoffs = Arc::new(AtomicUsize::new(0));
+        let arm = || {
+            let shutoffs = Arc::clone(&shutoffs);
+            let timeout = Duration::from_millis(20);
+            Some(Watchdog::arm(timeout, move || {
+                shutoffs.fetch_add(1, Ordering::SeqCst);
+            }))
+        };
+        let wait_for_fire = |slot: &Option<Watchdog>| {
+            for _ in 0..100 {
+                if slot.as_ref().unwrap().fired() {
+                    return;
+                }
+                sleep(Duration::from_millis(10));
+            }
+            panic!("The watchdog never fired");
+        };
+
+        let mut slot = None;
+        update_slot(&mut slot, true, arm);
+        wait_for_fire(&slot);
+
+        // turning the channel back on gets a fresh watchdog, which cuts it
+        // off again
+        update_slot(&mut slot, true, arm);
+        assert!(!slot.as_ref().unwrap().fired());
+        wait_for_fire(&slot);
+        sleep(Duration::from_millis(10));
+        assert_eq!(shutoffs.load(Ordering::SeqCst), 2);
+
+        update_slot(&mut slot, false, arm);
+        assert!(slot.is_none());
+    }
+
+    #[test]
+    fn test_watchdog_disarm() {
+        let (watchdog, fired) = flag_watchdog(Duration::from_millis(50));
+        drop(watchdog);
+        sleep(Duration::from_millis(200));
+        assert!(!fired.load(Ordering::SeqCst));
+    }
+}
@@ -1,11 +1,46 @@
+use std::time::Duration;
+
 #[derive(Debug)]
 pub enum Error {
     Gpio(rppal::gpio::Error),
     I2c(rppal::i2c::Error),
     Pwm(rppal::pwm::Error),
     Spi(rppal::spi::Error),
+    Io(std::io::Error),
     InvalidPwmChannel(u8),
+    InvalidPwmPin(u32),
+    InvalidPwmDuty(u32),
+    InvalidPwmFrequency(u32),
+    InvalidSpiBus(u8),
+    InvalidSpiSelect(u8),
+    InvalidAdcChannel(u8),
+    MissingDevice(&'static str),
+    InvalidDacValue(u16),
+    InvalidVoltage(f64),
+    InvalidFraction(f64),
+    RtdFault(crate::devices::max31865::FaultStatus),
+    ShortRead { expected: usize, actual: usize },
+    ShortWrite { expected: usize, actual: usize },
+    // a device sent this when it should have been quiet, like a bad wire
+    UnexpectedByte(u8),
+    // this gpio read back a different level than was written to it
+    StuckLine(u8),
+    // what the device was doing, and how long we gave it
+    DeviceTimeout(&'static str, Duration),
     Configuration(config::ConfigError),
+    Puddle(puddle_core::process::PuddleError),
+    // the grid can't be driven as wired, like two electrodes on one pin
+    Grid(Vec<puddle_core::grid::GridError>),
+    UnknownZone(String),
+    // no adc channel is wired to this electrode's feedback
+    NoFeedbackChannel(puddle_core::grid::Location),
+    ThermalRunaway(String, f32),
+    // the zones didn't all settle in time
+    HeatTimeout(Duration),
+    // something the hardware should do, but that isn't written yet
+    Unsupported(&'static str),
+    // everything that failed to shut down, by name
+    Shutdown(Vec<(String, Error)>),
 }
 
 impl std::error::Error for Error {}
@@ -26,7 +61,10 @@ impl_error!(rppal::gpio::Error, Gpio);
 impl_error!(rppal::i2c::Error, I2c);
 impl_error!(rppal::pwm::Error, Pwm);
 impl_error!(rppal::spi::Error, Spi);
+impl_error!(std::io::Error, Io);
 impl_error!(config::ConfigError, Configuration);
+impl_error!(puddle_core::process::PuddleError, Puddle);
+impl_error!(Vec<puddle_core::grid::GridError>, Grid);
 
 use std::fmt;
 
@@ -37,8 +75,75 @@ impl fmt::Display for Error {
             Error::I2c(inner) => write!(f, "{}", inner),
             Error::Pwm(inner) => write!(f, "{}", inner),
             Error::Spi(inner) => write!(f, "{}", inner),
+            Error::Io(inner) => write!(f, "{}", inner),
             Error::InvalidPwmChannel(chan) => write!(f, "Invalid PWM channel: {}", chan),
+            Error::InvalidPwmPin(gpio) => write!(f, "GPIO {} has no hardware PWM", gpio),
+            Error::InvalidPwmDuty(duty) => write!(f, "PWM duty {} is out of range", duty),
+            Error::InvalidPwmFrequency(freq) => write!(f, "Can't do PWM at {}Hz", freq),
+            Error::InvalidSpiBus(bus) => write!(f, "Invalid SPI bus: {}", bus),
+            Error::InvalidSpiSelect(ss) => write!(f, "Invalid SPI slave select: {}", ss),
+            Error::InvalidAdcChannel(ch) => write!(f, "Invalid ADC channel: {}", ch),
+            Error::MissingDevice(name) => write!(f, "No {} is configured", name),
+            Error::InvalidDacValue(v) => write!(f, "DAC value {} doesn't fit in 12 bits", v),
+            Error::InvalidVoltage(v) => write!(f, "The DAC can't put out {}V", v),
+            Error::InvalidFraction(x) => write!(f, "{} should be between 0 and 1", x),
+            Error::RtdFault(faults) => write!(f, "RTD fault: {:?}", faults),
+            Error::ShortRead { expected, actual } => {
+                write!(
+                    f,
+                    "Short read, expected {} bytes but got {}",
+                    expected, actual
+                )
+            }
+            Error::ShortWrite { expected, actual } => {
+                write!(
+                    f,
+                    "Short write, expected {} bytes but wrote {}",
+                    expected, actual
+                )
+            }
+            Error::UnexpectedByte(byte) => {
+                write!(
+                    f,
+                    "Device sent {:#04x} when it should have been quiet",
+                    byte
+                )
+            }
+            Error::StuckLine(gpio) => {
+                write!(f, "GPIO {} didn't take the level written to it", gpio)
+            }
+            Error::DeviceTimeout(what, timeout) => {
+                write!(f, "{} didn't finish within {:?}", what, timeout)
+            }
             Error::Configuration(inner) => write!(f, "{}", inner),
+            Error::Puddle(inner) => write!(f, "{}", inner),
+            Error::Grid(errors) => {
+                write!(f, "Invalid grid:")?;
+                for err in errors {
+                    write!(f, "\n  {}", err)?;
+                }
+                Ok(())
+            }
+            Error::UnknownZone(name) => write!(f, "No heater zone named '{}'", name),
+            Error::NoFeedbackChannel(loc) => write!(f, "No feedback channel for {}", loc),
+            Error::ThermalRunaway(name, temp) => {
+                write!(f, "Thermal runaway in zone '{}' at {}*C", name, temp)
+            }
+            Error::HeatTimeout(timeout) => {
+                write!(
+                    f,
+                    "Heater zones didn't hold their targets within {:?}",
+                    timeout
+                )
+            }
+            Error::Unsupported(what) => write!(f, "The pi can't {} yet", what),
+            Error::Shutdown(failures) => {
+                write!(f, "Failed to shut down")?;
+                for (name, err) in failures {
+                    write!(f, "\n  {}: {}", name, err)?;
+                }
+                Ok(())
+            }
         }
     }
 }
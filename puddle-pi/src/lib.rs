@@ -1,15 +1,29 @@
-use std::time::Duration;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use log::*;
 use serde::Deserialize;
 
-use puddle_core::grid::gridview::GridView;
-use puddle_core::grid::{location::yx, Peripheral};
+use puddle_core::grid::gridview::{GridView, Snapshot};
+use puddle_core::grid::{Drive, Droplet, Grid, Location, Peripheral, PinMap};
+use puddle_core::util::{duration_seconds, seconds_duration};
 
+pub mod audit;
 pub mod devices;
 mod error;
+mod mock;
+pub mod softpwm;
+pub mod thermal;
 
+pub use audit::ActuationLog;
 pub use error::{Error, Result};
+pub use mock::{MockRaspberryPi, PiCall};
+use softpwm::SoftPwm;
+use thermal::PidController;
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
@@ -17,9 +31,21 @@ pub struct Settings {
     pub mcp4725: Option<devices::mcp4725::Settings>,
     pub pca9685: Option<devices::pca9685::Settings>,
     pub max31865: Option<devices::max31865::Settings>,
+    #[serde(default)]
+    pub actuation_log: Option<audit::Settings>,
+    #[serde(default)]
+    pub sensor: Option<SensorSettings>,
+    #[serde(default)]
+    pub zones: Vec<thermal::ZoneSettings>,
 }
 
-const TABLE_KEYS: &[&str] = &["pi.mcp4725", "pi.pca9685", "pi.max31865"];
+const TABLE_KEYS: &[&str] = &[
+    "pi.mcp4725",
+    "pi.pca9685",
+    "pi.max31865",
+    "pi.actuation_log",
+    "pi.sensor",
+];
 
 impl Settings {
     pub fn from_config(conf: &mut config::Config) -> Result<Self> {
@@ -42,21 +68,745 @@ impl Settings {
     }
 }
 
+/// Something that can tell whether a droplet is sitting on an electrode.
+pub trait DropletSensor: Send {
+    fn droplet_present(&mut self, loc: Location) -> Result<bool>;
+
+    /// How much of the droplet's sensed footprint overlaps `loc`, between 0
+    /// and 1. By default this is all or nothing.
+    fn overlap(&mut self, loc: Location) -> Result<f32> {
+        let present = self.droplet_present(loc)?;
+        Ok(if present { 1.0 } else { 0.0 })
+    }
+}
+
+/// An analog input, like the one on a chip's capacitive feedback circuit.
+pub trait Adc: Send {
+    /// The reading on `channel`, scaled to between 0 and 1.
+    fn read_channel(&mut self, channel: u8) -> Result<f64>;
+}
+
+/// Senses droplets by electrode capacitance. Each electrode's feedback is
+/// wired to an ADC channel, and a droplet on it pushes the reading up past
+/// `threshold`.
+pub struct CapacitiveSensor {
+    pub adc: Box<dyn Adc>,
+    pub channels: HashMap<Location, u8>,
+    pub threshold: f64,
+}
+
+impl DropletSensor for CapacitiveSensor {
+    fn droplet_present(&mut self, loc: Location) -> Result<bool> {
+        let channel = *self
+            .channels
+            .get(&loc)
+            .ok_or(Error::NoFeedbackChannel(loc))?;
+        let reading = self.adc.read_channel(channel)?;
+        trace!("Feedback at {} on channel {}: {}", loc, channel, reading);
+        Ok(reading >= self.threshold)
+    }
+}
+
+// so an `Executor` can sense with it too
+impl puddle_core::exec::Sensor for CapacitiveSensor {
+    fn droplet_present(&mut self, loc: Location) -> puddle_core::process::PuddleResult<bool> {
+        DropletSensor::droplet_present(self, loc)
+            .map_err(|err| puddle_core::process::PuddleError::SensorFailed(err.to_string()))
+    }
+}
+
+/// A `CapacitiveSensor` read through an MCP3008.
+#[derive(Debug, Deserialize)]
+pub struct SensorSettings {
+    pub mcp3008: devices::mcp3008::Settings,
+    pub threshold: f64,
+    pub channels: Vec<FeedbackChannel>,
+}
+
+/// Which ADC channel an electrode's feedback is wired to.
+#[derive(Debug, Deserialize)]
+pub struct FeedbackChannel {
+    pub location: Location,
+    pub channel: u8,
+}
+
+impl SensorSettings {
+    pub fn make(&self) -> Result<CapacitiveSensor> {
+        let mut channels = HashMap::new();
+        for fb in &self.channels {
+            if fb.channel >= devices::mcp3008::N_CHANNELS {
+                return Err(Error::InvalidAdcChannel(fb.channel));
+            }
+            channels.insert(fb.location, fb.channel);
+        }
+        Ok(CapacitiveSensor {
+            adc: Box::new(self.mcp3008.make()?),
+            channels,
+            threshold: self.threshold,
+        })
+    }
+}
+
+fn verify_with(
+    sensor: Option<&mut dyn DropletSensor>,
+    loc: Location,
+    tolerance: f32,
+) -> Result<bool> {
+    let sensor = match sensor {
+        Some(sensor) => sensor,
+        None => {
+            warn!(
+                "No droplet sensor configured, can't verify the droplet at {}",
+                loc
+            );
+            return Ok(true);
+        }
+    };
+    let overlap = sensor.overlap(loc)?;
+    let present = overlap >= tolerance;
+    if !present {
+        warn!(
+            "Lost the droplet! Expected it at {}, but only {} of it was there",
+            loc, overlap
+        );
+    }
+    Ok(present)
+}
+
+/// A temperature sensor paired with the heater that warms it.
+pub trait HeaterZone: Send {
+    fn read_temperature(&mut self) -> Result<f32>;
+    /// Sets the heater's duty cycle, between 0 and 1.
+    fn set_heater(&mut self, duty_cycle: f64) -> Result<()>;
+}
+
+/// The outputs a pi drives, so code can run against `MockRaspberryPi` off
+/// the hardware.
+pub trait PiDevice {
+    fn gpio_write(&mut self, gpio: u32, high: bool) -> Result<()>;
+    /// See `RaspberryPi::set_pwm`, `duty` is NOT a percent.
+    fn set_pwm(&mut self, gpio: u32, freq: u32, duty: u32) -> Result<()>;
+    /// See `RaspberryPi::set_soft_pwm`, `duty` is from 0 to 1.
+    fn set_soft_pwm(&mut self, gpio: u32, freq: u32, duty: f64) -> Result<()>;
+    /// Writes `value` to the MCP4725 DAC.
+    fn dac_write(&mut self, value: u16) -> Result<()>;
+    /// Sets the electrodes under each droplet.
+    fn output_pins(&mut self, gv: &GridView) -> Result<()>;
+    /// See `RaspberryPi::shift_out`.
+    fn shift_out(&mut self, bits: &[bool]) -> Result<()>;
+}
+
+/// An output that can be driven to a known-off state.
+pub trait Quiesce {
+    fn quiesce(&mut self) -> Result<()>;
+}
+
+impl Quiesce for devices::mcp4725::Mcp4725 {
+    fn quiesce(&mut self) -> Result<()> {
+        self.write(0)
+    }
+}
+
+impl Quiesce for devices::pca9685::Pca9685 {
+    fn quiesce(&mut self) -> Result<()> {
+        self.all_off()
+    }
+}
+
+impl Quiesce for devices::hv507::Hv507 {
+    fn quiesce(&mut self) -> Result<()> {
+        self.clear_pins();
+        self.shift_and_latch()?;
+        self.set_blank(true)
+    }
+}
+
+impl Quiesce for rppal::pwm::Pwm {
+    fn quiesce(&mut self) -> Result<()> {
+        Ok(self.disable()?)
+    }
+}
+
+// for outputs shared with something else, like the pca9685 and the zones
+impl<Q: Quiesce> Quiesce for Arc<Mutex<Q>> {
+    fn quiesce(&mut self) -> Result<()> {
+        self.lock().unwrap().quiesce()
+    }
+}
+
+impl Quiesce for Box<dyn HeaterZone> {
+    fn quiesce(&mut self) -> Result<()> {
+        self.set_heater(0.0)
+    }
+}
+
+/// Quiesces every output in order. A failure doesn't stop the rest, they're
+/// all reported together at the end.
+fn quiesce_all(outputs: Vec<(String, &mut dyn Quiesce)>) -> Result<()> {
+    let mut failures = Vec::new();
+    for (name, output) in outputs {
+        if let Err(err) = output.quiesce() {
+            error!("Failed to quiesce {}: {}", name, err);
+            failures.push((name, err));
+        }
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Shutdown(failures))
+    }
+}
+
+/// Calls `connect` until it works, at most `attempts` times, sleeping
+/// between tries with the delay doubling each time. Gives back the last
+/// error if it never does.
+fn retry<T>(attempts: u32, delay: Duration, mut connect: impl FnMut() -> Result<T>) -> Result<T> {
+    let attempts = attempts.max(1);
+    let mut delay = delay;
+    for attempt in 1.. {
+        match connect() {
+            Ok(t) => return Ok(t),
+            Err(err) if attempt < attempts => {
+                warn!(
+                    "Attempt {}/{} failed, trying again in {:?}: {}",
+                    attempt, attempts, delay, err
+                );
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            Err(err) => {
+                error!("Giving up after {} attempts: {}", attempts, err);
+                return Err(err);
+            }
+        }
+    }
+    unreachable!()
+}
+
+// how far past its target a zone can get before we give up on everything
+const RUNAWAY_MARGIN: f32 = 10.0;
+/// How long zones get to reach their targets by default, on top of however
+/// long they're held there. See `RaspberryPi::set_zone_timeout`.
+pub const DEFAULT_ZONE_TIMEOUT: Duration = Duration::from_secs(600);
+const ZONE_PID_GAINS: (f64, f64, f64) = (1.0, 0.1, 0.0);
+// how often the zones report how they're doing
+const ZONE_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Runs a feedback loop per zone until every zone has been within
+/// `tolerance` of its target for `duration`, failing if that hasn't happened
+/// `timeout` past the `duration`. The heaters are turned off when this
+/// returns, whether or not it succeeded.
+fn hold_zones(
+    zones: &mut [(&str, &mut dyn HeaterZone, f32)],
+    tolerance: f32,
+    duration: Duration,
+    timeout: Duration,
+    interval: Duration,
+) -> Result<()> {
+    let deadline = Instant::now() + timeout + duration;
+    let ceilings: Vec<_> = zones.iter().map(|&(_, _, t)| t + RUNAWAY_MARGIN).collect();
+    let result = run_zones(zones, &ceilings, tolerance, duration, deadline, interval);
+    for (name, zone, _) in zones.iter_mut() {
+        if let Err(err) = zone.set_heater(0.0) {
+            error!("Failed to turn off heater zone '{}': {}", name, err);
+        }
+    }
+    result
+}
+
+/// Takes one zone through each `(target, seconds)` segment in order, holding
+/// each target like `hold_zones` does before moving on to the next. The whole
+/// profile gets `timeout` on top of its holds. The heater is turned off when
+/// this returns.
+fn run_profile(
+    name: &str,
+    zone: &mut dyn HeaterZone,
+    segments: &[(f32, f64)],
+    tolerance: f32,
+    timeout: Duration,
+    interval: Duration,
+) -> Result<()> {
+    // stepping down from the hottest segment isn't a runaway, but going
+    // past it is
+    let hottest = segments.iter().map(|&(t, _)| t).fold(0.0, f32::max);
+    let ceilings = [hottest + RUNAWAY_MARGIN];
+    let holds = segments.iter().map(|&(_, secs)| seconds_duration(secs));
+    let deadline = Instant::now() + timeout + holds.sum::<Duration>();
+
+    let mut result = Ok(());
+    for &(target, seconds) in segments {
+        info!("Zone '{}' heading to {}*C", name, target);
+        let this_zone: &mut dyn HeaterZone = &mut *zone;
+        let mut zones = [(name, this_zone, target)];
+        let hold = seconds_duration(seconds);
+        result = run_zones(&mut zones, &ceilings, tolerance, hold, deadline, interval);
+        if result.is_err() {
+            break;
+        }
+    }
+    if let Err(err) = zone.set_heater(0.0) {
+        error!("Failed to turn off heater zone '{}': {}", name, err);
+    }
+    result
+}
+
+/// Each zone fails as a runaway if it gets past its entry in `ceilings`.
+fn run_zones(
+    zones: &mut [(&str, &mut dyn HeaterZone, f32)],
+    ceilings: &[f32],
+    tolerance: f32,
+    duration: Duration,
+    deadline: Instant,
+    interval: Duration,
+) -> Result<()> {
+    let start = Instant::now();
+    let (p, i, d) = ZONE_PID_GAINS;
+    let mut pids: Vec<_> = zones
+        .iter()
+        .map(|&(_, _, target)| {
+            let mut pid = thermal::duty_cycle_pid(p, i, d);
+            pid.set_target(target.into());
+            pid
+        })
+        .collect();
+
+    let mut in_band_since: Option<Instant> = None;
+    let mut next_log = Instant::now();
+    loop {
+        let log_now = Instant::now() >= next_log;
+        if log_now {
+            next_log = Instant::now() + ZONE_LOG_INTERVAL;
+        }
+
+        let mut all_in_band = true;
+        let per_zone = zones.iter_mut().zip(&mut pids).zip(ceilings);
+        for (((name, zone, target), pid), &ceiling) in per_zone {
+            let measured = zone.read_temperature()?;
+            if log_now {
+                info!("Zone '{}' at {:.1}*C, target {}*C", name, measured, target);
+            }
+            if measured > ceiling {
+                error!("Zone '{}' ran away: {}*C", name, measured);
+                return Err(Error::ThermalRunaway(name.to_string(), measured));
+            }
+            all_in_band &= (measured - *target).abs() <= tolerance;
+            zone.set_heater(pid.step(measured, duration_seconds(&interval)))?;
+        }
+
+        if !all_in_band {
+            in_band_since = None;
+        } else if let Some(start) = in_band_since {
+            if start.elapsed() >= duration {
+                return Ok(());
+            }
+        } else {
+            in_band_since = Some(Instant::now());
+        }
+
+        if Instant::now() >= deadline {
+            error!("Heater zones didn't hold their targets in time");
+            return Err(Error::HeatTimeout(deadline - start));
+        }
+
+        thread::sleep(interval);
+    }
+}
+
+/// Sets the pin under every cell of every droplet high, going by `map`.
+/// Droplets hanging off the grid or over dead or disabled cells can't be
+/// actuated there, so those are skipped.
+fn pin_pattern<'a>(
+    grid: &Grid,
+    map: &PinMap,
+    droplets: impl Iterator<Item = &'a Droplet>,
+) -> Vec<bool> {
+    let mut pins = vec![false; map.n_pins()];
+    for d in droplets {
+        for loc in d.locations() {
+            match (grid.get_cell(loc), map.pin_of(loc)) {
+                (Some(electrode), _) if !electrode.enabled => {
+                    trace!("Not actuating disabled electrode at {}", loc)
+                }
+                (Some(_), Some(pin)) => {
+                    pins[pin] = true;
+                    trace!("Setting pin {} at {}", pin, loc);
+                }
+                _ => warn!("No electrode for droplet {:?} at {}", d.id, loc),
+            }
+        }
+    }
+    pins
+}
+
+/// The voltage and polarity frequency for actuating every droplet, from the
+/// grid's overrides on the cells `pin_pattern` would set. There's one DAC
+/// and one polarity PWM for the whole board, so where cells disagree the
+/// highest wins.
+fn step_drive<'a>(grid: &Grid, droplets: impl Iterator<Item = &'a Droplet>) -> Drive {
+    let max = |a: Option<f64>, b: Option<f64>| match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        _ => a.or(b),
+    };
+    let mut drive = Drive::default();
+    for d in droplets {
+        for loc in d
+            .locations()
+            .into_iter()
+            .filter(|&loc| grid.is_enabled(loc))
+        {
+            drive.voltage = max(drive.voltage, grid.voltage_at(loc));
+            drive.frequency = max(drive.frequency, grid.frequency_at(loc));
+        }
+    }
+    drive
+}
+
+// what the board-wide drive is set to, so overrides are only written when
+// they change and are undone once their cells are off
+#[derive(Debug, Default)]
+struct DriveState {
+    // what to go back to with no overrides, if we know
+    base_dac: Option<u16>,
+    base_frequency: Option<f64>,
+    dac: Option<u16>,
+    frequency: Option<f64>,
+}
+
+impl DriveState {
+    /// The DAC value and frequency to switch to for `drive`, where they
+    /// differ from what's set now. Call `applied` once they're written.
+    fn changes(&self, drive: Drive, full_scale: f64) -> Result<(Option<u16>, Option<f64>)> {
+        let dac = match drive.voltage {
+            Some(volts) => Some(devices::mcp4725::voltage_to_value(volts, full_scale)?),
+            None => self.base_dac,
+        };
+        let frequency = drive.frequency.or(self.base_frequency);
+        Ok((
+            dac.filter(|_| dac != self.dac),
+            frequency.filter(|_| frequency != self.frequency),
+        ))
+    }
+
+    fn applied(&mut self, dac: Option<u16>, frequency: Option<f64>) {
+        self.dac = dac.or(self.dac);
+        self.frequency = frequency.or(self.frequency);
+    }
+
+    // a write from outside the overrides becomes the new normal
+    fn set_base_dac(&mut self, value: u16) {
+        self.base_dac = Some(value);
+        self.dac = Some(value);
+    }
+
+    fn voltage(&self, full_scale: f64) -> Option<f64> {
+        self.dac
+            .map(|value| devices::mcp4725::value_to_voltage(value, full_scale))
+    }
+
+    /// Writes whatever `drive` needs that isn't set already.
+    fn apply(&mut self, drive: Drive, outputs: &mut dyn DriveOutputs) -> Result<()> {
+        let full_scale = match (outputs.full_scale_voltage(), drive.voltage) {
+            (Some(full_scale), _) => full_scale,
+            (None, Some(_)) => return Err(Error::MissingDevice("mcp4725")),
+            (None, None) => devices::mcp4725::DEFAULT_FULL_SCALE_VOLTAGE,
+        };
+        let (dac, frequency) = self.changes(drive, full_scale)?;
+        if let Some(value) = dac {
+            outputs.write_dac(value)?;
+        }
+        if let Some(frequency) = frequency {
+            outputs.set_polarity_frequency(frequency)?;
+        }
+        self.applied(dac, frequency);
+        Ok(())
+    }
+}
+
+// the board-wide outputs a drive override can change
+trait DriveOutputs {
+    // what the DAC's max value comes out as, or None with no DAC
+    fn full_scale_voltage(&self) -> Option<f64>;
+    fn write_dac(&mut self, value: u16) -> Result<()>;
+    fn set_polarity_frequency(&mut self, frequency: f64) -> Result<()>;
+}
+
+struct PiDriveOutputs<'a> {
+    mcp4725: Option<&'a mut devices::mcp4725::Mcp4725>,
+    hv507: &'a mut devices::hv507::Hv507,
+}
+
+impl<'a> DriveOutputs for PiDriveOutputs<'a> {
+    fn full_scale_voltage(&self) -> Option<f64> {
+        self.mcp4725.as_ref().map(|dac| dac.full_scale_voltage)
+    }
+
+    fn write_dac(&mut self, value: u16) -> Result<()> {
+        match &mut self.mcp4725 {
+            Some(dac) => dac.write(value),
+            None => Err(Error::MissingDevice("mcp4725")),
+        }
+    }
+
+    fn set_polarity_frequency(&mut self, frequency: f64) -> Result<()> {
+        self.hv507.set_polarity_frequency(frequency)
+    }
+}
+
+/// Checks every voltage override in `grid` against a DAC whose max value
+/// gives `full_scale` volts, or against having no DAC at all, so a grid
+/// that asks for too much fails when it's loaded rather than mid-run.
+fn check_drive(grid: &Grid, full_scale: Option<f64>) -> Result<()> {
+    for drive in grid.drive.values() {
+        if let Some(volts) = drive.voltage {
+            let full_scale = full_scale.ok_or(Error::MissingDevice("mcp4725"))?;
+            devices::mcp4725::voltage_to_value(volts, full_scale)?;
+        }
+    }
+    Ok(())
+}
+
+// long enough for a camera trigger input to notice
+const SYNC_PULSE: Duration = Duration::from_micros(100);
+
+// so the grid is blanked at most a tenth of the timeout late
+const IDLE_CHECKS_PER_TIMEOUT: u32 = 10;
+
+/// Full scale for `set_pwm` duty cycles, same as pigpio's `hardware_PWM`.
+pub const PWM_DUTY_RANGE: u32 = 1_000_000;
+
+/// Converts a 0-100 percent into a `set_pwm` duty, clamping anything outside
+/// that range.
+pub fn percent_to_duty(percent: f32) -> u32 {
+    let percent = if percent.is_nan() || percent < 0.0 {
+        0.0
+    } else if percent > 100.0 {
+        100.0
+    } else {
+        percent
+    };
+    (f64::from(percent) / 100.0 * f64::from(PWM_DUTY_RANGE)).round() as u32
+}
+
+// the hardware PWM channel behind each pin that has one
+fn pwm_channel(gpio: u32) -> Option<rppal::pwm::Channel> {
+    match gpio {
+        12 | 18 => Some(rppal::pwm::Channel::Pwm0),
+        13 | 19 => Some(rppal::pwm::Channel::Pwm1),
+        _ => None,
+    }
+}
+
+fn check_pwm(gpio: u32, duty: u32) -> Result<rppal::pwm::Channel> {
+    let channel = pwm_channel(gpio).ok_or(Error::InvalidPwmPin(gpio))?;
+    if duty > PWM_DUTY_RANGE {
+        return Err(Error::InvalidPwmDuty(duty));
+    }
+    if duty > 0 && duty <= 100 {
+        warn!(
+            "PWM duty {} on gpio {} is tiny, did you mean set_pwm_percent?",
+            duty, gpio
+        );
+    }
+    Ok(channel)
+}
+
+// There's no daemon connection to close, rppal releases the gpio, i2c and spi
+// handles as the fields drop right after this. That only happens once every
+// output has been driven off, so nothing is left energized.
+impl Drop for RaspberryPi {
+    fn drop(&mut self) {
+        debug!("Quiescing the pi");
+        match self.quiesce() {
+            Ok(()) => debug!("Pi shut down cleanly"),
+            Err(err) => error!("{}", err),
+        }
+    }
+}
+
+/// Decides when the grid has been idle long enough to blank it. Time is
+/// passed in so this can be tested without waiting around.
+struct IdleBlanker {
+    timeout: Option<Duration>,
+    last_activity: Instant,
+    blanked: bool,
+}
+
+impl IdleBlanker {
+    fn new(now: Instant) -> IdleBlanker {
+        IdleBlanker {
+            timeout: None,
+            last_activity: now,
+            blanked: false,
+        }
+    }
+
+    /// Notes some activity. Returns true if the grid was blanked and should
+    /// be turned back on.
+    fn activity(&mut self, now: Instant) -> bool {
+        self.last_activity = now;
+        std::mem::replace(&mut self.blanked, false)
+    }
+
+    /// Returns true if the grid should be blanked now.
+    fn should_blank(&mut self, now: Instant) -> bool {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return false,
+        };
+        if self.blanked || now - self.last_activity < timeout {
+            return false;
+        }
+        self.blanked = true;
+        true
+    }
+}
+
+/// Runs `blank` from its own thread once `idle` says the grid has been idle
+/// too long, checking every `period`. That way the grid gets blanked even
+/// when nothing is calling into the pi. Dropping it stops the thread.
+struct IdleTimer {
+    _stop: mpsc::Sender<()>,
+}
+
+impl IdleTimer {
+    fn start(
+        idle: Arc<Mutex<IdleBlanker>>,
+        period: Duration,
+        blank: impl Fn() + Send + 'static,
+    ) -> IdleTimer {
+        let (tx, rx) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            // the sender is only ever dropped, so a disconnect means stop
+            while let Err(RecvTimeoutError::Timeout) = rx.recv_timeout(period) {
+                // hold the lock while blanking, so a write can't slip in
+                // between deciding to blank and actually doing it
+                let mut idle = idle.lock().unwrap();
+                if idle.should_blank(Instant::now()) {
+                    info!("Grid has been idle, blanking the HV507");
+                    blank();
+                }
+            }
+        });
+        IdleTimer { _stop: tx }
+    }
+}
+
+/// Remembers the last pin pattern shifted out, so identical ones can be skipped.
+#[derive(Default)]
+struct PinCache {
+    last: Option<Vec<bool>>,
+}
+
+impl PinCache {
+    fn should_write(&mut self, pins: &[bool], force: bool) -> bool {
+        let unchanged = match &self.last {
+            Some(last) => last[..] == *pins,
+            None => false,
+        };
+        if !force && unchanged {
+            return false;
+        }
+        self.last = Some(pins.to_vec());
+        true
+    }
+}
+
 pub struct RaspberryPi {
     pub hv507: devices::hv507::Hv507,
     pub mcp4725: Option<devices::mcp4725::Mcp4725>,
-    pub pca9685: Option<devices::pca9685::Pca9685>,
+    // shared with the heater zones
+    pub pca9685: Option<Arc<Mutex<devices::pca9685::Pca9685>>>,
     pub max31865: Option<devices::max31865::Max31865>,
+    pub sensor: Option<Box<dyn DropletSensor>>,
+    pub zones: HashMap<String, Box<dyn HeaterZone>>,
+    zone_timeout: Duration,
+    // dropping these turns the output off, so keep them around
+    pwms: HashMap<u32, rppal::pwm::Pwm>,
+    soft_pwms: HashMap<u32, SoftPwm>,
+    gpio_outputs: HashMap<u32, rppal::gpio::OutputPin>,
+    // which pin drives each electrode, from the grid we were made with
+    pin_map: PinMap,
+    arrival_tolerance: f32,
+    pin_cache: PinCache,
+    idle: Arc<Mutex<IdleBlanker>>,
+    idle_timer: Option<IdleTimer>,
+    drive: DriveState,
+    actuation_log: Option<ActuationLog>,
 }
 
 impl RaspberryPi {
-    pub fn new(settings: Settings) -> Result<RaspberryPi> {
+    /// Opens every device in `settings`, once, to drive the electrodes of
+    /// `grid`. Fails if the grid can't be wired up, like if two electrodes
+    /// share a pin. See `connect_with_retry` if the devices might not be
+    /// ready yet.
+    pub fn new(settings: Settings, grid: &Grid) -> Result<RaspberryPi> {
+        let pin_map = PinMap::from_grid(grid)?;
+        check_drive(
+            grid,
+            settings.mcp4725.as_ref().map(|s| s.full_scale_voltage),
+        )?;
+        RaspberryPi::open(&settings, pin_map)
+    }
+
+    /// Like `new`, but if opening the devices fails it tries again, up to
+    /// `attempts` times in all. The wait starts at `delay` and doubles after
+    /// each failure. Right after boot the device nodes may not be there, or
+    /// not have their permissions set, for a little while.
+    pub fn connect_with_retry(
+        settings: Settings,
+        grid: &Grid,
+        attempts: u32,
+        delay: Duration,
+    ) -> Result<RaspberryPi> {
+        // a bad grid won't get any better by waiting
+        let pin_map = PinMap::from_grid(grid)?;
+        check_drive(
+            grid,
+            settings.mcp4725.as_ref().map(|s| s.full_scale_voltage),
+        )?;
+        retry(attempts, delay, || {
+            RaspberryPi::open(&settings, pin_map.clone())
+        })
+    }
+
+    fn open(settings: &Settings, pin_map: PinMap) -> Result<RaspberryPi> {
         trace!("Initializing pi...");
+        let pca9685 = settings
+            .pca9685
+            .as_ref()
+            .map(|s| s.make().map(|pca| Arc::new(Mutex::new(pca))))
+            .transpose()?;
+        let zones = thermal::make_zones(&settings.zones, pca9685.as_ref())?;
         let pi = RaspberryPi {
             hv507: settings.hv507.make()?,
-            mcp4725: settings.mcp4725.map(|s| s.make()).transpose()?,
-            pca9685: settings.pca9685.map(|s| s.make()).transpose()?,
-            max31865: settings.max31865.map(|s| s.make()).transpose()?,
+            mcp4725: settings.mcp4725.as_ref().map(|s| s.make()).transpose()?,
+            pca9685,
+            max31865: settings.max31865.as_ref().map(|s| s.make()).transpose()?,
+            sensor: settings
+                .sensor
+                .as_ref()
+                .map(|s| s.make().map(|s| Box::new(s) as Box<dyn DropletSensor>))
+                .transpose()?,
+            zones,
+            zone_timeout: DEFAULT_ZONE_TIMEOUT,
+            pwms: HashMap::new(),
+            soft_pwms: HashMap::new(),
+            gpio_outputs: HashMap::new(),
+            pin_map,
+            arrival_tolerance: 1.0,
+            pin_cache: PinCache::default(),
+            idle: Arc::new(Mutex::new(IdleBlanker::new(Instant::now()))),
+            idle_timer: None,
+            drive: DriveState {
+                base_frequency: Some(settings.hv507.frequency),
+                frequency: Some(settings.hv507.frequency),
+                ..DriveState::default()
+            },
+            actuation_log: settings
+                .actuation_log
+                .as_ref()
+                .map(|s| s.make())
+                .transpose()?,
         };
         trace!("Initialized pi!");
 
@@ -152,6 +902,75 @@ impl RaspberryPi {
         // Ok(())
     }
 
+    /// How long `hold_temperatures` waits for the zones to get to their
+    /// targets, on top of the `duration` they're held there. Defaults to
+    /// `DEFAULT_ZONE_TIMEOUT`.
+    pub fn set_zone_timeout(&mut self, timeout: Duration) {
+        self.zone_timeout = timeout;
+    }
+
+    /// Holds each named zone at its target temperature until they've all
+    /// been within `tolerance` for `duration`. If any zone runs away, or
+    /// they don't get there in time (see `set_zone_timeout`), every heater
+    /// is shut off and this fails. Zones come from `[[pi.zones]]`, see
+    /// `thermal::ZoneSettings`.
+    pub fn hold_temperatures(
+        &mut self,
+        targets: &[(String, f32)],
+        tolerance: f32,
+        duration: Duration,
+    ) -> Result<()> {
+        if let Some((name, _)) = targets.iter().find(|(n, _)| !self.zones.contains_key(n)) {
+            return Err(Error::UnknownZone(name.clone()));
+        }
+
+        let mut zones: Vec<(&str, &mut dyn HeaterZone, f32)> = self
+            .zones
+            .iter_mut()
+            .filter_map(|(name, zone)| {
+                let target = targets.iter().find(|(n, _)| n == name)?.1;
+                let zone: &mut dyn HeaterZone = zone.as_mut();
+                Some((name.as_str(), zone, target))
+            })
+            .collect();
+
+        let interval = Duration::from_millis(100);
+        hold_zones(&mut zones, tolerance, duration, self.zone_timeout, interval)
+    }
+
+    /// Takes the named zone through a heating profile, holding each
+    /// `(target, seconds)` segment in order. See `hold_temperatures`.
+    pub fn heat_profile(
+        &mut self,
+        zone: &str,
+        segments: &[(f32, f64)],
+        tolerance: f32,
+    ) -> Result<()> {
+        let heater = match self.zones.get_mut(zone) {
+            Some(heater) => heater.as_mut(),
+            None => return Err(Error::UnknownZone(zone.to_string())),
+        };
+        let interval = Duration::from_millis(100);
+        let timeout = self.zone_timeout;
+        run_profile(zone, heater, segments, tolerance, timeout, interval)
+    }
+
+    /// One step of the heater loop on the `max31865` and pwm `channel` of
+    /// the `pca9685`. See `thermal::drive_heater`.
+    pub fn heater_step(&mut self, pid: &mut PidController, channel: u8, dt: f64) -> Result<f32> {
+        let sensor = self
+            .max31865
+            .as_mut()
+            .ok_or(Error::MissingDevice("max31865"))?;
+        let mut pwm = self
+            .pca9685
+            .as_ref()
+            .ok_or(Error::MissingDevice("pca9685"))?
+            .lock()
+            .unwrap();
+        thermal::drive_heater(pid, sensor, &mut pwm, channel, dt)
+    }
+
     pub fn get_temperature(&mut self, _temp_sensor: Peripheral) -> Result<f32> {
         unimplemented!()
         // if let Peripheral::Heater { spi_channel, .. } = temp_sensor {
@@ -163,37 +982,293 @@ impl RaspberryPi {
         // }
     }
 
-    pub fn output_pins(&mut self, gv: &GridView) {
-        let mut pins = vec![0; (gv.grid.max_pin() + 1) as usize];
-
-        self.hv507.clear_pins();
-
-        // set pins to high if there's a droplet on that electrode
-        for d in gv.droplets.values() {
-            for i in 0..d.dimensions.y {
-                for j in 0..d.dimensions.x {
-                    let loc = yx(d.location.y + i, d.location.x + j);
-                    let electrode = gv
-                        .grid
-                        .get_cell(loc)
-                        .unwrap_or_else(|| panic!("Couldn't find electrode for {}", loc));
-                    pins[electrode.pin as usize] = 1;
-                    self.hv507.set_pin_hi(electrode.pin as usize);
-                    trace!(
-                        "Setting pin {} at ({}, {})",
-                        electrode.pin,
-                        d.location.y + i,
-                        d.location.x + j,
-                    );
+    /// Sets the electrodes under each droplet. If the pattern is the same
+    /// as the last one written, this does nothing.
+    pub fn output_pins(&mut self, gv: &GridView) -> Result<()> {
+        self.write_pins(gv, false)
+    }
+
+    /// Like `output_pins`, but always shifts out the pattern. Use this if
+    /// you've written to the `hv507` directly.
+    pub fn output_pins_force(&mut self, gv: &GridView) -> Result<()> {
+        self.write_pins(gv, true)
+    }
+
+    /// Clocks `bits` straight out to the HV507 and latches them, see
+    /// `Hv507::shift_out`. This goes around the pin cache, so the next
+    /// `output_pins` always writes.
+    pub fn shift_out(&mut self, bits: &[bool]) -> Result<()> {
+        self.pin_cache = PinCache::default();
+        self.wake()?;
+        self.hv507.shift_out(bits)
+    }
+
+    /// The exact pattern `output_pins` would shift out for `snap`, indexed
+    /// by pin. This doesn't touch the hardware, so it's handy for comparing
+    /// against known-good patterns.
+    pub fn shift_pattern_for(&self, grid: &Grid, snap: &Snapshot) -> Vec<bool> {
+        pin_pattern(grid, &self.pin_map, snap.droplets.values())
+    }
+
+    /// Drives `gpio` as an output, claiming it the first time.
+    pub fn gpio_write(&mut self, gpio: u32, high: bool) -> Result<()> {
+        // a write means whoever was doing software pwm here is done
+        self.soft_pwms.remove(&gpio);
+        let output = match self.gpio_outputs.entry(gpio) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let pin = rppal::gpio::Gpio::new()?.get(gpio as u8)?;
+                e.insert(pin.into_output())
+            }
+        };
+        if high {
+            output.set_high();
+        } else {
+            output.set_low();
+        }
+        Ok(())
+    }
+
+    /// Briefly pulses `gpio` high, for marking moments in external recordings.
+    pub fn pulse_sync(&mut self, gpio: u32) -> Result<()> {
+        self.gpio_write(gpio, true)?;
+        thread::sleep(SYNC_PULSE);
+        self.gpio_write(gpio, false)
+    }
+
+    /// Writes `value` to the MCP4725 DAC, if there is one. This is also
+    /// what the DAC goes back to after a voltage override.
+    pub fn dac_write(&mut self, value: u16) -> Result<()> {
+        match &mut self.mcp4725 {
+            Some(dac) => dac.write(value)?,
+            None => return Err(Error::MissingDevice("mcp4725")),
+        }
+        self.drive.set_base_dac(value);
+        Ok(())
+    }
+
+    // sets the voltage and frequency the grid's overrides call for, before
+    // the electrodes that need them go on
+    fn apply_drive(&mut self, drive: Drive) -> Result<()> {
+        let mut outputs = PiDriveOutputs {
+            mcp4725: self.mcp4725.as_mut(),
+            hv507: &mut self.hv507,
+        };
+        self.drive.apply(drive, &mut outputs)
+    }
+
+    fn write_pins(&mut self, gv: &GridView, force: bool) -> Result<()> {
+        // the sync goes out before the state it marks
+        for &gpio in gv.sync_pins() {
+            self.pulse_sync(gpio)?;
+        }
+
+        let pins = pin_pattern(&gv.grid, &self.pin_map, gv.droplets.values());
+        self.apply_drive(step_drive(&gv.grid, gv.droplets.values()))?;
+
+        self.wake()?;
+
+        let latched = if self.pin_cache.should_write(&pins, force) {
+            self.hv507.clear_pins();
+            for (pin, &value) in pins.iter().enumerate() {
+                if value {
+                    self.hv507.set_pin_hi(pin);
                 }
             }
+            let latched = self.hv507.shift_and_latch();
+            if latched.is_err() {
+                // the hv507 got blanked, so don't trust the cache anymore
+                self.pin_cache = PinCache::default();
+            }
+            latched
+        } else {
+            trace!("Pins unchanged, skipping the shift out");
+            Ok(())
+        };
+
+        // failures get logged too, they're what an audit is for
+        self.log_actuation(gv, &pins, latched.as_ref().err());
+        latched
+    }
+
+    /// Logs every `output_pins` from here on, see `ActuationLog`. Pass
+    /// `None` to stop.
+    pub fn set_actuation_log(&mut self, log: Option<ActuationLog>) {
+        self.actuation_log = log;
+    }
+
+    fn log_actuation(&mut self, gv: &GridView, pins: &[bool], error: Option<&Error>) {
+        let log = match &mut self.actuation_log {
+            Some(log) => log,
+            None => return,
+        };
+        let full_scale = self
+            .mcp4725
+            .as_ref()
+            .map_or(devices::mcp4725::DEFAULT_FULL_SCALE_VOLTAGE, |dac| {
+                dac.full_scale_voltage
+            });
+        let voltage = self.drive.voltage(full_scale);
+        let mut actuation = audit::Actuation::new(&self.pin_map, gv, pins, voltage);
+        actuation.error = error.map(|err| err.to_string());
+        log.record(&actuation);
+    }
+
+    /// Blanks the HV507 once nothing has been written for `timeout`, so the
+    /// electrodes aren't left energized between commands. The next write
+    /// turns them back on. A background thread keeps an eye on it.
+    pub fn set_idle_blank_timeout(&mut self, timeout: Duration) {
+        self.idle.lock().unwrap().timeout = Some(timeout);
+        let blanker = self.hv507.blanker();
+        let period = timeout / IDLE_CHECKS_PER_TIMEOUT;
+        let timer = IdleTimer::start(Arc::clone(&self.idle), period, move || {
+            blanker.set_blank(true)
+        });
+        self.idle_timer = Some(timer);
+    }
+
+    /// Blanks the grid right away if it's been idle for too long, without
+    /// waiting for the background check. See `set_idle_blank_timeout`.
+    pub fn check_idle(&mut self) -> Result<()> {
+        if self.idle.lock().unwrap().should_blank(Instant::now()) {
+            info!("Grid has been idle, blanking the HV507");
+            self.hv507.set_blank(true)?;
         }
+        Ok(())
+    }
 
-        self.hv507.shift_and_latch();
+    // notes a write, turning the grid back on if it was blanked for idling
+    fn wake(&mut self) -> Result<()> {
+        if self.idle.lock().unwrap().activity(Instant::now()) {
+            debug!("Waking the grid back up");
+            self.hv507.set_blank(false)?;
+        }
+        Ok(())
+    }
+
+    /// Drives hardware PWM on `gpio`. Like pigpio's `hardware_PWM`, `duty` is
+    /// scaled to `PWM_DUTY_RANGE` no matter the frequency, so this is NOT a
+    /// percent. Use `set_pwm_percent` for that.
+    pub fn set_pwm(&mut self, gpio: u32, freq: u32, duty: u32) -> Result<()> {
+        let channel = check_pwm(gpio, duty)?;
+        let fraction = f64::from(duty) / f64::from(PWM_DUTY_RANGE);
+        let freq = f64::from(freq);
+        match self.pwms.get(&gpio) {
+            Some(pwm) => pwm.set_frequency(freq, fraction)?,
+            None => {
+                let pol = rppal::pwm::Polarity::Normal;
+                let pwm = rppal::pwm::Pwm::with_frequency(channel, freq, fraction, pol, true)?;
+                self.pwms.insert(gpio, pwm);
+            }
+        }
+        Ok(())
+    }
+
+    /// Drives PWM on any `gpio` by toggling it from a background thread, one
+    /// per pin. `duty` is clamped to between 0 and 1, see
+    /// `softpwm::clamp_duty`. Calling this again replaces the old settings,
+    /// and a `freq` or `duty` of 0 stops it and leaves the pin low.
+    pub fn set_soft_pwm(&mut self, gpio: u32, freq: u32, duty: f64) -> Result<()> {
+        let duty = softpwm::clamp_duty(duty)?;
+        self.soft_pwms.remove(&gpio);
+        if freq == 0 || duty <= 0.0 {
+            return self.gpio_write(gpio, false);
+        }
+        let mut output = match self.gpio_outputs.remove(&gpio) {
+            Some(output) => output,
+            None => rppal::gpio::Gpio::new()?.get(gpio as u8)?.into_output(),
+        };
+        let pwm = SoftPwm::start(freq, duty, move |high| {
+            if high {
+                output.set_high();
+            } else {
+                output.set_low();
+            }
+            Ok(())
+        })?;
+        self.soft_pwms.insert(gpio, pwm);
+        Ok(())
+    }
+
+    /// Like `set_pwm`, but takes a duty cycle from 0 to 100.
+    pub fn set_pwm_percent(&mut self, gpio: u32, freq: u32, percent: f32) -> Result<()> {
+        self.set_pwm(gpio, freq, percent_to_duty(percent))
+    }
+
+    /// Drives everything to off for a safe shutdown: the DAC, the PCA9685
+    /// channels, heater zones, hardware and software PWM, and finally the
+    /// HV507, which is cleared and blanked. Every device gets a try even if an earlier one
+    /// fails. This also runs on drop.
+    pub fn quiesce(&mut self) -> Result<()> {
+        let mut outputs: Vec<(String, &mut dyn Quiesce)> = Vec::new();
+        if let Some(dac) = &mut self.mcp4725 {
+            outputs.push(("mcp4725".into(), dac));
+        }
+        if let Some(pca) = &mut self.pca9685 {
+            outputs.push(("pca9685".into(), pca));
+        }
+        for (name, zone) in &mut self.zones {
+            outputs.push((format!("heater zone '{}'", name), zone));
+        }
+        for (gpio, pwm) in &mut self.pwms {
+            outputs.push((format!("pwm on gpio {}", gpio), pwm));
+        }
+        for (gpio, pwm) in &mut self.soft_pwms {
+            outputs.push((format!("software pwm on gpio {}", gpio), pwm));
+        }
+        outputs.push(("hv507".into(), &mut self.hv507));
+        quiesce_all(outputs)
     }
 
+    /// Turns heaters back off after `timeout` even if the heating code hangs.
+    pub fn set_heater_watchdog(&mut self, timeout: Duration) {
+        match &mut self.pca9685 {
+            Some(pca) => pca.lock().unwrap().set_heater_watchdog(timeout),
+            None => warn!("No pca9685 configured, so there are no heaters to watch"),
+        }
+    }
+
+    /// Sets the whole grid to `snap` in one step. If the snapshot is invalid,
+    /// neither the state nor the electrodes are touched.
+    pub fn apply_snapshot(&mut self, gv: &mut GridView, snap: Snapshot) -> Result<()> {
+        gv.apply_snapshot(snap)?;
+        self.output_pins(gv)
+    }
+
+    /// Sets how much of a droplet has to overlap its target for
+    /// `verify_droplet` to accept it. The default of 1 means all of it.
+    /// Anything outside of 0 to 1 is an error, and leaves it as it was.
+    pub fn set_arrival_tolerance(&mut self, tolerance: f32) -> Result<()> {
+        if !(0.0..=1.0).contains(&tolerance) {
+            return Err(Error::InvalidFraction(tolerance.into()));
+        }
+        self.arrival_tolerance = tolerance;
+        Ok(())
+    }
+
+    /// Checks that a droplet made it to `loc`, warning if it didn't. If
+    /// there's no sensor configured (see `SensorSettings`), this warns
+    /// about that instead and always succeeds.
+    pub fn verify_droplet(&mut self, loc: Location) -> Result<bool> {
+        let sensor = self
+            .sensor
+            .as_mut()
+            .map(|s| s.as_mut() as &mut dyn DropletSensor);
+        verify_with(sensor, loc, self.arrival_tolerance)
+    }
+
+    /// Asks the sensor whether there's a droplet at `loc` right now.
+    pub fn sense(&mut self, loc: Location) -> Result<bool> {
+        match &mut self.sensor {
+            Some(sensor) => sensor.droplet_present(loc),
+            None => Err(Error::MissingDevice("droplet sensor")),
+        }
+    }
+
+    /// Pumps `volume` in through `input_port`. The pumps aren't wired up
+    /// yet, so for now this is always an `Error::Unsupported`.
     pub fn input(&mut self, _input_port: &Peripheral, _volume: f64) -> Result<()> {
-        unimplemented!()
+        Err(Error::Unsupported("run the input pumps"))
         //     let pwm_channel = if let Peripheral::Input { pwm_channel, .. } = input_port {
         //         *pwm_channel
         //     } else {
@@ -216,8 +1291,10 @@ impl RaspberryPi {
         //     Ok(())
     }
 
+    /// Pumps `volume` out through `output_port`. Like `input`, this is
+    /// always an `Error::Unsupported` for now.
     pub fn output(&mut self, _output_port: &Peripheral, _volume: f64) -> Result<()> {
-        unimplemented!()
+        Err(Error::Unsupported("run the output pumps"))
         //     let pwm_channel = if let Peripheral::Output { pwm_channel, .. } = output_port {
         //         *pwm_channel
         //     } else {
@@ -241,6 +1318,40 @@ impl RaspberryPi {
     }
 }
 
+impl PiDevice for RaspberryPi {
+    fn gpio_write(&mut self, gpio: u32, high: bool) -> Result<()> {
+        RaspberryPi::gpio_write(self, gpio, high)
+    }
+
+    fn set_pwm(&mut self, gpio: u32, freq: u32, duty: u32) -> Result<()> {
+        RaspberryPi::set_pwm(self, gpio, freq, duty)
+    }
+
+    fn set_soft_pwm(&mut self, gpio: u32, freq: u32, duty: f64) -> Result<()> {
+        RaspberryPi::set_soft_pwm(self, gpio, freq, duty)
+    }
+
+    fn dac_write(&mut self, value: u16) -> Result<()> {
+        RaspberryPi::dac_write(self, value)
+    }
+
+    fn output_pins(&mut self, gv: &GridView) -> Result<()> {
+        RaspberryPi::output_pins(self, gv)
+    }
+
+    fn shift_out(&mut self, bits: &[bool]) -> Result<()> {
+        RaspberryPi::shift_out(self, bits)
+    }
+}
+
+// so an `Executor` can drive the chip
+impl puddle_core::exec::Actuator for RaspberryPi {
+    fn actuate(&mut self, gv: &GridView) -> puddle_core::process::PuddleResult<()> {
+        RaspberryPi::output_pins(self, gv)
+            .map_err(|err| puddle_core::process::PuddleError::ActuationFailed(err.to_string()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -272,4 +1383,611 @@ mod tests {
             panic!("Should be None: {:#?}", m);
         }
     }
+
+    #[test]
+    fn test_i2c_overrides() {
+        let mut conf = Config::new();
+        conf.merge(File::from_str(YAML, FileFormat::Yaml)).unwrap();
+        let extra = "
+            pi:
+              mcp4725:
+                bus: 0
+              pca9685:
+                address: 0x240
+                addr_10bit: true
+        ";
+        conf.merge(File::from_str(extra, FileFormat::Yaml)).unwrap();
+        let settings = Settings::from_config(&mut conf).unwrap();
+
+        // whatever isn't given keeps the usual wiring
+        let mcp = settings.mcp4725.unwrap();
+        assert_eq!(mcp.bus, 0);
+        assert_eq!(mcp.address, devices::mcp4725::DEFAULT_ADDRESS);
+        assert!(!mcp.addr_10bit);
+
+        let pca = settings.pca9685.unwrap();
+        assert_eq!(pca.bus, devices::i2c::DEFAULT_BUS);
+        assert_eq!(pca.address, 0x240);
+        assert!(pca.addr_10bit);
+    }
+
+    #[test]
+    fn test_zone_settings() {
+        let mut conf = Config::new();
+        conf.merge(File::from_str(YAML, FileFormat::Yaml)).unwrap();
+        let settings = Settings::from_config(&mut conf).unwrap();
+        assert!(settings.zones.is_empty());
+
+        let extra = "
+            pi:
+              zones:
+                - name: incubator
+                  channel: 3
+                  max31865:
+                    bus: 0
+                    select: 1
+                    n_samples: 4
+                    resist_ref: 4000.0
+                    resist_zero: 1000.0
+        ";
+        conf.merge(File::from_str(extra, FileFormat::Yaml)).unwrap();
+        let settings = Settings::from_config(&mut conf).unwrap();
+        assert_eq!(settings.zones.len(), 1);
+        let zone = &settings.zones[0];
+        assert_eq!(zone.name, "incubator");
+        assert_eq!(zone.channel, 3);
+        assert_eq!(zone.max31865.select, 1);
+
+        // the zones can't heat without the pca9685
+        match thermal::make_zones(&settings.zones, None) {
+            Err(Error::MissingDevice("pca9685")) => (),
+            Err(e) => panic!("Expected a missing pca9685, got {:?}", e),
+            Ok(_) => panic!("Expected a missing pca9685"),
+        }
+    }
+
+    thread_local! {
+        static WARNINGS: std::cell::RefCell<Vec<String>> = Default::default();
+    }
+
+    /// Keeps the warnings logged on each thread, so tests can check for them
+    struct WarningCapture;
+
+    impl Log for WarningCapture {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= Level::Warn
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                let msg = record.args().to_string();
+                WARNINGS.with(|w| w.borrow_mut().push(msg));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// The warnings this thread logged since the last call.
+    fn take_warnings() -> Vec<String> {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&WarningCapture).unwrap();
+            log::set_max_level(LevelFilter::Warn);
+        });
+        WARNINGS.with(|w| w.borrow_mut().drain(..).collect())
+    }
+
+    /// Pretends the droplet falls off after a certain number of steps
+    struct LossySensor {
+        steps_until_loss: usize,
+    }
+
+    impl DropletSensor for LossySensor {
+        fn droplet_present(&mut self, _loc: Location) -> Result<bool> {
+            if self.steps_until_loss == 0 {
+                return Ok(false);
+            }
+            self.steps_until_loss -= 1;
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn test_verify_droplet_loss() {
+        use puddle_core::grid::location::yx;
+
+        take_warnings();
+        let mut sensor = LossySensor {
+            steps_until_loss: 2,
+        };
+        let found: Vec<bool> = (0..4)
+            .map(|x| verify_with(Some(&mut sensor), yx(0, x), 1.0).unwrap())
+            .collect();
+        assert_eq!(found, vec![true, true, false, false]);
+        let warnings = take_warnings();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("Lost the droplet! Expected it at (0, 2)"));
+    }
+
+    #[test]
+    fn test_verify_without_sensor() {
+        use puddle_core::grid::location::yx;
+
+        take_warnings();
+        assert!(verify_with(None, yx(0, 1), 1.0).unwrap());
+        let warnings = take_warnings();
+        assert_eq!(
+            warnings,
+            vec!["No droplet sensor configured, can't verify the droplet at (0, 1)"]
+        );
+    }
+
+    struct FakeAdc {
+        readings: Vec<f64>,
+    }
+
+    impl Adc for FakeAdc {
+        fn read_channel(&mut self, channel: u8) -> Result<f64> {
+            Ok(self.readings[channel as usize])
+        }
+    }
+
+    #[test]
+    fn test_capacitive_sensor() {
+        use puddle_core::grid::location::yx;
+
+        let mut sensor = CapacitiveSensor {
+            adc: Box::new(FakeAdc {
+                readings: vec![0.1, 0.8],
+            }),
+            channels: vec![(yx(0, 0), 0), (yx(0, 1), 1)].into_iter().collect(),
+            threshold: 0.5,
+        };
+        assert!(!sensor.droplet_present(yx(0, 0)).unwrap());
+        assert!(sensor.droplet_present(yx(0, 1)).unwrap());
+        match sensor.droplet_present(yx(3, 3)) {
+            Err(Error::NoFeedbackChannel(loc)) => assert_eq!(loc, yx(3, 3)),
+            other => panic!("Expected no channel, got {:?}", other),
+        }
+
+        // the executor sees the same readings
+        let sensor: &mut dyn puddle_core::exec::Sensor = &mut sensor;
+        assert!(sensor.droplet_present(yx(0, 1)).unwrap());
+        assert!(sensor.droplet_present(yx(3, 3)).is_err());
+    }
+
+    #[test]
+    fn test_sensor_settings_bad_channel() {
+        use puddle_core::grid::location::yx;
+
+        let settings = SensorSettings {
+            mcp3008: devices::mcp3008::Settings { bus: 0, select: 1 },
+            threshold: 0.5,
+            channels: vec![FeedbackChannel {
+                location: yx(0, 0),
+                channel: 8,
+            }],
+        };
+        match settings.make() {
+            Err(Error::InvalidAdcChannel(8)) => (),
+            Err(err) => panic!("Expected a bad channel, got {:?}", err),
+            Ok(_) => panic!("Expected a bad channel"),
+        }
+    }
+
+    /// A droplet sitting partly on the target cell
+    struct StraddlingSensor {
+        overlap: f32,
+    }
+
+    impl DropletSensor for StraddlingSensor {
+        fn droplet_present(&mut self, _loc: Location) -> Result<bool> {
+            Ok(self.overlap > 0.0)
+        }
+
+        fn overlap(&mut self, _loc: Location) -> Result<f32> {
+            Ok(self.overlap)
+        }
+    }
+
+    #[test]
+    fn test_arrival_tolerance() {
+        use puddle_core::grid::location::yx;
+
+        let mut sensor = StraddlingSensor { overlap: 0.6 };
+        assert!(verify_with(Some(&mut sensor), yx(1, 1), 0.5).unwrap());
+        assert!(!verify_with(Some(&mut sensor), yx(1, 1), 0.9).unwrap());
+    }
+
+    #[test]
+    fn test_identical_pins_shift_once() {
+        let mut cache = PinCache::default();
+        let writes = [
+            ([true, false, true], false),
+            ([true, false, true], false),
+            ([true, false, true], true),
+            ([false, false, true], false),
+        ];
+
+        // count how many times the actuator would actually get clocked
+        let mut shifts = 0;
+        for (i, (pins, force)) in writes.iter().enumerate() {
+            if cache.should_write(pins, *force) {
+                shifts += 1;
+            }
+            assert_eq!(shifts, [1, 1, 2, 3][i]);
+        }
+    }
+
+    // a board with no DAC, that records the frequencies it's set to
+    #[derive(Default)]
+    struct NoDac(Vec<f64>);
+
+    impl DriveOutputs for NoDac {
+        fn full_scale_voltage(&self) -> Option<f64> {
+            None
+        }
+
+        fn write_dac(&mut self, _value: u16) -> Result<()> {
+            panic!("There's no DAC to write")
+        }
+
+        fn set_polarity_frequency(&mut self, frequency: f64) -> Result<()> {
+            self.0.push(frequency);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_apply_drive() {
+        let mut state = DriveState {
+            base_frequency: Some(10_000.0),
+            frequency: Some(10_000.0),
+            ..DriveState::default()
+        };
+        let mut outputs = NoDac::default();
+        let fast = Drive {
+            voltage: None,
+            frequency: Some(20_000.0),
+        };
+
+        // only changes get written, and it goes back to the configured
+        // frequency once the override is done
+        state.apply(Drive::default(), &mut outputs).unwrap();
+        state.apply(fast, &mut outputs).unwrap();
+        state.apply(fast, &mut outputs).unwrap();
+        state.apply(Drive::default(), &mut outputs).unwrap();
+        assert_eq!(outputs.0, vec![20_000.0, 10_000.0]);
+
+        let high = Drive {
+            voltage: Some(1.0),
+            frequency: None,
+        };
+        match state.apply(high, &mut outputs) {
+            Err(Error::MissingDevice("mcp4725")) => (),
+            other => panic!("Expected a missing dac, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_check_drive() {
+        use puddle_core::grid::location::yx;
+
+        let mut grid = Grid::rectangle(1, 2);
+        check_drive(&grid, None).unwrap();
+        let drive = |voltage| Drive {
+            voltage: Some(voltage),
+            frequency: None,
+        };
+        grid.drive.insert(yx(0, 1), drive(3.0));
+        check_drive(&grid, Some(3.3)).unwrap();
+        match check_drive(&grid, None) {
+            Err(Error::MissingDevice("mcp4725")) => (),
+            other => panic!("Expected a missing dac, got {:?}", other),
+        }
+        grid.drive.insert(yx(0, 0), drive(4.0));
+        match check_drive(&grid, Some(3.3)) {
+            Err(Error::InvalidVoltage(4.0)) => (),
+            other => panic!("Expected an invalid voltage, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_shift_pattern() {
+        use puddle_core::grid::{location::yx, DropletId};
+
+        // pins are numbered row by row, with a dead cell in the middle
+        let mut grid = Grid::rectangle(3, 3);
+        grid.vec[1][1] = None;
+
+        let id = DropletId {
+            id: 0,
+            process_id: 0,
+        };
+        let mut snap = Snapshot::default();
+        snap.droplets
+            .insert(id, Droplet::new(id, 1.0, yx(0, 1), yx(2, 2)));
+
+        let map = PinMap::from_grid(&grid).unwrap();
+        let expected = vec![false, true, true, false, false, true, false, false, false];
+        assert_eq!(pin_pattern(&grid, &map, snap.droplets.values()), expected);
+
+        // the pins go by the map, not the order of the cells
+        grid.get_cell_mut(yx(0, 1)).unwrap().pin = 8;
+        grid.get_cell_mut(yx(2, 2)).unwrap().pin = 1;
+        let map = PinMap::from_grid(&grid).unwrap();
+        let expected = vec![false, false, true, false, false, true, false, false, true];
+        assert_eq!(pin_pattern(&grid, &map, snap.droplets.values()), expected);
+    }
+
+    /// A lump that heats up with its heater, and cools toward room temperature
+    struct SimZone {
+        temperature: f32,
+        duty_cycle: f64,
+        stuck_on: bool,
+    }
+
+    impl SimZone {
+        fn new() -> SimZone {
+            SimZone {
+                temperature: 20.0,
+                duty_cycle: 0.0,
+                stuck_on: false,
+            }
+        }
+    }
+
+    impl HeaterZone for SimZone {
+        fn read_temperature(&mut self) -> Result<f32> {
+            let duty = if self.stuck_on { 1.0 } else { self.duty_cycle };
+            self.temperature += duty as f32 - 0.02 * (self.temperature - 20.0);
+            Ok(self.temperature)
+        }
+
+        fn set_heater(&mut self, duty_cycle: f64) -> Result<()> {
+            self.duty_cycle = duty_cycle;
+            Ok(())
+        }
+    }
+
+    struct SimOutput {
+        level: u32,
+        broken: bool,
+    }
+
+    impl Quiesce for SimOutput {
+        fn quiesce(&mut self) -> Result<()> {
+            if self.broken {
+                return Err(Error::InvalidPwmChannel(9));
+            }
+            self.level = 0;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_quiesce_all() {
+        let mut outs: Vec<_> = [false, true, false, true]
+            .iter()
+            .map(|&broken| SimOutput { level: 7, broken })
+            .collect();
+        let mut zone: Box<dyn HeaterZone> = Box::new(SimZone::new());
+        zone.set_heater(0.5).unwrap();
+
+        let mut outputs: Vec<(String, &mut dyn Quiesce)> = outs
+            .iter_mut()
+            .enumerate()
+            .map(|(i, o)| (i.to_string(), o as &mut dyn Quiesce))
+            .collect();
+        outputs.push(("zone".into(), &mut zone));
+
+        // everything that could be turned off was, despite the failures
+        let failed: Vec<_> = match quiesce_all(outputs) {
+            Err(Error::Shutdown(failures)) => failures.into_iter().map(|(n, _)| n).collect(),
+            other => panic!("Expected a shutdown error, got {:?}", other),
+        };
+        assert_eq!(failed, vec!["1", "3"]);
+        let levels: Vec<_> = outs.iter().map(|o| o.level).collect();
+        assert_eq!(levels, vec![0, 7, 0, 7]);
+        // the sim zone only warms up if its heater is on
+        assert_eq!(zone.read_temperature().unwrap(), 20.0);
+    }
+
+    #[test]
+    fn test_retry() {
+        let delay = Duration::from_millis(1);
+
+        // fails until the third try
+        let mut tries = 0;
+        let result = retry(5, delay, || {
+            tries += 1;
+            if tries < 3 {
+                Err(Error::MissingDevice("not yet"))
+            } else {
+                Ok(tries)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        // never works, and we get the last error back
+        let mut tries = 0;
+        let start = Instant::now();
+        let result: Result<()> = retry(4, delay, || {
+            tries += 1;
+            Err(Error::InvalidPwmChannel(tries))
+        });
+        assert_eq!(tries, 4);
+        match result {
+            Err(Error::InvalidPwmChannel(4)) => (),
+            other => panic!("Expected the last error, got {:?}", other),
+        }
+        // waited 1 + 2 + 4 ms in between
+        assert!(start.elapsed() >= Duration::from_millis(7));
+
+        // no attempts still means one
+        let mut tries = 0;
+        let _ = retry(0, delay, || -> Result<()> {
+            tries += 1;
+            Err(Error::MissingDevice("nope"))
+        });
+        assert_eq!(tries, 1);
+    }
+
+    #[test]
+    fn test_hold_two_zones() {
+        let mut a = SimZone::new();
+        let mut b = SimZone::new();
+        let mut zones: Vec<(&str, &mut dyn HeaterZone, f32)> =
+            vec![("a", &mut a, 60.0), ("b", &mut b, 45.0)];
+        let interval = Duration::from_millis(1);
+        let timeout = Duration::from_secs(10);
+        hold_zones(
+            &mut zones,
+            1.0,
+            Duration::from_millis(20),
+            timeout,
+            interval,
+        )
+        .unwrap();
+
+        assert!((a.temperature - 60.0).abs() <= 1.0);
+        assert!((b.temperature - 45.0).abs() <= 1.0);
+        assert_eq!((a.duty_cycle, b.duty_cycle), (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_zones_time_out() {
+        // the sim zone levels off well short of this
+        let mut a = SimZone::new();
+        let mut zones: Vec<(&str, &mut dyn HeaterZone, f32)> = vec![("a", &mut a, 500.0)];
+        let interval = Duration::from_millis(1);
+        let timeout = Duration::from_millis(50);
+        let start = Instant::now();
+        let result = hold_zones(
+            &mut zones,
+            1.0,
+            Duration::from_millis(20),
+            timeout,
+            interval,
+        );
+
+        match result {
+            Err(Error::HeatTimeout(_)) => (),
+            r => panic!("Expected a timeout, got {:?}", r),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(70));
+        assert_eq!(a.duty_cycle, 0.0);
+    }
+
+    #[test]
+    fn test_run_profile() {
+        let mut zone = SimZone::new();
+        let interval = Duration::from_millis(1);
+        let segments = [(60.0, 0.02), (45.0, 0.02)];
+        let timeout = Duration::from_secs(10);
+        run_profile("a", &mut zone, &segments, 1.0, timeout, interval).unwrap();
+
+        // it only cools off with the heater off, so it got down to the last one
+        assert!((zone.temperature - 45.0).abs() <= 1.0);
+        assert_eq!(zone.duty_cycle, 0.0);
+    }
+
+    #[test]
+    fn test_profile_limits() {
+        let interval = Duration::from_millis(1);
+        let timeout = Duration::from_millis(50);
+
+        // the ceiling is above the hottest segment, not the one it's on
+        let mut zone = SimZone::new();
+        zone.stuck_on = true;
+        let segments = [(30.0, 0.0), (45.0, 10.0)];
+        match run_profile("a", &mut zone, &segments, 1.0, timeout, interval) {
+            Err(Error::ThermalRunaway(_, temp)) => assert!(temp > 55.0),
+            r => panic!("Expected a runaway, got {:?}", r),
+        }
+        assert_eq!(zone.duty_cycle, 0.0);
+
+        // it never gets there, so the profile times out
+        let mut zone = SimZone::new();
+        let segments = [(40.0, 0.0), (100.0, 0.02)];
+        match run_profile("a", &mut zone, &segments, 1.0, timeout, interval) {
+            Err(Error::HeatTimeout(_)) => (),
+            r => panic!("Expected a timeout, got {:?}", r),
+        }
+        assert_eq!(zone.duty_cycle, 0.0);
+    }
+
+    #[test]
+    fn test_runaway_stops_all_zones() {
+        let mut a = SimZone::new();
+        let mut b = SimZone::new();
+        b.stuck_on = true;
+        let mut zones: Vec<(&str, &mut dyn HeaterZone, f32)> =
+            vec![("a", &mut a, 60.0), ("b", &mut b, 30.0)];
+        let interval = Duration::from_millis(1);
+        let timeout = Duration::from_secs(10);
+        let result = hold_zones(&mut zones, 1.0, Duration::from_secs(10), timeout, interval);
+
+        match result {
+            Err(Error::ThermalRunaway(zone, _)) => assert_eq!(zone, "b"),
+            r => panic!("Expected a runaway, got {:?}", r),
+        }
+        assert_eq!((a.duty_cycle, b.duty_cycle), (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_percent_to_duty() {
+        assert_eq!(percent_to_duty(50.0), 500_000);
+        assert_eq!(percent_to_duty(0.0), 0);
+        assert_eq!(percent_to_duty(100.0), PWM_DUTY_RANGE);
+        assert_eq!(percent_to_duty(150.0), PWM_DUTY_RANGE);
+        assert_eq!(percent_to_duty(-5.0), 0);
+    }
+
+    #[test]
+    fn test_idle_blank() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        let mut idle = IdleBlanker::new(start);
+        assert!(!idle.should_blank(at(10_000)));
+
+        idle.timeout = Some(Duration::from_millis(500));
+        assert!(!idle.activity(at(100)));
+        assert!(!idle.should_blank(at(599)));
+        assert!(idle.should_blank(at(600)));
+        // only blank once
+        assert!(!idle.should_blank(at(700)));
+
+        // new activity turns it back on and restarts the clock
+        assert!(idle.activity(at(800)));
+        assert!(!idle.should_blank(at(1200)));
+        assert!(idle.should_blank(at(1300)));
+    }
+
+    #[test]
+    fn test_idle_timer_blanks() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let mut idle = IdleBlanker::new(Instant::now());
+        idle.timeout = Some(Duration::from_millis(20));
+        let idle = Arc::new(Mutex::new(idle));
+        let blanks = Arc::new(AtomicUsize::new(0));
+        let blanks2 = Arc::clone(&blanks);
+        let _timer = IdleTimer::start(Arc::clone(&idle), Duration::from_millis(2), move || {
+            blanks2.fetch_add(1, Ordering::SeqCst);
+        });
+
+        // nobody calls in, but it still gets blanked, and only once
+        let mut tries = 0;
+        while blanks.load(Ordering::SeqCst) == 0 {
+            assert!(tries < 500, "the idle timer never fired");
+            tries += 1;
+            thread::sleep(Duration::from_millis(2));
+        }
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(blanks.load(Ordering::SeqCst), 1);
+        assert!(idle.lock().unwrap().activity(Instant::now()));
+    }
 }
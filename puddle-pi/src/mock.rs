@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rppal::gpio::Level;
+
+use puddle_core::grid::gridview::GridView;
+use puddle_core::grid::{Grid, PinMap};
+
+use crate::audit::{Actuation, ActuationLog};
+use crate::devices::hv507::{self, Line, Lines};
+use crate::devices::mcp4725;
+use crate::softpwm::{self, SoftPwm};
+use crate::{
+    check_drive, check_pwm, pin_pattern, step_drive, DriveOutputs, DriveState, Error, PiDevice,
+    Result,
+};
+
+/// Something a `MockRaspberryPi` was asked to do.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PiCall {
+    GpioWrite {
+        gpio: u32,
+        high: bool,
+    },
+    SetPwm {
+        gpio: u32,
+        freq: u32,
+        duty: u32,
+    },
+    SetSoftPwm {
+        gpio: u32,
+        freq: u32,
+        duty: f64,
+    },
+    DacWrite(u16),
+    /// The HV507 polarity PWM's frequency changed
+    PolarityFrequency(f64),
+    /// One write to one of the HV507's control lines
+    Hv507Write {
+        line: Line,
+        high: bool,
+    },
+    /// The pattern that would have been shifted out, indexed by pin
+    OutputPins(Vec<bool>),
+}
+
+/// Stands in for a `RaspberryPi`, recording every call instead of touching
+/// the hardware. Arguments are checked the same way, so a call that would
+/// fail on the pi fails here too and isn't recorded.
+///
+/// Drive overrides are written the same way too, but the mock doesn't know
+/// the board's normal polarity frequency, so a frequency override sticks.
+///
+/// Software PWM really does run, but its toggles go to a separate log, see
+/// `soft_pwm_writes`, since they happen on another thread.
+#[derive(Debug, Default)]
+pub struct MockRaspberryPi {
+    calls: Vec<PiCall>,
+    soft_pwms: HashMap<u32, SoftPwm>,
+    soft_pwm_writes: Arc<Mutex<Vec<PiCall>>>,
+    drive: DriveState,
+    pin_map: PinMap,
+    actuation_log: Option<ActuationLog>,
+}
+
+impl MockRaspberryPi {
+    /// A mock wired up to drive `grid`, see `RaspberryPi::new`.
+    pub fn new(grid: &Grid) -> Result<MockRaspberryPi> {
+        check_drive(grid, Some(mcp4725::DEFAULT_FULL_SCALE_VOLTAGE))?;
+        Ok(MockRaspberryPi {
+            pin_map: PinMap::from_grid(grid)?,
+            ..MockRaspberryPi::default()
+        })
+    }
+
+    /// Everything that's been called so far, oldest first.
+    pub fn calls(&self) -> &[PiCall] {
+        &self.calls
+    }
+
+    /// The `GpioWrite`s software PWM has done so far, oldest first.
+    pub fn soft_pwm_writes(&self) -> Vec<PiCall> {
+        self.soft_pwm_writes.lock().unwrap().clone()
+    }
+
+    /// See `RaspberryPi::set_actuation_log`.
+    pub fn set_actuation_log(&mut self, log: Option<ActuationLog>) {
+        self.actuation_log = log;
+    }
+}
+
+// records what drive overrides write, as if there were a DAC at the
+// default full scale
+struct MockDriveOutputs<'a>(&'a mut Vec<PiCall>);
+
+impl<'a> DriveOutputs for MockDriveOutputs<'a> {
+    fn full_scale_voltage(&self) -> Option<f64> {
+        Some(mcp4725::DEFAULT_FULL_SCALE_VOLTAGE)
+    }
+
+    fn write_dac(&mut self, value: u16) -> Result<()> {
+        self.0.push(PiCall::DacWrite(value));
+        Ok(())
+    }
+
+    fn set_polarity_frequency(&mut self, frequency: f64) -> Result<()> {
+        self.0.push(PiCall::PolarityFrequency(frequency));
+        Ok(())
+    }
+}
+
+// records the hv507 clocking in with everything else
+struct MockLines<'a>(&'a mut Vec<PiCall>);
+
+impl<'a> Lines for MockLines<'a> {
+    fn write(&mut self, line: Line, level: Level) -> Result<()> {
+        let high = level == Level::High;
+        self.0.push(PiCall::Hv507Write { line, high });
+        Ok(())
+    }
+}
+
+impl PiDevice for MockRaspberryPi {
+    fn gpio_write(&mut self, gpio: u32, high: bool) -> Result<()> {
+        self.calls.push(PiCall::GpioWrite { gpio, high });
+        Ok(())
+    }
+
+    fn set_pwm(&mut self, gpio: u32, freq: u32, duty: u32) -> Result<()> {
+        check_pwm(gpio, duty)?;
+        self.calls.push(PiCall::SetPwm { gpio, freq, duty });
+        Ok(())
+    }
+
+    fn set_soft_pwm(&mut self, gpio: u32, freq: u32, duty: f64) -> Result<()> {
+        let duty = softpwm::clamp_duty(duty)?;
+        self.calls.push(PiCall::SetSoftPwm { gpio, freq, duty });
+        self.soft_pwms.remove(&gpio);
+        if freq == 0 || duty <= 0.0 {
+            return Ok(());
+        }
+        let writes = Arc::clone(&self.soft_pwm_writes);
+        let pwm = SoftPwm::start(freq, duty, move |high| {
+            writes
+                .lock()
+                .unwrap()
+                .push(PiCall::GpioWrite { gpio, high });
+            Ok(())
+        })?;
+        self.soft_pwms.insert(gpio, pwm);
+        Ok(())
+    }
+
+    fn dac_write(&mut self, value: u16) -> Result<()> {
+        if value > mcp4725::VALUE_MAX {
+            return Err(Error::InvalidDacValue(value));
+        }
+        self.calls.push(PiCall::DacWrite(value));
+        self.drive.set_base_dac(value);
+        Ok(())
+    }
+
+    fn output_pins(&mut self, gv: &GridView) -> Result<()> {
+        // same order as the real thing, syncs go out first
+        for &gpio in gv.sync_pins() {
+            self.gpio_write(gpio, true)?;
+            self.gpio_write(gpio, false)?;
+        }
+        let pins = pin_pattern(&gv.grid, &self.pin_map, gv.droplets.values());
+        let drive = step_drive(&gv.grid, gv.droplets.values());
+        self.drive
+            .apply(drive, &mut MockDriveOutputs(&mut self.calls))?;
+        if let Some(log) = &mut self.actuation_log {
+            let voltage = self.drive.voltage(mcp4725::DEFAULT_FULL_SCALE_VOLTAGE);
+            log.record(&Actuation::new(&self.pin_map, gv, &pins, voltage));
+        }
+        self.calls.push(PiCall::OutputPins(pins));
+        Ok(())
+    }
+
+    fn shift_out(&mut self, bits: &[bool]) -> Result<()> {
+        let levels: Vec<Level> = bits
+            .iter()
+            .map(|&b| if b { Level::High } else { Level::Low })
+            .collect();
+        let no_delay = Duration::from_nanos(0);
+        hv507::shift_and_latch(&mut MockLines(&mut self.calls), &levels, no_delay)
+    }
+}
+
+impl puddle_core::exec::Actuator for MockRaspberryPi {
+    fn actuate(&mut self, gv: &GridView) -> puddle_core::process::PuddleResult<()> {
+        PiDevice::output_pins(self, gv)
+            .map_err(|err| puddle_core::process::PuddleError::ActuationFailed(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use puddle_core::grid::{location::yx, Drive, Droplet, DropletId, Grid, GridError};
+
+    #[test]
+    fn test_mock_records_move() {
+        let mut gv = GridView::new(Grid::rectangle(1, 3));
+        let id = DropletId {
+            id: 0,
+            process_id: 0,
+        };
+        gv.droplets
+            .insert(id, Droplet::new(id, 1.0, yx(0, 0), yx(1, 1)));
+
+        let mut pi = MockRaspberryPi::new(&gv.grid).unwrap();
+        for x in 0..3 {
+            gv.droplets.get_mut(&id).unwrap().location = yx(0, x);
+            pi.output_pins(&gv).unwrap();
+        }
+
+        let expected = [
+            PiCall::OutputPins(vec![true, false, false]),
+            PiCall::OutputPins(vec![false, true, false]),
+            PiCall::OutputPins(vec![false, false, true]),
+        ];
+        assert_eq!(pi.calls(), &expected);
+    }
+
+    #[test]
+    fn test_mock_drives_executor() {
+        use puddle_core::exec::Executor;
+        use puddle_core::process::Manager;
+
+        // the mock's calls stay readable through the Arc
+        struct Shared(Arc<Mutex<MockRaspberryPi>>);
+
+        impl puddle_core::exec::Actuator for Shared {
+            fn actuate(&mut self, gv: &GridView) -> puddle_core::process::PuddleResult<()> {
+                self.0.lock().unwrap().actuate(gv)
+            }
+        }
+
+        let grid = Grid::rectangle(1, 3);
+        let pi = Arc::new(Mutex::new(MockRaspberryPi::new(&grid).unwrap()));
+        let mut executor = Executor::new(grid.clone()).unwrap();
+        executor.set_actuator(Some(Box::new(Shared(Arc::clone(&pi)))));
+        let manager = Manager::with_executor(false, grid, Box::new(executor));
+        let pid = manager.new_process("mock").unwrap();
+        let p = manager.get_process(pid).unwrap();
+        let d = p.create(Some(yx(0, 0)), 1.0, None).unwrap();
+        p.move_droplet(d, yx(0, 2)).unwrap();
+        p.flush().unwrap();
+
+        // the droplet showed up, then walked across
+        let pi = pi.lock().unwrap();
+        let pins: Vec<&[bool]> = pi
+            .calls()
+            .iter()
+            .filter_map(|call| match call {
+                PiCall::OutputPins(pins) => Some(&pins[..]),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(pins.first(), Some(&&[true, false, false][..]));
+        assert_eq!(pins.last(), Some(&&[false, false, true][..]));
+        assert!(pins.contains(&&[false, true, false][..]));
+    }
+
+    #[test]
+    fn test_mock_duplicate_pin() {
+        // two electrodes on one pin would always switch together
+        let mut grid = Grid::rectangle(1, 3);
+        grid.get_cell_mut(yx(0, 2)).unwrap().pin = 1;
+        match MockRaspberryPi::new(&grid) {
+            Err(Error::Grid(errors)) => {
+                assert_eq!(errors, vec![GridError::DuplicatePin(1, yx(0, 1), yx(0, 2))])
+            }
+            other => panic!("Expected a duplicate pin, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_mock_actuation_log() {
+        let path = std::env::temp_dir().join(format!("puddle-audit-{}.ndjson", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut gv = GridView::new(Grid::rectangle(1, 3));
+        let id = DropletId {
+            id: 0,
+            process_id: 0,
+        };
+        gv.droplets
+            .insert(id, Droplet::new(id, 1.0, yx(0, 0), yx(1, 1)));
+
+        let mut pi = MockRaspberryPi::new(&gv.grid).unwrap();
+        let log = ActuationLog::append(&path, crate::audit::Format::Ndjson).unwrap();
+        pi.set_actuation_log(Some(log));
+        pi.dac_write(mcp4725::VALUE_MAX).unwrap();
+        for x in 0..3 {
+            gv.droplets.get_mut(&id).unwrap().location = yx(0, x);
+            pi.output_pins(&gv).unwrap();
+        }
+        // nothing changed, but it still gets logged
+        pi.output_pins(&gv).unwrap();
+        pi.set_actuation_log(None);
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let entries: Vec<serde_json::Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 4);
+        for (entry, x) in entries.iter().zip(&[0, 1, 2, 2]) {
+            assert_eq!(entry["energized"], serde_json::json!([{"y": 0, "x": x}]));
+            assert_eq!(entry["droplets"], serde_json::json!(["p0.d0"]));
+            assert_eq!(entry["voltage"], mcp4725::DEFAULT_FULL_SCALE_VOLTAGE);
+        }
+    }
+
+    #[test]
+    fn test_mock_voltage_override() {
+        let mut grid = Grid::rectangle(1, 3);
+        let half = Drive {
+            voltage: Some(mcp4725::DEFAULT_FULL_SCALE_VOLTAGE / 2.0),
+            frequency: None,
+        };
+        grid.drive.insert(yx(0, 1), half);
+        let mut gv = GridView::new(grid);
+        let id = DropletId {
+            id: 0,
+            process_id: 0,
+        };
+        gv.droplets
+            .insert(id, Droplet::new(id, 1.0, yx(0, 0), yx(1, 1)));
+
+        let mut pi = MockRaspberryPi::new(&gv.grid).unwrap();
+        pi.dac_write(1000).unwrap();
+        for x in 0..3 {
+            gv.droplets.get_mut(&id).unwrap().location = yx(0, x);
+            pi.output_pins(&gv).unwrap();
+        }
+
+        // the dac goes up for the middle cell, then back to where it was
+        let expected = [
+            PiCall::DacWrite(1000),
+            PiCall::OutputPins(vec![true, false, false]),
+            PiCall::DacWrite(2048),
+            PiCall::OutputPins(vec![false, true, false]),
+            PiCall::DacWrite(1000),
+            PiCall::OutputPins(vec![false, false, true]),
+        ];
+        assert_eq!(pi.calls(), &expected);
+
+        // more than the dac can do
+        gv.grid.drive.get_mut(&yx(0, 1)).unwrap().voltage = Some(5.0);
+        gv.droplets.get_mut(&id).unwrap().location = yx(0, 1);
+        match pi.output_pins(&gv) {
+            Err(Error::InvalidVoltage(5.0)) => (),
+            other => panic!("Expected an invalid voltage, got {:?}", other),
+        }
+        assert_eq!(pi.calls().len(), expected.len());
+
+        // and a grid like that is turned away up front
+        match MockRaspberryPi::new(&gv.grid) {
+            Err(Error::InvalidVoltage(5.0)) => (),
+            other => panic!("Expected an invalid voltage, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_mock_checks_pwm() {
+        let mut pi = MockRaspberryPi::new(&Grid::rectangle(1, 3)).unwrap();
+        pi.set_pwm(18, 1000, 500_000).unwrap();
+        match pi.set_pwm(4, 1000, 500_000) {
+            Err(Error::InvalidPwmPin(4)) => (),
+            other => panic!("Expected an invalid pin, got {:?}", other),
+        }
+        let expected = PiCall::SetPwm {
+            gpio: 18,
+            freq: 1000,
+            duty: 500_000,
+        };
+        assert_eq!(pi.calls(), &[expected]);
+    }
+
+    #[test]
+    fn test_mock_shift_out() {
+        let mut pi = MockRaspberryPi::new(&Grid::rectangle(1, 3)).unwrap();
+        pi.shift_out(&[true, false, true]).unwrap();
+
+        let write = |line, high| PiCall::Hv507Write { line, high };
+        let clock = |data| {
+            vec![
+                write(Line::Data, data),
+                write(Line::Clock, true),
+                write(Line::Clock, false),
+            ]
+        };
+        let mut expected = vec![];
+        expected.extend(clock(true));
+        expected.extend(clock(false));
+        expected.extend(clock(true));
+        // latch it in, then make sure the outputs are on
+        expected.push(write(Line::LatchEnable, true));
+        expected.push(write(Line::LatchEnable, false));
+        expected.push(write(Line::Blank, true));
+        assert_eq!(pi.calls(), &expected[..]);
+    }
+
+    #[test]
+    fn test_mock_soft_pwm() {
+        let mut pi = MockRaspberryPi::new(&Grid::rectangle(1, 3)).unwrap();
+        // 5ms periods, high for 1.25ms of each
+        pi.set_soft_pwm(5, 200, 0.25).unwrap();
+        // wait on the writes themselves, however long the scheduler takes
+        while pi.soft_pwm_writes().len() < 6 {
+            std::thread::yield_now();
+        }
+        pi.set_soft_pwm(5, 200, 0.0).unwrap();
+
+        let writes = pi.soft_pwm_writes();
+        let levels: Vec<bool> = writes
+            .iter()
+            .map(|call| match *call {
+                PiCall::GpioWrite { gpio: 5, high } => high,
+                ref other => panic!("Unexpected call {:?}", other),
+            })
+            .collect();
+        // it toggles every time, starting high and left low
+        assert!(levels.len() >= 6, "Only toggled {} times", levels.len());
+        for (i, &high) in levels[..levels.len() - 1].iter().enumerate() {
+            assert_eq!(high, i % 2 == 0);
+        }
+        assert_eq!(levels.last(), Some(&false));
+
+        // stopped means stopped
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert_eq!(pi.soft_pwm_writes().len(), writes.len());
+
+        let expected = [
+            PiCall::SetSoftPwm {
+                gpio: 5,
+                freq: 200,
+                duty: 0.25,
+            },
+            PiCall::SetSoftPwm {
+                gpio: 5,
+                freq: 200,
+                duty: 0.0,
+            },
+        ];
+        assert_eq!(pi.calls(), &expected);
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::*;
+
+use crate::{Error, Quiesce, Result};
+
+/// Clamps `duty` to between 0 and 1, but anything that isn't a number at
+/// all is an error.
+pub fn clamp_duty(duty: f64) -> Result<f64> {
+    if duty.is_finite() {
+        Ok(duty.max(0.0).min(1.0))
+    } else {
+        Err(Error::InvalidFraction(duty))
+    }
+}
+
+// a period has to be at least a nanosecond, or the thread would just spin
+fn period(freq: u32) -> Result<Duration> {
+    let nanos = 1_000_000_000 / u64::from(freq.max(1));
+    if freq == 0 || nanos == 0 {
+        return Err(Error::InvalidPwmFrequency(freq));
+    }
+    Ok(Duration::from_nanos(nanos))
+}
+
+/// PWM done by toggling a pin from a background thread, for when the pin
+/// isn't one of the hardware PWM ones. The timing is only as good as the
+/// scheduler, so this is fine for heaters and fans but not much else.
+///
+/// Stopping it, or dropping it, leaves the pin low.
+#[derive(Debug)]
+pub struct SoftPwm {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SoftPwm {
+    /// Starts toggling at `freq` Hz, high for `duty` of each period. `write`
+    /// sets the pin, and gets called from the background thread. `duty` is
+    /// clamped like `clamp_duty`, and `freq` has to be between 1Hz and 1GHz.
+    pub fn start<W>(freq: u32, duty: f64, mut write: W) -> Result<SoftPwm>
+    where
+        W: FnMut(bool) -> Result<()> + Send + 'static,
+    {
+        let duty = clamp_duty(duty)?;
+        let period = period(freq)?;
+        let high = Duration::from_nanos((period.as_nanos() as f64 * duty) as u64);
+        let low = period - high;
+
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            // true if it's time to quit
+            let wait = |d: Duration| stopped.recv_timeout(d) != Err(RecvTimeoutError::Timeout);
+            let mut run = || -> Result<()> {
+                loop {
+                    for &(level, time) in &[(true, high), (false, low)] {
+                        if time == Duration::from_secs(0) {
+                            continue;
+                        }
+                        write(level)?;
+                        if wait(time) {
+                            return Ok(());
+                        }
+                    }
+                }
+            };
+            if let Err(err) = run() {
+                error!("Software PWM stopped: {}", err);
+            }
+            if let Err(err) = write(false) {
+                error!("Software PWM failed to leave the pin low: {}", err);
+            }
+        });
+
+        Ok(SoftPwm {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    /// Stops toggling and waits for the pin to be left low.
+    pub fn stop(&mut self) {
+        // hanging up wakes the thread just like a message would
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Software PWM thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for SoftPwm {
+    fn drop(&mut self) {
+        self.stop()
+    }
+}
+
+impl Quiesce for SoftPwm {
+    fn quiesce(&mut self) -> Result<()> {
+        self.stop();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bad_settings() {
+        let start = |freq, duty| SoftPwm::start(freq, duty, |_| Ok(()));
+        match start(100, std::f64::NAN) {
+            Err(Error::InvalidFraction(duty)) => assert!(duty.is_nan()),
+            other => panic!("Expected a bad duty, got {:?}", other),
+        }
+        match start(0, 0.5) {
+            Err(Error::InvalidPwmFrequency(0)) => (),
+            other => panic!("Expected a bad frequency, got {:?}", other),
+        }
+        // anything faster than 1GHz would have a zero period
+        match start(2_000_000_000, 0.5) {
+            Err(Error::InvalidPwmFrequency(2_000_000_000)) => (),
+            other => panic!("Expected a bad frequency, got {:?}", other),
+        }
+        assert_eq!(clamp_duty(1.5).unwrap(), 1.0);
+        assert_eq!(clamp_duty(-1.0).unwrap(), 0.0);
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use log::*;
+use serde::Deserialize;
+
+use crate::devices::{max31865, max31865::Max31865, pca9685::Pca9685};
+use crate::{Error, HeaterZone, Result};
+
+/// The PID loop for a heater, turning temperatures into a duty cycle once
+/// it's limited to between 0 and 1, see `duty_cycle_pid`.
+pub use puddle_core::util::pid::PidController;
+
+/// A `PidController` with these gains whose output is a duty cycle.
+pub fn duty_cycle_pid(kp: f64, ki: f64, kd: f64) -> PidController {
+    PidController::new(kp, ki, kd).with_output_limits(0.0, 1.0)
+}
+
+/// A heater zone, under `[[pi.zones]]`: an RTD on its own MAX31865, and a
+/// heater on `channel` of the PCA9685 all the zones share.
+#[derive(Debug, Deserialize)]
+pub struct ZoneSettings {
+    pub name: String,
+    pub max31865: max31865::Settings,
+    pub channel: u8,
+}
+
+impl ZoneSettings {
+    pub fn make(&self, pca: &Arc<Mutex<Pca9685>>) -> Result<PcaZone> {
+        Ok(PcaZone {
+            sensor: self.max31865.make()?,
+            pca: Arc::clone(pca),
+            channel: self.channel,
+        })
+    }
+}
+
+/// Makes every configured zone, by name. They all need the `pca9685`.
+pub fn make_zones(
+    settings: &[ZoneSettings],
+    pca: Option<&Arc<Mutex<Pca9685>>>,
+) -> Result<HashMap<String, Box<dyn HeaterZone>>> {
+    let mut zones = HashMap::new();
+    for zone in settings {
+        let pca = pca.ok_or(Error::MissingDevice("pca9685"))?;
+        let made: Box<dyn HeaterZone> = Box::new(zone.make(pca)?);
+        zones.insert(zone.name.clone(), made);
+    }
+    Ok(zones)
+}
+
+/// The `HeaterZone` for real hardware, see `ZoneSettings`.
+pub struct PcaZone {
+    sensor: Max31865,
+    pca: Arc<Mutex<Pca9685>>,
+    channel: u8,
+}
+
+impl HeaterZone for PcaZone {
+    fn read_temperature(&mut self) -> Result<f32> {
+        self.sensor.read_temperature()
+    }
+
+    fn set_heater(&mut self, duty_cycle: f64) -> Result<()> {
+        let mut pca = self.pca.lock().unwrap();
+        pca.set_duty_cycle_phased(self.channel, duty_cycle, 0.0)
+    }
+}
+
+/// Reads `sensor` and runs heater `channel` at whatever `pid` says to.
+/// Returns the temperature that was read.
+pub fn drive_heater(
+    pid: &mut PidController,
+    sensor: &mut Max31865,
+    pwm: &mut Pca9685,
+    channel: u8,
+    dt: f64,
+) -> Result<f32> {
+    let measured = sensor.read_temperature()?;
+    let duty = pid.step(measured, dt);
+    trace!("Heater {} at {}*C, duty {}", channel, measured, duty);
+    pwm.set_duty_cycle_phased(channel, duty, 0.0)?;
+    Ok(measured)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_proportional_plus_integral() {
+        let mut pid = duty_cycle_pid(0.02, 0.01, 0.0);
+        pid.set_target(50.0);
+
+        // 10 degrees off is 0.2 of p, and the i adds 0.05 every half second
+        for n in 1..=4 {
+            let out = pid.step(40.0, 0.5);
+            assert_close(out, 0.2 + 0.05 * f64::from(n));
+        }
+
+        // on target, only the integral is left
+        assert_close(pid.step(50.0, 0.5), 0.2);
+    }
+
+    #[test]
+    fn test_output_clamped() {
+        let mut pid = duty_cycle_pid(1.0, 0.0, 0.0);
+        pid.set_target(50.0);
+        assert_close(pid.step(0.0, 1.0), 1.0);
+        assert_close(pid.step(100.0, 1.0), 0.0);
+    }
+
+    #[test]
+    fn test_no_windup() {
+        let mut pid = duty_cycle_pid(0.01, 0.01, 0.0);
+        pid.set_target(100.0);
+
+        // way too cold for a long time, so it's pinned at full power
+        for _ in 0..1000 {
+            assert_close(pid.step(20.0, 1.0), 1.0);
+        }
+
+        // once it overshoots it should back off right away, instead of
+        // waiting for a thousand seconds of integral to drain
+        assert!(pid.step(105.0, 1.0) < 0.5);
+    }
+}
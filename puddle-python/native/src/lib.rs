@@ -0,0 +1,292 @@
+//! Runs a `Process` right here instead of through a server, for the Python
+//! bindings. With the `python` feature, this builds the `puddle_native`
+//! module, see `python.rs`:
+//!
+//! ```python
+//! from puddle_native import Session
+//! s = Session("tests/arches/arch01.yaml")
+//! a = s.create(None, 1.0, None)
+//! b = s.create(None, 1.0, None)
+//! a, b = s.split(s.mix(a, b))
+//! print(s.flush())
+//! ```
+//!
+//! Without it, `Session` is just the plain Rust underneath, so it gets
+//! built and tested along with everything else.
+//!
+//! Droplets are just the `id` half of their `DropletId`, since a session
+//! only ever has the one process.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use config::{Config, Environment, File};
+
+use puddle_core::exec::{Actuator, Execute, Executor};
+use puddle_core::grid::{
+    DropletId, DropletInfo, Grid, GridFormatError, GridView, Location, Peripheral,
+};
+use puddle_core::process::{Manager, ProcessHandle, ProcessId, PuddleError, PuddleResult};
+use puddle_core::sim::SimExecutor;
+use puddle_pi::{RaspberryPi, Settings};
+
+#[cfg(feature = "python")]
+mod python;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The grid file couldn't be read.
+    Io(io::Error),
+    /// The grid file isn't a grid.
+    Grid(GridFormatError),
+    /// The pi couldn't be set up, or can't do what was asked.
+    Pi(String),
+    Puddle(PuddleError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Grid(err) => write!(f, "{}", err),
+            Error::Pi(why) => write!(f, "{}", why),
+            Error::Puddle(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<PuddleError> for Error {
+    fn from(err: PuddleError) -> Error {
+        Error::Puddle(err)
+    }
+}
+
+impl From<puddle_pi::Error> for Error {
+    fn from(err: puddle_pi::Error) -> Error {
+        Error::Pi(err.to_string())
+    }
+}
+
+impl From<config::ConfigError> for Error {
+    fn from(err: config::ConfigError) -> Error {
+        Error::Pi(err.to_string())
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// the executor and the session both need the pi
+struct SharedPi(Arc<Mutex<RaspberryPi>>);
+
+impl Actuator for SharedPi {
+    fn actuate(&mut self, gv: &GridView) -> PuddleResult<()> {
+        self.0.lock().unwrap().actuate(gv)
+    }
+}
+
+// the chip set up by the config file in PI_CONFIG, the same one pi-test
+// uses, held for each step like the hardware needs
+fn pi_executor(grid: &Grid) -> Result<(Executor, Arc<Mutex<RaspberryPi>>)> {
+    let path = env::var("PI_CONFIG")
+        .map_err(|_| Error::Pi("PI_CONFIG should be the pi's config file".into()))?;
+    let mut conf = Config::new();
+    conf.merge(File::with_name(&path))?;
+    conf.merge(Environment::new().separator("__"))?;
+    let settings = Settings::from_config(&mut conf)?;
+    let pi = Arc::new(Mutex::new(RaspberryPi::new(settings, grid)?));
+
+    let mut executor = Executor::new(grid.clone())?;
+    executor.set_realtime(true);
+    executor.set_actuator(Some(Box::new(SharedPi(Arc::clone(&pi)))));
+    Ok((executor, pi))
+}
+
+/// One process on its own grid.
+pub struct Session {
+    manager: Manager,
+    pid: ProcessId,
+    grid: Grid,
+    // only when it's driving a real chip
+    pi: Option<Arc<Mutex<RaspberryPi>>>,
+}
+
+impl Session {
+    /// Loads the grid at `grid_path`. With `simulate`, droplets are run
+    /// through the `SimExecutor`, otherwise they drive a Raspberry Pi.
+    pub fn new(grid_path: &str, simulate: bool, name: &str) -> Result<Session> {
+        let file = fs::File::open(grid_path).map_err(Error::Io)?;
+        let grid = Grid::from_reader(file).map_err(Error::Grid)?;
+        let (executor, pi): (Box<dyn Execute>, _) = if simulate {
+            (Box::new(SimExecutor::new(grid.clone())), None)
+        } else {
+            let (executor, pi) = pi_executor(&grid)?;
+            (Box::new(executor), Some(pi))
+        };
+        let manager = Manager::with_executor(false, grid.clone(), executor);
+        let pid = manager.new_process(name)?;
+        Ok(Session {
+            manager,
+            pid,
+            grid,
+            pi,
+        })
+    }
+
+    pub fn create(
+        &self,
+        location: Option<Location>,
+        volume: f64,
+        dimensions: Option<Location>,
+    ) -> Result<usize> {
+        let d = self.process()?.create(location, volume, dimensions)?;
+        Ok(d.id)
+    }
+
+    /// On a real chip, this runs the input's pump first. The executor only
+    /// sets electrodes, so the pumps are run here, before anything is
+    /// planned; if they fail, the process is left as it was.
+    pub fn input(&self, name: &str, volume: f64, dimensions: Location) -> Result<usize> {
+        if let (Some(pi), Some(port)) = (&self.pi, self.port(name, true)) {
+            pi.lock().unwrap().input(&port, volume)?;
+        }
+        let d = self.process()?.input(name, volume, dimensions)?;
+        Ok(d.id)
+    }
+
+    /// Like `input`, this runs the output's pump first on a real chip. That
+    /// flushes so `d` is on the chip and its volume is known.
+    pub fn output(&self, name: &str, d: usize) -> Result<()> {
+        if let (Some(pi), Some(port)) = (&self.pi, self.port(name, false)) {
+            let volume = self.volume(d)?;
+            pi.lock().unwrap().output(&port, volume)?;
+        }
+        self.process()?.output(name, self.id(d))?;
+        Ok(())
+    }
+
+    pub fn move_droplet(&self, d: usize, location: Location) -> Result<usize> {
+        let d = self.process()?.move_droplet(self.id(d), location)?;
+        Ok(d.id)
+    }
+
+    pub fn mix(&self, d1: usize, d2: usize) -> Result<usize> {
+        let d = self.process()?.mix(self.id(d1), self.id(d2))?;
+        Ok(d.id)
+    }
+
+    pub fn split(&self, d: usize) -> Result<(usize, usize)> {
+        let (d1, d2) = self.process()?.split(self.id(d))?;
+        Ok((d1.id, d2.id))
+    }
+
+    pub fn heat(&self, d: usize, temperature: f32, seconds: f64) -> Result<usize> {
+        let d = self.process()?.heat(self.id(d), temperature, seconds)?;
+        Ok(d.id)
+    }
+
+    /// Runs everything planned so far, returning the droplets left.
+    pub fn flush(&self) -> Result<Vec<DropletInfo>> {
+        Ok(self.process()?.flush()?)
+    }
+
+    fn process(&self) -> Result<ProcessHandle<'_>> {
+        Ok(self.manager.get_process(self.pid)?)
+    }
+
+    fn id(&self, id: usize) -> DropletId {
+        DropletId {
+            id,
+            process_id: self.pid,
+        }
+    }
+
+    // the input (or output) called `name`
+    fn port(&self, name: &str, input: bool) -> Option<Peripheral> {
+        let mut ports = self.grid.locations().filter_map(|(_, e)| e.peripheral);
+        ports.find(|port| match port {
+            Peripheral::Input { name: n, .. } => input && n == name,
+            Peripheral::Output { name: n, .. } => !input && n == name,
+            Peripheral::Heater { .. } => false,
+        })
+    }
+
+    // flushes so `d` is on the chip
+    fn volume(&self, d: usize) -> Result<f64> {
+        let info = self.process()?.flush()?;
+        info.iter()
+            .find(|info| info.id == self.id(d))
+            .map(|info| info.volume)
+            .ok_or(Error::Puddle(PuddleError::NonExistentDropletId(d)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use puddle_core::grid::location::yx;
+
+    fn arch(name: &str) -> String {
+        format!("{}/../../tests/arches/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    #[test]
+    fn test_dilution() {
+        let s = Session::new(&arch("arch01.yaml"), true, "dilution").unwrap();
+
+        // halve the concentration a few times by mixing with buffer and
+        // setting half aside
+        let mut sample = s.create(None, 1.0, None).unwrap();
+        let mut waste = Vec::new();
+        for _ in 0..3 {
+            let buffer = s.create(None, 1.0, None).unwrap();
+            let (kept, w) = s.split(s.mix(sample, buffer).unwrap()).unwrap();
+            sample = kept;
+            waste.push(w);
+        }
+
+        let droplets = s.flush().unwrap();
+        let mut ids: Vec<usize> = droplets.iter().map(|d| d.id.id).collect();
+        ids.sort();
+        let mut expected = waste.clone();
+        expected.push(sample);
+        expected.sort();
+        assert_eq!(ids, expected);
+        for d in &droplets {
+            assert!((d.volume - 1.0).abs() < 1e-6, "{:?}", d);
+        }
+
+        let d = s.move_droplet(sample, yx(0, 0)).unwrap();
+        let droplets = s.flush().unwrap();
+        let moved = droplets.iter().find(|info| info.id.id == d).unwrap();
+        assert_eq!(moved.location, yx(0, 0));
+    }
+
+    #[test]
+    fn test_errors() {
+        let s = Session::new(&arch("arch01.yaml"), true, "errors").unwrap();
+        let a = s.create(None, 1.0, None).unwrap();
+        let b = s.create(None, 1.0, None).unwrap();
+        let ab = s.mix(a, b).unwrap();
+        match s.mix(a, b) {
+            Err(Error::Puddle(PuddleError::PlanError(_))) => (),
+            other => panic!("Expected a PlanError, got {:?}", other),
+        }
+        // arch01 doesn't have any outputs
+        match s.output("waste", ab) {
+            Err(Error::Puddle(PuddleError::NonExistentOutput(_))) => (),
+            other => panic!("Expected NonExistentOutput, got {:?}", other),
+        }
+
+        match Session::new("no/such/grid.yaml", true, "errors") {
+            Err(Error::Io(_)) => (),
+            Err(other) => panic!("Expected an io error, got {:?}", other),
+            Ok(_) => panic!("Expected an io error"),
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! The `puddle_native` module, a thin layer over `Session`. Locations go
+//! back and forth as `(y, x)` tuples, and `PuddleError`s come out as
+//! `PuddleException`.
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use puddle_core::grid::Location;
+
+use crate::Error;
+
+create_exception!(puddle_native, PuddleException, PyException);
+
+impl From<Error> for PyErr {
+    fn from(err: Error) -> PyErr {
+        match err {
+            Error::Io(err) => PyIOError::new_err(err.to_string()),
+            Error::Grid(err) => PyValueError::new_err(err.to_string()),
+            Error::Pi(_) | Error::Puddle(_) => PuddleException::new_err(err.to_string()),
+        }
+    }
+}
+
+type Loc = (i32, i32);
+
+fn loc((y, x): Loc) -> Location {
+    Location { y, x }
+}
+
+/// One process on its own grid, loaded from `grid_path`. With `simulate`,
+/// droplets are run through the simulator, otherwise they drive a
+/// Raspberry Pi set up by the config file in PI_CONFIG.
+#[pyclass]
+struct Session {
+    inner: crate::Session,
+}
+
+#[pymethods]
+impl Session {
+    #[new]
+    #[pyo3(signature = (grid_path, simulate = true, name = "python"))]
+    fn new(grid_path: &str, simulate: bool, name: &str) -> PyResult<Session> {
+        let inner = crate::Session::new(grid_path, simulate, name)?;
+        Ok(Session { inner })
+    }
+
+    #[pyo3(signature = (location, volume, dimensions = None))]
+    fn create(
+        &self,
+        location: Option<Loc>,
+        volume: f64,
+        dimensions: Option<Loc>,
+    ) -> PyResult<usize> {
+        Ok(self
+            .inner
+            .create(location.map(loc), volume, dimensions.map(loc))?)
+    }
+
+    #[pyo3(signature = (name, volume, dimensions = (1, 1)))]
+    fn input(&self, name: &str, volume: f64, dimensions: Loc) -> PyResult<usize> {
+        Ok(self.inner.input(name, volume, loc(dimensions))?)
+    }
+
+    fn output(&self, name: &str, d: usize) -> PyResult<()> {
+        Ok(self.inner.output(name, d)?)
+    }
+
+    fn r#move(&self, d: usize, location: Loc) -> PyResult<usize> {
+        Ok(self.inner.move_droplet(d, loc(location))?)
+    }
+
+    fn mix(&self, d1: usize, d2: usize) -> PyResult<usize> {
+        Ok(self.inner.mix(d1, d2)?)
+    }
+
+    fn split(&self, d: usize) -> PyResult<(usize, usize)> {
+        Ok(self.inner.split(d)?)
+    }
+
+    fn heat(&self, d: usize, temperature: f32, seconds: f64) -> PyResult<usize> {
+        Ok(self.inner.heat(d, temperature, seconds)?)
+    }
+
+    /// Runs everything planned so far. Returns the droplets left, as a dict
+    /// from id to a dict of `location`, `dimensions` and `volume`.
+    fn flush<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let droplets = PyDict::new(py);
+        for d in self.inner.flush()? {
+            let dict = PyDict::new(py);
+            dict.set_item("location", (d.location.y, d.location.x))?;
+            dict.set_item("dimensions", (d.dimensions.y, d.dimensions.x))?;
+            dict.set_item("volume", d.volume)?;
+            droplets.set_item(d.id.id, dict)?;
+        }
+        Ok(droplets)
+    }
+}
+
+#[pymodule]
+fn puddle_native(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<Session>()?;
+    m.add("PuddleException", py.get_type::<PuddleException>())?;
+    Ok(())
+}
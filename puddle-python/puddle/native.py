@@ -0,0 +1,9 @@
+"""Runs a process right here instead of through a server.
+
+This needs the puddle_native extension, built from native/ with its python
+feature, see the README.
+"""
+
+from puddle_native import PuddleException, Session
+
+__all__ = ["PuddleException", "Session"]
@@ -0,0 +1,65 @@
+"""Tests for puddle.native, which runs processes without a server.
+
+These only run if `puddle_native` is importable, i.e. native/ was built
+with `--features python` and the library is on the path as
+puddle_native.so. The Rust underneath is tested by `cargo test` either way.
+"""
+
+import os
+
+import pytest
+
+pytest.importorskip('puddle_native')
+
+from puddle.native import PuddleException, Session  # noqa: E402
+
+ARCH = os.path.join(
+    os.path.dirname(__file__), '..', '..', 'tests', 'arches', 'arch01.yaml')
+
+
+@pytest.fixture
+def session():
+    return Session(ARCH, simulate=True)
+
+
+def test_dilution(session):
+    # halve the concentration a few times by mixing with buffer and
+    # setting half aside
+    sample = session.create(None, 1.0, None)
+    waste = []
+    for _ in range(3):
+        buffer = session.create(None, 1.0, None)
+        sample, w = session.split(session.mix(sample, buffer))
+        waste.append(w)
+
+    droplets = session.flush()
+    assert set(droplets) == {sample, *waste}
+    for d in droplets.values():
+        assert d['volume'] == pytest.approx(1.0)
+
+
+def test_move(session):
+    a = session.create((0, 0), 1.0, (1, 1))
+    a = session.move(a, (2, 3))
+    assert session.flush()[a]['location'] == (2, 3)
+
+
+def test_errors(session):
+    a = session.create(None, 1.0, None)
+    b = session.create(None, 1.0, None)
+    ab = session.mix(a, b)
+    with pytest.raises(PuddleException):
+        session.mix(a, b)
+    # arch01 doesn't have any outputs
+    with pytest.raises(PuddleException):
+        session.output('waste', ab)
+
+    with pytest.raises(IOError):
+        Session('no/such/grid.yaml')
+
+
+def test_no_pi(monkeypatch):
+    # the real backend fails with an exception, not a crash
+    monkeypatch.delenv('PI_CONFIG', raising=False)
+    with pytest.raises(PuddleException):
+        Session(ARCH, simulate=False)
@@ -1,6 +1,9 @@
 use std::error::Error;
 use std::fs::File;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
+use std::thread;
 
 use jsonrpc_core::IoHandler;
 use jsonrpc_http_server::{
@@ -15,6 +18,8 @@ use structopt::StructOpt;
 
 use futures::Future;
 
+pub mod live;
+pub mod rest;
 mod rpc;
 use rpc::Rpc;
 
@@ -32,6 +37,36 @@ pub struct Server {
     grid_file: String,
     #[structopt(long = "sync")]
     should_sync: bool,
+    // also serve the plain JSON api, see `rest::serve_rest`
+    #[structopt(long = "rest-address")]
+    rest_address: Option<std::net::SocketAddr>,
+    // also serve the live view, see `live::serve_snapshots`
+    #[structopt(long = "live-address")]
+    live_address: Option<std::net::SocketAddr>,
+}
+
+// starts a server at `addr` that answers every request itself through
+// `middleware`, returning the address it ended up on
+fn serve_middleware<M>(
+    addr: impl ToSocketAddrs,
+    threads: usize,
+    middleware: M,
+) -> io::Result<SocketAddr>
+where
+    M: Fn(Request<Body>) -> RequestMiddlewareAction + Send + Sync + 'static,
+{
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to bind to"))?;
+    let server = ServerBuilder::new(IoHandler::default())
+        .threads(threads.max(1))
+        .request_middleware(middleware)
+        .start_http(&addr)?;
+    let local_addr = *server.address();
+    // dropping the server stops it, so it gets a thread to wait on
+    thread::spawn(move || server.wait());
+    Ok(local_addr)
 }
 
 fn serve(req: Request<Body>, statik: &Static) -> RequestMiddlewareAction {
@@ -90,19 +125,27 @@ impl Server {
         debug!("threads: {}", self.threads);
         debug!("address: {}", self.address);
 
-        let grid: Grid = if self.grid_file == "-" {
-            serde_yaml::from_reader(std::io::stdin())?
+        let grid = if self.grid_file == "-" {
+            Grid::from_reader(std::io::stdin())?
         } else {
             let reader = File::open(&self.grid_file)?;
-            serde_yaml::from_reader(reader)?
+            Grid::from_reader(reader)?
         };
 
         debug!("Grid parsed.");
 
-        let manager = Arc::new(Manager::new(self.should_sync, grid));
+        let manager = Arc::new(Manager::new(self.should_sync, grid)?);
 
         debug!("Manager created.");
 
+        if let Some(addr) = self.rest_address {
+            rest::serve_rest(addr, Arc::clone(&manager), self.threads)?;
+        }
+
+        if let Some(addr) = self.live_address {
+            live::serve_snapshots(addr, Arc::clone(&manager))?;
+        }
+
         let mut io = IoHandler::default();
         io.extend_with(manager.to_delegate());
 
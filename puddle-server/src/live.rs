@@ -0,0 +1,238 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use futures::{future, Future};
+use jsonrpc_http_server::{
+    hyper::{body::Sender, header, Body, Method, Request, Response},
+    RequestMiddlewareAction,
+};
+use serde::Serialize;
+
+use puddle_core::grid::{Droplet, Grid, GridView, Snapshot, SnapshotDiff};
+use puddle_core::process::Manager;
+
+use log::*;
+
+/// How often each client checks the gridview for changes.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What gets sent to live view clients, as JSON tagged by `type`, one per
+/// server-sent event.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Update<'a> {
+    /// Sent once on connect, everything there is to see.
+    Full {
+        grid: &'a Grid,
+        droplets: Vec<&'a Droplet>,
+    },
+    /// Sent whenever something changes, see `SnapshotDiff`.
+    Delta(SnapshotDiff),
+}
+
+/// Something the live view can watch. Clients poll this every
+/// `POLL_INTERVAL`, so it should hold any locks just long enough to copy.
+pub trait Watch: Send + Sync + 'static {
+    fn grid(&self) -> Grid;
+    fn snapshot(&self) -> Snapshot;
+}
+
+impl Watch for Mutex<GridView> {
+    fn grid(&self) -> Grid {
+        self.lock().unwrap().grid.clone()
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        self.lock().unwrap().snapshot()
+    }
+}
+
+impl Watch for Manager {
+    fn grid(&self) -> Grid {
+        Manager::grid(self)
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Manager::snapshot(self)
+    }
+}
+
+/// Serves a live view of `source` at `addr` as a stream of server-sent
+/// events, returning the address it ended up on. Each client gets an
+/// `Update::Full` when it connects, then an `Update::Delta` whenever the
+/// droplets change. Each event is a single `data:` line, so a browser can
+/// just point an `EventSource` at it.
+///
+/// The server runs until the process exits, with a thread per client. A
+/// client going away only ends its own thread.
+pub fn serve_snapshots<W: Watch>(
+    addr: impl ToSocketAddrs,
+    source: Arc<W>,
+) -> io::Result<SocketAddr> {
+    let local_addr = crate::serve_middleware(addr, 1, move |req| subscribe(req, &source))?;
+    info!("Serving live view on http://{}", local_addr);
+    Ok(local_addr)
+}
+
+fn subscribe<W: Watch>(req: Request<Body>, source: &Arc<W>) -> RequestMiddlewareAction {
+    if req.method() != Method::GET {
+        return Response::builder()
+            .status(405)
+            .body("the live view only answers GET".into())
+            .unwrap()
+            .into();
+    }
+
+    let (sender, body) = Body::channel();
+    let source = Arc::clone(source);
+    thread::spawn(move || {
+        if let Err(err) = stream_to(sender, &*source) {
+            debug!("Live view client left: {}", err);
+        }
+    });
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(body)
+        .unwrap()
+        .into()
+}
+
+// only ever sends copies, so a slow client can't hold up the source; this
+// only returns once the client is gone
+fn stream_to(mut sender: Sender, source: &impl Watch) -> io::Result<()> {
+    let grid = source.grid();
+    let mut seen = source.snapshot();
+    let droplets = seen.droplets.values().collect();
+    send(
+        &mut sender,
+        &Update::Full {
+            grid: &grid,
+            droplets,
+        },
+    )?;
+
+    loop {
+        thread::sleep(POLL_INTERVAL);
+        // notice a client that hung up even when there's nothing to send
+        ready(&mut sender)?;
+
+        let now = source.snapshot();
+        let diff = now.diff(&seen);
+        if diff.is_empty() {
+            continue;
+        }
+        send(&mut sender, &Update::Delta(diff))?;
+        seen = now;
+    }
+}
+
+// waits for room to send, failing once the client is gone
+fn ready(sender: &mut Sender) -> io::Result<()> {
+    future::poll_fn(|| sender.poll_ready())
+        .wait()
+        .map_err(|err| io::Error::new(io::ErrorKind::BrokenPipe, err))
+}
+
+fn send(sender: &mut Sender, update: &Update) -> io::Result<()> {
+    let json = serde_json::to_string(update).expect("updates serialize");
+    ready(sender)?;
+    sender
+        .send_data(format!("data: {}\n\n", json).into())
+        .map_err(|_| io::ErrorKind::BrokenPipe.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpStream;
+
+    use puddle_core::grid::{location::yx, DropletId};
+    use serde_json::Value;
+
+    // just enough of an EventSource for these tests
+    fn connect(addr: SocketAddr) -> BufReader<TcpStream> {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET / HTTP/1.1\r\nHost: {}\r\n\r\n", addr).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with("HTTP/1.1 200"), "bad status: {}", line);
+        reader
+    }
+
+    // skips the headers and any chunk framing, only data lines matter
+    fn next_update(reader: &mut BufReader<TcpStream>) -> Value {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            assert!(reader.read_line(&mut line).unwrap() > 0, "stream ended");
+            let data = line.trim_start_matches("data: ");
+            if data.len() < line.len() {
+                return serde_json::from_str(data).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_live_view() {
+        let gridview = Arc::new(Mutex::new(GridView::new(Grid::rectangle(3, 3))));
+        let addr = serve_snapshots("127.0.0.1:0", Arc::clone(&gridview)).unwrap();
+        let mut client = connect(addr);
+
+        let full = next_update(&mut client);
+        assert_eq!(full["type"], "full");
+        assert_eq!(full["droplets"], Value::Array(vec![]));
+
+        let id = DropletId {
+            id: 0,
+            process_id: 0,
+        };
+        let droplet = Droplet::new(id, 1.0, yx(1, 1), yx(1, 1));
+        gridview.lock().unwrap().droplets.insert(id, droplet);
+
+        let delta = next_update(&mut client);
+        assert_eq!(delta["type"], "delta");
+        let location = &delta["added"][0]["location"];
+        assert_eq!(*location, serde_json::json!({"y": 1, "x": 1}));
+        assert_eq!(delta["removed"], Value::Null);
+
+        // other changes send the whole droplet
+        let mut gv = gridview.lock().unwrap();
+        gv.droplets.get_mut(&id).unwrap().volume = 2.0;
+        drop(gv);
+        let delta = next_update(&mut client);
+        assert_eq!(delta["changed"][0]["volume"], 2.0);
+
+        gridview.lock().unwrap().droplets.clear();
+        let delta = next_update(&mut client);
+        assert_eq!(delta["removed"][0]["id"], 0);
+
+        // hanging up shouldn't bother the server
+        drop(client);
+        let mut client = connect(addr);
+        assert_eq!(next_update(&mut client)["type"], "full");
+    }
+
+    #[test]
+    fn test_live_view_of_manager() {
+        let manager = Arc::new(Manager::new(false, Grid::rectangle(3, 3)).unwrap());
+        let addr = serve_snapshots("127.0.0.1:0", Arc::clone(&manager)).unwrap();
+        let mut client = connect(addr);
+        assert_eq!(next_update(&mut client)["type"], "full");
+
+        let p = manager.get_new_process("live");
+        p.create(Some(yx(1, 1)), 1.0, None).unwrap();
+        p.flush().unwrap();
+
+        let delta = next_update(&mut client);
+        let location = &delta["added"][0]["location"];
+        assert_eq!(*location, serde_json::json!({"y": 1, "x": 1}));
+    }
+}
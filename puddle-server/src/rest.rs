@@ -0,0 +1,300 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+
+use futures::{Future, Stream};
+use jsonrpc_http_server::{
+    hyper::{header, Body, Method, Request, Response},
+    RequestMiddlewareAction,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+
+use puddle_core::prelude::*;
+
+use log::*;
+
+// the argument names match the rpc methods of the same name
+#[derive(Deserialize)]
+struct NewProcessArgs {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct CreateArgs {
+    pid: ProcessId,
+    loc: Option<Location>,
+    vol: f64,
+    dim: Option<Location>,
+}
+
+#[derive(Deserialize)]
+struct MoveArgs {
+    pid: ProcessId,
+    d: DropletId,
+    loc: Location,
+}
+
+#[derive(Deserialize)]
+struct MixArgs {
+    pid: ProcessId,
+    d1: DropletId,
+    d2: DropletId,
+}
+
+#[derive(Deserialize)]
+struct SplitArgs {
+    pid: ProcessId,
+    d: DropletId,
+}
+
+#[derive(Deserialize)]
+struct FlushArgs {
+    pid: ProcessId,
+}
+
+// what went wrong, and how the client should hear about it
+struct RestError {
+    status: u16,
+    body: Value,
+}
+
+impl RestError {
+    fn bad_request(kind: &str, message: impl ToString) -> RestError {
+        RestError {
+            status: 400,
+            body: json!({ "kind": kind, "message": message.to_string() }),
+        }
+    }
+
+    fn not_found(path: &str) -> RestError {
+        RestError {
+            status: 404,
+            body: json!({ "kind": "NotFound", "message": format!("No endpoint at {}", path) }),
+        }
+    }
+}
+
+// the variant name, without any of its fields
+fn error_kind(err: &PuddleError) -> &'static str {
+    use PuddleError::*;
+    match err {
+        PlanError(_) => "PlanError",
+        NonExistentDropletId(_) => "NonExistentDropletId",
+        NonExistentProcess(_) => "NonExistentProcess",
+        InvalidFraction(_) => "InvalidFraction",
+        InvalidRatios(_) => "InvalidRatios",
+        InvalidPath(_) => "InvalidPath",
+        InvalidWashBuffer(..) => "InvalidWashBuffer",
+        InvalidHeatProfile(_) => "InvalidHeatProfile",
+        InvalidSnapshot(_) => "InvalidSnapshot",
+        OutOfBounds(_) => "OutOfBounds",
+        NonExistentOutput(_) => "NonExistentOutput",
+        RegionOccupied(_) => "RegionOccupied",
+        InvalidDilution(..) => "InvalidDilution",
+        NotEnoughVolume(..) => "NotEnoughVolume",
+        InvalidAgitation(..) => "InvalidAgitation",
+        ProcessIdTaken(_) => "ProcessIdTaken",
+        SensorFailed(_) => "SensorFailed",
+        ActuationFailed(_) => "ActuationFailed",
+        InvalidDuration(_) => "InvalidDuration",
+        InvalidStepDelay(_) => "InvalidStepDelay",
+        VolumeChanged(..) => "VolumeChanged",
+    }
+}
+
+impl From<PuddleError> for RestError {
+    fn from(err: PuddleError) -> RestError {
+        RestError::bad_request(error_kind(&err), err)
+    }
+}
+
+type RestResult = Result<Value, RestError>;
+
+/// Serves a JSON-over-HTTP interface to `manager`'s processes at `addr`,
+/// returning the address it ended up on. Requests are handled on `threads`
+/// threads.
+///
+/// | endpoint              | body                   | returns              |
+/// |-----------------------|------------------------|----------------------|
+/// | `POST /new_process`   | `{name}`               | `ProcessId`          |
+/// | `POST /create`        | `{pid, loc, vol, dim}` | `DropletId`          |
+/// | `POST /move`          | `{pid, d, loc}`        | `DropletId`          |
+/// | `POST /mix`           | `{pid, d1, d2}`        | `DropletId`          |
+/// | `POST /split`         | `{pid, d}`             | `[DropletId; 2]`     |
+/// | `POST /flush`         | `{pid}`                | `Vec<DropletInfo>`   |
+/// | `GET /droplets?pid=N` |                        | `Vec<DropletInfo>`   |
+///
+/// `GET /droplets` is where the droplets are right now, without running
+/// anything; `POST /flush` runs everything queued first. Failures come back
+/// as a 4xx with `{kind, message}`, where `kind` is the `PuddleError`
+/// variant if that's what it was. Each request goes through the `Process`
+/// methods, which lock the system for the whole command, so concurrent
+/// requests are safe; they're just planned one at a time.
+pub fn serve_rest(
+    addr: impl ToSocketAddrs,
+    manager: Arc<Manager>,
+    threads: usize,
+) -> io::Result<SocketAddr> {
+    let local_addr =
+        crate::serve_middleware(addr, threads, move |req| respond(req, Arc::clone(&manager)))?;
+    info!("Serving REST api on http://{}", local_addr);
+    Ok(local_addr)
+}
+
+fn respond(request: Request<Body>, manager: Arc<Manager>) -> RequestMiddlewareAction {
+    debug!("{} {}", request.method(), request.uri());
+    let (parts, body) = request.into_parts();
+    let response = body.concat2().map(move |body| {
+        let path = parts.uri.path();
+        let query = parts.uri.query().unwrap_or("");
+        let (status, body) = match handle(&parts.method, path, query, &body, &manager) {
+            Ok(value) => (200, value),
+            Err(err) => (err.status, err.body),
+        };
+        Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.to_string().into())
+            .expect("status and header are valid")
+    });
+    RequestMiddlewareAction::Respond {
+        should_validate_hosts: true,
+        response: Box::new(response),
+    }
+}
+
+fn handle(method: &Method, path: &str, query: &str, body: &[u8], manager: &Manager) -> RestResult {
+    match (method, path) {
+        (&Method::GET, "/droplets") => {
+            let pid = query
+                .split('&')
+                .find(|kv| kv.starts_with("pid="))
+                .map(|kv| &kv["pid=".len()..])
+                .ok_or_else(|| RestError::bad_request("BadRequest", "missing ?pid="))?;
+            let pid: ProcessId = pid
+                .parse()
+                .map_err(|err| RestError::bad_request("BadRequest", err))?;
+            // just to check it exists, this doesn't run anything
+            manager.get_process(pid)?;
+            let info: Vec<DropletInfo> = manager
+                .snapshot()
+                .droplets
+                .values()
+                .filter(|d| d.id.process_id == pid)
+                .map(|d| d.info())
+                .collect();
+            Ok(json!(info))
+        }
+        (&Method::POST, "/new_process") => {
+            let args: NewProcessArgs = parse(body)?;
+            Ok(json!(manager.new_process(args.name)?))
+        }
+        (&Method::POST, "/create") => {
+            let args: CreateArgs = parse(body)?;
+            let p = manager.get_process(args.pid)?;
+            Ok(json!(p.create(args.loc, args.vol, args.dim)?))
+        }
+        (&Method::POST, "/move") => {
+            let args: MoveArgs = parse(body)?;
+            let p = manager.get_process(args.pid)?;
+            Ok(json!(p.move_droplet(args.d, args.loc)?))
+        }
+        (&Method::POST, "/mix") => {
+            let args: MixArgs = parse(body)?;
+            let p = manager.get_process(args.pid)?;
+            Ok(json!(p.mix(args.d1, args.d2)?))
+        }
+        (&Method::POST, "/split") => {
+            let args: SplitArgs = parse(body)?;
+            let p = manager.get_process(args.pid)?;
+            Ok(json!(p.split(args.d)?))
+        }
+        (&Method::POST, "/flush") => {
+            let args: FlushArgs = parse(body)?;
+            let p = manager.get_process(args.pid)?;
+            Ok(json!(p.flush()?))
+        }
+        _ => Err(RestError::not_found(path)),
+    }
+}
+
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, RestError> {
+    serde_json::from_slice(body).map_err(|err| RestError::bad_request("BadRequest", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    // just enough of an http client for these tests
+    fn call(addr: SocketAddr, method: &str, path: &str, body: Value) -> (u16, Value) {
+        let body = body.to_string();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            addr,
+            body.len(),
+            body
+        )
+        .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let (_head, body) = response.split_at(response.find("\r\n\r\n").unwrap() + 4);
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn test_create_then_move() {
+        let manager = Arc::new(Manager::new(false, Grid::rectangle(5, 5)).unwrap());
+        let addr = serve_rest("127.0.0.1:0", manager, 2).unwrap();
+
+        let (status, pid) = call(addr, "POST", "/new_process", json!({"name": "rest"}));
+        assert_eq!(status, 200);
+
+        let create = json!({"pid": pid, "loc": {"y": 0, "x": 0}, "vol": 1.0, "dim": null});
+        let (status, id) = call(addr, "POST", "/create", create);
+        assert_eq!(status, 200);
+
+        let mv = json!({"pid": pid, "d": id, "loc": {"y": 3, "x": 2}});
+        let (status, moved) = call(addr, "POST", "/move", mv);
+        assert_eq!(status, 200);
+
+        // nothing has run yet, so there's nothing on the grid
+        let path = format!("/droplets?pid={}", pid);
+        let (status, droplets) = call(addr, "GET", &path, json!(null));
+        assert_eq!(status, 200);
+        assert_eq!(droplets, json!([]));
+
+        let (status, flushed) = call(addr, "POST", "/flush", json!({ "pid": pid }));
+        assert_eq!(status, 200);
+        let (status, droplets) = call(addr, "GET", &path, json!(null));
+        assert_eq!(status, 200);
+        assert_eq!(droplets, flushed);
+        assert_eq!(droplets.as_array().unwrap().len(), 1);
+        assert_eq!(droplets[0]["id"], moved);
+        assert_eq!(droplets[0]["location"], json!({"y": 3, "x": 2}));
+
+        // the old droplet is gone, so moving it again is an error
+        let mv = json!({"pid": pid, "d": id, "loc": {"y": 0, "x": 0}});
+        let (status, err) = call(addr, "POST", "/move", mv);
+        assert_eq!(status, 400);
+        assert_eq!(err["kind"], "PlanError");
+
+        let (status, err) = call(addr, "GET", "/droplets?pid=99", json!(null));
+        assert_eq!(status, 400);
+        assert_eq!(err["kind"], "NonExistentProcess");
+
+        let (status, _) = call(addr, "POST", "/nope", json!({}));
+        assert_eq!(status, 404);
+    }
+}